@@ -0,0 +1,228 @@
+use crate::hal::Quaternion;
+use crate::real::{FineNumber, Real};
+
+/// Length of a vector from its squared length. The square root of micromath is off by up to a
+/// few percent, one Newton step makes it accurate enough to keep the quaternion normalized.
+fn length<T: Real>(squared: T) -> T {
+    let root = squared.sqrt();
+    if root > T::default() { T::from_f32(0.5) * (root + squared / root) } else { root }
+}
+
+/// Mahony attitude and heading reference system, the math runs on T and the interface is f32.
+/// The gyro rates are integrated into a quaternion, the error between the measured and the
+/// estimated direction of gravity is fed back through a PI controller. The integral of the
+/// error is the estimate of the gyro bias. Without a magnetometer the yaw bias stays unknown
+/// while the drone is level.
+#[derive(Copy, Clone)]
+pub struct Mahony<T: Real = FineNumber> {
+    kp: T,          // proportional gain, in 1/s
+    ki: T,          // integral gain, in 1/s^2
+    q: [T; 4],      // attitude, body to earth frame, as w, x, y, z
+    integral: [T; 3], // integral feedback, the negative gyro bias in rad/s
+}
+
+impl<T: Real> Default for Mahony<T> {
+    fn default() -> Self {
+        Mahony::new(1.0, 0.05)
+    }
+}
+
+impl<T: Real> Mahony<T> {
+    pub fn new(kp: f32, ki: f32) -> Self {
+        let zero = T::default();
+        Mahony {
+            kp: T::from_f32(kp),
+            ki: T::from_f32(ki),
+            q: [T::from_f32(1.0), zero, zero, zero],
+            integral: [zero; 3],
+        }
+    }
+
+    pub fn set_gains(&mut self, kp: f32, ki: f32) {
+        self.kp = T::from_f32(kp);
+        self.ki = T::from_f32(ki);
+    }
+
+    /// Update with the accelerometer in any unit and the gyro rates in rad/s, both in the body
+    /// frame with z up. Returns the new attitude.
+    pub fn update(&mut self, accel: [f32; 3], gyro: [f32; 3], dt: f32) -> Quaternion {
+        let [ax, ay, az] = accel.map(T::from_f32);
+        let [mut gx, mut gy, mut gz] = gyro.map(T::from_f32);
+        let dt = T::from_f32(dt);
+        let [w, x, y, z] = self.q;
+        let two = T::from_f32(2.0);
+
+        // Without a measurement (free fall, sensor error) the gyro is integrated on its own
+        let norm = length(ax * ax + ay * ay + az * az);
+        if norm > T::default() {
+            let [ax, ay, az] = [ax / norm, ay / norm, az / norm];
+
+            // Direction of gravity in the body frame as estimated by the quaternion
+            let vx = two * (x * z - w * y);
+            let vy = two * (w * x + y * z);
+            let vz = w * w - x * x - y * y + z * z;
+
+            // Error is the cross product of the measured and the estimated direction
+            let error = [ay * vz - az * vy, az * vx - ax * vz, ax * vy - ay * vx];
+
+            for (integral, e) in self.integral.iter_mut().zip(error) {
+                *integral += self.ki * e * dt;
+            }
+            gx += self.kp * error[0] + self.integral[0];
+            gy += self.kp * error[1] + self.integral[1];
+            gz += self.kp * error[2] + self.integral[2];
+        }
+
+        // Integrate the rate of change of the quaternion, q' = q * (0, g) / 2
+        let half = T::from_f32(0.5) * dt;
+        let q = [
+            w + (-x * gx - y * gy - z * gz) * half,
+            x + (w * gx + y * gz - z * gy) * half,
+            y + (w * gy - x * gz + z * gx) * half,
+            z + (w * gz + x * gy - y * gx) * half,
+        ];
+
+        let norm = length(q[0] * q[0] + q[1] * q[1] + q[2] * q[2] + q[3] * q[3]);
+        if norm > T::default() {
+            self.q = q.map(|c| c / norm);
+        }
+        self.quaternion()
+    }
+
+    pub fn quaternion(&self) -> Quaternion {
+        let [w, x, y, z] = self.q.map(T::to_f32);
+        Quaternion { w, x, y, z }
+    }
+
+    /// Estimated gyro bias in rad/s, subtract it from the gyro rates
+    pub fn gyro_bias(&self) -> [f32; 3] {
+        self.integral.map(|i| -i.to_f32())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yaw_pitch_roll::YawPitchRoll;
+    use crate::test_support::{DT, noise};
+
+    fn multiply(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+        [
+            a[0] * b[0] - a[1] * b[1] - a[2] * b[2] - a[3] * b[3],
+            a[0] * b[1] + a[1] * b[0] + a[2] * b[3] - a[3] * b[2],
+            a[0] * b[2] - a[1] * b[3] + a[2] * b[0] + a[3] * b[1],
+            a[0] * b[3] + a[1] * b[2] - a[2] * b[1] + a[3] * b[0],
+        ]
+    }
+
+    /// Synthetic motion, integrated exactly from the body rates
+    struct Motion {
+        q: [f32; 4],
+    }
+
+    impl Motion {
+        /// Start at a roll about x and a pitch about y
+        fn new(roll: f32, pitch: f32) -> Self {
+            let roll = [(roll / 2.0).cos(), (roll / 2.0).sin(), 0.0, 0.0];
+            let pitch = [(pitch / 2.0).cos(), 0.0, (pitch / 2.0).sin(), 0.0];
+            Motion { q: multiply(pitch, roll) }
+        }
+
+        /// Rotate with the body rates for one step, returns what the accelerometer measures
+        fn step(&mut self, rates: [f32; 3]) -> [f32; 3] {
+            let angle = (rates[0] * rates[0] + rates[1] * rates[1] + rates[2] * rates[2]).sqrt() * DT;
+            if angle > 0.0 {
+                let s = (angle / 2.0).sin() / (angle / DT);
+                self.q = multiply(self.q, [(angle / 2.0).cos(), rates[0] * s, rates[1] * s, rates[2] * s]);
+            }
+            self.gravity()
+        }
+
+        /// Direction of gravity in the body frame
+        fn gravity(&self) -> [f32; 3] {
+            let [w, x, y, z] = self.q;
+            [2.0 * (x * z - w * y), 2.0 * (w * x + y * z), w * w - x * x - y * y + z * z]
+        }
+    }
+
+    /// Angle between the estimated and the true attitude
+    fn error(estimate: Quaternion, truth: [f32; 4]) -> f32 {
+        let dot = estimate.w * truth[0] + estimate.x * truth[1] + estimate.y * truth[2] + estimate.z * truth[3];
+        2.0 * dot.abs().min(1.0).acos()
+    }
+
+    #[test]
+    fn test_converges_to_tilt() {
+        // Standing still at a tilt, starting from level. Without the integral, which would take
+        // the error of the start as a bias and decay slowly
+        let mut ahrs = Mahony::<f32>::new(1.0, 0.0);
+        let mut motion = Motion::new(0.4, -0.3);
+        let mut estimate = ahrs.quaternion();
+        for _ in 0..1000 {
+            estimate = ahrs.update(motion.step([0.0; 3]), [0.0; 3], DT);
+        }
+
+        // The yaw is not observed, so only the tilt is compared
+        let estimated = Motion { q: [estimate.w, estimate.x, estimate.y, estimate.z] }.gravity();
+        let tilt_error = estimated.iter().zip(motion.gravity()).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+        assert!(tilt_error < 1e-4, "{:?} {:?}", estimated, motion.gravity());
+
+        let angles = YawPitchRoll::from(estimate);
+        let truth = YawPitchRoll::from(Quaternion { w: motion.q[0], x: motion.q[1], y: motion.q[2], z: motion.q[3] });
+        assert!((angles.roll - truth.roll).abs() < 1e-3, "{:?} {:?}", angles, truth);
+        assert!((angles.pitch - truth.pitch).abs() < 1e-3, "{:?} {:?}", angles, truth);
+    }
+
+    #[test]
+    fn test_estimates_gyro_bias() {
+        // Standing still with a biased gyro, the bias of the tilt axes is found
+        let bias = [0.03, -0.02, 0.0];
+        let mut ahrs = Mahony::<f32>::new(1.0, 0.1);
+        let mut motion = Motion::new(0.0, 0.0);
+        for _ in 0..6000 {
+            ahrs.update(motion.step([0.0; 3]), bias, DT);
+        }
+        let estimated = ahrs.gyro_bias();
+        assert!((estimated[0] - bias[0]).abs() < 2e-3, "{:?}", estimated);
+        assert!((estimated[1] - bias[1]).abs() < 2e-3, "{:?}", estimated);
+        assert!(error(ahrs.quaternion(), motion.q) < 0.01);
+    }
+
+    #[test]
+    fn test_tracks_large_angles() {
+        // Pitch up to almost vertical and roll over, where Euler angles break down, with gyro
+        // and accelerometer noise
+        let mut ahrs = Mahony::<f32>::default();
+        let mut motion = Motion::new(0.0, 0.0);
+        let mut max_error: f32 = 0.0;
+        for k in 0..800 {
+            let t = k as f32 * DT;
+            let rates = [if t > 4.0 { 1.5 } else { 0.0 }, if t < 2.0 { 0.75 } else { 0.0 }, 0.3];
+            let gravity = motion.step(rates);
+            let accel = gravity.map(|g| g + 0.05 * noise(k));
+            let gyro = [rates[0] + 0.02 * noise(k + 1), rates[1] + 0.02 * noise(k + 2), rates[2] + 0.02 * noise(k + 3)];
+            let estimate = ahrs.update(accel, gyro, DT);
+            max_error = max_error.max(error(estimate, motion.q));
+        }
+        assert!(max_error < 0.05, "{}", max_error);
+    }
+
+    #[cfg(feature = "fixed-point")]
+    #[test]
+    fn test_fixed_point_matches_f32() {
+        use crate::test_support::max_difference;
+        let mut float = Mahony::<f32>::default();
+        let mut fixed = Mahony::<crate::real::Q24>::default();
+        let mut motion = Motion::new(0.2, 0.1);
+        let input = |k: u32| {
+            let t = k as f32 * DT;
+            let rates = [0.5 * (t * 1.5).cos(), -0.4 * (t * 0.7).sin(), 0.1];
+            (motion.step(rates).map(|g| g + 0.05 * noise(k)), rates)
+        };
+        let max_error = max_difference(input,
+            |(accel, rates)| float.update(accel, rates, DT),
+            |(accel, rates)| fixed.update(accel, rates, DT),
+            |a, b| error(a, [b.w, b.x, b.y, b.z]));
+        assert!(max_error < 5e-3, "{}", max_error);
+    }
+}
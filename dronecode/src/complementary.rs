@@ -0,0 +1,91 @@
+use crate::real::{Number, Real};
+
+/// Complementary filter of one axis, the math runs on T and the interface is f32.
+/// The gyro rate is integrated and the angle is pulled towards the accelerometer angle with the
+/// time constant tau: changes faster than tau come from the gyro, slower ones from the accelerometer.
+#[derive(Copy, Clone)]
+pub struct ComplementaryFilter<T: Real = Number> {
+    tau: T,     // time constant in seconds
+    angle: T,   // the angle calculated by the filter
+}
+
+impl<T: Real> Default for ComplementaryFilter<T> {
+    fn default() -> Self {
+        ComplementaryFilter::new(0.5)
+    }
+}
+
+impl<T: Real> ComplementaryFilter<T> {
+    pub fn new(tau: f32) -> Self {
+        ComplementaryFilter { tau: T::from_f32(tau), angle: T::default() }
+    }
+
+    // Update the filter and retrieve the angle
+    pub fn update(&mut self, new_angle: f32, new_rate: f32, dt: f32) -> f32 {
+        let [new_angle, new_rate, dt] = [new_angle, new_rate, dt].map(T::from_f32);
+
+        // Weight of the gyro path, a time constant of zero follows the accelerometer only
+        let alpha = self.tau / (self.tau + dt);
+        self.angle = alpha * (self.angle + new_rate * dt) + (T::from_f32(1.0) - alpha) * new_angle;
+
+        self.angle.to_f32()
+    }
+
+    // Set the time constant in seconds
+    pub fn set_time_constant(&mut self, tau: f32) {
+        self.tau = T::from_f32(tau);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{DT, noise};
+
+    #[test]
+    fn test_converges_to_accelerometer() {
+        // Standing still at a tilt with a gyro bias, the error left is bias * tau
+        let mut filter = ComplementaryFilter::<f32>::new(0.5);
+        let mut angle = 0.0;
+        for k in 0..1000 {
+            angle = filter.update(0.3 + 0.05 * noise(k), 0.02, DT);
+        }
+        assert!((angle - 0.3 - 0.02 * 0.5).abs() < 5e-3, "{}", angle);
+
+        // Without a time constant the accelerometer angle is passed through
+        let mut filter = ComplementaryFilter::<f32>::new(0.0);
+        assert_eq!(filter.update(0.7, 5.0, DT), 0.7);
+    }
+
+    #[test]
+    fn test_rejects_accelerometer_noise() {
+        // A swinging angle, the gyro follows it and the accelerometer is noisy
+        let mut filter = ComplementaryFilter::<f32>::new(0.5);
+        let (mut filter_error, mut accelerometer_error) = (0.0_f32, 0.0_f32);
+        for k in 0..2000 {
+            let t = (k + 1) as f32 * DT;
+            let angle = 0.4 * (t * 1.5).sin();
+            let rate = 0.6 * (t * 1.5).cos();
+            let measured = angle + 0.1 * noise(k);
+            let estimate = filter.update(measured, rate, DT);
+            if k >= 500 {
+                filter_error = filter_error.max((estimate - angle).abs());
+                accelerometer_error = accelerometer_error.max((measured - angle).abs());
+            }
+        }
+        assert!(filter_error < 0.2 * accelerometer_error, "{} {}", filter_error, accelerometer_error);
+    }
+
+    #[cfg(feature = "fixed-point")]
+    #[test]
+    fn test_fixed_point_matches_f32() {
+        use crate::test_support::{max_difference, swinging_angle};
+        let mut float = ComplementaryFilter::<f32>::default();
+        let mut fixed = ComplementaryFilter::<crate::real::Q16>::default();
+        let max_error = max_difference(swinging_angle,
+            |(angle, rate)| float.update(angle, rate, DT),
+            |(angle, rate)| fixed.update(angle, rate, DT),
+            |a, b| (a - b).abs());
+        assert!(max_error < 2e-3, "{}", max_error);
+    }
+}
@@ -0,0 +1,302 @@
+use protocol::NackReason;
+use crate::hal::{Flash, FlashError};
+use crate::imu_calibration::ImuCalibration;
+
+/// Size of the external flash, 128 KiB
+pub const FLASH_SIZE: usize = 0x20000;
+
+/// The last two kilobytes of the flash hold the configuration, the flash log uses the rest
+pub const CONFIG_SIZE: usize = 0x800;
+const CONFIG_ADDRESS: usize = FLASH_SIZE - CONFIG_SIZE;
+
+/// Largest value that can be stored in a region
+const MAX_VALUE_LEN: usize = 63;
+
+/// Marker in the first byte of a slot that holds a value, erased flash reads as 0xff
+const SLOT_USED: u8 = 0;
+
+/// Part of the configuration that holds one value. Without erasing the chip flash bits can only
+/// be cleared, so every change is written to the next free slot and the last used slot is current.
+pub struct Region {
+    offset: usize,
+    len: usize,     // length of the value, each slot also has a marker byte
+    slots: usize,
+}
+
+/// Number of starts of the drone, a u32. It is the session authenticated packets are bound to,
+/// so it is only counted when a key is set. It was added in front of the other regions, so
+/// they kept their place in the flash.
+pub const BOOT_COUNT: Region = Region { offset: 0, len: 4, slots: 204 };
+
+/// System id of the drone in the packet header
+pub const VEHICLE_ID: Region = Region { offset: 0x400, len: 1, slots: 128 };
+
+/// Gyro bias of the IMU calibration, three f32
+pub const GYRO_BIAS: Region = Region { offset: 0x500, len: 12, slots: 32 };
+
+/// Accelerometer offsets and scales of the IMU calibration, six f32. The six orientation
+/// calibration is rarely repeated, so it gets fewer slots.
+pub const ACCEL_CALIBRATION: Region = Region { offset: 0x6a0, len: 24, slots: 12 };
+
+/// All regions, they are kept when the flash log is erased
+const REGIONS: [Region; 4] = [BOOT_COUNT, VEHICLE_ID, GYRO_BIAS, ACCEL_CALIBRATION];
+
+/// Vehicle id used when none was stored
+pub const DEFAULT_VEHICLE_ID: u8 = 1;
+
+impl Region {
+    fn address(&self, slot: usize) -> u32 {
+        (CONFIG_ADDRESS + self.offset + slot * (self.len + 1)) as u32
+    }
+
+    fn slot_used(&self, flash: &mut impl Flash, slot: usize) -> bool {
+        let mut marker = [0u8; 1];
+        match flash.read(self.address(slot), &mut marker) {
+            Ok(()) => marker[0] == SLOT_USED,
+            Err(_) => false,
+        }
+    }
+
+    /// Number of used slots, slots are used in order so a binary search finds the first free one
+    fn used_slots(&self, flash: &mut impl Flash) -> usize {
+        let (mut low, mut high) = (0, self.slots);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.slot_used(flash, mid) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    /// Read the current value, returns false when it was never stored
+    pub fn read(&self, flash: &mut impl Flash, value: &mut [u8]) -> bool {
+        let used = self.used_slots(flash);
+        if used == 0 {
+            return false;
+        }
+        let len = value.len().min(self.len);
+        flash.read(self.address(used - 1) + 1, &mut value[..len]).is_ok()
+    }
+
+    /// Every slot is used, no new value can be stored until the flash is erased
+    pub fn full(&self, flash: &mut impl Flash) -> bool {
+        self.used_slots(flash) == self.slots
+    }
+
+    /// Store a new value in the next free slot
+    pub fn write(&self, flash: &mut impl Flash, value: &[u8]) -> Result<(), NackReason> {
+        let used = self.used_slots(flash);
+        if used == self.slots {
+            return Err(NackReason::ConfigFull);
+        }
+
+        let mut slot = [SLOT_USED; MAX_VALUE_LEN + 1];
+        let len = value.len().min(self.len);
+        slot[1..=len].copy_from_slice(&value[..len]);
+        flash.write(self.address(used), &slot[..=self.len]).map_err(|_| NackReason::FlashError)
+    }
+
+    /// Read a value of little endian f32s, returns false when it was never stored
+    pub fn read_floats(&self, flash: &mut impl Flash, values: &mut [f32]) -> bool {
+        let mut bytes = [0u8; MAX_VALUE_LEN];
+        if !self.read(flash, &mut bytes) {
+            return false;
+        }
+        for (value, chunk) in values.iter_mut().zip(bytes[..self.len].chunks_exact(4)) {
+            *value = f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        true
+    }
+
+    /// Store a value of f32s
+    pub fn write_floats(&self, flash: &mut impl Flash, values: &[f32]) -> Result<(), NackReason> {
+        let mut bytes = [0u8; MAX_VALUE_LEN];
+        for (chunk, value) in bytes.chunks_exact_mut(4).zip(values) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        self.write(flash, &bytes[..values.len() * 4])
+    }
+}
+
+/// Stored vehicle id, or the default when none was stored
+pub fn vehicle_id(flash: &mut impl Flash) -> u8 {
+    let mut id = [DEFAULT_VEHICLE_ID];
+    VEHICLE_ID.read(flash, &mut id);
+    id[0]
+}
+
+/// Count this start of the drone, returns the new session and whether it was stored. When the
+/// region is full the count does not increase until the flash log is erased, so the session
+/// of an earlier start is repeated.
+pub fn start_session(flash: &mut impl Flash) -> (u32, bool) {
+    let mut count = [0u8; 4];
+    BOOT_COUNT.read(flash, &mut count);
+    next_session(flash, u32::from_le_bytes(count))
+}
+
+/// Store the session after the given one, returns it and whether it was stored
+pub fn next_session(flash: &mut impl Flash, session: u32) -> (u32, bool) {
+    let next = session.wrapping_add(1);
+    (next, BOOT_COUNT.write(flash, &next.to_le_bytes()).is_ok())
+}
+
+/// Stored IMU calibration, the parts that were never stored keep their defaults
+pub fn imu_calibration(flash: &mut impl Flash) -> ImuCalibration {
+    let mut calibration = ImuCalibration::default();
+    GYRO_BIAS.read_floats(flash, &mut calibration.gyro_bias);
+
+    let mut accel = [0.0; 6];
+    if ACCEL_CALIBRATION.read_floats(flash, &mut accel) {
+        calibration.accel_offset.copy_from_slice(&accel[..3]);
+        calibration.accel_scale.copy_from_slice(&accel[3..]);
+    }
+    calibration
+}
+
+/// Erase the whole flash chip. The current configuration values are written back, which also
+/// frees the slots of older values. The boot count is written first, sessions that start over
+/// would accept packets of earlier sessions again. A value that is not written back is an error.
+pub fn erase_flash(flash: &mut impl Flash) -> Result<(), FlashError> {
+    let mut values = [[0u8; MAX_VALUE_LEN]; REGIONS.len()];
+    let mut stored = [false; REGIONS.len()];
+    for ((region, value), stored) in REGIONS.iter().zip(values.iter_mut()).zip(stored.iter_mut()) {
+        *stored = region.read(flash, value);
+    }
+
+    flash.chip_erase()?;
+
+    let mut result = Ok(());
+    for ((region, value), stored) in REGIONS.iter().zip(values.iter()).zip(stored.iter()) {
+        if *stored && region.write(flash, value).is_err() {
+            result = Err(FlashError);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::mock::MockHal;
+
+    #[test]
+    fn test_regions_fit() {
+        let mut end = 0;
+        for region in REGIONS {
+            assert!(region.offset >= end && region.len <= MAX_VALUE_LEN);
+            end = region.offset + region.slots * (region.len + 1);
+        }
+        assert!(end <= CONFIG_SIZE);
+    }
+
+    #[test]
+    fn test_slots_used_in_order() {
+        let mut hal = MockHal::new();
+        let mut id = [0u8];
+        assert!(!VEHICLE_ID.read(&mut hal, &mut id));
+        assert_eq!(vehicle_id(&mut hal), DEFAULT_VEHICLE_ID);
+
+        for k in 1..=3 {
+            VEHICLE_ID.write(&mut hal, &[k]).unwrap();
+            assert_eq!(VEHICLE_ID.used_slots(&mut hal), k as usize);
+            assert_eq!(vehicle_id(&mut hal), k);
+        }
+
+        // The other regions are not touched
+        assert_eq!(start_session(&mut hal), (1, true));
+        assert_eq!(start_session(&mut hal), (2, true));
+        assert_eq!(vehicle_id(&mut hal), 3);
+
+        let mut bias = [0.0; 3];
+        assert!(!GYRO_BIAS.read_floats(&mut hal, &mut bias));
+        GYRO_BIAS.write_floats(&mut hal, &[0.5, -1.25, 3.0]).unwrap();
+        assert!(GYRO_BIAS.read_floats(&mut hal, &mut bias));
+        assert_eq!(bias, [0.5, -1.25, 3.0]);
+    }
+
+    #[test]
+    fn test_full_region() {
+        let mut hal = MockHal::new();
+        let values: [[f32; 6]; 2] = [[1.0, 2.0, 3.0, 0.9, 1.0, 1.1], [-1.0, 0.0, 1.0, 1.0, 1.0, 1.0]];
+        for k in 0..ACCEL_CALIBRATION.slots {
+            ACCEL_CALIBRATION.write_floats(&mut hal, &values[k % 2]).unwrap();
+        }
+        assert_eq!(ACCEL_CALIBRATION.write_floats(&mut hal, &values[0]), Err(NackReason::ConfigFull));
+
+        // The refused value was not stored, the last one is still current
+        let mut stored = [0.0; 6];
+        assert!(ACCEL_CALIBRATION.read_floats(&mut hal, &mut stored));
+        assert_eq!(stored, values[(ACCEL_CALIBRATION.slots - 1) % 2]);
+    }
+
+    #[test]
+    fn test_erase_keeps_values() {
+        let mut hal = MockHal::new();
+        hal.write(0, &[0x12, 0x34]).unwrap();
+        VEHICLE_ID.write(&mut hal, &[7]).unwrap();
+        for k in 0..GYRO_BIAS.slots {
+            GYRO_BIAS.write_floats(&mut hal, &[k as f32, 0.0, 0.0]).unwrap();
+        }
+        assert_eq!(GYRO_BIAS.write_floats(&mut hal, &[0.0; 3]), Err(NackReason::ConfigFull));
+
+        // The log is gone, the current values are written back to the first slot
+        erase_flash(&mut hal).unwrap();
+        assert_eq!(hal.flash[..2], [0xff, 0xff]);
+        assert_eq!(vehicle_id(&mut hal), 7);
+        assert_eq!(VEHICLE_ID.used_slots(&mut hal), 1);
+        assert_eq!(GYRO_BIAS.used_slots(&mut hal), 1);
+        assert_eq!(imu_calibration(&mut hal).gyro_bias, [(GYRO_BIAS.slots - 1) as f32, 0.0, 0.0]);
+
+        // Regions that were never stored stay empty, and the freed slots can be used again
+        assert_eq!(BOOT_COUNT.used_slots(&mut hal), 0);
+        assert_eq!(imu_calibration(&mut hal).accel_scale, ImuCalibration::default().accel_scale);
+        GYRO_BIAS.write_floats(&mut hal, &[1.0, 2.0, 3.0]).unwrap();
+        assert_eq!(imu_calibration(&mut hal).gyro_bias, [1.0, 2.0, 3.0]);
+    }
+
+    /// Flash that stops taking writes after the chip erase
+    struct FailingWriteBack {
+        hal: MockHal,
+        erased: bool,
+    }
+
+    impl Flash for FailingWriteBack {
+        fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+            self.hal.read(address, buf)
+        }
+
+        fn write(&mut self, address: u32, bytes: &[u8]) -> Result<(), FlashError> {
+            if self.erased { Err(FlashError) } else { self.hal.write(address, bytes) }
+        }
+
+        fn chip_erase(&mut self) -> Result<(), FlashError> {
+            self.erased = true;
+            self.hal.chip_erase()
+        }
+    }
+
+    #[test]
+    fn test_sessions() {
+        let mut hal = MockHal::new();
+        assert_eq!(start_session(&mut hal), (1, true));
+        assert_eq!(start_session(&mut hal), (2, true));
+
+        // A full region repeats the session until the flash is erased, then the sessions go on
+        while !BOOT_COUNT.full(&mut hal) {
+            start_session(&mut hal);
+        }
+        let last = BOOT_COUNT.slots as u32;
+        assert_eq!(start_session(&mut hal), (last + 1, false));
+        assert_eq!(start_session(&mut hal), (last + 1, false));
+        erase_flash(&mut hal).unwrap();
+        assert_eq!(next_session(&mut hal, last + 1), (last + 2, true));
+        assert_eq!(start_session(&mut hal), (last + 3, true));
+
+        // Losing the boot count in the erase is reported
+        let mut flash = FailingWriteBack { hal, erased: false };
+        assert_eq!(erase_flash(&mut flash), Err(FlashError));
+    }
+}
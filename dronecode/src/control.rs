@@ -1,84 +1,298 @@
-use alloc::vec::Vec;
-use tudelft_quadrupel::barometer::{read_pressure, read_temperature};
-use protocol::{self, Message, Datalog, WorkingModes};
-use tudelft_quadrupel::battery::read_battery;
-use tudelft_quadrupel::block;
-use tudelft_quadrupel::led::{Blue, Green, Red, Yellow};
-use tudelft_quadrupel::motor::{get_motors, set_motor_max};
-use tudelft_quadrupel::time::{set_tick_frequency, wait_for_next_tick, Instant};
-use tudelft_quadrupel::mpu::read_dmp_bytes;
-use crate::drone_transmission::{write_packet, read_message};
+use protocol::{self, Message, Datalog, CompactDatalog, WorkingModes, NackReason, FrameDecoder, TelemetryConfig, Authenticator, PC_ID, BROADCAST_ID};
+use crate::drone_transmission::{write_packet, read_message, write_telemetry, Replies, link_info, log, AUTH_KEY, vehicle_id, set_vehicle_id};
 use crate::working_mode::raw_sensor_mode::{measure_raw, filter, calculate_altitude, measure_velocity};
 use crate::kalman::AltitudeKalmanFilter;
 use crate::yaw_pitch_roll::YawPitchRoll;
 use crate::drone::{Drone, Getter, Setter};
+use crate::working_mode::mode_switch;
 use crate::working_mode::panic_mode::{panic_mode, panic_check};
+use crate::parameters::{ParamId, PARAM_COUNT};
+use crate::crash::record_motors;
+use crate::log_storage_manager::LogStorageManager;
+use crate::imu_calibration::ImuCalibrator;
+use crate::config::{self, FLASH_SIZE, CONFIG_SIZE};
+use crate::hal::{Hal, Led, Leds, Imu, Barometer, Battery, Motors, Clock};
+use crate::hal::queued::QueuedHal;
 
 const FIXED_FREQUENCY:u64 = 100; //100 Hz
 
-pub fn control_loop() -> ! {
-    set_motor_max(600);
-    set_tick_frequency(FIXED_FREQUENCY);
-    let begin_loop = Instant::now();
-    let mut drone = Drone::initialize();
+/// Datalogs are stored in the flash log every this many ticks in RawSensorMode
+const STORE_DIVIDER: u64 = 10;
+
+/// Execute a command from the PC and acknowledge it when it was sent with a sequence number
+fn execute_command<H: Hal>(drone: &mut Drone<H>, replies: &mut Replies, message: &Message, seq: u16) {
+    let result = drone.message_check(message);
+    replies.reply(drone.hal(), seq, message, result);
+}
+
+/// Commands executed while the session of this start could not be stored. Packets recorded in an
+/// earlier start with the same session are accepted again, so only the safety commands and what is
+/// needed to download and erase the flash log, which makes room for the session, are executed.
+fn allowed_without_session(message: &Message) -> bool {
+    matches!(message, Message::Hello(_) | Message::SafeMode | Message::PanicMode
+        | Message::LogInfoRequest | Message::LogReadChunk(..) | Message::LogErase(_))
+}
+
+/// Send the current value of a parameter to the PC
+fn write_parameter<H: Hal>(drone: &mut Drone<H>, index: u8) -> Result<(), NackReason> {
+    let info = drone.get_parameters().info(index).ok_or(NackReason::UnknownParameter)?;
+    write_packet(drone.hal(), Message::Param(info));
+    Ok(())
+}
+
+/// Show the working mode on the LEDs
+fn set_leds(leds: &mut impl Leds, yellow: bool, red: bool, green: bool) {
+    leds.set_led(Led::Yellow, yellow);
+    leds.set_led(Led::Red, red);
+    leds.set_led(Led::Green, green);
+}
+
+/// Run the drone on the given peripherals, on the quadrupel board or against a simulation
+pub fn control_loop<H: Hal>(hal: H) -> ! {
+    run_control_loop(hal, |_, _| true);
+    unreachable!();
+}
+
+/// Run the control loop as long as `keep_running` returns true. It is called with the peripherals and
+/// the tick number before every tick, so a simulation can talk to the drone and follow the flight.
+/// Returns the peripherals when it stops.
+pub fn run_control_loop<H: Hal>(mut hal: H, mut keep_running: impl FnMut(&mut H, u64) -> bool) -> H {
+    hal.set_motor_max(600);
+    hal.set_tick_frequency(FIXED_FREQUENCY);
+
+    // Packets are queued by priority and sent at the rate of the link, so replies are not stuck behind telemetry
+    let mut drone = Drone::initialize(QueuedHal::new(hal));
     let mut message = Message::SafeMode;
 
+    //sequence number of the last message, non-zero when the PC expects an ACK/NACK
+    let mut seq: u16 = 0;
+
+    //reply to the last command, sent again when the PC retransmits it
+    let mut replies = Replies::new();
+
     let mut connection = true;
 
     //flag for recording the duration of no new message
-    let mut no_message = 0;
+    let mut no_message: u16 = 0;
+
+    //next parameter to send when the PC asked for the parameter list
+    let mut param_cursor: Option<u8> = None;
+
+    //telemetry groups selected by the PC, the compact full datalog is sent at 20 Hz until the PC sends a config
+    let mut telemetry = TelemetryConfig::none(5);
 
     //flag for detecting if there is new message
     let mut new_message = false;
 
-    // Buffer to store received bytes
-    let mut shared_buf = Vec::new();
+    // Flash log, kept over restarts until the PC downloaded and erased it
+    let mut storage_manager = LogStorageManager::new(drone.hal(), FLASH_SIZE - CONFIG_SIZE);
+
+    // System id of this drone, frames for other vehicles are ignored
+    set_vehicle_id(config::vehicle_id(drone.hal()));
+
+    // IMU calibration stored in flash, and the calibration the PC is running
+    let imu_calibration = config::imu_calibration(drone.hal());
+    drone.set_imu_calibration(imu_calibration);
+    let mut imu_calibrator = ImuCalibrator::new();
+
+    // Decoder that collects received bytes into packets, only authenticated commands are accepted when a key is set.
+    // They are bound to the session of this start, so packets recorded before the restart are refused.
+    let mut decoder = FrameDecoder::new();
+    let mut session = 0;
+    let mut session_stored = true;
+    if let Some(key) = AUTH_KEY {
+        (session, session_stored) = config::start_session(drone.hal());
+        decoder.set_authenticator(Authenticator::new(key, 0).with_session(session));
+    }
+
+    // Let a connected PC know the drone started, it signs the next packets for the new session
+    write_packet(drone.hal(), Message::HelloAck(link_info(session)));
 
     let mut angles = YawPitchRoll { yaw: 0.0, pitch: 0.0, roll: 0.0};
 
     let mut absolute_altitude: f32 = 0.0;
 
     for _ in 0..2000 {
-        absolute_altitude = calculate_altitude(read_pressure(), read_temperature());
+        absolute_altitude = calculate_altitude(drone.hal().read_pressure(), drone.hal().read_temperature());
     }
 
-    let mut altitude_kalman = AltitudeKalmanFilter::default();
+    let mut altitude_kalman: AltitudeKalmanFilter = AltitudeKalmanFilter::default();
+
+    // Time of the last raw IMU sample in RawSensorMode, the estimators integrate over the time in between
+    let mut last_raw_sample: Option<u64> = None;
 
     for i in 0.. {
+        if !keep_running(&mut drone.hal().inner, i) {
+            break;
+        }
+
         // Measure time of loop iteration
-        let begin = Instant::now();
+        let begin = drone.hal().now_us();
 
         if i % 50 == 0 {
-            Blue.toggle();
+            drone.hal().toggle_led(Led::Blue);
         }
 
-        let time = begin_loop.ns_since_start() / 1_000_000;
+        let time = begin / 1_000;
 
         // Check battery voltage
-        if !panic_check() {
-            drone.set_mode(panic_mode());
+        let battery_min = drone.get_parameters().get_u16(ParamId::BatteryMin);
+        if !panic_check(drone.hal(), battery_min) {
+            mode_switch(&mut drone, WorkingModes::PanicMode, 0.0).ok();
         }
 
-        // Read data
-        let packet_result = read_message(&mut shared_buf);
+        // Read data, frames addressed to other vehicles are treated as no message
+        let packet_result = read_message(drone.hal(), &mut decoder).filter(|packet| packet.accepted_by(vehicle_id()));
 
         match packet_result {
             None => {
-                no_message += 1;
+                no_message = no_message.saturating_add(1);
                 new_message = false
             },
+            // A retransmitted command whose reply got lost is answered again, not executed twice
+            Some(packet) if replies.repeat(drone.hal(), &packet) => {
+                no_message = 0;
+                connection = true;
+                new_message = false;
+            }
+            Some(packet) if !session_stored && !allowed_without_session(&packet.message) => {
+                no_message = 0;
+                connection = true;
+                replies.reply(drone.hal(), packet.seq, &packet.message, Err(NackReason::ConfigFull));
+                new_message = false;
+            }
             Some(packet) => {
-                message = packet.message;
-                new_message = true;
                 no_message = 0;
                 connection = true;
+
+                match packet.message {
+                    // Link handshake, answered in any mode. The PC numbers its commands from the start again
+                    Message::Hello(_) => {
+                        replies.clear();
+                        write_packet(drone.hal(), Message::HelloAck(link_info(session)));
+                        if !session_stored {
+                            log!(drone.hal(), Error, "Session not stored, commands are refused until the flash log is erased");
+                        }
+                        new_message = false;
+                    }
+                    // Time synchronisation, answered in any mode with the drone time in us since boot
+                    Message::TimeSyncRequest { host_time } => {
+                        let drone_time = drone.hal().now_us();
+                        write_packet(drone.hal(), Message::TimeSyncReply { host_time, drone_time });
+                        new_message = false;
+                    }
+                    // Telemetry selection, accepted in any mode
+                    Message::TelemetryConfig(config) => {
+                        telemetry = config;
+                        replies.reply(drone.hal(), packet.seq, &packet.message, Ok(()));
+                        new_message = false;
+                    }
+                    // Parameter access, answered in any mode
+                    Message::ParamList => {
+                        param_cursor = Some(0);
+                        new_message = false;
+                    }
+                    Message::ParamGet(index) => {
+                        let result = write_parameter(&mut drone, index);
+                        if result.is_err() {
+                            replies.reply(drone.hal(), packet.seq, &packet.message, result);
+                        }
+                        new_message = false;
+                    }
+                    Message::ParamSet(index, value) => {
+                        let result = drone.set_parameter(index, value);
+                        if result.is_ok() {
+                            write_parameter(&mut drone, index).ok();
+                        }
+                        replies.reply(drone.hal(), packet.seq, &packet.message, result);
+                        new_message = false;
+                    }
+                    // Flash log download, answered in any mode
+                    Message::LogInfoRequest => {
+                        write_packet(drone.hal(), storage_manager.info());
+                        new_message = false;
+                    }
+                    Message::LogReadChunk(offset, len) => {
+                        // No answer on a flash error, the PC asks again
+                        storage_manager.write_chunk(drone.hal(), offset, len).ok();
+                        new_message = false;
+                    }
+                    // Erasing takes long, so it is only done in SafeMode
+                    Message::LogErase(records) => {
+                        let result = match drone.get_mode() {
+                            WorkingModes::SafeMode => storage_manager.erase(drone.hal(), records),
+                            mode => Err(NackReason::ModeRejected(mode)),
+                        };
+                        replies.reply(drone.hal(), packet.seq, &packet.message, result);
+
+                        // The erase made room to store a new session, an empty log was not erased yet.
+                        // The PC signs the next packets for it after the HelloAck
+                        if let (Ok(()), false, Some(key)) = (result, session_stored, AUTH_KEY) {
+                            let renewed = match config::next_session(drone.hal(), session) {
+                                (_, false) if config::erase_flash(drone.hal()).is_ok() => config::next_session(drone.hal(), session),
+                                renewed => renewed,
+                            };
+                            if let (next, true) = renewed {
+                                (session, session_stored) = (next, true);
+                                decoder.set_authenticator(Authenticator::new(key, 0).with_session(session));
+                                write_packet(drone.hal(), Message::HelloAck(link_info(session)));
+                            }
+                        }
+                        new_message = false;
+                    }
+                    // The vehicle id is only changed in SafeMode, the reply is still sent from the old id
+                    Message::SetVehicleId(id) => {
+                        let result = match (drone.get_mode(), id) {
+                            (_, PC_ID | BROADCAST_ID) => Err(NackReason::ParameterRange),
+                            (WorkingModes::SafeMode, _) => config::VEHICLE_ID.write(drone.hal(), &[id]),
+                            (mode, _) => Err(NackReason::ModeRejected(mode)),
+                        };
+                        replies.reply(drone.hal(), packet.seq, &packet.message, result);
+                        if result.is_ok() {
+                            set_vehicle_id(id);
+                        }
+                        new_message = false;
+                    }
+                    // Estimator selection, only on the ground: a switch in RawSensorMode would make the attitude
+                    // the controllers see jump. All estimators keep running and are logged, so they can still
+                    // be compared in flight
+                    Message::SetEstimator(estimator) => {
+                        let result = match drone.get_mode() {
+                            WorkingModes::SafeMode => {
+                                drone.set_estimator(estimator);
+                                log!(drone.hal(), Info, "Attitude estimator {}", estimator);
+                                Ok(())
+                            }
+                            mode => Err(NackReason::ModeRejected(mode)),
+                        };
+                        replies.reply(drone.hal(), packet.seq, &packet.message, result);
+                        new_message = false;
+                    }
+                    // The IMU is only calibrated in SafeMode, the progress is reported while the step runs
+                    Message::ImuCalibrate(step) => {
+                        let result = match drone.get_mode() {
+                            WorkingModes::SafeMode => {
+                                imu_calibrator.start(step);
+                                Ok(())
+                            }
+                            mode => Err(NackReason::ModeRejected(mode)),
+                        };
+                        replies.reply(drone.hal(), packet.seq, &packet.message, result);
+                        new_message = false;
+                    }
+                    _ => {
+                        message = packet.message;
+                        seq = packet.seq;
+                        new_message = true;
+                    }
+                }
             }
         }
 
         // Check usb connection with PC
         if connection == true {
-            if no_message >= 3 {
-                drone.set_mode(panic_mode());
+            if no_message >= drone.get_parameters().get_u16(ParamId::LinkTimeout) {
+                mode_switch(&mut drone, WorkingModes::PanicMode, 0.0).ok();
                 connection = false;
             }
         }
@@ -86,81 +300,74 @@ pub fn control_loop() -> ! {
         //First the control part
         match drone.get_mode() {
             WorkingModes::PanicMode => {
-                drone.set_mode(panic_mode());
+                // Commands other than SafeMode and PanicMode are rejected until the drone is down
+                if new_message {
+                    execute_command(&mut drone, &mut replies, &message, seq);
+                }
+                panic_mode(&mut drone);
 
-                Yellow.off();
-                Red.on();
-                Green.off();
+                set_leds(drone.hal(), false, true, false);
             },
             WorkingModes::SafeMode => {
                 if new_message {
-                    drone.message_check(&message);
+                    execute_command(&mut drone, &mut replies, &message, seq);
                 }
 
-                Yellow.on();
-                Red.off();
-                Green.off();
+                set_leds(drone.hal(), true, false, false);
             },
             WorkingModes::ManualMode => {
                 if new_message {
-                    drone.message_check(&message);
+                    execute_command(&mut drone, &mut replies, &message, seq);
                 }
 
-                Yellow.on();
-                Red.off();
-                Green.on();
+                set_leds(drone.hal(), true, false, true);
             },
             WorkingModes::CalibrationMode => {
                 if new_message {
-                    drone.message_check(&message);
+                    execute_command(&mut drone, &mut replies, &message, seq);
                 }
-                Yellow.off();
-                Red.off();
-                Green.off();
+                set_leds(drone.hal(), false, false, false);
             },
             WorkingModes::FullControlMode => {
                 if new_message {
-                    drone.message_check(&message);
+                    execute_command(&mut drone, &mut replies, &message, seq);
                 }
-                Yellow.off();
-                Red.off();
-                Green.on();
+                set_leds(drone.hal(), false, false, true);
             },
             WorkingModes::YawControlMode => {
                 if new_message {
-                    drone.message_check(&message);
+                    execute_command(&mut drone, &mut replies, &message, seq);
                 }
 
-                Yellow.off();
-                Red.on();
-                Green.on();
+                set_leds(drone.hal(), false, true, true);
             },
             WorkingModes::HeightControlMode => {
                 if new_message {
-                    drone.message_check(&message);
+                    execute_command(&mut drone, &mut replies, &message, seq);
                 }
 
-                Yellow.on();
-                Red.on();
-                Green.on();
+                set_leds(drone.hal(), true, true, true);
             }
             WorkingModes::RawSensorMode => {
                 if new_message {
-                    drone.message_check(&message);
+                    execute_command(&mut drone, &mut replies, &message, seq);
                 }
-                Yellow.on();
-                Red.on();
-                Green.off();
+                set_leds(drone.hal(), true, true, false);
             },
             _ => {
                 if new_message {
-                    drone.message_check(&message);
+                    execute_command(&mut drone, &mut replies, &message, seq);
                 }
             }
         };
 
+        if let Some((step, state)) = imu_calibrator.update(&mut drone) {
+            write_packet(drone.hal(), Message::ImuCalibrationStatus(step, state));
+        }
+
         // Read motor and sensor values
-        let motors = get_motors();
+        let motors = drone.hal().get_motors();
+        record_motors(motors);
 
         //CODE FOR BETTER PERFORMANCE WITHOUT WAVEFORM COMPARISON
         // let mut angles_filtered = drone.get_current_attitude();
@@ -180,26 +387,35 @@ pub fn control_loop() -> ! {
         // }
 
         //CODE FOR WAVEFORM COMPARISON
-        let sensor_data = block!(read_dmp_bytes()).unwrap();
+        let sensor_data = drone.hal().read_dmp();
         angles = drone.get_calibration().full_compensation_dmp(YawPitchRoll::from(sensor_data));
         drone.set_dmp_angles([angles.yaw, angles.pitch, angles.roll]);
 
-        let mut angles_filtered = drone.get_current_attitude();
-        match drone.get_mode(){
+        let [angles_kalman, angles_complementary, angles_ahrs] = match drone.get_mode(){
             WorkingModes::RawSensorMode => {
-                measure_raw(&mut drone, 10000);
-                filter(&mut drone, 10000);
+                // The first sample after entering the mode is taken as one tick after the previous one
+                let now = drone.hal().now_us();
+                let sample_dt = last_raw_sample.map_or(10000, |last| now.saturating_sub(last)) as u128;
+                last_raw_sample = Some(now);
+
+                let imu = measure_raw(&mut drone, sample_dt);
+                filter(&mut drone, &imu, sample_dt)
                 //drone.set_dmp_angles([0.0, 0.0, 0.0]);
             }
             _ => {
+                // Report what the AHRS learned about the gyro when RawSensorMode ends
+                if last_raw_sample.take().is_some() {
+                    let [x, y, z] = drone.get_ahrs().gyro_bias();
+                    log!(drone.hal(), Info, "AHRS gyro bias {:.4} {:.4} {:.4} rad/s", x, y, z);
+                }
                 drone.set_current_attitude([angles.yaw, angles.pitch, angles.roll]);
-                angles_filtered = YawPitchRoll{yaw: 0.0, pitch: 0.0, roll: 0.0};
+                [YawPitchRoll{yaw: 0.0, pitch: 0.0, roll: 0.0}; 3]
             }
-        }
+        };
 
-        let end = Instant::now();
-        let control_loop_time = end.duration_since(begin).as_micros();
-        let sample_time = Instant::now();
+        let end = drone.hal().now_us();
+        let control_loop_time = (end - begin) as u128;
+        let sample_time = drone.hal().now_us();
         drone.set_sample_time(sample_time);
 
         let angles_raw = drone.get_raw_angles();
@@ -209,13 +425,13 @@ pub fn control_loop() -> ! {
         let dt = (10000 as f32) / 1_000_000.0;
 
         let vel_z = measure_velocity(&mut drone);
-        let altitude = calculate_altitude(read_pressure(), read_temperature()) - absolute_altitude;
+        let altitude = calculate_altitude(drone.hal().read_pressure(), drone.hal().read_temperature()) - absolute_altitude;
         let (altitude_state, velocity_state) = altitude_kalman.update(altitude * 100.0, vel_z - 70.0, dt);
 
         drone.set_height(altitude_state);
 
         //Store the log files
-        let log = Message::Datalogging(Datalog
+        let datalog = Datalog
         {
             motor1: motors[0],
             motor2: motors[1],
@@ -227,31 +443,61 @@ pub fn control_loop() -> ! {
             pitch: angles_dmp.pitch,
             roll: angles_dmp.roll,
             //filtered
-            yaw_f: angles_filtered.yaw,
-            pitch_f: angles_filtered.pitch,
-            roll_f: angles_filtered.roll,
+            yaw_f: angles_kalman.yaw,
+            pitch_f: angles_kalman.pitch,
+            roll_f: angles_kalman.roll,
+            yaw_c: angles_complementary.yaw,
+            pitch_c: angles_complementary.pitch,
+            roll_c: angles_complementary.roll,
+            yaw_m: angles_ahrs.yaw,
+            pitch_m: angles_ahrs.pitch,
+            roll_m: angles_ahrs.roll,
             //raw
             yaw_r: angles_raw.yaw,
             pitch_r: angles_raw.pitch,
             roll_r: angles_raw.roll,
-            bat: read_battery(),
+            bat: drone.hal().read_battery(),
             bar: drone.get_calibration().height_compensation(drone.get_height()) / 100.0,
             workingmode: drone.get_mode(),
+            estimator: drone.get_estimator(),
             arguments: drone.get_arguments(),
             control_loop_time,
+            pid: drone.get_pid().to_array(),
             test: [drone.get_test()[0], drone.get_test()[1], drone.get_test()[2], drone.get_test()[3]]
-        });
+        };
 
-        // Store log on drone flash
-        // storage_manager.store_logging(log).unwrap();
+        // Store log on drone flash, in compact form to fit in a record
+        if drone.get_mode() == WorkingModes::RawSensorMode && i % STORE_DIVIDER == 0 {
+            let record = Message::CompactDatalogging(CompactDatalog::from(&datalog));
+            if storage_manager.store_logging(drone.hal(), &record).is_err() {
+                log!(drone.hal(), Error, "Writing the flash log failed");
+            }
+        }
 
-        if i % 5 == 0 {
-            write_packet(log);
+        // Send the telemetry groups the PC selected, or the full datalog in compact form
+        if telemetry.is_due(i) {
+            if telemetry.is_compact() {
+                write_packet(drone.hal(), Message::CompactDatalogging(CompactDatalog::from(&datalog)));
+            } else {
+                write_telemetry(drone.hal(), &telemetry, &datalog);
+            }
         }
 
+        if i % 100 == 1 {
+            // Let the PC know how well the drone receives its messages
+            write_packet(drone.hal(), Message::LinkStatus(decoder.stats()));
+        } else if let Some(index) = param_cursor {
+            // Send the parameter list one entry per tick
+            write_parameter(&mut drone, index).ok();
+            param_cursor = if (index as usize) + 1 < PARAM_COUNT { Some(index + 1) } else { None };
+        }
+
+        // Send the queued packets the link has room for
+        drone.hal().flush();
+
         // wait until the timer interrupt goes off again
         // based on the frequency set above
-        wait_for_next_tick();
+        drone.hal().wait_for_next_tick();
     }
-    unreachable!();
+    drone.into_hal().inner
 }
\ No newline at end of file
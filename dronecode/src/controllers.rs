@@ -1,70 +1,526 @@
-#[derive(Copy, Clone)]
-pub struct PID {
-    pub(crate) kp: f32,
-    pub(crate) ki: f32,
-    pub(crate) kd: f32,
-    pub(crate) last_error: f32,
-    pub(crate) previous_error: f32,
-    pub(crate) pwm_change: f32,
-}
-
-fn signum_f32(x: f32) -> f32 {
-    if x > 0.0 {
-        1.0
-    } else if x < 0.0 {
-        -1.0
-    } else {
-        0.0
-    }
-}
-
-
-
-impl PID {
-    pub fn new(kp: f32, ki: f32, kd: f32) -> Self {
-        Self {
-            kp,
-            ki,
-            kd,
-            last_error: 0 as f32,
-            previous_error: 0 as f32,
-            pwm_change: 0 as f32,
-        }
-    }
-
-    ///NEW:
-    ///Incremental PID controller: Kp * (e(k) - e(k-1)) + Ki * e(k)    + Kd * (e(k) - 2e(k-1) + e(k-2))
-    ///OLD:
-    ///Positional PID controller: Kp * e(k)             + Ki * ∑e(k..) + Kd * (e(k) - e(k-1))
-    ///
-    ///Those to types of PID are almost same when I controller is not used, except the output.
-    ///Pos PID outputs a direct controlled value but Inc PID output a difference value
-    ///For example: if we want to control the car speed from 0km/h to 9 and to 0 again.
-    ///Output from Pos PID: 9km/h -> 5km/h -> 3km/h -> 0km/h (real speed)
-    ///Output from Inc PID: +9km/h -> -4km/h -> -2km/h -> -3km/h (Δ value, variation)
-    ///
-    ///The big difference between two types of controllers is the I controller. Pos PID needs more computations
-    ///on summing all errors together but Inc PID doesn't. In case we might us I controller, Inc PID
-    ///is chosen
-    pub fn step(&mut self, target: f32, current: f32) -> (f32, f32, f32){
-        let current_err = target - current as f32;
-        let output = self.kp * (current_err - self.last_error)
-            //+ self.ki * current_err
-            + self.kd * (current_err - 2 as f32 * self.last_error + self.previous_error);
-        self.previous_error = self.last_error;
-        self.last_error = current_err;
-        (output, current_err, self.last_error)
-    }
-
-    pub fn step2(&mut self, target: f32, current: f32) -> (f32, f32, f32){
-        let current_err = current;
-        let output = self.kp * (current_err - self.last_error)
-            //+ self.ki * current_err
-            + self.kd * (current_err - 2 as f32 * self.last_error + self.previous_error);
-        self.previous_error = self.last_error;
-        self.last_error = current_err;
-        (output, current_err, self.last_error)
-    }
-    
-}
- 
+use crate::real::{Number, Real};
+
+/// Gain of the back-calculation anti-windup. The part of the output cut off by the output limit
+/// is fed back into the integrator with this gain, 1 unwinds it in a single update.
+const ANTI_WINDUP_GAIN: f32 = 0.5;
+
+/// Longest time step in s an update integrates over, three ticks of the 100 Hz control loop.
+/// Longer gaps, like the first update after the setpoints stopped for a while, count as this.
+pub const MAX_DT: f32 = 0.03;
+
+/// Clamp without the panic of f32::clamp when the limits are not numbers
+fn limit<T: Real>(value: T, min: T, max: T) -> T {
+    value.max(min).min(max)
+}
+
+/// Controller of a single axis. The setpoint and the measurement are in the same unit, the output
+/// is what the next loop or the motors take.
+pub trait Controller {
+    /// Run one update, dt seconds after the last one, and return the output
+    fn update(&mut self, setpoint: f32, measurement: f32, dt: f32) -> f32;
+
+    /// Forget the integral, the derivative and the last output
+    fn reset(&mut self);
+
+    /// Terms of the last update
+    fn telemetry(&self) -> ControllerTelemetry;
+}
+
+/// Contribution of each term to the last output, the output also holds a feedforward
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ControllerTelemetry {
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+    pub output: f32,
+}
+
+impl ControllerTelemetry {
+    /// The four values of the pid field in the datalog
+    pub fn to_array(self) -> [f32; 4] {
+        [self.p, self.i, self.d, self.output]
+    }
+}
+
+/// Gains of a controller, ki is per second and kd in seconds. kff is only used by FeedforwardPid.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Gains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    pub kff: f32,
+}
+
+impl Gains {
+    pub const fn pid(kp: f32, ki: f32, kd: f32) -> Self {
+        Gains { kp, ki, kd, kff: 0.0 }
+    }
+}
+
+/// Limits of a controller, fixed by the loop it is used in
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Limits {
+    pub output: f32,            // the output is clamped to +-output
+    pub integral: f32,          // the integral term is clamped to +-integral
+    pub derivative_filter: f32, // low pass coefficient from 0-1, 1 does not filter
+}
+
+impl Limits {
+    pub const NONE: Limits = Limits { output: f32::MAX, integral: f32::MAX, derivative_filter: 1.0 };
+}
+
+/// Gains and limits in the numbers the controller runs on, converted once when it is made
+#[derive(Copy, Clone, Debug)]
+struct Constants<T> {
+    gains: Gains,
+    limits: Limits,
+    kp: T,
+    ki: T,
+    kd: T,
+    kff: T,
+    output_limit: T,
+    integral_limit: T,
+    derivative_filter: T,
+}
+
+impl<T: Real> Constants<T> {
+    fn new(gains: Gains, limits: Limits) -> Self {
+        Constants {
+            gains,
+            limits,
+            kp: T::from_f32(gains.kp),
+            ki: T::from_f32(gains.ki),
+            kd: T::from_f32(gains.kd),
+            kff: T::from_f32(gains.kff),
+            output_limit: T::from_f32(limits.output),
+            integral_limit: T::from_f32(limits.integral),
+            derivative_filter: T::from_f32(limits.derivative_filter),
+        }
+    }
+}
+
+/// Terms of the last update, the output is the state of the incremental form
+#[derive(Copy, Clone, Debug, Default)]
+struct Terms<T> {
+    p: T,
+    i: T,
+    d: T,
+    output: T,
+}
+
+impl<T: Real> Terms<T> {
+    fn telemetry(&self) -> ControllerTelemetry {
+        ControllerTelemetry { p: self.p.to_f32(), i: self.i.to_f32(), d: self.d.to_f32(), output: self.output.to_f32() }
+    }
+}
+
+/// Derivative of the measurement through a first order low pass, y(k) = y(k-1) + alpha * (x(k) - y(k-1)).
+/// Taking the measurement instead of the error keeps the derivative from kicking when the
+/// setpoint jumps.
+#[derive(Copy, Clone, Debug, Default)]
+struct Derivative<T> {
+    filtered: T,
+    last_measurement: Option<T>,
+}
+
+impl<T: Real> Derivative<T> {
+    fn update(&mut self, kd: T, alpha: T, measurement: T, dt: T) -> T {
+        // There is nothing to differentiate on the first update
+        let raw = match self.last_measurement {
+            Some(last) => -kd * ((measurement - last) / dt),
+            None => T::default(),
+        };
+        self.filtered += alpha * (raw - self.filtered);
+        self.last_measurement = Some(measurement);
+        self.filtered
+    }
+}
+
+/// Check the inputs of an update, a broken sample keeps the last output instead of poisoning the state
+fn valid(setpoint: f32, measurement: f32, dt: f32) -> bool {
+    setpoint.is_finite() && measurement.is_finite() && dt > 0.0 && dt.is_finite()
+}
+
+///Positional PID controller: Kp * e(k) + Ki * ∑e(k..) * dt + Kd * d(k)
+///The output is the controlled value itself. The integral is clamped, and winds back with
+///back-calculation while the output is limited.
+#[derive(Copy, Clone, Debug)]
+pub struct PositionalPid<T: Real = Number> {
+    constants: Constants<T>,
+    integral: T,
+    derivative: Derivative<T>,
+    terms: Terms<T>,
+}
+
+impl<T: Real> PositionalPid<T> {
+    pub fn new(gains: Gains, limits: Limits) -> Self {
+        PositionalPid { constants: Constants::new(gains, limits), integral: T::default(), derivative: Derivative::default(), terms: Terms::default() }
+    }
+
+    /// Update with an extra term added to the output before it is limited
+    fn update_with(&mut self, setpoint: T, measurement: T, dt: T, feedforward: T) -> T {
+        let Constants { kp, ki, kd, output_limit, integral_limit, derivative_filter, .. } = self.constants;
+        let error = setpoint - measurement;
+
+        let p = kp * error;
+        let i = limit(self.integral + ki * error * dt, -integral_limit, integral_limit);
+        let d = self.derivative.update(kd, derivative_filter, measurement, dt);
+        let unlimited = p + i + d + feedforward;
+        let output = limit(unlimited, -output_limit, output_limit);
+
+        // Back-calculation: while the output is limited the integrator winds back
+        let anti_windup = T::from_f32(ANTI_WINDUP_GAIN);
+        self.integral = limit(i + anti_windup * (output - unlimited), -integral_limit, integral_limit);
+        self.terms = Terms { p, i, d, output };
+        output
+    }
+}
+
+impl<T: Real> Controller for PositionalPid<T> {
+    fn update(&mut self, setpoint: f32, measurement: f32, dt: f32) -> f32 {
+        if !valid(setpoint, measurement, dt) {
+            return self.terms.output.to_f32();
+        }
+        let [setpoint, measurement, dt] = [setpoint, measurement, dt].map(T::from_f32);
+        self.update_with(setpoint, measurement, dt, T::default()).to_f32()
+    }
+
+    fn reset(&mut self) {
+        *self = PositionalPid::new(self.constants.gains, self.constants.limits);
+    }
+
+    fn telemetry(&self) -> ControllerTelemetry {
+        self.terms.telemetry()
+    }
+}
+
+///Incremental PID controller: u(k) = u(k-1) + Kp * (e(k) - e(k-1)) + Ki * e(k) * dt + Kd * (d(k) - d(k-1))
+///The output is accumulated from its changes, so the integral is kept in the output and no sum
+///of the errors is needed. Limiting the output also stops the integral from winding up.
+#[derive(Copy, Clone, Debug)]
+pub struct IncrementalPid<T: Real = Number> {
+    constants: Constants<T>,
+    last_error: T,
+    derivative: Derivative<T>,
+    terms: Terms<T>,
+}
+
+impl<T: Real> IncrementalPid<T> {
+    pub fn new(gains: Gains, limits: Limits) -> Self {
+        IncrementalPid { constants: Constants::new(gains, limits), last_error: T::default(), derivative: Derivative::default(), terms: Terms::default() }
+    }
+}
+
+impl<T: Real> Controller for IncrementalPid<T> {
+    fn update(&mut self, setpoint: f32, measurement: f32, dt: f32) -> f32 {
+        if !valid(setpoint, measurement, dt) {
+            return self.terms.output.to_f32();
+        }
+        let [setpoint, measurement, dt] = [setpoint, measurement, dt].map(T::from_f32);
+        let Constants { kp, ki, kd, output_limit, integral_limit, derivative_filter, .. } = self.constants;
+        let error = setpoint - measurement;
+
+        let last_d = self.derivative.filtered;
+        let d = self.derivative.update(kd, derivative_filter, measurement, dt);
+        let p = kp * error;
+        let change = kp * (error - self.last_error) + ki * error * dt + (d - last_d);
+
+        // The integral is the part of the output the P and D terms do not explain
+        let output = limit(self.terms.output + change, p + d - integral_limit, p + d + integral_limit);
+        let output = limit(output, -output_limit, output_limit);
+
+        self.last_error = error;
+        self.terms = Terms { p, i: output - p - d, d, output };
+        output.to_f32()
+    }
+
+    fn reset(&mut self) {
+        *self = IncrementalPid::new(self.constants.gains, self.constants.limits);
+    }
+
+    fn telemetry(&self) -> ControllerTelemetry {
+        self.terms.telemetry()
+    }
+}
+
+/// Positional PID with the setpoint fed forward: Kff * r(k) + PID. The feedforward gives the
+/// output the loop needs for the setpoint, the PID only corrects what is left.
+#[derive(Copy, Clone, Debug)]
+pub struct FeedforwardPid<T: Real = Number> {
+    pid: PositionalPid<T>,
+}
+
+impl<T: Real> FeedforwardPid<T> {
+    pub fn new(gains: Gains, limits: Limits) -> Self {
+        FeedforwardPid { pid: PositionalPid::new(gains, limits) }
+    }
+}
+
+impl<T: Real> Controller for FeedforwardPid<T> {
+    fn update(&mut self, setpoint: f32, measurement: f32, dt: f32) -> f32 {
+        if !valid(setpoint, measurement, dt) {
+            return self.pid.terms.output.to_f32();
+        }
+        let [setpoint, measurement, dt] = [setpoint, measurement, dt].map(T::from_f32);
+        let feedforward = self.pid.constants.kff * setpoint;
+        self.pid.update_with(setpoint, measurement, dt, feedforward).to_f32()
+    }
+
+    fn reset(&mut self) {
+        self.pid.reset();
+    }
+
+    fn telemetry(&self) -> ControllerTelemetry {
+        self.pid.telemetry()
+    }
+}
+
+/// Controller implementations an axis can use, the discriminant is the value of the *_ctl parameters
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ControllerKind {
+    Positional = 0,
+    Incremental = 1,
+    Feedforward = 2,
+}
+
+impl ControllerKind {
+    pub fn from_u16(value: u16) -> Option<Self> {
+        match value {
+            0 => Some(ControllerKind::Positional),
+            1 => Some(ControllerKind::Incremental),
+            2 => Some(ControllerKind::Feedforward),
+            _ => None,
+        }
+    }
+}
+
+/// Controller of one axis, with the implementation picked in the parameters. The limits belong
+/// to the loop, the kind and the gains can change in flight.
+#[derive(Copy, Clone, Debug)]
+pub enum AxisController<T: Real = Number> {
+    Positional(PositionalPid<T>),
+    Incremental(IncrementalPid<T>),
+    Feedforward(FeedforwardPid<T>),
+}
+
+impl<T: Real> AxisController<T> {
+    pub fn new(kind: ControllerKind, gains: Gains, limits: Limits) -> Self {
+        match kind {
+            ControllerKind::Positional => AxisController::Positional(PositionalPid::new(gains, limits)),
+            ControllerKind::Incremental => AxisController::Incremental(IncrementalPid::new(gains, limits)),
+            ControllerKind::Feedforward => AxisController::Feedforward(FeedforwardPid::new(gains, limits)),
+        }
+    }
+
+    pub fn kind(&self) -> ControllerKind {
+        match self {
+            AxisController::Positional(_) => ControllerKind::Positional,
+            AxisController::Incremental(_) => ControllerKind::Incremental,
+            AxisController::Feedforward(_) => ControllerKind::Feedforward,
+        }
+    }
+
+    fn constants(&self) -> &Constants<T> {
+        match self {
+            AxisController::Positional(pid) => &pid.constants,
+            AxisController::Incremental(pid) => &pid.constants,
+            AxisController::Feedforward(pid) => &pid.pid.constants,
+        }
+    }
+
+    fn constants_mut(&mut self) -> &mut Constants<T> {
+        match self {
+            AxisController::Positional(pid) => &mut pid.constants,
+            AxisController::Incremental(pid) => &mut pid.constants,
+            AxisController::Feedforward(pid) => &mut pid.pid.constants,
+        }
+    }
+
+    pub fn gains(&self) -> Gains {
+        self.constants().gains
+    }
+
+    /// Switch to another implementation or other gains. New gains keep the state, so tuning in
+    /// flight does not throw away the integral. Only another implementation starts over, returns
+    /// whether it did.
+    pub fn configure(&mut self, kind: ControllerKind, gains: Gains) -> bool {
+        let limits = self.constants().limits;
+        if self.kind() != kind {
+            *self = AxisController::new(kind, gains, limits);
+            return true;
+        }
+        if self.gains() != gains {
+            *self.constants_mut() = Constants::new(gains, limits);
+        }
+        false
+    }
+}
+
+impl<T: Real> Controller for AxisController<T> {
+    fn update(&mut self, setpoint: f32, measurement: f32, dt: f32) -> f32 {
+        match self {
+            AxisController::Positional(pid) => pid.update(setpoint, measurement, dt),
+            AxisController::Incremental(pid) => pid.update(setpoint, measurement, dt),
+            AxisController::Feedforward(pid) => pid.update(setpoint, measurement, dt),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            AxisController::Positional(pid) => pid.reset(),
+            AxisController::Incremental(pid) => pid.reset(),
+            AxisController::Feedforward(pid) => pid.reset(),
+        }
+    }
+
+    fn telemetry(&self) -> ControllerTelemetry {
+        match self {
+            AxisController::Positional(pid) => pid.telemetry(),
+            AxisController::Incremental(pid) => pid.telemetry(),
+            AxisController::Feedforward(pid) => pid.telemetry(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.01;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn test_proportional() {
+        let mut pid = PositionalPid::<f32>::new(Gains::pid(0.5, 0.0, 0.0), Limits::NONE);
+        assert_eq!(pid.update(1.0, 0.2, DT), 0.4);
+        assert_eq!(pid.telemetry(), ControllerTelemetry { p: 0.4, i: 0.0, d: 0.0, output: 0.4 });
+
+        let mut pid = IncrementalPid::<f32>::new(Gains::pid(0.5, 0.0, 0.0), Limits::NONE);
+        assert_eq!(pid.update(1.0, 0.2, DT), 0.4);
+        assert!(close(pid.update(1.0, 0.6, DT), 0.2));
+    }
+
+    #[test]
+    fn test_integral_per_second() {
+        // An error of 1 for one second adds ki to the output, however fast the loop runs
+        for ticks in [100, 500] {
+            let mut pid = PositionalPid::<f32>::new(Gains::pid(0.0, 2.0, 0.0), Limits::NONE);
+            for _ in 0..ticks {
+                pid.update(1.0, 0.0, 1.0 / ticks as f32);
+            }
+            assert!((pid.telemetry().i - 2.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_forms_agree() {
+        let gains = Gains::pid(0.6, 10.0, 0.002);
+        let mut positional = AxisController::<f32>::new(ControllerKind::Positional, gains, Limits::NONE);
+        let mut incremental = AxisController::<f32>::new(ControllerKind::Incremental, gains, Limits::NONE);
+        for k in 0..50 {
+            let measurement = (k as f32 * 0.3).sin();
+            let a = positional.update(0.5, measurement, DT);
+            let b = incremental.update(0.5, measurement, DT);
+            assert!((a - b).abs() < 1e-4, "tick {}: {} != {}", k, a, b);
+        }
+    }
+
+    #[test]
+    fn test_output_limit_and_anti_windup() {
+        let limits = Limits { output: 1.0, integral: 0.8, derivative_filter: 1.0 };
+        for kind in [ControllerKind::Positional, ControllerKind::Incremental, ControllerKind::Feedforward] {
+            let mut pid = AxisController::<f32>::new(kind, Gains::pid(0.2, 10.0, 0.0), limits);
+
+            // A long lasting error saturates the output, the integrator stays within its limit
+            for _ in 0..1000 {
+                assert!(pid.update(10.0, 0.0, DT) <= 1.0);
+                assert!(pid.telemetry().i <= 0.8);
+            }
+            assert_eq!(pid.update(10.0, 0.0, DT), 1.0);
+
+            // Without windup the output leaves the limit as soon as the error turns
+            assert!(pid.update(-1.0, 0.0, DT) < 1.0, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_derivative_on_measurement() {
+        let limits = Limits { derivative_filter: 0.5, ..Limits::NONE };
+        let mut pid = PositionalPid::<f32>::new(Gains::pid(0.0, 0.0, 0.01), limits);
+        assert_eq!(pid.update(0.0, 0.0, DT), 0.0);
+
+        // A setpoint step does not kick the derivative
+        assert_eq!(pid.update(1.0, 0.0, DT), 0.0);
+
+        // A measurement step does, through the low pass
+        assert!(close(pid.update(1.0, 0.4, DT), -0.2));
+        assert!(close(pid.update(1.0, 0.4, DT), -0.1));
+        assert!(close(pid.telemetry().d, -0.1));
+    }
+
+    #[test]
+    fn test_feedforward() {
+        let gains = Gains { kp: 0.5, ki: 0.0, kd: 0.0, kff: 1.0 };
+        let mut pid = FeedforwardPid::<f32>::new(gains, Limits::NONE);
+        assert_eq!(pid.update(0.4, 0.0, DT), 0.6);
+        assert_eq!(pid.update(0.4, 0.4, DT), 0.4);
+        assert_eq!(pid.telemetry().p, 0.0);
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let mut pid = PositionalPid::<f32>::new(Gains::pid(0.5, 1.0, 0.01), Limits::NONE);
+        let output = pid.update(1.0, 0.0, DT);
+        assert_eq!(pid.update(1.0, f32::NAN, DT), output);
+        assert_eq!(pid.update(1.0, 0.0, 0.0), output);
+        assert!(pid.update(1.0, 0.0, DT).is_finite());
+    }
+
+    #[test]
+    fn test_configure() {
+        let limits = Limits { output: 1.0, ..Limits::NONE };
+        let mut pid = AxisController::<f32>::new(ControllerKind::Positional, Gains::pid(0.0, 10.0, 0.0), limits);
+        pid.update(1.0, 0.0, DT);
+        assert!(!pid.configure(ControllerKind::Positional, Gains::pid(0.0, 10.0, 0.0)));
+        assert!(close(pid.telemetry().i, 0.1));
+
+        // New gains keep the integral, the next update goes on from it
+        assert!(!pid.configure(ControllerKind::Positional, Gains::pid(0.5, 20.0, 0.0)));
+        assert_eq!(pid.gains(), Gains::pid(0.5, 20.0, 0.0));
+        assert!(close(pid.update(1.0, 0.0, DT), 0.8));
+        assert!(close(pid.telemetry().i, 0.3));
+
+        // Another kind starts over with the same limits
+        assert!(pid.configure(ControllerKind::Incremental, Gains::pid(5.0, 0.0, 0.0)));
+        assert_eq!(pid.kind(), ControllerKind::Incremental);
+        assert_eq!(pid.telemetry(), ControllerTelemetry::default());
+        assert_eq!(pid.update(1.0, 0.0, DT), 1.0);
+        assert_eq!(ControllerKind::from_u16(2), Some(ControllerKind::Feedforward));
+        assert_eq!(ControllerKind::from_u16(3), None);
+    }
+
+    #[cfg(feature = "fixed-point")]
+    #[test]
+    fn test_fixed_point_matches_f32() {
+        use crate::real::Q16;
+
+        // The gains and limits of the rate loop, following a disturbed measurement. The incremental
+        // form adds up the rounding of every update, it stays within a few 1e-3 as well.
+        let gains = Gains { kp: 0.5, ki: 1.0, kd: 0.003, kff: 0.2 };
+        let limits = Limits { output: 1.0, integral: 0.3, derivative_filter: 0.5 };
+        for kind in [ControllerKind::Positional, ControllerKind::Incremental, ControllerKind::Feedforward] {
+            let mut float = AxisController::<f32>::new(kind, gains, limits);
+            let mut fixed = AxisController::<Q16>::new(kind, gains, limits);
+            for k in 0..500 {
+                let setpoint = if k < 250 { 0.4 } else { -0.2 };
+                let measurement = 0.3 * (k as f32 * 0.05).sin();
+                let a = float.update(setpoint, measurement, DT);
+                let b = fixed.update(setpoint, measurement, DT);
+                assert!((a - b).abs() < 2e-3, "{:?} tick {}: {} != {}", kind, k, a, b);
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU8, AtomicU16, Ordering};
+use protocol::{CrashReport, WorkingModes};
+
+// State of the drone that is reported when it panics. The panic handler cannot reach the
+// Drone struct, so the control loop keeps a copy here. Only atomic loads and stores are used,
+// which are supported on the Cortex-M0.
+static MODE: AtomicU8 = AtomicU8::new(0);
+static MOTORS: [AtomicU16; 4] = [AtomicU16::new(0), AtomicU16::new(0), AtomicU16::new(0), AtomicU16::new(0)];
+
+/// Working modes in the order they are stored in MODE
+const MODES: [WorkingModes; 8] = [
+    WorkingModes::SafeMode,
+    WorkingModes::PanicMode,
+    WorkingModes::ManualMode,
+    WorkingModes::CalibrationMode,
+    WorkingModes::YawControlMode,
+    WorkingModes::FullControlMode,
+    WorkingModes::HeightControlMode,
+    WorkingModes::RawSensorMode,
+];
+
+/// Remember the current working mode for a crash report
+pub fn record_mode(mode: WorkingModes) {
+    if let Some(index) = MODES.iter().position(|&m| m == mode) {
+        MODE.store(index as u8, Ordering::Relaxed);
+    }
+}
+
+/// Remember the last motor outputs for a crash report
+pub fn record_motors(motors: [u16; 4]) {
+    for (stored, motor) in MOTORS.iter().zip(motors) {
+        stored.store(motor, Ordering::Relaxed);
+    }
+}
+
+/// Build the crash report of a panic, the uptime is in ms
+pub fn crash_report(info: &PanicInfo, uptime: u64) -> CrashReport {
+    let (file, line) = match info.location() {
+        Some(location) => (location.file(), location.line()),
+        None => ("unknown", 0),
+    };
+
+    let mode = MODES[MODE.load(Ordering::Relaxed) as usize];
+    let motors = [
+        MOTORS[0].load(Ordering::Relaxed),
+        MOTORS[1].load(Ordering::Relaxed),
+        MOTORS[2].load(Ordering::Relaxed),
+        MOTORS[3].load(Ordering::Relaxed),
+    ];
+
+    CrashReport::new(file, line, format_args!("{}", info.message()), mode, uptime, motors)
+}
@@ -1,24 +1,31 @@
-use tudelft_quadrupel::motor::set_motor_max;
-use protocol::{Message, WorkingModes};
-use crate::controllers::PID;
+use protocol::{Message, WorkingModes, NackReason, ParamValue, Setpoint, Estimator};
+use crate::controllers::{AxisController, Controller, ControllerTelemetry, MAX_DT};
+use crate::ahrs::Mahony;
+use crate::imu_calibration::ImuCalibration;
 use crate::drone::{Drone, Getter, Setter};
-use crate::working_mode::raw_sensor_mode::{YawPitchRollRate, Kalman};
+use crate::working_mode::raw_sensor_mode::{YawPitchRollRate, Kalman, Complementary};
 use crate::yaw_pitch_roll::YawPitchRoll;
-use tudelft_quadrupel::time::Instant;
-use crate::drone::motors::{MOTOR_MAX_MANUAL, MOTOR_MAX_CONTROL};
+use crate::parameters::{Parameters, ParamId, PARAM_COUNT};
+use crate::crash::record_mode;
+use crate::hal::Hal;
 
-use crate::working_mode::{mode_switch, motions};
+use crate::working_mode::{mode_switch, motions, Reference};
+use crate::working_mode::panic_mode::PanicRamp;
 use crate::working_mode::calibration_mode::Calibration;
 use crate::working_mode::full_control_mode::FullController;
+use crate::working_mode::yaw_control_mode::{yaw_controller, configure_yaw_controller};
+use crate::working_mode::height_control_mode::{height_controller, configure_height_controller};
 
 fn gain_u16_to_f32(u16_value: u16) -> f32 {
     let f32_value = u16_value as f32 / 10000.0;
     f32_value
 }
 
-impl Drone {
-    pub fn initialize() -> Drone{
-        Drone{
+impl<H: Hal> Drone<H> {
+    pub fn initialize(hal: H) -> Self {
+        let now = hal.now_us();
+        let mut drone = Drone{
+            hal,
             mode: WorkingModes::SafeMode,
             current_attitude: YawPitchRoll{ yaw: 0.0, pitch: 0.0, roll: 0.0 },
             last_attitude:YawPitchRoll{ yaw: 0.0, pitch: 0.0, roll: 0.0 },
@@ -26,82 +33,152 @@ impl Drone {
             angles_dmp: YawPitchRoll{yaw: 0.0, pitch: 0.0, roll: 0.0},
             angles_filtered: YawPitchRoll{yaw: 0.0, pitch: 0.0, roll: 0.0},
             height: 0.0,
-            height_start_flag: 0,
-            yaw_controller: PID::new(0.0,0.0,0.0),
+            reference: Reference::Holding,
+            panic_ramp: PanicRamp::new([0, 0, 0, 0]),
+            yaw_controller: yaw_controller(),
             full_controller: FullController::new(),
-            height_controller: PID::new(0.0, 0.5, 0.0),
+            height_controller: height_controller(),
             arguments: [0, 0, 0, 0],
-            sample_time: Instant::now(),
-            last_sample_time: Instant::now(),
+            sample_time: now,
+            last_sample_time: now,
             calibration: Calibration::new(),
+            imu_calibration: ImuCalibration::default(),
             test: [0.0, 0.0, 0.0, 0.0],
+            pid: ControllerTelemetry::default(),
             angles_raw: YawPitchRoll { yaw: 0.0, pitch: 0.0, roll: 0.0 },
             rates_raw: YawPitchRollRate { yaw_rate: 0.0, pitch_rate: 0.0, roll_rate: 0.0 },
             kalman: Kalman::new(),
-            raw_flag: 0,
+            complementary: Complementary::new(),
+            ahrs: Mahony::default(),
+            estimator: Estimator::Kalman,
+            parameters: Parameters::new(),
+            message_gains: [0; PARAM_COUNT],
+        };
+        drone.apply_parameters();
+        drone
+    }
+
+    /// Peripherals of the drone
+    pub fn hal(&mut self) -> &mut H {
+        &mut self.hal
+    }
+
+    /// Give the peripherals back when the drone stops
+    pub fn into_hal(self) -> H {
+        self.hal
+    }
+
+    /// Push the parameter table into the controllers and filters
+    fn apply_parameters(&mut self) {
+        let parameters = self.parameters;
+
+        configure_yaw_controller(&mut self.yaw_controller, &parameters);
+        self.full_controller.configure(&parameters);
+        configure_height_controller(&mut self.height_controller, &parameters);
+        self.kalman.set_noise(parameters.get_f32(ParamId::KalmanQAngle),
+                              parameters.get_f32(ParamId::KalmanQBias),
+                              parameters.get_f32(ParamId::KalmanRMeasure));
+        self.complementary.set_time_constant(parameters.get_f32(ParamId::ComplementaryTau));
+        self.ahrs.set_gains(parameters.get_f32(ParamId::AhrsKp), parameters.get_f32(ParamId::AhrsKi));
+    }
+
+    /// Gains are still sent along with the control messages. They only overwrite the parameter
+    /// table when the PC changes them, so values set with ParamSet are kept otherwise.
+    fn gain_from_message(&mut self, id: ParamId, gain: u16) {
+        if self.message_gains[id as usize] != gain {
+            self.message_gains[id as usize] = gain;
+            let _ = self.set_parameter(id as u8, ParamValue::F32(gain_u16_to_f32(gain)));
         }
     }
 
-    //Used to check new command and react to corresponding commands
-    pub fn message_check(&mut self, message: &Message){
+    /// Switch to a flight mode and steer towards the setpoint. When the mode change is rejected
+    /// the drone keeps following the setpoint in its current mode.
+    fn setpoint_command(&mut self, mode: WorkingModes, setpoint: Setpoint) -> Result<(), NackReason> {
+        match mode {
+            WorkingModes::ManualMode
+            | WorkingModes::YawControlMode
+            | WorkingModes::FullControlMode
+            | WorkingModes::HeightControlMode
+            | WorkingModes::RawSensorMode => (),
+            _ => {
+                // Setpoints are only meant for the flight modes
+                let _ = mode_switch(self, WorkingModes::SafeMode, 0.0);
+                return Err(NackReason::Unsupported);
+            }
+        }
+
+        let result = mode_switch(self, mode, setpoint.thrust);
+        match self.mode {
+            WorkingModes::ManualMode => self.hal.set_motor_max(self.parameters.get_u16(ParamId::MotorMaxManual)),
+            WorkingModes::YawControlMode
+            | WorkingModes::FullControlMode
+            | WorkingModes::HeightControlMode => self.hal.set_motor_max(self.parameters.get_u16(ParamId::MotorMaxControl)),
+            _ => (),
+        }
+        motions(self, setpoint);
+        self.arguments = setpoint.sticks();
+        result
+    }
+
+    //Used to check new command and react to corresponding commands. Returns whether the
+    //requested mode was accepted, so it can be reported back to the PC.
+    //The mode messages with raw stick values are still accepted, they are converted to a setpoint.
+    pub fn message_check(&mut self, message: &Message) -> Result<(), NackReason> {
         match message {
-            Message::SafeMode => mode_switch(self, WorkingModes::SafeMode),
-            Message::PanicMode => mode_switch(self, WorkingModes::PanicMode),
-            Message::ManualMode(pitch, roll, yaw, lift)
-            => {
-                set_motor_max(MOTOR_MAX_MANUAL);
-                mode_switch(self, WorkingModes::ManualMode);
-                motions(self, [*pitch, *roll, *yaw, *lift]);
-                self.arguments = [*pitch, *roll, *yaw, *lift]
+            Message::SafeMode => mode_switch(self, WorkingModes::SafeMode, 0.0),
+            Message::PanicMode => mode_switch(self, WorkingModes::PanicMode, 0.0),
+            Message::Setpoint(mode, setpoint) => self.setpoint_command(*mode, *setpoint),
+            Message::ManualMode(pitch, roll, yaw, lift) => {
+                self.setpoint_command(WorkingModes::ManualMode, Setpoint::from_sticks([*pitch, *roll, *yaw, *lift]))
             }
             Message::YawControlMode(pitch, roll, yaw, lift, p) => {
-                set_motor_max(MOTOR_MAX_CONTROL);
-                mode_switch(self, WorkingModes::YawControlMode);
-                motions(self, [*pitch, *roll, *yaw, *lift]);
-                self.set_yaw_gain((gain_u16_to_f32(*p), 0.0, 0.1));
-                self.arguments = [*pitch, *roll, *yaw, *lift]
+                let result = self.setpoint_command(WorkingModes::YawControlMode, Setpoint::from_sticks([*pitch, *roll, *yaw, *lift]));
+                self.gain_from_message(ParamId::YawP, *p);
+                result
             }
             Message::CalibrationMode => {
-                mode_switch(self, WorkingModes::CalibrationMode);
-                motions(self, [0, 0, 0, 0]);
-                self.arguments = [0, 0, 0, 0]
+                let result = mode_switch(self, WorkingModes::CalibrationMode, 0.0);
+                if result.is_ok() {
+                    motions(self, Setpoint::default());
+                }
+                self.arguments = [0, 0, 0, 0];
+                result
             }
             Message::FullControlMode(pitch, roll, yaw, lift
                                      , yaw_p2, pitch_roll_p1, pitch_roll_p2) => {
-                set_motor_max(MOTOR_MAX_CONTROL);
-                mode_switch(self, WorkingModes::FullControlMode);
-                motions(self, [*pitch, *roll, *yaw, *lift]);
-                self.set_full_gain(gain_u16_to_f32(*yaw_p2),
-                                   gain_u16_to_f32(*pitch_roll_p1),
-                                   gain_u16_to_f32(*pitch_roll_p2));
-                self.arguments = [*pitch, *roll, *yaw, *lift]
+                let result = self.setpoint_command(WorkingModes::FullControlMode, Setpoint::from_sticks([*pitch, *roll, *yaw, *lift]));
+                self.gain_from_message(ParamId::YawRateP, *yaw_p2);
+                self.gain_from_message(ParamId::AngleP, *pitch_roll_p1);
+                self.gain_from_message(ParamId::RateP, *pitch_roll_p2);
+                result
             }
             Message::HeightControlMode(pitch, roll, yaw, lift, yaw_p2, pitch_roll_p1,
                                         pitch_roll_p2, height_p) =>{
-                set_motor_max(MOTOR_MAX_CONTROL);
-                mode_switch( self, WorkingModes::HeightControlMode);
-                motions( self, [ *pitch, *roll, *yaw, *lift]);
-                self.set_full_gain(gain_u16_to_f32( *yaw_p2),
-                gain_u16_to_f32( *pitch_roll_p1),
-                gain_u16_to_f32( *pitch_roll_p2));
-                self.set_height_gain(gain_u16_to_f32( *height_p));
-                self.arguments = [*pitch, *roll, *yaw, *lift]
+                let result = self.setpoint_command(WorkingModes::HeightControlMode, Setpoint::from_sticks([*pitch, *roll, *yaw, *lift]));
+                self.gain_from_message(ParamId::YawRateP, *yaw_p2);
+                self.gain_from_message(ParamId::AngleP, *pitch_roll_p1);
+                self.gain_from_message(ParamId::RateP, *pitch_roll_p2);
+                self.gain_from_message(ParamId::HeightP, *height_p);
+                result
             }
             Message::RawSensorMode(pitch, roll, yaw, lift
                                    , yaw_p2, pitch_roll_p1, pitch_roll_p2) => {
-                mode_switch(self, WorkingModes::RawSensorMode);
-                motions(self, [ *pitch, *roll, *yaw, *lift]);
-                self.set_full_gain(gain_u16_to_f32( *yaw_p2),
-                                   gain_u16_to_f32( *pitch_roll_p1),
-                                   gain_u16_to_f32( *pitch_roll_p2));
-                self.arguments = [*pitch, *roll, *yaw, *lift]
+                let result = self.setpoint_command(WorkingModes::RawSensorMode, Setpoint::from_sticks([*pitch, *roll, *yaw, *lift]));
+                self.gain_from_message(ParamId::YawRateP, *yaw_p2);
+                self.gain_from_message(ParamId::AngleP, *pitch_roll_p1);
+                self.gain_from_message(ParamId::RateP, *pitch_roll_p2);
+                result
+            }
+            _ => {
+                // Unknown commands put the drone in safe mode
+                let _ = mode_switch(self, WorkingModes::SafeMode, 0.0);
+                Err(NackReason::Unsupported)
             }
-            _ => mode_switch(self, WorkingModes::SafeMode),//TODO: add new mode and change the 'new' argument
         }
     }
 }
 
-impl Getter for Drone {
+impl<H: Hal> Getter for Drone<H> {
     fn get_mode(&self) -> WorkingModes {
         match self.mode {
             WorkingModes::SafeMode => WorkingModes::SafeMode,
@@ -122,39 +199,34 @@ impl Getter for Drone {
 
     fn get_height(&self) -> f32 { self.height }
 
-    fn get_yaw_controller(&self) -> PID { self.yaw_controller }
-    fn get_full_controller(&self) -> FullController { self.full_controller }
-    fn get_height_controller(&self) -> PID { self.height_controller }
+    fn get_yaw_controller(&mut self) -> &mut AxisController { &mut self.yaw_controller }
+    fn get_full_controller(&mut self) -> &mut FullController { &mut self.full_controller }
+    fn get_height_controller(&mut self) -> &mut AxisController { &mut self.height_controller }
     fn get_arguments(&self) -> [u16; 4] { self.arguments }
-    fn get_sample_time(&self) -> Instant { self.sample_time }
-    fn get_time_diff(&self) -> u128 { self.sample_time.duration_since(self.last_sample_time).as_millis() }
+    fn get_sample_time(&self) -> u64 { self.sample_time }
+    fn get_time_diff(&self) -> u128 { self.sample_time.saturating_sub(self.last_sample_time) as u128 }
+    fn get_dt(&self) -> f32 { (self.get_time_diff() as f32 / 1_000_000.0).min(MAX_DT) }
     fn get_calibration(&self) -> Calibration { self.calibration }
+    fn get_imu_calibration(&self) -> ImuCalibration { self.imu_calibration }
     fn get_test(&self) -> [f32; 4] { self.test }
-    fn get_yaw_pwm_change(&self) -> f32 { self.yaw_controller.pwm_change }
-    fn get_angle_pwm_change(&self) -> [f32; 2] {
-        [self.full_controller.pitch_p1.pwm_change,
-        self.full_controller.roll_p1.pwm_change]
-    }
-    fn get_rate_pwm_change(&self) -> [f32; 3] {
-        [self.full_controller.yaw_p2.pwm_change,
-        self.full_controller.pitch_p2.pwm_change,
-        self.full_controller.roll_p2.pwm_change]
-    }
-    fn get_height_pwm_change(&self) -> f32 {
-        self.height_controller.pwm_change
-    }
+    fn get_pid(&self) -> ControllerTelemetry { self.pid }
     fn get_filtered_angles(&self) -> YawPitchRoll { self.angles_filtered }
     fn get_dmp_angles(&self) -> YawPitchRoll { self.angles_dmp }
     fn get_raw_angles(&self) -> YawPitchRoll { self.angles_raw }
     fn get_raw_rates(&self) -> YawPitchRollRate { self.rates_raw }
-    fn get_raw_flag(&self) -> u16 { self.raw_flag }
     fn get_kalman(&mut self) -> &mut Kalman { &mut self.kalman }
-    fn get_height_flag(&self) -> u16 { self.height_start_flag }
+    fn get_complementary(&mut self) -> &mut Complementary { &mut self.complementary }
+    fn get_ahrs(&mut self) -> &mut Mahony { &mut self.ahrs }
+    fn get_estimator(&self) -> Estimator { self.estimator }
+    fn get_reference(&self) -> Reference { self.reference }
+    fn get_panic_ramp(&self) -> PanicRamp { self.panic_ramp }
+    fn get_parameters(&self) -> &Parameters { &self.parameters }
 }
 
-impl Setter for Drone {
+impl<H: Hal> Setter for Drone<H> {
     fn set_mode(&mut self, mode: WorkingModes){
         self.mode = mode;
+        record_mode(mode);
     }
     fn set_current_attitude(&mut self, angles: [f32; 3]){
         self.current_attitude.yaw = angles[0];
@@ -173,89 +245,28 @@ impl Setter for Drone {
         self.height = current_height;
     }
 
-    fn set_yaw_controller(&mut self, errors: (f32, f32), pwm: f32) {
-        self.yaw_controller.last_error = errors.0;
-        self.yaw_controller.previous_error = errors.1;
-        self.yaw_controller.pwm_change += pwm;
-    }
-
-    fn set_full_angle_controller(&mut self, pitch_p1: [f32; 2], roll_p1: [f32; 2], pwm: [f32; 2]){
-        self.full_controller.pitch_p1.last_error = pitch_p1[0];
-        self.full_controller.pitch_p1.previous_error = pitch_p1[1];
-        self.full_controller.pitch_p1.pwm_change += pwm[0];
-        self.full_controller.roll_p1.last_error = roll_p1[0];
-        self.full_controller.roll_p1.previous_error = roll_p1[1];
-        self.full_controller.roll_p1.pwm_change += pwm[1];
-    }
-
-    fn set_full_rate_controller(&mut self, yaw_p2: [f32; 2], pitch_p2: [f32; 2], roll_p2: [f32; 2], pwm: [f32; 3]){
-        self.full_controller.yaw_p2.last_error = yaw_p2[0];
-        self.full_controller.yaw_p2.previous_error = yaw_p2[1];
-        self.full_controller.yaw_p2.pwm_change += pwm[0];
-
-        self.full_controller.pitch_p2.last_error = pitch_p2[0];
-        self.full_controller.pitch_p2.previous_error = pitch_p2[1];
-        self.full_controller.pitch_p2.pwm_change += pwm[1];
-
-        self.full_controller.roll_p2.last_error = roll_p2[0];
-        self.full_controller.roll_p2.previous_error = roll_p2[1];
-        self.full_controller.roll_p2.pwm_change += pwm[2];
-    }
-
-    fn set_height_controller(&mut self, errors: [f32; 2], pwm: f32) {
-        self.height_controller.last_error = errors[0];
-        self.height_controller.previous_error = errors[1];
-        self.height_controller.pwm_change += pwm;
-    }
-
-    fn set_height_gain(&mut self, gain: f32) {
-        self.height_controller.kp = gain;
-    }
-    fn set_yaw_gain(&mut self, gain: (f32, f32, f32)) {
-        if self.yaw_controller.kp != gain.0 {
-            self.yaw_controller.kp = gain.0;
-            self.yaw_controller.ki = gain.1;
-            self.yaw_controller.kd = gain.2;
-            self.reset_yaw_controller();
-        }
-    }
-
-    fn set_full_gain(&mut self, yaw_p2: f32, pitch_roll_p1: f32, pitch_roll_p2: f32) {
-        if self.full_controller.pitch_p1.kp != pitch_roll_p1{
-            self.full_controller.pitch_p1.kp = pitch_roll_p1;
-            self.full_controller.roll_p1.kp = pitch_roll_p1;
-            self.reset_fpr1_controller();
-        }
-        if self.full_controller.pitch_p2.kp != pitch_roll_p2{
-            self.full_controller.pitch_p2.kp = pitch_roll_p2;
-            self.full_controller.roll_p2.kp = pitch_roll_p2;
-
-            self.reset_fpr2_controller();
-        }
-        if self.full_controller.yaw_p2.kp != yaw_p2{
-            self.full_controller.yaw_p2.kp = yaw_p2;
-            self.reset_fy2_controller();
-        }
-    }
-    fn set_sample_time(&mut self, time: Instant) {
+    fn set_sample_time(&mut self, time: u64) {
         self.sample_time = time;
     }
 
-    fn set_last_time(&mut self, time: Instant) { self.last_sample_time = time; }
+    fn set_last_time(&mut self, time: u64) { self.last_sample_time = time; }
     fn set_calibration(&mut self, yaw: [f32; 2], pitch: [f32; 2], roll: [f32; 2], acc_z: f32) {
         self.calibration.yaw_dmp = yaw;
         self.calibration.pitch_dmp = pitch;
         self.calibration.roll_dmp = roll;
         self.calibration.acceleration_z = acc_z;
+        // Every call is one calibration sample
+        self.calibration.samples = self.calibration.samples.saturating_add(1);
     }
     fn set_test(&mut self, test_value: [f32; 4]) { self.test = test_value }
+    fn set_pid(&mut self, pid: ControllerTelemetry) { self.pid = pid }
     fn set_dmp_angles(&mut self, angles: [f32; 3]) {
         self.angles_dmp.yaw = angles[0];
         self.angles_dmp.pitch = angles[1];
         self.angles_dmp.roll = angles[2];
     }
     fn set_raw_angles(&mut self, angles:[f32; 3]) {
-        self.angles_raw.yaw -= angles[0];
+        self.angles_raw.yaw = angles[0];
         self.angles_raw.pitch = angles[1];
         self.angles_raw.roll = angles[2];
     }
@@ -264,61 +275,20 @@ impl Setter for Drone {
         self.rates_raw.pitch_rate = rate[1];
         self.rates_raw.roll_rate = rate[2];
     }
-    fn set_raw_flag(&mut self, count: u16) {
-        self.raw_flag += count;
-    }
-
-    fn reset_raw_flag(&mut self) {
-        self.raw_flag = 0;
-    }
     fn reset_all_controller(&mut self) {
-        self.reset_yaw_controller();
-        self.reset_fpr1_controller();
-        self.reset_fpr2_controller();
-        self.reset_fy2_controller();
-        self.reset_h_controller();
-    }
-    fn reset_yaw_controller(&mut self) {
-        self.yaw_controller.pwm_change = 0.0;
-        self.yaw_controller.last_error = 0.0;
-        self.yaw_controller.previous_error = 0.0;
-    }
-    fn reset_fpr1_controller(&mut self) {
-        self.full_controller.pitch_p1.pwm_change = 0.0;
-        self.full_controller.pitch_p1.last_error = 0.0;
-        self.full_controller.pitch_p1.previous_error = 0.0;
-        self.full_controller.roll_p1.pwm_change = 0.0;
-        self.full_controller.roll_p1.last_error = 0.0;
-        self.full_controller.roll_p1.previous_error = 0.0;
-    }
-
-    fn reset_fpr2_controller(&mut self) {
-        self.full_controller.pitch_p2.pwm_change = 0.0;
-        self.full_controller.pitch_p2.last_error = 0.0;
-        self.full_controller.pitch_p2.previous_error = 0.0;
-        self.full_controller.roll_p2.pwm_change = 0.0;
-        self.full_controller.roll_p2.last_error = 0.0;
-        self.full_controller.roll_p2.previous_error = 0.0;
-    }
-
-    fn reset_fy2_controller(&mut self) {
-        self.full_controller.yaw_p2.pwm_change = 0.0;
-        self.full_controller.yaw_p2.last_error = 0.0;
-        self.full_controller.yaw_p2.previous_error = 0.0;
+        self.yaw_controller.reset();
+        self.full_controller.reset();
+        self.height_controller.reset();
+        // The time of the first update is counted from now, not from when the controllers last ran
+        self.last_sample_time = self.hal.now_us();
     }
 
-    fn reset_h_controller(&mut self) {
-        self.height_controller.pwm_change = 0.0;
-        self.height_controller.last_error = 0.0;
-        self.height_controller.previous_error = 0.0;
+    fn set_reference(&mut self, reference: Reference) {
+        self.reference = reference;
     }
 
-    fn reset_height_flag(&mut self) {
-        self.height_start_flag = 0;
-    }
-
-    fn set_height_flag(&mut self, count: u16) {
-        self.height_start_flag += count;
+    fn set_panic_ramp(&mut self, ramp: PanicRamp) {
+        self.panic_ramp = ramp;
     }
 
     fn set_height_calibration(&mut self, cali: f32) {
@@ -333,4 +303,16 @@ impl Setter for Drone {
     fn set_filtered_angles(&mut self, angles: YawPitchRoll) {
         self.angles_filtered = angles;
     }
+    fn set_estimator(&mut self, estimator: Estimator) {
+        self.estimator = estimator;
+    }
+    fn set_imu_calibration(&mut self, calibration: ImuCalibration) {
+        self.imu_calibration = calibration;
+    }
+
+    fn set_parameter(&mut self, index: u8, value: ParamValue) -> Result<(), NackReason> {
+        self.parameters.set(index, value)?;
+        self.apply_parameters();
+        Ok(())
+    }
 }
\ No newline at end of file
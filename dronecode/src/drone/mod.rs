@@ -1,35 +1,49 @@
 mod drone;
 pub mod motors;
 
-use crate::controllers::PID;
-use crate::working_mode::raw_sensor_mode::{YawPitchRollRate, Kalman};
-use protocol::WorkingModes;
-use tudelft_quadrupel::time::Instant;
+use crate::controllers::{AxisController, ControllerTelemetry};
+use crate::ahrs::Mahony;
+use crate::imu_calibration::ImuCalibration;
+use crate::working_mode::raw_sensor_mode::{YawPitchRollRate, Kalman, Complementary};
+use protocol::{WorkingModes, ParamValue, NackReason, Estimator};
 use crate::yaw_pitch_roll::YawPitchRoll;
 use crate::working_mode::calibration_mode::Calibration;
 use crate::working_mode::full_control_mode::FullController;
+use crate::working_mode::Reference;
+use crate::working_mode::panic_mode::PanicRamp;
+use crate::parameters::{Parameters, PARAM_COUNT};
+use crate::hal::Hal;
 
-pub struct Drone{
+/// State of the drone, the flight code reaches the hardware through the peripherals it owns
+pub struct Drone<H: Hal>{
+    hal: H,
     mode: WorkingModes,
     current_attitude: YawPitchRoll,
     last_attitude: YawPitchRoll,
     acceleration_z: f32,
     height: f32,
-    height_start_flag: u16,
+    reference: Reference,   // sub-state of HeightControlMode and RawSensorMode
+    panic_ramp: PanicRamp,
     angles_dmp: YawPitchRoll,
     angles_filtered: YawPitchRoll,
     angles_raw: YawPitchRoll,
     rates_raw: YawPitchRollRate,
-    yaw_controller: PID,
+    yaw_controller: AxisController,
     full_controller: FullController, // pitch p1, roll p1, yaw p1, pitch p2, roll p2
-    height_controller: PID,
+    height_controller: AxisController,
     arguments: [u16; 4],
-    sample_time: Instant,
-    last_sample_time: Instant,
+    sample_time: u64,      // us since boot
+    last_sample_time: u64,
     calibration: Calibration,
-    test: [f32; 4],
-    raw_flag: u16,
+    imu_calibration: ImuCalibration,   // gyro bias and accelerometer offsets and scales of the raw IMU
+    test: [f32; 4],     // free for debugging values
+    pid: ControllerTelemetry,   // terms of the main controller of the working mode
     kalman: Kalman,
+    complementary: Complementary,
+    ahrs: Mahony,
+    estimator: Estimator,  // estimator that gives the attitude in RawSensorMode
+    parameters: Parameters,
+    message_gains: [u16; PARAM_COUNT], // last gains received in control messages, raw u16 values
 }
 
 pub trait Getter{
@@ -38,25 +52,28 @@ pub trait Getter{
     fn get_last_attitude(&self) -> YawPitchRoll;
     fn get_acceleration_z(&self) -> f32;
     fn get_height(&self) -> f32;
-    fn get_yaw_controller(&self) -> PID;
-    fn get_full_controller(&self) -> FullController;
-    fn get_height_controller(&self) -> PID;
+    fn get_yaw_controller(&mut self) -> &mut AxisController;
+    fn get_full_controller(&mut self) -> &mut FullController;
+    fn get_height_controller(&mut self) -> &mut AxisController;
     fn get_arguments(&self) -> [u16; 4];
-    fn get_sample_time(&self) -> Instant;
-    fn get_time_diff(&self) -> u128;
+    fn get_sample_time(&self) -> u64;
+    fn get_time_diff(&self) -> u128;   // us since the last sample
+    fn get_dt(&self) -> f32;           // s since the last sample for the controllers, at most MAX_DT
     fn get_calibration(&self) -> Calibration;
+    fn get_imu_calibration(&self) -> ImuCalibration;
     fn get_test(&self) -> [f32; 4];
-    fn get_yaw_pwm_change(&self) -> f32;
-    fn get_angle_pwm_change(&self) -> [f32; 2];
-    fn get_rate_pwm_change(&self) -> [f32; 3];
-    fn get_height_pwm_change(&self) -> f32;
+    fn get_pid(&self) -> ControllerTelemetry;
     fn get_filtered_angles(&self) -> YawPitchRoll;
     fn get_dmp_angles(&self) -> YawPitchRoll;
     fn get_raw_angles(&self) -> YawPitchRoll;
     fn get_raw_rates(&self) -> YawPitchRollRate;
-    fn get_raw_flag(&self) -> u16;
     fn get_kalman(&mut self) -> &mut Kalman;
-    fn get_height_flag(&self) -> u16;
+    fn get_complementary(&mut self) -> &mut Complementary;
+    fn get_ahrs(&mut self) -> &mut Mahony;
+    fn get_estimator(&self) -> Estimator;
+    fn get_reference(&self) -> Reference;
+    fn get_panic_ramp(&self) -> PanicRamp;
+    fn get_parameters(&self) -> &Parameters;
 }
 
 pub trait Setter{
@@ -65,33 +82,23 @@ pub trait Setter{
     fn set_last_attitude(&mut self, angles:[f32; 3]);
     fn set_acceleration_z(&mut self, current_acceleration_z: f32);
     fn set_height(&mut self, current_height: f32);
-    fn set_yaw_controller(&mut self, errors: (f32, f32), pwm: f32);
-    fn set_full_angle_controller(&mut self, pitch_p1: [f32; 2], roll_p1: [f32; 2], pwm: [f32; 2]);
-    fn set_full_rate_controller(&mut self, yaw_p2: [f32; 2], pitch_p2: [f32; 2], roll_p2: [f32; 2], pwm: [f32; 3]);
-    fn set_height_controller(&mut self, errors: [f32; 2], pwm: f32);
-    fn set_height_gain(&mut self, gain: f32);
-    fn set_yaw_gain(&mut self, gain: (f32, f32, f32));
-    fn set_full_gain(&mut self,  yaw_p2: f32, pitch_roll_p1: f32, pitch_roll_p2: f32);
-    fn set_sample_time(&mut self, time: Instant);
-    fn set_last_time(&mut self, time: Instant);
+    fn set_sample_time(&mut self, time: u64);
+    fn set_last_time(&mut self, time: u64);
     fn set_calibration(&mut self, yaw: [f32; 2], pitch: [f32; 2], roll: [f32; 2], acc_z: f32);
     fn set_test(&mut self, test_value: [f32; 4]);
+    fn set_pid(&mut self, pid: ControllerTelemetry);
     fn set_filtered_angles(&mut self, angles: YawPitchRoll);
     fn set_dmp_angles(&mut self, angles:[f32; 3]);
     fn set_raw_angles(&mut self, angles:[f32; 3]);
     fn set_raw_rates(&mut self, rate:[f32; 3]);
-    fn set_raw_flag(&mut self, count: u16);
-    fn reset_raw_flag(&mut self);
     fn reset_all_controller(&mut self);
-    fn reset_yaw_controller(&mut self);
-    fn reset_fpr1_controller(&mut self);
-    fn reset_fpr2_controller(&mut self);
-    fn reset_fy2_controller(&mut self);
-    fn reset_h_controller(&mut self);
-    fn set_height_flag(&mut self, count: u16);
-    fn reset_height_flag(&mut self);
+    fn set_reference(&mut self, reference: Reference);
+    fn set_panic_ramp(&mut self, ramp: PanicRamp);
     fn set_height_calibration(&mut self, cali: f32);
     fn set_kal_calibration(&mut self, cali: YawPitchRoll);
+    fn set_estimator(&mut self, estimator: Estimator);
+    fn set_imu_calibration(&mut self, calibration: ImuCalibration);
+    fn set_parameter(&mut self, index: u8, value: ParamValue) -> Result<(), NackReason>;
 }
 
 
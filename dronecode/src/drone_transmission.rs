@@ -1,52 +1,243 @@
-use protocol::{self, Packet, Message, PacketManager};
-use tudelft_quadrupel::uart::{send_bytes, receive_bytes};
-use tudelft_quadrupel::led::{Blue, Green, Red, Yellow};
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use protocol::{self, Packet, Message, Datalog, LinkInfo, LogLevel, NackReason, FrameDecoder, Telemetry, TelemetryConfig, TelemetryGroup, AuthKey, capabilities, PC_ID, MAX_COMMANDS_IN_FLIGHT};
+use crate::config::DEFAULT_VEHICLE_ID;
+use crate::hal::Uart;
 
-use alloc::{vec::Vec};
+/// Build id of this firmware, reported to the PC in the link handshake.
+/// Can be overridden at build time with the QUADRUPEL_BUILD_ID environment variable.
+pub const FIRMWARE_BUILD_ID: u32 = protocol::build_id(match option_env!("QUADRUPEL_BUILD_ID") {
+    Some(id) => id,
+    None => concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION")),
+});
+
+/// Pre-shared key for authenticated commands, 32 hex characters in the DRONE_AUTH_KEY environment
+/// variable at build time. Without it the drone accepts unauthenticated commands.
+pub const AUTH_KEY: Option<AuthKey> = match option_env!("DRONE_AUTH_KEY") {
+    Some(hex) => match protocol::auth::parse_key(hex) {
+        Some(key) => Some(key),
+        None => panic!("DRONE_AUTH_KEY must be 32 hex characters"),
+    },
+    None => None,
+};
+
+/// Features this firmware supports
+pub const FIRMWARE_CAPABILITIES: u32 = capabilities::MANUAL_MODE
+    | capabilities::CALIBRATION_MODE
+    | capabilities::YAW_CONTROL_MODE
+    | capabilities::FULL_CONTROL_MODE
+    | capabilities::HEIGHT_CONTROL_MODE
+    | capabilities::RAW_SENSOR_MODE
+    | capabilities::DATALOGGING
+    | capabilities::RELIABLE_COMMANDS
+    | capabilities::PARAMETERS
+    | capabilities::LINK_STATS
+    | capabilities::LOG_MESSAGES
+    | capabilities::CRASH_REPORTS
+    | capabilities::TIME_SYNC
+    | capabilities::TELEMETRY_GROUPS
+    | capabilities::COMPACT_DATALOG
+    | capabilities::LOG_DOWNLOAD
+    | capabilities::ADDRESSING
+    | capabilities::SETPOINTS
+    | capabilities::MODE_GUARDS
+    | capabilities::ESTIMATORS
+    | capabilities::AHRS
+    | capabilities::IMU_CALIBRATION
+    | if AUTH_KEY.is_some() { capabilities::AUTHENTICATION } else { 0 };
+
+/// System id of the drone, the source of every packet it sends. An atomic, so the panic handler can use it.
+static VEHICLE_ID: AtomicU8 = AtomicU8::new(DEFAULT_VEHICLE_ID);
+
+pub fn vehicle_id() -> u8 {
+    VEHICLE_ID.load(Ordering::Relaxed)
+}
+
+pub fn set_vehicle_id(id: u8) {
+    VEHICLE_ID.store(id, Ordering::Relaxed);
+}
+
+/// Handshake information of the drone in the given session, sent in reply to Hello
+pub fn link_info(session: u32) -> LinkInfo {
+    LinkInfo::new(FIRMWARE_BUILD_ID, FIRMWARE_CAPABILITIES).with_session(session)
+}
 
 /// Write message to the PC
-pub fn write_packet(message: Message) {
+pub fn write_packet(uart: &mut impl Uart, message: Message) {
+
+    // Create packet and send it over the serial port, or queue it when the uart has a send queue
+    uart.send_packet(Packet::new(message).addressed(vehicle_id(), PC_ID));
+}
+
+/// Send the telemetry groups selected in the config, taken from the full datalog
+pub fn write_telemetry(uart: &mut impl Uart, config: &TelemetryConfig, datalog: &Datalog) {
+    for group in TelemetryGroup::ALL {
+        if config.contains(group) {
+            write_packet(uart, Message::Telemetry(datalog.rtc, Telemetry::from_datalog(group, datalog)));
+        }
+    }
+}
 
-    // Create packet
-    let mut packet = Packet::new(message);
+/// Send a text message to the PC. Use the log! macro instead of calling this directly.
+pub fn write_log(uart: &mut impl Uart, level: LogLevel, args: fmt::Arguments) {
+    write_packet(uart, Message::Log { level, text: protocol::log_text(args) });
+}
 
-    // Serialize packet
-    let serialized_packet = packet.to_bytes();
-    
-    // Send data over serial port
-    send_bytes(&serialized_packet);
+/// Send a formatted text message to the PC without corrupting the packet stream,
+/// e.g. `log!(uart, Info, "Erasing flash")`
+macro_rules! log {
+    ($uart:expr, $level:ident, $($arg:tt)*) => {
+        $crate::drone_transmission::write_log($uart, protocol::LogLevel::$level, format_args!($($arg)*))
+    };
 }
+pub(crate) use log;
 
-/// Read message from the data
-pub fn read_packet(buf: &mut [u8]) -> Result<Packet, ()> {      
-    if let Ok(packet) = Packet::from_bytes(buf) {    
-        // write_packet(packet.message);                   
-        Ok(packet)
-    } else {
-        Err(())
+/// Let the PC know whether the command with the given sequence number was accepted
+pub fn write_reply(uart: &mut impl Uart, seq: u16, result: Result<(), NackReason>) {
+    match result {
+        Ok(()) => write_packet(uart, Message::Ack(seq)),
+        Err(reason) => write_packet(uart, Message::Nack(seq, reason)),
     }
 }
 
-/// Read message from the drone, if available
-pub fn read_message(shared_buf: &mut Vec<u8>) -> Option<Packet> {
-    let mut end_byte_idx = shared_buf.iter().position(|&byte| byte == 0);
-    if end_byte_idx.is_none() {
-        let mut read_buf = [1u8; 255];
-        let num = receive_bytes(&mut read_buf);
-        if num > 0 {
-            shared_buf.extend_from_slice(&read_buf[0..num]);
-            end_byte_idx = shared_buf.iter().position(|&byte| byte == 0);
+/// Number of replies that are remembered. The PC has a limited number of commands in flight, but
+/// while it waits for a lost reply the other commands can be answered and replaced by new ones.
+const REPLIES: usize = 2 * MAX_COMMANDS_IN_FLIGHT;
+
+/// A command with a sequence number, known by the checksum of its message, and the reply it got
+#[derive(Copy, Clone)]
+struct Reply {
+    seq: u16,
+    checksum: u32,
+    result: Result<(), NackReason>,
+}
+
+/// The last commands with a sequence number and the replies they got. The PC sends a command again
+/// when the reply got lost, the copy is answered with the same reply instead of executing it twice.
+pub struct Replies {
+    recent: [Option<Reply>; REPLIES],
+    next: usize,    // slot of the next reply, the oldest one is replaced
+}
+
+impl Replies {
+    pub const fn new() -> Self {
+        Replies { recent: [None; REPLIES], next: 0 }
+    }
+
+    /// Reply to a command and remember the result, commands without a sequence number get no reply
+    pub fn reply(&mut self, uart: &mut impl Uart, seq: u16, message: &Message, result: Result<(), NackReason>) {
+        if seq != 0 {
+            write_reply(uart, seq, result);
+            self.recent[self.next] = Some(Reply { seq, checksum: message.checksum(), result });
+            self.next = (self.next + 1) % REPLIES;
+        }
+    }
+
+    /// Answer a retransmission of a recent command again, returns true when the packet was one
+    pub fn repeat(&self, uart: &mut impl Uart, packet: &Packet) -> bool {
+        if packet.seq == 0 || !self.recent.iter().flatten().any(|reply| reply.seq == packet.seq) {
+            return false;
+        }
+        let checksum = packet.message.checksum();
+        match self.recent.iter().flatten().find(|reply| reply.seq == packet.seq && reply.checksum == checksum) {
+            Some(reply) => {
+                write_reply(uart, reply.seq, reply.result);
+                true
+            }
+            None => false,
         }
     }
 
-    if let Some(end_byte_idx) = end_byte_idx {
-        let packet_result = read_packet(&mut shared_buf[..=end_byte_idx].to_vec());
-        if let Ok(packet) = packet_result {
-            shared_buf.drain(..=end_byte_idx);
+    /// Forget the commands, a new link session starts its sequence numbers again
+    pub fn clear(&mut self) {
+        *self = Replies::new();
+    }
+}
+
+impl Default for Replies {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read message from the PC, if available. Received bytes are collected by the frame decoder,
+/// bad frames are skipped and counted in its link statistics.
+pub fn read_message(uart: &mut impl Uart, decoder: &mut FrameDecoder) -> Option<Packet> {
+    let mut read_buf = [0u8; 255];
+    let space = decoder.space().min(read_buf.len());
+    if space > 0 {
+        let num = uart.receive_bytes(&mut read_buf[..space]);
+        decoder.receive(&read_buf[..num]);
+    }
+
+    while let Some(result) = decoder.next_packet() {
+        if let Ok(packet) = result {
             return Some(packet);
-        } else {
-            shared_buf.drain(..(end_byte_idx + 1));
         }
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{WorkingModes, ParamValue};
+    use crate::hal::mock::MockHal;
+
+    #[test]
+    fn test_retransmission_answered_again() {
+        let mut hal = MockHal::new();
+        let mut replies = Replies::new();
+        let packet = Packet::with_seq(Message::CalibrationMode, 7);
+        assert!(!replies.repeat(&mut hal, &packet));
+        replies.reply(&mut hal, packet.seq, &packet.message, Err(NackReason::ModeRejected(WorkingModes::PanicMode)));
+        assert_eq!(hal.take_messages()[..], [Message::Nack(7, NackReason::ModeRejected(WorkingModes::PanicMode))]);
+
+        // The copy gets the same reply
+        assert!(replies.repeat(&mut hal, &packet));
+        assert_eq!(hal.take_messages()[..], [Message::Nack(7, NackReason::ModeRejected(WorkingModes::PanicMode))]);
+
+        // A new command with the same number, or the same command with a new number, is executed
+        assert!(!replies.repeat(&mut hal, &Packet::with_seq(Message::SafeMode, 7)));
+        assert!(!replies.repeat(&mut hal, &Packet::with_seq(Message::CalibrationMode, 8)));
+        assert!(hal.take_messages().is_empty());
+
+        // Without a sequence number nothing is replied or remembered
+        replies.reply(&mut hal, 0, &Message::SafeMode, Ok(()));
+        assert!(hal.take_messages().is_empty());
+        assert!(replies.repeat(&mut hal, &packet));
+        hal.take_messages();
+
+        // A new session starts the numbers again
+        replies.clear();
+        assert!(!replies.repeat(&mut hal, &packet));
+    }
+
+    #[test]
+    fn test_reply_lost_between_commands() {
+        // The Ack of the mode change got lost, the parameter change sent after it was answered
+        let mut hal = MockHal::new();
+        let mut replies = Replies::new();
+        let mode = Packet::with_seq(Message::CalibrationMode, 1);
+        let param = Packet::with_seq(Message::ParamSet(3, ParamValue::F32(0.5)), 2);
+        replies.reply(&mut hal, mode.seq, &mode.message, Ok(()));
+        replies.reply(&mut hal, param.seq, &param.message, Err(NackReason::ParameterRange));
+        hal.take_messages();
+
+        // Both retransmissions get their reply without executing them
+        assert!(replies.repeat(&mut hal, &mode));
+        assert!(replies.repeat(&mut hal, &param));
+        assert_eq!(hal.take_messages()[..], [Message::Ack(1), Message::Nack(2, NackReason::ParameterRange)]);
+
+        // The mode change is remembered while the other commands are answered and replaced
+        for seq in 3..=REPLIES as u16 {
+            replies.reply(&mut hal, seq, &Message::ParamSet(seq as u8, ParamValue::F32(0.5)), Ok(()));
+            hal.take_sent();
+        }
+        assert!(replies.repeat(&mut hal, &mode));
+        assert_eq!(hal.take_messages()[..], [Message::Ack(1)]);
+        replies.reply(&mut hal, REPLIES as u16 + 1, &Message::SafeMode, Ok(()));
+        hal.take_sent();
+        assert!(!replies.repeat(&mut hal, &mode));
+    }
+}
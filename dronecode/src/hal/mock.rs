@@ -0,0 +1,197 @@
+use heapless::{Deque, Vec};
+use protocol::{FrameDecoder, Message};
+use crate::config::FLASH_SIZE;
+use super::{Accel, Gyro, Quaternion, FlashError, Led, Imu, Barometer, Battery, Motors, Uart, Clock, Flash, Leds};
+
+/// Size of the receive and send buffers of the mock UART
+pub const MOCK_UART_LEN: usize = 1024;
+
+/// Peripherals without hardware, to run the flight code on a PC. Sensor values are set by the
+/// caller, everything the flight code outputs can be read back from the fields.
+pub struct MockHal {
+    pub dmp: Quaternion,
+    pub accel: Accel,
+    pub gyro: Gyro,
+    pub pressure: u32,
+    pub temperature: i32,
+    pub battery: u16,
+    pub motors: [u16; 4],
+    pub motor_max: u16,
+    pub rx: Deque<u8, MOCK_UART_LEN>,   // bytes the drone will receive
+    pub tx: Vec<u8, MOCK_UART_LEN>,     // bytes the drone sent
+    pub time_us: u64,                   // advanced by one tick in wait_for_next_tick
+    pub tick_us: u64,
+    pub flash: [u8; FLASH_SIZE],
+    pub leds: [bool; 4],                // red, yellow, green, blue
+}
+
+impl MockHal {
+    /// A level drone at sea level with a full battery and erased flash
+    pub fn new() -> Self {
+        MockHal {
+            dmp: Quaternion { w: 1.0, x: 0.0, y: 0.0, z: 0.0 },
+            accel: Accel { x: 0, y: 0, z: 16384 },
+            gyro: Gyro::default(),
+            pressure: 101325,
+            temperature: 2000,
+            battery: 1200,
+            motors: [0; 4],
+            motor_max: u16::MAX,
+            rx: Deque::new(),
+            tx: Vec::new(),
+            time_us: 0,
+            tick_us: 10_000,
+            flash: [0xff; FLASH_SIZE],
+            leds: [false; 4],
+        }
+    }
+
+    /// Queue bytes for the drone to receive, returns false when they did not fit
+    pub fn receive(&mut self, bytes: &[u8]) -> bool {
+        bytes.iter().all(|&byte| self.rx.push_back(byte).is_ok())
+    }
+
+    /// Take the bytes the drone sent since the last call
+    pub fn take_sent(&mut self) -> Vec<u8, MOCK_UART_LEN> {
+        core::mem::take(&mut self.tx)
+    }
+
+    /// Decode the messages the drone sent since the last call
+    pub fn take_messages(&mut self) -> Vec<Message, 16> {
+        let sent = self.take_sent();
+        let mut decoder = FrameDecoder::new();
+        let mut messages = Vec::new();
+        let mut rest = &sent[..];
+        loop {
+            // The decoder buffer is smaller than the UART buffer, so the bytes are fed in parts
+            rest = &rest[decoder.receive(rest)..];
+            while let Some(result) = decoder.next_packet() {
+                if let Ok(packet) = result {
+                    messages.push(packet.message).ok();
+                }
+            }
+            if rest.is_empty() {
+                return messages;
+            }
+        }
+    }
+
+    fn flash_range(address: u32, len: usize) -> Result<core::ops::Range<usize>, FlashError> {
+        let start = address as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= FLASH_SIZE => Ok(start..end),
+            _ => Err(FlashError),
+        }
+    }
+}
+
+impl Default for MockHal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Imu for MockHal {
+    fn read_dmp(&mut self) -> Quaternion {
+        self.dmp
+    }
+
+    fn read_raw(&mut self) -> (Accel, Gyro) {
+        (self.accel, self.gyro)
+    }
+}
+
+impl Barometer for MockHal {
+    fn read_pressure(&mut self) -> u32 {
+        self.pressure
+    }
+
+    fn read_temperature(&mut self) -> i32 {
+        self.temperature
+    }
+}
+
+impl Battery for MockHal {
+    fn read_battery(&mut self) -> u16 {
+        self.battery
+    }
+}
+
+impl Motors for MockHal {
+    fn set_motors(&mut self, motors: [u16; 4]) {
+        self.motors = motors.map(|motor| motor.min(self.motor_max));
+    }
+
+    fn get_motors(&self) -> [u16; 4] {
+        self.motors
+    }
+
+    fn set_motor_max(&mut self, max: u16) {
+        self.motor_max = max;
+    }
+}
+
+impl Uart for MockHal {
+    fn send_bytes(&mut self, bytes: &[u8]) -> bool {
+        self.tx.extend_from_slice(bytes).is_ok()
+    }
+
+    fn receive_bytes(&mut self, buf: &mut [u8]) -> usize {
+        let mut num = 0;
+        while num < buf.len() {
+            match self.rx.pop_front() {
+                Some(byte) => buf[num] = byte,
+                None => break,
+            }
+            num += 1;
+        }
+        num
+    }
+}
+
+impl Clock for MockHal {
+    fn now_us(&self) -> u64 {
+        self.time_us
+    }
+
+    fn set_tick_frequency(&mut self, hz: u64) {
+        self.tick_us = 1_000_000 / hz;
+    }
+
+    fn wait_for_next_tick(&mut self) {
+        self.time_us += self.tick_us;
+    }
+
+    fn delay(&mut self, _cycles: usize) {}
+}
+
+impl Flash for MockHal {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        let range = Self::flash_range(address, buf.len())?;
+        buf.copy_from_slice(&self.flash[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, address: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        let range = Self::flash_range(address, bytes.len())?;
+        for (cell, byte) in self.flash[range].iter_mut().zip(bytes) {
+            *cell &= byte;
+        }
+        Ok(())
+    }
+
+    fn chip_erase(&mut self) -> Result<(), FlashError> {
+        self.flash = [0xff; FLASH_SIZE];
+        Ok(())
+    }
+}
+
+impl Leds for MockHal {
+    fn set_led(&mut self, led: Led, on: bool) {
+        self.leds[led as usize] = on;
+    }
+
+    fn toggle_led(&mut self, led: Led) {
+        self.leds[led as usize] = !self.leds[led as usize];
+    }
+}
@@ -0,0 +1,117 @@
+//! Hardware abstraction of the drone peripherals. The flight code only reaches the hardware through
+//! these traits, so it runs on the quadrupel board as well as on a PC against mock implementations.
+//! Without the default `quadrupel` feature the library builds for the host, e.g.
+//! `cargo build -p eslgroup14 --lib --no-default-features --target x86_64-unknown-linux-gnu`.
+
+#[cfg(feature = "quadrupel")]
+pub mod quadrupel;
+#[cfg(any(test, not(feature = "quadrupel")))]
+pub mod mock;
+pub mod queued;
+
+use protocol::{Packet, MAX_PACKET_SIZE};
+
+/// Orientation from the motion processor of the MPU
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+/// Raw accelerometer sample, 16384 LSB per g
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Accel {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+/// Raw gyroscope sample, 16.4 LSB per deg/s
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Gyro {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+/// The flash could not be read or written
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FlashError;
+
+/// LEDs on the board
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Led {
+    Red,
+    Yellow,
+    Green,
+    Blue,
+}
+
+pub trait Imu {
+    /// Orientation of the motion processor, waits until it has a new sample
+    fn read_dmp(&mut self) -> Quaternion;
+    fn read_raw(&mut self) -> (Accel, Gyro);
+}
+
+pub trait Barometer {
+    /// Pressure in 10^-5 bar
+    fn read_pressure(&mut self) -> u32;
+    /// Temperature in centi-degrees Celsius
+    fn read_temperature(&mut self) -> i32;
+}
+
+pub trait Battery {
+    /// Battery voltage in 10 mV
+    fn read_battery(&mut self) -> u16;
+}
+
+pub trait Motors {
+    fn set_motors(&mut self, motors: [u16; 4]);
+    fn get_motors(&self) -> [u16; 4];
+    /// Motor values above the maximum are cut off
+    fn set_motor_max(&mut self, max: u16);
+}
+
+pub trait Uart {
+    /// Queue bytes for sending, returns false when they did not fit
+    fn send_bytes(&mut self, bytes: &[u8]) -> bool;
+    /// Read received bytes into the buffer, returns the number of bytes read
+    fn receive_bytes(&mut self, buf: &mut [u8]) -> usize;
+
+    /// Serialize the packet on the stack, so telemetry does not use the heap, and send it
+    fn send_packet(&mut self, mut packet: Packet) {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        if let Ok(bytes) = packet.encode_into(&mut buf) {
+            self.send_bytes(bytes);
+        }
+    }
+}
+
+pub trait Clock {
+    /// Time since boot in us
+    fn now_us(&self) -> u64;
+    fn set_tick_frequency(&mut self, hz: u64);
+    /// Wait until the next tick of the control loop
+    fn wait_for_next_tick(&mut self);
+    /// Busy wait for the given number of delay loop iterations
+    fn delay(&mut self, cycles: usize);
+}
+
+pub trait Flash {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), FlashError>;
+    /// Write bytes to erased flash, flash bits can only be cleared without erasing
+    fn write(&mut self, address: u32, bytes: &[u8]) -> Result<(), FlashError>;
+    fn chip_erase(&mut self) -> Result<(), FlashError>;
+}
+
+pub trait Leds {
+    fn set_led(&mut self, led: Led, on: bool);
+    fn toggle_led(&mut self, led: Led);
+}
+
+/// All peripherals the flight code uses
+pub trait Hal: Imu + Barometer + Battery + Motors + Uart + Clock + Flash + Leds {}
+
+impl<T: Imu + Barometer + Battery + Motors + Uart + Clock + Flash + Leds> Hal for T {}
@@ -0,0 +1,118 @@
+use tudelft_quadrupel::block;
+use tudelft_quadrupel::{barometer, battery, flash, motor, mpu, time, uart};
+use tudelft_quadrupel::led::{Blue, Green, Red, Yellow};
+use super::{Accel, Gyro, Quaternion, FlashError, Led, Imu, Barometer, Battery, Motors, Uart, Clock, Flash, Leds};
+
+/// Peripherals of the quadrupel board. The tudelft_quadrupel drivers keep their own state,
+/// so this is a unit struct and the panic handler can create one as well.
+#[derive(Debug, Copy, Clone)]
+pub struct Quadrupel;
+
+impl Imu for Quadrupel {
+    fn read_dmp(&mut self) -> Quaternion {
+        let q = block!(mpu::read_dmp_bytes()).unwrap();
+        Quaternion { w: q.w.to_num(), x: q.x.to_num(), y: q.y.to_num(), z: q.z.to_num() }
+    }
+
+    fn read_raw(&mut self) -> (Accel, Gyro) {
+        let (acc, gyro) = mpu::read_raw().unwrap();
+        (Accel { x: acc.x, y: acc.y, z: acc.z }, Gyro { x: gyro.x, y: gyro.y, z: gyro.z })
+    }
+}
+
+impl Barometer for Quadrupel {
+    fn read_pressure(&mut self) -> u32 {
+        barometer::read_pressure()
+    }
+
+    fn read_temperature(&mut self) -> i32 {
+        barometer::read_temperature()
+    }
+}
+
+impl Battery for Quadrupel {
+    fn read_battery(&mut self) -> u16 {
+        battery::read_battery()
+    }
+}
+
+impl Motors for Quadrupel {
+    fn set_motors(&mut self, motors: [u16; 4]) {
+        motor::set_motors(motors);
+    }
+
+    fn get_motors(&self) -> [u16; 4] {
+        motor::get_motors()
+    }
+
+    fn set_motor_max(&mut self, max: u16) {
+        motor::set_motor_max(max);
+    }
+}
+
+impl Uart for Quadrupel {
+    fn send_bytes(&mut self, bytes: &[u8]) -> bool {
+        uart::send_bytes(bytes)
+    }
+
+    fn receive_bytes(&mut self, buf: &mut [u8]) -> usize {
+        uart::receive_bytes(buf)
+    }
+}
+
+impl Clock for Quadrupel {
+    fn now_us(&self) -> u64 {
+        time::Instant::now().ns_since_start() / 1_000
+    }
+
+    fn set_tick_frequency(&mut self, hz: u64) {
+        time::set_tick_frequency(hz);
+    }
+
+    fn wait_for_next_tick(&mut self) {
+        time::wait_for_next_tick();
+    }
+
+    fn delay(&mut self, cycles: usize) {
+        time::assembly_delay(cycles);
+    }
+}
+
+impl Flash for Quadrupel {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        flash::flash_read_bytes(address, buf).map_err(|_| FlashError)
+    }
+
+    fn write(&mut self, address: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        flash::flash_write_bytes(address, bytes).map_err(|_| FlashError)
+    }
+
+    fn chip_erase(&mut self) -> Result<(), FlashError> {
+        flash::flash_chip_erase().map_err(|_| FlashError)
+    }
+}
+
+impl Leds for Quadrupel {
+    fn set_led(&mut self, led: Led, on: bool) {
+        let led = match led {
+            Led::Red => Red,
+            Led::Yellow => Yellow,
+            Led::Green => Green,
+            Led::Blue => Blue,
+        };
+        if on {
+            led.on();
+        } else {
+            led.off();
+        }
+    }
+
+    fn toggle_led(&mut self, led: Led) {
+        let _ = match led {
+            Led::Red => Red.toggle(),
+            Led::Yellow => Yellow.toggle(),
+            Led::Green => Green.toggle(),
+            Led::Blue => Blue.toggle(),
+        };
+    }
+}
@@ -0,0 +1,196 @@
+use protocol::{Packet, PacketManager, QueueStats, MAX_PACKET_SIZE};
+use super::{Accel, Gyro, Quaternion, FlashError, Led, Imu, Barometer, Battery, Motors, Uart, Clock, Flash, Leds};
+
+/// Number of packets that can wait for the link
+const OUTBOX_LEN: usize = 8;
+
+/// Bytes per second the serial link carries, 115200 baud with a start and a stop bit
+const LINK_RATE: u64 = 11_520;
+
+/// Send credit that can be saved up while the outbox is empty, in bytes
+const MAX_CREDIT: i64 = MAX_PACKET_SIZE as i64;
+
+/// Peripherals with a send queue in front of the UART. Packets are queued by the priority of their
+/// message and sent at the rate of the link when the control loop flushes, so replies and safety
+/// messages go out before telemetry, and telemetry is dropped first when the link can not keep up.
+pub struct QueuedHal<H> {
+    pub inner: H,
+    outbox: PacketManager<OUTBOX_LEN>,
+    credit: i64,
+    last_flush: u64,
+}
+
+impl<H: Uart + Clock> QueuedHal<H> {
+    pub fn new(inner: H) -> Self {
+        let last_flush = inner.now_us();
+        QueuedHal { inner, outbox: PacketManager::new(), credit: MAX_CREDIT, last_flush }
+    }
+
+    /// Send the queued packets the link has room for since the last flush, highest priority first.
+    /// A packet is sent when there is any credit left, the bytes it takes extra are paid back later.
+    pub fn flush(&mut self) {
+        let now = self.inner.now_us();
+        let earned = (now.saturating_sub(self.last_flush) * LINK_RATE / 1_000_000) as i64;
+        self.credit = (self.credit + earned).min(MAX_CREDIT);
+        self.last_flush = now;
+
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        while self.credit > 0 {
+            let Some(mut packet) = self.outbox.read_packet() else { break };
+            if let Ok(bytes) = packet.encode_into(&mut buf) {
+                self.credit -= bytes.len() as i64;
+                self.inner.send_bytes(bytes);
+            }
+        }
+    }
+
+    pub fn outbox_stats(&self) -> QueueStats {
+        self.outbox.stats()
+    }
+}
+
+impl<H: Uart> Uart for QueuedHal<H> {
+    fn send_bytes(&mut self, bytes: &[u8]) -> bool {
+        self.inner.send_bytes(bytes)
+    }
+
+    fn receive_bytes(&mut self, buf: &mut [u8]) -> usize {
+        self.inner.receive_bytes(buf)
+    }
+
+    /// Queue the packet until the next flush, it is dropped when the outbox is full of more important packets
+    fn send_packet(&mut self, packet: Packet) {
+        self.outbox.add_packet(packet).ok();
+    }
+}
+
+impl<H: Imu> Imu for QueuedHal<H> {
+    fn read_dmp(&mut self) -> Quaternion {
+        self.inner.read_dmp()
+    }
+
+    fn read_raw(&mut self) -> (Accel, Gyro) {
+        self.inner.read_raw()
+    }
+}
+
+impl<H: Barometer> Barometer for QueuedHal<H> {
+    fn read_pressure(&mut self) -> u32 {
+        self.inner.read_pressure()
+    }
+
+    fn read_temperature(&mut self) -> i32 {
+        self.inner.read_temperature()
+    }
+}
+
+impl<H: Battery> Battery for QueuedHal<H> {
+    fn read_battery(&mut self) -> u16 {
+        self.inner.read_battery()
+    }
+}
+
+impl<H: Motors> Motors for QueuedHal<H> {
+    fn set_motors(&mut self, motors: [u16; 4]) {
+        self.inner.set_motors(motors)
+    }
+
+    fn get_motors(&self) -> [u16; 4] {
+        self.inner.get_motors()
+    }
+
+    fn set_motor_max(&mut self, max: u16) {
+        self.inner.set_motor_max(max)
+    }
+}
+
+impl<H: Clock> Clock for QueuedHal<H> {
+    fn now_us(&self) -> u64 {
+        self.inner.now_us()
+    }
+
+    fn set_tick_frequency(&mut self, hz: u64) {
+        self.inner.set_tick_frequency(hz)
+    }
+
+    fn wait_for_next_tick(&mut self) {
+        self.inner.wait_for_next_tick()
+    }
+
+    fn delay(&mut self, cycles: usize) {
+        self.inner.delay(cycles)
+    }
+}
+
+impl<H: Flash> Flash for QueuedHal<H> {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.inner.read(address, buf)
+    }
+
+    fn write(&mut self, address: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        self.inner.write(address, bytes)
+    }
+
+    fn chip_erase(&mut self) -> Result<(), FlashError> {
+        self.inner.chip_erase()
+    }
+}
+
+impl<H: Leds> Leds for QueuedHal<H> {
+    fn set_led(&mut self, led: Led, on: bool) {
+        self.inner.set_led(led, on)
+    }
+
+    fn toggle_led(&mut self, led: Led) {
+        self.inner.toggle_led(led)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{Message, LinkStats};
+    use crate::hal::mock::MockHal;
+    use crate::drone_transmission::{write_packet, write_reply, log};
+
+    #[test]
+    fn test_replies_before_telemetry() {
+        let mut hal = QueuedHal::new(MockHal::new());
+        for _ in 0..OUTBOX_LEN {
+            write_packet(&mut hal, Message::LinkStatus(LinkStats::default()));
+        }
+        write_reply(&mut hal, 3, Ok(()));
+        write_packet(&mut hal, Message::PanicMode);
+        assert!(hal.inner.tx.is_empty());
+
+        // The oldest telemetry made room, the reply goes out first
+        hal.flush();
+        let sent = hal.inner.take_messages();
+        assert_eq!(sent[..2], [Message::Ack(3), Message::PanicMode]);
+        assert_eq!(hal.outbox_stats().dropped, 2);
+        assert_eq!(sent.len() + hal.outbox.len(), OUTBOX_LEN);
+    }
+
+    #[test]
+    fn test_link_rate() {
+        let mut hal = QueuedHal::new(MockHal::new());
+        for _ in 0..OUTBOX_LEN {
+            log!(&mut hal, Info, "{:064}", 0);
+        }
+
+        // The saved up credit is spent and the last packet sent goes into debt
+        hal.flush();
+        let sent = hal.inner.take_messages().len();
+        assert!(sent > 0 && sent < OUTBOX_LEN);
+        assert!(hal.credit <= 0);
+
+        // Nothing is sent until the debt is paid back
+        hal.flush();
+        assert!(hal.inner.take_messages().is_empty());
+
+        hal.inner.time_us += 1_000_000;
+        hal.flush();
+        assert_eq!(hal.inner.take_messages().len(), OUTBOX_LEN - sent);
+        assert!(hal.outbox.is_empty());
+    }
+}
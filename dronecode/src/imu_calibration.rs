@@ -0,0 +1,364 @@
+use protocol::{ImuCalibrationStep, ImuCalibrationState, ImuOrientation, NackReason, WorkingModes};
+use crate::config::{GYRO_BIAS, ACCEL_CALIBRATION};
+use crate::drone::{Drone, Getter, Setter};
+use crate::drone_transmission::log;
+use crate::working_mode::raw_sensor_mode::LSB_SENSITIVITY;
+use crate::hal::{Accel, Gyro, Hal};
+
+/// Accelerometer sensitivity in LSB per g
+const ACCEL_LSB: f32 = 16384.0;
+
+/// Raw samples averaged in a calibration step, 2 s at 100 Hz
+const SAMPLES: u16 = 200;
+
+/// The progress is reported every this many samples
+const PROGRESS_SAMPLES: u16 = 20;
+
+/// Largest spread of the gyro samples of a step in LSB, about 2 deg/s. The noise of a gyro at
+/// rest stays well below it, a drone that is picked up or bumped does not.
+const GYRO_STILL: i32 = 33;
+
+/// Largest spread of the accelerometer samples of a step in LSB, 0.05 g
+const ACCEL_STILL: i32 = 820;
+
+/// In an orientation the axis that points up measures more than AXIS_UP, the others less than AXIS_LEVEL, in g
+const AXIS_UP: f32 = 0.8;
+const AXIS_LEVEL: f32 = 0.3;
+
+/// Accelerometer scales outside of this range come from a wrong orientation
+const SCALE_MIN: f32 = 0.8;
+const SCALE_MAX: f32 = 1.2;
+
+/// Corrections of the raw IMU. The gyro bias is in LSB, the accelerometer offsets are in g and are
+/// removed before the scales are applied.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ImuCalibration {
+    pub gyro_bias: [f32; 3],
+    pub accel_offset: [f32; 3],
+    pub accel_scale: [f32; 3],
+}
+
+impl Default for ImuCalibration {
+    fn default() -> Self {
+        ImuCalibration { gyro_bias: [0.0; 3], accel_offset: [0.0; 3], accel_scale: [1.0; 3] }
+    }
+}
+
+impl ImuCalibration {
+    /// Gyro rates in LSB without the bias, along x, y and z
+    pub fn gyro(&self, gyro: Gyro) -> [f32; 3] {
+        let raw = [gyro.x, gyro.y, gyro.z];
+        [0, 1, 2].map(|axis| raw[axis] as f32 - self.gyro_bias[axis])
+    }
+
+    /// Accelerometer in g, along x, y and z
+    pub fn accel(&self, accel: Accel) -> [f32; 3] {
+        let raw = [accel.x, accel.y, accel.z];
+        [0, 1, 2].map(|axis| (raw[axis] as f32 / ACCEL_LSB - self.accel_offset[axis]) * self.accel_scale[axis])
+    }
+
+    /// Accelerometer offsets and scales as they are stored in flash
+    fn accel_values(&self) -> [f32; 6] {
+        let mut values = [0.0; 6];
+        values[..3].copy_from_slice(&self.accel_offset);
+        values[3..].copy_from_slice(&self.accel_scale);
+        values
+    }
+}
+
+/// Range and sum of the raw samples of one axis
+#[derive(Copy, Clone)]
+struct AxisSamples {
+    min: i16,
+    max: i16,
+    sum: i32,
+}
+
+impl AxisSamples {
+    const EMPTY: AxisSamples = AxisSamples { min: i16::MAX, max: i16::MIN, sum: 0 };
+
+    fn add(&mut self, value: i16) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value as i32;
+    }
+
+    fn spread(&self) -> i32 {
+        self.max as i32 - self.min as i32
+    }
+
+    fn mean(&self) -> f32 {
+        self.sum as f32 / SAMPLES as f32
+    }
+}
+
+/// Offsets and scales of the accelerometer from its mean in g in each orientation of
+/// ImuOrientation::ALL. Every axis has to measure 1 g when it points up and -1 g when it points down.
+fn solve(means: &[[f32; 3]; 6]) -> Result<([f32; 3], [f32; 3]), NackReason> {
+    let (mut up, mut down) = ([0.0; 3], [0.0; 3]);
+    for (orientation, mean) in ImuOrientation::ALL.iter().zip(means) {
+        let (axis, gravity) = orientation.axis();
+        if gravity > 0.0 { up[axis] = mean[axis] } else { down[axis] = mean[axis] }
+    }
+
+    let offset = [0, 1, 2].map(|axis| (up[axis] + down[axis]) / 2.0);
+    let scale = [0, 1, 2].map(|axis| 2.0 / (up[axis] - down[axis]));
+    if scale.iter().all(|scale| (SCALE_MIN..=SCALE_MAX).contains(scale)) {
+        Ok((offset, scale))
+    } else {
+        Err(NackReason::WrongOrientation)
+    }
+}
+
+/// Calibration of the raw IMU, the PC runs it one step at a time in SafeMode. A step averages
+/// raw samples and is rejected when the drone moved, the results are stored in flash.
+pub struct ImuCalibrator {
+    step: Option<ImuCalibrationStep>,
+    samples: u16,
+    accel: [AxisSamples; 3],
+    gyro: [AxisSamples; 3],
+    orientations: [Option<[f32; 3]>; 6],    // mean accelerometer in g, in the order of ImuOrientation::ALL
+}
+
+impl ImuCalibrator {
+    pub fn new() -> Self {
+        ImuCalibrator {
+            step: None,
+            samples: 0,
+            accel: [AxisSamples::EMPTY; 3],
+            gyro: [AxisSamples::EMPTY; 3],
+            orientations: [None; 6],
+        }
+    }
+
+    /// Start a calibration step, a step that is still running is dropped
+    pub fn start(&mut self, step: ImuCalibrationStep) {
+        self.step = Some(step);
+        self.samples = 0;
+        self.accel = [AxisSamples::EMPTY; 3];
+        self.gyro = [AxisSamples::EMPTY; 3];
+    }
+
+    /// Take the sample of this tick. Returns the state of the step when it has to be reported,
+    /// the progress while sampling and the result when the step is over.
+    pub fn update<H: Hal>(&mut self, drone: &mut Drone<H>) -> Option<(ImuCalibrationStep, ImuCalibrationState)> {
+        let step = self.step?;
+
+        // Leaving SafeMode starts the motors or calibration mode, the drone is not held still any more
+        if drone.get_mode() != WorkingModes::SafeMode {
+            self.step = None;
+            return Some((step, ImuCalibrationState::Failed(NackReason::Moving)));
+        }
+
+        if step != ImuCalibrationStep::Reset {
+            let (accel, gyro) = drone.hal().read_raw();
+            for (samples, value) in self.accel.iter_mut().zip([accel.x, accel.y, accel.z]) {
+                samples.add(value);
+            }
+            for (samples, value) in self.gyro.iter_mut().zip([gyro.x, gyro.y, gyro.z]) {
+                samples.add(value);
+            }
+
+            self.samples += 1;
+            if self.samples < SAMPLES {
+                let percent = (self.samples as u32 * 100 / SAMPLES as u32) as u8;
+                return self.samples.is_multiple_of(PROGRESS_SAMPLES).then_some((step, ImuCalibrationState::Sampling(percent)));
+            }
+        }
+
+        self.step = None;
+        let state = self.finish(drone, step).unwrap_or_else(ImuCalibrationState::Failed);
+        Some((step, state))
+    }
+
+    /// Use the samples of a finished step and store the new calibration. It is only used once it
+    /// is stored, so the drone does not fly on a calibration that is gone after a restart.
+    fn finish<H: Hal>(&mut self, drone: &mut Drone<H>, step: ImuCalibrationStep) -> Result<ImuCalibrationState, NackReason> {
+        let mut calibration = drone.get_imu_calibration();
+        match step {
+            ImuCalibrationStep::Reset => {
+                // Both values are reset or neither
+                if GYRO_BIAS.full(drone.hal()) || ACCEL_CALIBRATION.full(drone.hal()) {
+                    return Err(NackReason::ConfigFull);
+                }
+                calibration = ImuCalibration::default();
+                GYRO_BIAS.write_floats(drone.hal(), &calibration.gyro_bias)?;
+                ACCEL_CALIBRATION.write_floats(drone.hal(), &calibration.accel_values())?;
+                self.orientations = [None; 6];
+                drone.set_imu_calibration(calibration);
+            }
+            ImuCalibrationStep::Gyro => {
+                self.check_still()?;
+                calibration.gyro_bias = self.gyro.map(|samples| samples.mean());
+                GYRO_BIAS.write_floats(drone.hal(), &calibration.gyro_bias)?;
+                drone.set_imu_calibration(calibration);
+
+                let [x, y, z] = calibration.gyro_bias.map(|bias| bias * LSB_SENSITIVITY);
+                log!(drone.hal(), Info, "Gyro bias {:.2} {:.2} {:.2} deg/s", x, y, z);
+            }
+            ImuCalibrationStep::Accel(orientation) => {
+                self.check_still()?;
+                let mean = self.accel.map(|samples| samples.mean() / ACCEL_LSB);
+                let (up, gravity) = orientation.axis();
+                let level = (0..3).all(|axis| axis == up || mean[axis].abs() < AXIS_LEVEL);
+                if mean[up] * gravity < AXIS_UP || !level {
+                    return Err(NackReason::WrongOrientation);
+                }
+                self.orientations[orientation as usize] = Some(mean);
+
+                let mut means = [[0.0; 3]; 6];
+                for (mean, measured) in means.iter_mut().zip(self.orientations) {
+                    match measured {
+                        Some(measured) => *mean = measured,
+                        None => return Ok(ImuCalibrationState::Measured),
+                    }
+                }
+
+                // The orientations are kept when the flash is full, after erasing it one of them
+                // is measured again to store the result
+                let (offset, scale) = solve(&means)?;
+                calibration.accel_offset = offset;
+                calibration.accel_scale = scale;
+                ACCEL_CALIBRATION.write_floats(drone.hal(), &calibration.accel_values())?;
+                self.orientations = [None; 6];
+                drone.set_imu_calibration(calibration);
+
+                let [x, y, z] = scale;
+                log!(drone.hal(), Info, "Accelerometer scale {:.3} {:.3} {:.3}", x, y, z);
+            }
+        }
+        Ok(ImuCalibrationState::Done)
+    }
+
+    /// The drone moved when the samples of the gyro or the accelerometer spread too far
+    fn check_still(&self) -> Result<(), NackReason> {
+        let moved = self.gyro.iter().any(|samples| samples.spread() > GYRO_STILL)
+            || self.accel.iter().any(|samples| samples.spread() > ACCEL_STILL);
+        if moved { Err(NackReason::Moving) } else { Ok(()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::hal::mock::MockHal;
+
+    /// Run a step to its end, returns the number of progress reports and the result
+    fn run(drone: &mut Drone<MockHal>, calibrator: &mut ImuCalibrator, step: ImuCalibrationStep) -> (usize, ImuCalibrationState) {
+        calibrator.start(step);
+        let mut progress = 0;
+        loop {
+            match calibrator.update(drone) {
+                Some((_, ImuCalibrationState::Sampling(_))) => progress += 1,
+                Some((reported, state)) => {
+                    assert_eq!(reported, step);
+                    return (progress, state);
+                }
+                None => (),
+            }
+        }
+    }
+
+    #[test]
+    fn test_gyro_bias() {
+        let mut drone = Drone::initialize(MockHal::new());
+        let mut calibrator = ImuCalibrator::new();
+        drone.hal().gyro = Gyro { x: 20, y: -12, z: 5 };
+        assert_eq!(run(&mut drone, &mut calibrator, ImuCalibrationStep::Gyro), (9, ImuCalibrationState::Done));
+
+        let calibration = drone.get_imu_calibration();
+        assert_eq!(calibration.gyro_bias, [20.0, -12.0, 5.0]);
+        assert_eq!(calibration.gyro(Gyro { x: 20, y: -12, z: 5 }), [0.0; 3]);
+
+        // The bias is read back from flash after a restart
+        assert_eq!(config::imu_calibration(drone.hal()), calibration);
+
+        // Reset goes back to the raw sensor
+        assert_eq!(run(&mut drone, &mut calibrator, ImuCalibrationStep::Reset), (0, ImuCalibrationState::Done));
+        assert_eq!(drone.get_imu_calibration(), ImuCalibration::default());
+        assert_eq!(config::imu_calibration(drone.hal()), ImuCalibration::default());
+    }
+
+    #[test]
+    fn test_motion_rejected() {
+        let mut drone = Drone::initialize(MockHal::new());
+        let mut calibrator = ImuCalibrator::new();
+        calibrator.start(ImuCalibrationStep::Gyro);
+        let mut result = None;
+        for k in 0..SAMPLES {
+            // The drone is bumped halfway
+            drone.hal().gyro.x = if k == SAMPLES / 2 { 200 } else { 0 };
+            result = calibrator.update(&mut drone);
+        }
+        assert_eq!(result, Some((ImuCalibrationStep::Gyro, ImuCalibrationState::Failed(NackReason::Moving))));
+        assert_eq!(drone.get_imu_calibration(), ImuCalibration::default());
+        assert!(!GYRO_BIAS.read_floats(drone.hal(), &mut [0.0; 3]));
+
+        // Leaving SafeMode ends the step
+        calibrator.start(ImuCalibrationStep::Gyro);
+        drone.set_mode(WorkingModes::CalibrationMode);
+        assert_eq!(calibrator.update(&mut drone), Some((ImuCalibrationStep::Gyro, ImuCalibrationState::Failed(NackReason::Moving))));
+        assert_eq!(calibrator.update(&mut drone), None);
+    }
+
+    #[test]
+    fn test_six_orientations() {
+        let mut drone = Drone::initialize(MockHal::new());
+        let mut calibrator = ImuCalibrator::new();
+        let offset = [0.02, -0.03, 0.05];
+        let scale = [1.04, 0.97, 1.01];
+        let raw = |g: [f32; 3]| {
+            let [x, y, z] = [0, 1, 2].map(|axis| ((g[axis] / scale[axis] + offset[axis]) * ACCEL_LSB) as i16);
+            Accel { x, y, z }
+        };
+
+        // Held level while the x axis step runs
+        assert_eq!(run(&mut drone, &mut calibrator, ImuCalibrationStep::Accel(ImuOrientation::XUp)).1,
+                   ImuCalibrationState::Failed(NackReason::WrongOrientation));
+
+        for (k, orientation) in ImuOrientation::ALL.iter().enumerate() {
+            let (axis, gravity) = orientation.axis();
+            let mut g = [0.0; 3];
+            g[axis] = gravity;
+            drone.hal().accel = raw(g);
+
+            let expected = if k == 5 { ImuCalibrationState::Done } else { ImuCalibrationState::Measured };
+            assert_eq!(run(&mut drone, &mut calibrator, ImuCalibrationStep::Accel(*orientation)), (9, expected));
+        }
+
+        let calibration = drone.get_imu_calibration();
+        for axis in 0..3 {
+            assert!((calibration.accel_offset[axis] - offset[axis]).abs() < 1e-3, "{:?}", calibration);
+            assert!((calibration.accel_scale[axis] - scale[axis]).abs() < 1e-3, "{:?}", calibration);
+        }
+        let level = calibration.accel(raw([0.0, 0.0, 1.0]));
+        assert!((level[2] - 1.0).abs() < 1e-3 && level[0].abs() < 1e-3 && level[1].abs() < 1e-3, "{:?}", level);
+        assert_eq!(config::imu_calibration(drone.hal()), calibration);
+    }
+
+    #[test]
+    fn test_config_full() {
+        let mut drone = Drone::initialize(MockHal::new());
+        let mut calibrator = ImuCalibrator::new();
+        while !GYRO_BIAS.full(drone.hal()) {
+            GYRO_BIAS.write_floats(drone.hal(), &[0.0; 3]).unwrap();
+        }
+
+        // The new bias can not be stored, so it is not used either
+        drone.hal().gyro = Gyro { x: 20, y: -12, z: 5 };
+        assert_eq!(run(&mut drone, &mut calibrator, ImuCalibrationStep::Gyro).1, ImuCalibrationState::Failed(NackReason::ConfigFull));
+        assert_eq!(drone.get_imu_calibration(), ImuCalibration::default());
+
+        // A reset is refused before the accelerometer calibration is overwritten
+        ACCEL_CALIBRATION.write_floats(drone.hal(), &[0.1, 0.0, 0.0, 1.0, 1.0, 1.0]).unwrap();
+        assert_eq!(run(&mut drone, &mut calibrator, ImuCalibrationStep::Reset).1, ImuCalibrationState::Failed(NackReason::ConfigFull));
+        assert_eq!(config::imu_calibration(drone.hal()).accel_offset, [0.1, 0.0, 0.0]);
+
+        // Erasing the flash makes room again
+        config::erase_flash(drone.hal()).unwrap();
+        assert_eq!(run(&mut drone, &mut calibrator, ImuCalibrationStep::Gyro).1, ImuCalibrationState::Done);
+        assert_eq!(drone.get_imu_calibration().gyro_bias, [20.0, -12.0, 5.0]);
+        assert_eq!(config::imu_calibration(drone.hal()).gyro_bias, [20.0, -12.0, 5.0]);
+    }
+}
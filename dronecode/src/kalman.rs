@@ -1,86 +1,68 @@
-use micromath::F32Ext;
+use crate::real::{FineNumber, Number, Real};
 
-// Kalman filter variables
+// Kalman filter variables, the math runs on T and the interface is f32
 #[derive(Copy, Clone)]
-pub struct KalmanFilter {
-    q_angle:f32,             // Process noise variance for the accelerometer
-    q_bias: f32,             // Process noise variance for the gyro bias
-    r_measure: f32,          // Measurement noise variance, the actual variance of the measurement noise
-    angle: f32,              // The angle caclulated by the Kalman filter, part of the 2x1 state vector
-    bias: f32,               // The gyro bias calculated by the Kalman filter, part of the 2x1 state vector
-    rate: f32,               // Unbiased rate caclulated from the rate and the caclulated bias 
-    p_error: [[f32; 2]; 2],  // The error covariance 2x2 matrix
-    k_gain: [f32; 2],        // Kalman gain a 2x1 vector
-    y:f32,                   // Angle difference
-    s:f32,                   // Estimate error
+pub struct KalmanFilter<T: Real = FineNumber> {
+    q_angle: T,              // Process noise variance for the accelerometer
+    q_bias: T,               // Process noise variance for the gyro bias
+    r_measure: T,            // Measurement noise variance, the actual variance of the measurement noise
+    angle: T,                // The angle caclulated by the Kalman filter, part of the 2x1 state vector
+    bias: T,                 // The gyro bias calculated by the Kalman filter, part of the 2x1 state vector
+    rate: T,                 // Unbiased rate caclulated from the rate and the caclulated bias 
+    p_error: [[T; 2]; 2],    // The error covariance 2x2 matrix
+    k_gain: [T; 2],          // Kalman gain a 2x1 vector
+    y: T,                    // Angle difference
+    s: T,                    // Estimate error
  }
 
- pub struct AltitudeKalmanFilter {
-    altitude_state: f32,    // State variable altitude
-    velocity_state: f32,    // State variable velocity
-    g: [f32; 2],            // Control input matrix G
-    q: [[f32; 2]; 2],       // Process noise covariance matrix Q
-    p: [[f32; 2]; 2],       // State covariance matrix P
-    h: [f32; 2],            // Measurement observation matrix H
-    r: f32,                 // Measurement noise covariance matrix R
-    k_gain: [f32; 2],        // Kalman gain a 2x1 vector
+ pub struct AltitudeKalmanFilter<T: Real = Number> {
+    altitude_state: T,      // State variable altitude
+    velocity_state: T,      // State variable velocity
+    g: [T; 2],              // Control input matrix G
+    q: [[T; 2]; 2],         // Process noise covariance matrix Q
+    p: [[T; 2]; 2],         // State covariance matrix P
+    h: [T; 2],              // Measurement observation matrix H
+    r: T,                   // Measurement noise covariance matrix R
+    k_gain: [T; 2],         // Kalman gain a 2x1 vector
  }
 
- impl Default for AltitudeKalmanFilter {
+ impl<T: Real> Default for AltitudeKalmanFilter<T> {
      fn default() -> Self {
-         AltitudeKalmanFilter { 
-            altitude_state: 0.0, 
-            velocity_state: 0.0, 
-            g: [0.0, 0.0], 
-            q: [[0.0, 0.0], [0.0, 0.0]], 
-            p: [[0.0, 0.0,], [0.0, 0.0]], 
-            h: [1.0, 0.0],
-             r: 20.0*20.0,                    //5 cm/s^2
-            k_gain: [0.0, 0.0] 
-        }
+         AltitudeKalmanFilter::new([[0.0, 0.0], [0.0, 0.0]], 20.0*20.0)    //5 cm/s^2
      }
  }
  
- impl Default for KalmanFilter {
+ impl<T: Real> Default for KalmanFilter<T> {
      fn default() -> Self {
-         KalmanFilter{
-             q_angle: 0.004,
-             q_bias: 0.003,
-             r_measure: 0.0001,
-             angle: 0.0,
-             bias: 0.0,
-             rate: 0.0,
-             p_error: [[0.0, 0.0], [0.0, 0.0]],
-             k_gain: [0.0, 0.0],
-             y: 0.0,
-             s: 0.0,
-         }
+         KalmanFilter::new(0.004, 0.003, 0.0001)
      }
  }
 
- impl AltitudeKalmanFilter {
+ impl<T: Real> AltitudeKalmanFilter<T> {
     pub fn new(q: [[f32; 2]; 2], r: f32) -> Self {
         AltitudeKalmanFilter { 
-            altitude_state: 0.0, 
-            velocity_state: 0.0, 
-            g: [0.0, 0.0], 
-            q: q, 
-            p: [[0.0, 0.0,], [0.0, 0.0]], 
-            h: [1.0, 0.0], 
-            r: r,        //30 cm/s^2
-            k_gain: [0.0, 0.0],                   
+            altitude_state: T::default(), 
+            velocity_state: T::default(), 
+            g: [T::default(); 2], 
+            q: q.map(|row| row.map(T::from_f32)), 
+            p: [[T::default(); 2]; 2], 
+            h: [T::from_f32(1.0), T::default()], 
+            r: T::from_f32(r),        //30 cm/s^2
+            k_gain: [T::default(); 2],                   
         }    
     }
 
     pub fn update(&mut self, new_altitude: f32, new_velocity: f32, dt: f32) -> (f32, f32) {
+        let [new_altitude, new_velocity, dt] = [new_altitude, new_velocity, dt].map(T::from_f32);
+        let one = T::from_f32(1.0);
 
-        self.q[0][0] = 5.0 * dt * dt * dt *dt;
-        self.q[0][1] = 10.0 * dt * dt * dt;
-        self.q[1][0] = 10.0 * dt * dt * dt;
-        self.q[1][1] = 50.0 * dt * dt;
+        self.q[0][0] = T::from_f32(5.0) * dt * dt * dt *dt;
+        self.q[0][1] = T::from_f32(10.0) * dt * dt * dt;
+        self.q[1][0] = T::from_f32(10.0) * dt * dt * dt;
+        self.q[1][1] = T::from_f32(50.0) * dt * dt;
 
         // Prediction step: estimate the next state based on the current state and the control input
-        self.altitude_state = self.altitude_state + self.velocity_state * dt + 0.5 * dt * dt * new_velocity;
+        self.altitude_state = self.altitude_state + self.velocity_state * dt + T::from_f32(0.5) * dt * dt * new_velocity;
         self.velocity_state = self.velocity_state + dt * new_velocity;
 
         // Compute the predicted state covariance matrix
@@ -100,33 +82,35 @@ pub struct KalmanFilter {
         // Compute the updated state covariance matrix
         self.p[1][0] = self.p[1][0] - self.p[0][0] * self.k_gain[1];
         self.p[1][1] = self.p[1][1] - self.p[0][1] * self.k_gain[1];
-        self.p[0][0] = self.p[0][0] * (1.0 - self.k_gain[0]);
-        self.p[0][1] = self.p[0][1] * (1.0 - self.k_gain[0]);
+        self.p[0][0] = self.p[0][0] * (one - self.k_gain[0]);
+        self.p[0][1] = self.p[0][1] * (one - self.k_gain[0]);
 
         // Return the updated altitude and velocity estimates
-        (self.altitude_state, self.velocity_state)
+        (self.altitude_state.to_f32(), self.velocity_state.to_f32())
     }
  }
  
- impl KalmanFilter {
+ impl<T: Real> KalmanFilter<T> {
      // The variables can be tuned
      pub fn new(q_angle: f32, q_bias: f32, r_measure: f32) -> Self {
          
          KalmanFilter{
-             q_angle: q_angle,
-             q_bias: q_bias,
-             r_measure: r_measure,
-             angle: 0.0,
-             bias: 0.0,
-             rate: 0.0,
-             p_error: [[0.0, 0.0], [0.0, 0.0]],
-             k_gain: [0.0, 0.0],
-             y: 0.0,
-             s: 0.0,            
+             q_angle: T::from_f32(q_angle),
+             q_bias: T::from_f32(q_bias),
+             r_measure: T::from_f32(r_measure),
+             angle: T::default(),
+             bias: T::default(),
+             rate: T::default(),
+             p_error: [[T::default(); 2]; 2],
+             k_gain: [T::default(); 2],
+             y: T::default(),
+             s: T::default(),            
          }
      }
      // Update Kalman filter and retrieve the angle and rate
      pub fn update(&mut self, new_angle: f32, new_rate: f32, dt: f32) -> f32 {
+         let [new_angle, new_rate, dt] = [new_angle, new_rate, dt].map(T::from_f32);
+
          // Update the estimated state
          // Since we can not directly measure the bias the estimate of the a priori bias is just equal to the previous one.
          self.rate = new_rate - self.bias;
@@ -154,26 +138,59 @@ pub struct KalmanFilter {
          self.p_error[1][0] -= self.k_gain[1] * self.p_error[0][0];
          self.p_error[1][1] -= self.k_gain[1] * self.p_error[0][1];
  
-         return self.angle
+         return self.angle.to_f32()
      }
  
      // This should be set as the starting angle
      pub fn set_angle(&mut self, new_angle: f32) {
-         self.angle = new_angle;
+         self.angle = T::from_f32(new_angle);
      }
  
      // Set the process noise variance for the accelerometer
      pub fn set_covariance_angle(&mut self, new_q_angle: f32) {
-         self.q_angle = new_q_angle;
+         self.q_angle = T::from_f32(new_q_angle);
      }
  
      // Set the process noise variance for the gyro scope bias
      pub fn set_covariance_bias(&mut self, new_q_bias: f32) {
-         self.q_bias = new_q_bias;
+         self.q_bias = T::from_f32(new_q_bias);
      }
  
      // Set the measurement noise variance
      pub fn set_measurement_noise(&mut self, new_r_measure: f32) {
-         self.r_measure = new_r_measure;
+         self.r_measure = T::from_f32(new_r_measure);
      }
- }
\ No newline at end of file
+ }
+#[cfg(all(test, feature = "fixed-point"))]
+mod tests {
+    use super::*;
+    use crate::real::{Q16, Q24};
+    use crate::test_support::{DT, noise, max_difference, swinging_angle};
+
+    #[test]
+    fn test_fixed_point_matches_f32() {
+        let mut float = KalmanFilter::<f32>::default();
+        let mut fixed = KalmanFilter::<Q24>::default();
+        let max_error = max_difference(swinging_angle,
+            |(angle, rate)| float.update(angle, rate, DT),
+            |(angle, rate)| fixed.update(angle, rate, DT),
+            |a, b| (a - b).abs());
+        assert!(max_error < 1e-4, "{}", max_error);
+    }
+
+    #[test]
+    fn test_altitude_fixed_point_matches_f32() {
+        let mut float = AltitudeKalmanFilter::<f32>::default();
+        let mut fixed = AltitudeKalmanFilter::<Q16>::default();
+        // Climbing and sinking by a meter, with barometer noise of 20 cm, in cm and cm/s^2
+        let input = |k: u32| {
+            let t = k as f32 * DT;
+            (100.0 * (t * 0.5).sin() + 20.0 * noise(k), -25.0 * (t * 0.5).sin() + 5.0 * noise(k + 7919))
+        };
+        let max_error = max_difference(input,
+            |(altitude, acceleration)| float.update(altitude, acceleration, DT).0,
+            |(altitude, acceleration)| fixed.update(altitude, acceleration, DT).0,
+            |a, b| (a - b).abs());
+        assert!(max_error < 1.0, "{}", max_error);
+    }
+}
@@ -0,0 +1,23 @@
+#![no_std]
+
+//! Flight code of the drone. It reaches the hardware through the traits in `hal`, the firmware
+//! in main.rs runs it on the quadrupel board.
+
+pub mod hal;
+pub mod control;
+pub mod drone_transmission;
+pub mod crash;
+mod working_mode;
+pub mod yaw_pitch_roll;
+mod drone;
+mod log_storage_manager;
+mod controllers;
+mod kalman;
+mod complementary;
+mod ahrs;
+mod imu_calibration;
+mod parameters;
+mod config;
+mod real;
+#[cfg(test)]
+mod test_support;
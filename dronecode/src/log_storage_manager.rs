@@ -1,99 +1,239 @@
-use core::mem::size_of;
+use postcard::{to_slice, from_bytes};
+use heapless::Vec;
 
-use alloc::format;
-use postcard::{to_allocvec, from_bytes};
-
-use protocol::{Message};
-use crate::drone_transmission::{write_packet};
-use tudelft_quadrupel::flash::{flash_read_bytes, flash_write_bytes, FlashError, flash_chip_erase};
-use tudelft_quadrupel::uart::send_bytes;
+use protocol::{Message, NackReason, LOG_RECORD_SIZE, LOG_CHUNK_LEN, chunk_crc};
+use crate::drone_transmission::{write_packet, log};
+use crate::config::erase_flash;
+use crate::hal::{Flash, FlashError, Uart};
 
+/// Flash log with one serialized message per fixed size record. Erased flash reads as 0xff and a
+/// serialized message never starts with 0xff, so the end of the log is found again after a restart.
 pub struct LogStorageManager {
-    max_flash_size: usize,
-    remaining_flash_size: usize,
+    max_records: usize,
     pub written_packets: usize,
+    full: bool,
 }
 
-impl LogStorageManager {
-
-    pub fn new(max_size: usize) -> LogStorageManager {
+/// Check whether a record holds a message
+fn record_used(flash: &mut impl Flash, index: usize) -> bool {
+    let mut first = [0u8; 1];
+    match flash.read((index * LOG_RECORD_SIZE) as u32, &mut first) {
+        Ok(()) => first[0] != 0xff,
+        Err(_) => false,
+    }
+}
 
-        let managar = LogStorageManager {
-            max_flash_size: max_size, 
-            remaining_flash_size: max_size, 
-            written_packets: 0
-        };
+impl LogStorageManager {
 
-        flash_chip_erase().unwrap();
+    pub fn new(flash: &mut impl Flash, max_size: usize) -> LogStorageManager {
+        let max_records = max_size / LOG_RECORD_SIZE;
+
+        // Records are written in order, so binary search for the first free one
+        let (mut low, mut high) = (0, max_records);
+        while low < high {
+            let mid = (low + high) / 2;
+            if record_used(flash, mid) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
 
-        managar
+        LogStorageManager {
+            max_records,
+            written_packets: low,
+            full: low == max_records,
+        }
     }
 
-    pub fn store_logging(&mut self, log: Message) -> Result<(), FlashError> {
+    /// Store a message in the next free record. When the flash is full nothing is stored
+    /// until the PC downloaded and erased the log.
+    pub fn store_logging<H: Flash + Uart>(&mut self, hal: &mut H, log: &Message) -> Result<(), FlashError> {
         //check whether we can still write to the flash
-        if self.remaining_flash_size.saturating_sub(size_of::<Message>()) > 0 {
-            // Convert the log struct to a byte slice
-            let log_bytes = to_allocvec(&log).unwrap();
+        if self.written_packets >= self.max_records {
+            if !self.full {
+                log!(hal, Warn, "Flash log full, logging stopped");
+                self.full = true;
+            }
+            return Ok(());
+        }
 
-            // Calculate the address to write to based on the number of written packets
-            let address = self.written_packets * size_of::<Message>() as usize;
+        // Messages that do not fit in a record are skipped, the PC is told what is missing
+        let mut buf = [0u8; LOG_RECORD_SIZE];
+        let log_bytes = match to_slice(log, &mut buf) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                log!(hal, Error, "{} does not fit in a flash log record, not stored", log);
+                return Ok(());
+            }
+        };
 
-            // Write the log bytes to the flash memory
-            flash_write_bytes(address as u32, &log_bytes)?;
+        // Calculate the address to write to based on the number of written packets
+        let address = self.written_packets * LOG_RECORD_SIZE;
 
-            // Update the log storage manager state
-            self.remaining_flash_size -= size_of::<Message>();
-            self.written_packets += 1;
+        // Write the log bytes to the flash memory
+        hal.write(address as u32, log_bytes)?;
 
-        }
-        else {
-            self.dump_loggins();
-        }
+        self.written_packets += 1;
         Ok(())
     }
 
-    pub fn retrieve_logging(&self, index: usize) -> Option<Message> {
+    pub fn retrieve_logging(&self, flash: &mut impl Flash, index: usize) -> Option<Message> {
         // Check if the index is within the range of written packets
- 
         if index >= self.written_packets {
             return None;
         }
 
-        // Calculate the address to read from based on the packet index
-        let address = index * size_of::<Message>() as usize;
+        // Read the record from the flash memory
+        let mut packet_bytes = [0u8; LOG_RECORD_SIZE];
+        flash.read((index * LOG_RECORD_SIZE) as u32, &mut packet_bytes).ok()?;
 
-        // Allocate a buffer to store the log bytes
-        let mut packet_bytes = [0u8; size_of::<Message>()];
+        // Convert the packet bytes back to a message struct
+        from_bytes::<Message>(&packet_bytes).ok()
+    }
 
-        // Read the log bytes from the flash memory into the buffer
-        if let Err(_err) = flash_read_bytes(address as u32, &mut packet_bytes) {
-            // Return None if the read operation failed
-            return None;
+    /// Number of records and bytes in the log
+    pub fn info(&self) -> Message {
+        Message::LogInfo {
+            records: self.written_packets as u32,
+            record_size: LOG_RECORD_SIZE as u16,
+            used_bytes: (self.written_packets * LOG_RECORD_SIZE) as u32,
         }
+    }
 
-        // Convert the packet bytes back to a message struct
-        let log = from_bytes::<Message>(&packet_bytes).unwrap();
+    /// Send the log bytes from offset to the PC, at most LOG_CHUNK_LEN bytes are sent at once.
+    /// A chunk past the end of the log is sent empty.
+    pub fn write_chunk<H: Flash + Uart>(&self, hal: &mut H, offset: u32, len: u16) -> Result<(), FlashError> {
+        let used = self.written_packets * LOG_RECORD_SIZE;
+        let offset = offset as usize;
+        let len = (len as usize).min(LOG_CHUNK_LEN).min(used.saturating_sub(offset));
+
+        let mut buf = [0u8; LOG_CHUNK_LEN];
+        if len > 0 {
+            hal.read(offset as u32, &mut buf[..len])?;
+        }
+
+        let data = Vec::from_slice(&buf[..len]).unwrap();
+        let crc = chunk_crc(&data);
+        write_packet(hal, Message::LogChunk { offset: offset as u32, data, crc });
+        Ok(())
+    }
+
+    /// Erase the log. The PC confirms the erase with the number of records it downloaded,
+    /// so records stored after the download are never thrown away unseen.
+    /// An empty log needs no erase, so an erase that is sent again because the chip erase outlasted
+    /// the timeout of the PC is accepted, although the count it confirms is gone.
+    pub fn erase<H: Flash + Uart>(&mut self, hal: &mut H, records: u32) -> Result<(), NackReason> {
+        if self.written_packets == 0 {
+            return Ok(());
+        }
+        if records as usize != self.written_packets {
+            return Err(NackReason::EraseNotConfirmed);
+        }
 
-        Some(log)
+        erase_flash(hal).map_err(|_| NackReason::FlashError)?;
+        log!(hal, Info, "Erased flash log of {} records", records);
+
+        self.written_packets = 0;
+        self.full = false;
+        Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::LogLevel;
+    use crate::hal::mock::MockHal;
+
+    const RECORDS: usize = 8;
 
-    pub fn retrieve_loggings(&self, messages: usize) {
+    fn record(k: usize) -> Message {
+        Message::Log { level: LogLevel::Info, text: protocol::log_text(format_args!("record {}", k)) }
+    }
 
-        for message in 0..messages {
-            match  self.retrieve_logging(message) {
-                Some(log) => write_packet(log),
-                None => {
-                    send_bytes(format!("Erasing flash\n").as_bytes());
-                    flash_chip_erase().unwrap();
-                    break;
-                },
+    fn store(hal: &mut MockHal, manager: &mut LogStorageManager, count: usize) {
+        for k in 0..count {
+            manager.store_logging(hal, &record(k)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_end_found_after_restart() {
+        for count in [0, 1, 3, RECORDS - 1, RECORDS] {
+            let mut hal = MockHal::new();
+            let mut manager = LogStorageManager::new(&mut hal, RECORDS * LOG_RECORD_SIZE);
+            store(&mut hal, &mut manager, count);
+
+            let manager = LogStorageManager::new(&mut hal, RECORDS * LOG_RECORD_SIZE);
+            assert_eq!(manager.written_packets, count);
+            assert_eq!(manager.retrieve_logging(&mut hal, count.saturating_sub(1)), count.checked_sub(1).map(record));
+            assert_eq!(manager.retrieve_logging(&mut hal, count), None);
+        }
+    }
+
+    #[test]
+    fn test_full_log() {
+        let mut hal = MockHal::new();
+        let mut manager = LogStorageManager::new(&mut hal, RECORDS * LOG_RECORD_SIZE);
+        store(&mut hal, &mut manager, RECORDS + 2);
+        assert_eq!(manager.written_packets, RECORDS);
+        assert_eq!(hal.flash[RECORDS * LOG_RECORD_SIZE], 0xff);
+
+        // The PC is told once
+        let warnings = hal.take_messages();
+        assert_eq!(warnings.len(), 1);
+        assert!(matches!(warnings[0], Message::Log { level: LogLevel::Warn, .. }));
+    }
+
+    #[test]
+    fn test_write_chunk() {
+        let mut hal = MockHal::new();
+        let mut manager = LogStorageManager::new(&mut hal, RECORDS * LOG_RECORD_SIZE);
+        store(&mut hal, &mut manager, 3);
+        let used = 3 * LOG_RECORD_SIZE;
+
+        // Chunks are limited to LOG_CHUNK_LEN and to the end of the log
+        let mut offset = 0;
+        while offset < used {
+            manager.write_chunk(&mut hal, offset as u32, u16::MAX).unwrap();
+            match &hal.take_messages()[..] {
+                [Message::LogChunk { offset: chunk_offset, data, crc }] => {
+                    assert_eq!(*chunk_offset as usize, offset);
+                    assert_eq!(data.len(), LOG_CHUNK_LEN.min(used - offset));
+                    assert_eq!(data[..], hal.flash[offset..offset + data.len()]);
+                    assert_eq!(*crc, chunk_crc(data));
+                    offset += data.len();
+                }
+                messages => panic!("{:?}", messages),
             }
         }
+
+        manager.write_chunk(&mut hal, used as u32, 16).unwrap();
+        assert!(matches!(&hal.take_messages()[..], [Message::LogChunk { data, .. }] if data.is_empty()));
     }
 
-    pub fn dump_loggins(&mut self) {
-        self.retrieve_loggings(self.written_packets + 1);
-        self.written_packets = 0;
-        self.remaining_flash_size = self.max_flash_size;
+    #[test]
+    fn test_erase() {
+        let mut hal = MockHal::new();
+        let mut manager = LogStorageManager::new(&mut hal, RECORDS * LOG_RECORD_SIZE);
+        store(&mut hal, &mut manager, 3);
+
+        // Records stored after the download are not thrown away
+        assert_eq!(manager.erase(&mut hal, 2), Err(NackReason::EraseNotConfirmed));
+        assert_eq!(manager.written_packets, 3);
+
+        assert_eq!(manager.erase(&mut hal, 3), Ok(()));
+        assert_eq!(manager.written_packets, 0);
+        assert_eq!(LogStorageManager::new(&mut hal, RECORDS * LOG_RECORD_SIZE).written_packets, 0);
+
+        // The retransmitted erase finds the log empty and does not erase the chip again
+        hal.take_messages();
+        assert_eq!(manager.erase(&mut hal, 3), Ok(()));
+        assert!(hal.take_messages().is_empty());
+
+        // The log is used again after an erase
+        manager.store_logging(&mut hal, &Message::CalibrationMode).unwrap();
+        assert_eq!(manager.retrieve_logging(&mut hal, 0), Some(Message::CalibrationMode));
     }
 }
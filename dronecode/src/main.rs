@@ -4,25 +4,18 @@
 #![feature(log_syntax)]
 
 extern crate alloc;
-use crate::control::control_loop;
-use alloc::format;
 use core::alloc::Layout;
 use core::mem::MaybeUninit;
 use core::panic::PanicInfo;
 use tudelft_quadrupel::initialize::initialize;
 use tudelft_quadrupel::led::Led::{Green, Red};
 use tudelft_quadrupel::time::assembly_delay;
-use tudelft_quadrupel::uart::send_bytes;
 use tudelft_quadrupel::{entry, uart};
-
-mod working_mode;
-mod control;
-mod yaw_pitch_roll;
-mod drone;
-mod drone_transmission;
-mod log_storage_manager;
-mod controllers;
-mod kalman;
+use eslgroup14::control::control_loop;
+use eslgroup14::drone_transmission::write_packet;
+use eslgroup14::crash::crash_report;
+use eslgroup14::hal::{Clock, quadrupel::Quadrupel};
+use protocol::Message;
 
 /// The heap size of your drone code in bytes.
 /// Note: there are 8192 bytes of RAM available.
@@ -46,7 +39,7 @@ fn main() -> ! {
         initialize(unsafe { &mut HEAP_MEMORY }, false);
     }
 
-    control_loop();
+    control_loop(Quadrupel);
 }
 
 #[inline(never)]
@@ -54,12 +47,12 @@ fn main() -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     // On panic:
-    // * try and write the panic message on UART
+    // * try and send a crash report to the PC
     // * blink the red light
 
     if uart::is_initialized() {
-        let msg = format!("{info}\n");
-        send_bytes(msg.as_bytes());
+        let uptime = Quadrupel.now_us() / 1_000;
+        write_packet(&mut Quadrupel, Message::CrashReport(crash_report(info, uptime)));
     }
 
     // Start blinking red
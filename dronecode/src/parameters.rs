@@ -0,0 +1,166 @@
+use heapless::String;
+use protocol::{ParamInfo, ParamValue, NackReason};
+use crate::controllers::ControllerKind;
+
+/// All runtime parameters of the drone. The discriminant is the parameter index used in the protocol.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ParamId {
+    YawP,
+    YawI,
+    YawD,
+    AngleP,
+    RateP,
+    RateI,
+    RateD,
+    YawRateP,
+    HeightP,
+    HeightI,
+    KalmanQAngle,
+    KalmanQBias,
+    KalmanRMeasure,
+    MotorMaxManual,
+    MotorMaxControl,
+    MotorMin,
+    LinkTimeout,
+    BatteryMin,
+    YawCtl,
+    AngleCtl,
+    RateCtl,
+    YawRateCtl,
+    HeightCtl,
+    YawFF,
+    RateFF,
+    YawRateFF,
+    ComplementaryTau,
+    AhrsKp,
+    AhrsKi,
+}
+
+pub const PARAM_COUNT: usize = 29;
+
+/// Name, limits and default value of a parameter
+struct ParamDescriptor {
+    name: &'static str,
+    default: ParamValue,
+    min: ParamValue,
+    max: ParamValue,
+}
+
+const fn gain(name: &'static str, default: f32) -> ParamDescriptor {
+    ParamDescriptor { name, default: ParamValue::F32(default), min: ParamValue::F32(0.0), max: ParamValue::F32(100.0) }
+}
+
+const fn noise(name: &'static str, default: f32) -> ParamDescriptor {
+    ParamDescriptor { name, default: ParamValue::F32(default), min: ParamValue::F32(0.0), max: ParamValue::F32(10.0) }
+}
+
+/// Controller implementation of an axis, the values of ControllerKind
+const fn controller(name: &'static str, default: ControllerKind) -> ParamDescriptor {
+    integer(name, default as u16, 0, ControllerKind::Feedforward as u16)
+}
+
+const fn integer(name: &'static str, default: u16, min: u16, max: u16) -> ParamDescriptor {
+    ParamDescriptor { name, default: ParamValue::U16(default), min: ParamValue::U16(min), max: ParamValue::U16(max) }
+}
+
+/// Parameter table, in the order of ParamId
+const DESCRIPTORS: [ParamDescriptor; PARAM_COUNT] = [
+    gain("yaw_p", 0.0),
+    gain("yaw_i", 0.0),                         // per second
+    gain("yaw_d", 0.001),                       // in seconds
+    gain("angle_p", 0.0),
+    gain("rate_p", 0.0),
+    gain("rate_i", 1.0),                        // per second
+    gain("rate_d", 0.003),                      // in seconds
+    gain("yaw_rate_p", 0.0),
+    gain("height_p", 0.0),
+    gain("height_i", 0.1),                      // per second
+    noise("kal_q_angle", 0.004),
+    noise("kal_q_bias", 0.003),
+    noise("kal_r_measure", 0.0001),
+    integer("motor_max_man", 400, 200, 1000),   // motor limit in manual mode
+    integer("motor_max_ctl", 600, 200, 1000),   // motor limit in the control modes
+    integer("motor_min", 200, 0, 400),          // minimum motor value while flying
+    integer("link_timeout", 3, 1, 100),         // ticks without message before panic
+    integer("battery_min", 900, 0, 1300),       // battery panic level in 10 mV
+    controller("yaw_ctl", ControllerKind::Positional),          // 0 positional, 1 incremental, 2 feedforward
+    controller("angle_ctl", ControllerKind::Positional),
+    controller("rate_ctl", ControllerKind::Positional),
+    controller("yaw_rate_ctl", ControllerKind::Incremental),
+    controller("height_ctl", ControllerKind::Positional),
+    gain("yaw_ff", 0.0),                        // feedforward gains of the feedforward controllers
+    gain("rate_ff", 0.0),
+    gain("yaw_rate_ff", 0.0),
+    gain("comp_tau", 0.5),                      // time constant of the complementary filter in seconds
+    gain("ahrs_kp", 1.0),                       // feedback gains of the Mahony AHRS, per second
+    gain("ahrs_ki", 0.05),                      // per second squared, the gyro bias estimation
+];
+
+/// Current values of all parameters
+#[derive(Copy, Clone)]
+pub struct Parameters {
+    values: [ParamValue; PARAM_COUNT],
+}
+
+impl Parameters {
+    pub fn new() -> Self {
+        let mut values = [ParamValue::U16(0); PARAM_COUNT];
+        for (value, descriptor) in values.iter_mut().zip(DESCRIPTORS.iter()) {
+            *value = descriptor.default;
+        }
+        Parameters { values }
+    }
+
+    pub fn get(&self, id: ParamId) -> ParamValue {
+        self.values[id as usize]
+    }
+
+    pub fn get_f32(&self, id: ParamId) -> f32 {
+        match self.get(id) {
+            ParamValue::F32(value) => value,
+            ParamValue::U16(value) => value as f32,
+        }
+    }
+
+    pub fn get_u16(&self, id: ParamId) -> u16 {
+        match self.get(id) {
+            ParamValue::U16(value) => value,
+            ParamValue::F32(value) => value as u16,
+        }
+    }
+
+    /// Controller implementation selected by a *_ctl parameter
+    pub fn get_controller(&self, id: ParamId) -> ControllerKind {
+        ControllerKind::from_u16(self.get_u16(id)).unwrap_or(ControllerKind::Positional)
+    }
+
+    /// Change a parameter, the value has to be of the right type and within the limits
+    pub fn set(&mut self, index: u8, value: ParamValue) -> Result<(), NackReason> {
+        let descriptor = DESCRIPTORS.get(index as usize).ok_or(NackReason::UnknownParameter)?;
+
+        if !value.same_type(&descriptor.default) {
+            return Err(NackReason::ParameterType);
+        }
+        if !value.within(&descriptor.min, &descriptor.max) {
+            return Err(NackReason::ParameterRange);
+        }
+
+        self.values[index as usize] = value;
+        Ok(())
+    }
+
+    /// Description and current value of a parameter, used to answer the PC
+    pub fn info(&self, index: u8) -> Option<ParamInfo> {
+        let descriptor = DESCRIPTORS.get(index as usize)?;
+
+        Some(ParamInfo {
+            index,
+            count: PARAM_COUNT as u8,
+            name: String::from(descriptor.name),
+            value: self.values[index as usize],
+            min: descriptor.min,
+            max: descriptor.max,
+            default: descriptor.default,
+        })
+    }
+}
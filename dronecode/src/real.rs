@@ -0,0 +1,267 @@
+//! Numbers of the attitude math. The nRF51 has no FPU, every f32 operation is a call into the
+//! software float library. With the `fixed-point` feature the quaternion conversion, the Kalman
+//! filters and the controllers run on fixed-point numbers instead, their inputs and outputs stay f32.
+
+use core::fmt::Debug;
+use core::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+/// Number the attitude math is written for, f32 or one of the fixed-point types
+pub trait Real:
+    Copy + Default + Debug + PartialOrd
+    + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self> + Neg<Output = Self>
+    + AddAssign + SubAssign
+{
+    /// Convert from f32, values out of range saturate and NaN becomes zero
+    fn from_f32(value: f32) -> Self;
+    fn to_f32(self) -> f32;
+    fn max(self, other: Self) -> Self;
+    fn min(self, other: Self) -> Self;
+    fn sqrt(self) -> Self;
+    /// Angle of the vector (x, self) in radians, with the approximation of micromath
+    fn atan2(self, x: Self) -> Self;
+}
+
+/// Number of the quaternion conversion, the controllers and the altitude filter
+#[cfg(not(feature = "fixed-point"))]
+pub type Number = f32;
+#[cfg(feature = "fixed-point")]
+pub type Number = Q16;
+
+/// Number of the attitude Kalman filter, the noise variances need more fractional bits
+#[cfg(not(feature = "fixed-point"))]
+pub type FineNumber = f32;
+#[cfg(feature = "fixed-point")]
+pub type FineNumber = Q24;
+
+impl Real for f32 {
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn max(self, other: Self) -> Self {
+        f32::max(self, other)
+    }
+
+    fn min(self, other: Self) -> Self {
+        f32::min(self, other)
+    }
+
+    fn sqrt(self) -> Self {
+        micromath::F32Ext::sqrt(self)
+    }
+
+    fn atan2(self, x: Self) -> Self {
+        micromath::F32Ext::atan2(self, x)
+    }
+}
+
+/// Integer square root, rounded down
+#[cfg(feature = "fixed-point")]
+fn isqrt(value: u64) -> u64 {
+    let mut rest = value;
+    let mut root = 0;
+    let mut bit = 1 << 62;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rest >= root + bit {
+            rest -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+/// atan2 of raw fixed-point numbers with `frac` fractional bits. It uses the approximation of
+/// micromath, atan = n / (x^2 + n) with n = B * |x * y| + y^2 in quarter turns, so both paths agree.
+#[cfg(feature = "fixed-point")]
+fn atan2_bits(y: i32, x: i32, frac: u32) -> i32 {
+    const B: u64 = 39_075;          // 0.596227 with 16 fractional bits
+    const FRAC_PI_2: i64 = 102_944; // pi / 2 with 16 fractional bits
+    const HALF_TURN: i64 = 2 << 16; // two quarter turns with 16 fractional bits
+
+    let (a, b) = (x.unsigned_abs() as u64, y.unsigned_abs() as u64);
+    let bits = 64 - a.max(b).leading_zeros() as i32;
+    if bits == 0 {
+        return 0;
+    }
+
+    // The ratio does not depend on the length of the vector, scaling it to 16 bits keeps the
+    // products in range without losing precision on short vectors
+    let (a, b) = if bits > 16 { (a >> (bits - 16), b >> (bits - 16)) } else { (a << (16 - bits), b << (16 - bits)) };
+    let bxy = (B * a * b) >> 16;
+    let n = bxy + b * b;
+    let first_quadrant = ((n << 16) / (a * a + bxy + n)) as i64;
+
+    let turns = match (x >= 0, y >= 0) {
+        (true, true) => first_quadrant,
+        (true, false) => -first_quadrant,
+        (false, true) => HALF_TURN - first_quadrant,
+        (false, false) => first_quadrant - HALF_TURN,
+    };
+    ((turns * FRAC_PI_2) >> (32 - frac)) as i32
+}
+
+/// Fixed-point number with saturating arithmetic, the controllers and filters must not wrap around
+/// or panic on an overflow. Division by zero saturates like it gives infinity on f32.
+#[cfg(feature = "fixed-point")]
+macro_rules! fixed_real {
+    ($name:ident, $fixed:ty, $frac:literal) => {
+        #[derive(Copy, Clone, Debug, Default, PartialEq, PartialOrd)]
+        pub struct $name(pub $fixed);
+
+        impl Add for $name {
+            type Output = Self;
+            fn add(self, rhs: Self) -> Self {
+                $name(self.0.saturating_add(rhs.0))
+            }
+        }
+
+        impl Sub for $name {
+            type Output = Self;
+            fn sub(self, rhs: Self) -> Self {
+                $name(self.0.saturating_sub(rhs.0))
+            }
+        }
+
+        impl Mul for $name {
+            type Output = Self;
+            fn mul(self, rhs: Self) -> Self {
+                // Rounded to the nearest, truncating would pull every product down and the
+                // integrators would drift away from the f32 path
+                let product = self.0.to_bits() as i64 * rhs.0.to_bits() as i64;
+                let rounded = (product + (1 << ($frac - 1))) >> $frac;
+                $name(<$fixed>::from_bits(rounded.clamp(i32::MIN as i64, i32::MAX as i64) as i32))
+            }
+        }
+
+        impl Div for $name {
+            type Output = Self;
+            fn div(self, rhs: Self) -> Self {
+                if self.0 == <$fixed>::ZERO {
+                    return self;
+                }
+                let saturated = if self.0.is_negative() == rhs.0.is_negative() { <$fixed>::MAX } else { <$fixed>::MIN };
+                $name(self.0.checked_div(rhs.0).unwrap_or(saturated))
+            }
+        }
+
+        impl Neg for $name {
+            type Output = Self;
+            fn neg(self) -> Self {
+                $name(self.0.saturating_neg())
+            }
+        }
+
+        impl AddAssign for $name {
+            fn add_assign(&mut self, rhs: Self) {
+                *self = *self + rhs;
+            }
+        }
+
+        impl SubAssign for $name {
+            fn sub_assign(&mut self, rhs: Self) {
+                *self = *self - rhs;
+            }
+        }
+
+        impl Real for $name {
+            fn from_f32(value: f32) -> Self {
+                if value.is_nan() {
+                    return $name(<$fixed>::ZERO);
+                }
+                $name(<$fixed>::saturating_from_num(value))
+            }
+
+            fn to_f32(self) -> f32 {
+                self.0.to_num()
+            }
+
+            fn max(self, other: Self) -> Self {
+                $name(Ord::max(self.0, other.0))
+            }
+
+            fn min(self, other: Self) -> Self {
+                $name(Ord::min(self.0, other.0))
+            }
+
+            fn sqrt(self) -> Self {
+                if self.0.to_bits() <= 0 {
+                    return $name(<$fixed>::ZERO);
+                }
+                let root = isqrt((self.0.to_bits() as u64) << $frac);
+                $name(<$fixed>::from_bits(root as i32))
+            }
+
+            fn atan2(self, x: Self) -> Self {
+                $name(<$fixed>::from_bits(atan2_bits(self.0.to_bits(), x.0.to_bits(), $frac)))
+            }
+        }
+    };
+}
+
+#[cfg(feature = "fixed-point")]
+fixed_real!(Q16, fixed::types::I16F16, 16);
+#[cfg(feature = "fixed-point")]
+fixed_real!(Q24, fixed::types::I8F24, 24);
+
+#[cfg(all(test, feature = "fixed-point"))]
+mod tests {
+    use super::*;
+    use fixed::types::{I16F16, I8F24};
+
+    fn close(a: f32, b: f32, tolerance: f32) -> bool {
+        (a - b).abs() <= tolerance
+    }
+
+    #[test]
+    fn test_saturation() {
+        let big = Q16::from_f32(30000.0);
+        assert_eq!(big + big, Q16(I16F16::MAX));
+        assert_eq!(big * -big, Q16(I16F16::MIN));
+        assert_eq!(Q16::from_f32(-1.0) / Q16::from_f32(0.0), Q16(I16F16::MIN));
+        assert_eq!(Q16::from_f32(0.0) / Q16::from_f32(0.0), Q16::default());
+        assert_eq!(Q16::from_f32(f32::NAN), Q16::default());
+        assert_eq!(Q24::from_f32(f32::MAX), Q24(I8F24::MAX));
+    }
+
+    #[test]
+    fn test_sqrt() {
+        for value in [0.0, 1.0 / 1024.0, 0.25, 1.0, 2.0, 100.0, 30000.0] {
+            assert!(close(Q16::from_f32(value).sqrt().to_f32(), newton_sqrt(value), 1e-4), "sqrt {}", value);
+        }
+        for value in [1.0 / 65536.0, 0.5, 3.0, 120.0] {
+            assert!(close(Q24::from_f32(value).sqrt().to_f32(), newton_sqrt(value), 1e-6), "sqrt {}", value);
+        }
+    }
+
+    #[test]
+    fn test_atan2_matches_f32() {
+        // Every quadrant, the axes and vectors of very different lengths
+        let vectors = [(0.0, 1.0), (1.0, 0.0), (0.0, -1.0), (-1.0, 0.0), (0.3, 0.9), (0.9, -0.3),
+                       (-0.5, -0.5), (-0.01, 0.7), (0.001953125, -0.0009765625), (12.0, 5.0), (-100.0, 3.0)];
+        for (y, x) in vectors {
+            let expected = Real::atan2(y, x);
+            assert!(close(Q16::from_f32(y).atan2(Q16::from_f32(x)).to_f32(), expected, 1e-3), "atan2({}, {})", y, x);
+            assert!(close(Q24::from_f32(y).atan2(Q24::from_f32(x)).to_f32(), expected, 1e-4), "atan2({}, {})", y, x);
+        }
+        assert_eq!(Q16::from_f32(0.0).atan2(Q16::from_f32(0.0)).to_f32(), 0.0);
+    }
+
+    /// Square root by Newton's method, micromath only approximates it
+    fn newton_sqrt(value: f32) -> f32 {
+        let mut root = value.max(1.0);
+        for _ in 0..40 {
+            root = 0.5 * (root + value / root);
+        }
+        root
+    }
+}
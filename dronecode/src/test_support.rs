@@ -0,0 +1,35 @@
+//! Inputs shared by the tests of the filters
+
+/// Time step of the filter tests, in s
+pub const DT: f32 = 0.01;
+
+/// Repeatable noise from -1 to 1
+pub fn noise(k: u32) -> f32 {
+    (k.wrapping_mul(2_654_435_761) >> 16) as f32 / 32768.0 - 1.0
+}
+
+/// A swinging angle with a noisy accelerometer angle, and a gyro rate with a bias, at step `k`
+#[cfg(feature = "fixed-point")]
+pub fn swinging_angle(k: u32) -> (f32, f32) {
+    let t = k as f32 * DT;
+    let angle = 0.4 * (t * 1.5).sin() + 0.02 * noise(k);
+    let rate = 0.6 * (t * 1.5).cos() + 0.05 + 0.1 * noise(k + 7919);
+    (angle, rate)
+}
+
+/// Run the f32 and the fixed-point version of a filter side by side for 2000 steps of the same
+/// input, returns the largest difference of their outputs
+#[cfg(feature = "fixed-point")]
+pub fn max_difference<I: Copy, O>(
+    mut input: impl FnMut(u32) -> I,
+    mut float: impl FnMut(I) -> O,
+    mut fixed: impl FnMut(I) -> O,
+    difference: impl Fn(O, O) -> f32,
+) -> f32 {
+    let mut max: f32 = 0.0;
+    for k in 0..2000 {
+        let input = input(k);
+        max = max.max(difference(float(input), fixed(input)));
+    }
+    max
+}
@@ -1,7 +1,9 @@
-use tudelft_quadrupel::block;
-use tudelft_quadrupel::mpu::read_dmp_bytes;
 use crate::drone::{Drone, Getter, Setter};
 use crate::yaw_pitch_roll::YawPitchRoll;
+use crate::hal::Hal;
+
+/// DMP samples needed before the control modes can be used
+const CALIBRATION_SAMPLES: u16 = 50;
 
 #[derive(Copy, Clone)]
 pub struct Calibration{
@@ -13,11 +15,12 @@ pub struct Calibration{
     pub(crate) roll_kal: f32,
     pub(crate) acceleration_z: f32,
     pub(crate) height: f32,
+    pub(crate) samples: u16,
     // pub(crate) acceleration: Accel,
     // pub(crate) pressure: u32,
 }
-pub fn calibrate(drone: &mut Drone){
-    let quaternion = block!(read_dmp_bytes()).unwrap();
+pub fn calibrate<H: Hal>(drone: &mut Drone<H>){
+    let quaternion = drone.hal().read_dmp();
     let ypr = YawPitchRoll::from(quaternion);
     let last_calibration = drone.get_calibration();
     if last_calibration.pitch_dmp[0] == 0.0000000{
@@ -49,9 +52,15 @@ impl Calibration {
             roll_kal: 0.0,
             acceleration_z: 0.0,
             height:  0.0,
+            samples: 0,
         }
     }
 
+    /// Whether calibration mode ran long enough to measure the DMP offsets
+    pub fn is_calibrated(&self) -> bool {
+        self.samples >= CALIBRATION_SAMPLES
+    }
+
     pub fn yaw_compensation(&self, yaw: f32) -> f32 { yaw - self.yaw_dmp[0] }
 
     pub fn full_compensation_dmp(&self, full: YawPitchRoll) -> YawPitchRoll {
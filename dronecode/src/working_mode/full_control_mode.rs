@@ -1,28 +1,69 @@
-use tudelft_quadrupel::time::Instant;
-use crate::controllers::PID;
+use crate::controllers::{AxisController, Controller, ControllerKind, Gains, Limits};
+use crate::parameters::{Parameters, ParamId};
 use crate::drone::{Drone, Getter, Setter};
 use crate::yaw_pitch_roll::full_rate;
 use crate::drone::motors::{motor_assign, normalize_full};
+use protocol::Setpoint;
+use crate::hal::Hal;
 
 #[derive(Copy, Clone)]
 pub struct FullController{
-    pub(crate) pitch_p1: PID,
-    pub(crate) roll_p1: PID,
-    pub(crate) yaw_p2: PID,
-    pub(crate) pitch_p2: PID,
-    pub(crate) roll_p2: PID,
+    pub(crate) pitch_p1: AxisController,
+    pub(crate) roll_p1: AxisController,
+    pub(crate) yaw_p2: AxisController,
+    pub(crate) pitch_p2: AxisController,
+    pub(crate) roll_p2: AxisController,
 }
 
+/// Largest rate target of the angle loops, in the -1..1 range of the rates
+const ANGLE_LIMITS: Limits = Limits { output: 1.0, ..Limits::NONE };
+
+/// The rate loops give at most the -1..1 range of the pwm, the integrators a share of it.
+/// The rate measurement is noisy, so its derivative is filtered.
+const RATE_LIMITS: Limits = Limits { output: 1.0, integral: 0.3, derivative_filter: 0.5 };
+
 impl FullController {
+    /// Controllers without gains, they are set from the parameters
     pub fn new() -> Self{
+        let angle = AxisController::new(ControllerKind::Positional, Gains::default(), ANGLE_LIMITS);
+        let rate = AxisController::new(ControllerKind::Positional, Gains::default(), RATE_LIMITS);
         FullController{
-            pitch_p1: PID::new(0.0, 0.0, 0.0),
-            roll_p1: PID::new(0.0, 0.0, 0.0),
-            yaw_p2: PID::new(0.0, 0.0, 0.0),
-            pitch_p2: PID::new(0.0, 0.1, 0.3),
-            roll_p2: PID::new(0.0, 0.1, 0.3),
+            pitch_p1: angle,
+            roll_p1: angle,
+            yaw_p2: rate,
+            pitch_p2: rate,
+            roll_p2: rate,
         }
     }
+
+    /// Take the implementation and the gains of each loop from the parameters
+    pub fn configure(&mut self, parameters: &Parameters) {
+        let angle = parameters.get_controller(ParamId::AngleCtl);
+        let angle_gains = Gains::pid(parameters.get_f32(ParamId::AngleP), 0.0, 0.0);
+        self.pitch_p1.configure(angle, angle_gains);
+        self.roll_p1.configure(angle, angle_gains);
+
+        let rate = parameters.get_controller(ParamId::RateCtl);
+        let rate_gains = Gains {
+            kp: parameters.get_f32(ParamId::RateP),
+            ki: parameters.get_f32(ParamId::RateI),
+            kd: parameters.get_f32(ParamId::RateD),
+            kff: parameters.get_f32(ParamId::RateFF),
+        };
+        self.pitch_p2.configure(rate, rate_gains);
+        self.roll_p2.configure(rate, rate_gains);
+
+        let yaw_rate_gains = Gains { kff: parameters.get_f32(ParamId::YawRateFF), ..Gains::pid(parameters.get_f32(ParamId::YawRateP), 0.0, 0.0) };
+        self.yaw_p2.configure(parameters.get_controller(ParamId::YawRateCtl), yaw_rate_gains);
+    }
+
+    pub fn reset(&mut self) {
+        self.pitch_p1.reset();
+        self.roll_p1.reset();
+        self.yaw_p2.reset();
+        self.pitch_p2.reset();
+        self.roll_p2.reset();
+    }
 }
 
 fn map_velocity_to_f32(data: [f32; 3]) -> [f32; 3] {
@@ -39,9 +80,9 @@ fn map_velocity_to_f32(data: [f32; 3]) -> [f32; 3] {
 }
 
 ///Do the motion according to the argument from command by changing motor speed
-pub fn motion(drone: &mut Drone, argument: [u16; 4]){
+pub fn motion<H: Hal>(drone: &mut Drone<H>, setpoint: Setpoint){
 
-    let pwm = full_control(drone, argument);
+    let pwm = full_control(drone, setpoint);
 
     //Assign motor speed according to the pwm signal
     motor_assign(drone, pwm);
@@ -49,42 +90,31 @@ pub fn motion(drone: &mut Drone, argument: [u16; 4]){
 
 
 //P1: 0.84 P2: 0.96
-pub fn full_control(drone: &mut Drone, argument: [u16; 4]) -> [f32; 4]{
+pub fn full_control<H: Hal>(drone: &mut Drone<H>, setpoint: Setpoint) -> [f32; 4]{
 
-    let [target_yaw, mut target_pitch, mut target_roll, target_lift]
-        = normalize_full(argument[2], argument[0], argument[1], argument[3]);
+    let [target_yaw, target_pitch, target_roll, target_lift] = normalize_full(setpoint);
 
     let angles = drone.get_current_attitude();
 
-    let mut full_controllers = drone.get_full_controller();
+    // Time since the last update, the rates are taken over the same time
+    let dt = drone.get_dt();
+    let velocities = map_velocity_to_f32(full_rate(drone, angles));
 
     let temp = 1.0 / 0.5236;
 
-    let pitch = full_controllers.pitch_p1.step(target_pitch, angles.pitch);
-    let roll = full_controllers.roll_p1.step(target_roll, angles.roll);
-    drone.set_full_angle_controller([pitch.1, pitch.2], [roll.1, roll.2], [pitch.0, roll.0]);
-    let pwm_change = drone.get_angle_pwm_change();
+    let full_controllers = drone.get_full_controller();
 
-    target_pitch = pwm_change[0] * temp;
-    target_roll = pwm_change[1] * temp;
+    // The angle loops give the rate targets of the rate loops
+    let target_pitch_rate = full_controllers.pitch_p1.update(target_pitch, angles.pitch, dt) * temp;
+    let target_roll_rate = full_controllers.roll_p1.update(target_roll, angles.roll, dt) * temp;
 
+    let yaw_pwm = full_controllers.yaw_p2.update(target_yaw, velocities[0], dt);
+    let pitch_pwm = full_controllers.pitch_p2.update(target_pitch_rate, velocities[1], dt);
+    let roll_pwm = full_controllers.roll_p2.update(target_roll_rate, velocities[2], dt);
 
-    let velocities = map_velocity_to_f32(full_rate(drone, angles));
-    let mut full_controllers = drone.get_full_controller();
-    // Calculate PID output
-    let yaw_pwm = full_controllers.yaw_p2.step(target_yaw, velocities[0]);
-    let pitch_pwm = full_controllers.pitch_p2.step2(target_pitch, velocities[1]);
-    let roll_pwm = full_controllers.roll_p2.step2(target_roll, velocities[2]);
-
-    if pitch_pwm.0 > -10000.0 || pitch_pwm.0 < 10000.0 || roll_pwm.0 > -10000.0 || roll_pwm.0 < 10000.0{
-        drone.set_full_rate_controller([yaw_pwm.1, yaw_pwm.2],
-                                       [pitch_pwm.1, pitch_pwm.2],
-                                       [roll_pwm.1, roll_pwm.2],
-                                       [yaw_pwm.0, pitch_pwm.0, roll_pwm.0]);
-    }
+    let telemetry = full_controllers.pitch_p2.telemetry();
+    drone.set_pid(telemetry);
 
-    let pwm_change = drone.get_rate_pwm_change();
-    //drone.set_test([pwm_change[0], target_pitch - pwm_change[1], target_roll - pwm_change[2], 0.0]);
-    [pwm_change[0], target_pitch - pwm_change[1], target_roll - pwm_change[2], target_lift]
+    [yaw_pwm, pitch_pwm, roll_pwm, target_lift]
 
 }
\ No newline at end of file
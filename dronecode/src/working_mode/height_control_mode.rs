@@ -1,41 +1,59 @@
 use crate::drone::{Drone, Getter, Setter};
-use crate::drone::motors::{motor_assign, MOTOR_MAX_CONTROL};
+use crate::controllers::{AxisController, Controller, ControllerKind, Gains, Limits};
+use crate::drone::motors::motor_assign;
 use crate::working_mode::full_control_mode::full_control;
+use crate::parameters::{Parameters, ParamId};
+use protocol::Setpoint;
+use crate::hal::Hal;
 
 const LIFT_MOTOR_VARIATION: f32 = 200.0;
-const FLOATING_PARAMETER: f32 = 315.0 / MOTOR_MAX_CONTROL as f32;
-const LIFT_VARIATION_PARAMETER: f32 = LIFT_MOTOR_VARIATION / MOTOR_MAX_CONTROL as f32;
+const FLOATING_MOTOR: f32 = 315.0;
 
+/// The output of the height controller is in units of LIFT_MOTOR_VARIATION. The integrator
+/// can take up half of the range to make up for the battery running down.
+const HEIGHT_LIMITS: Limits = Limits { output: 1.0, integral: 0.5, derivative_filter: 1.0 };
 
-pub fn motion(drone: &mut Drone, argument: [u16; 4]){
+/// Controller of the height without gains, they are set from the parameters
+pub fn height_controller() -> AxisController {
+    AxisController::new(ControllerKind::Positional, Gains::default(), HEIGHT_LIMITS)
+}
+
+/// Take the implementation and the gains from the parameters
+pub fn configure_height_controller(controller: &mut AxisController, parameters: &Parameters) {
+    let gains = Gains::pid(parameters.get_f32(ParamId::HeightP), parameters.get_f32(ParamId::HeightI), 0.0);
+    controller.configure(parameters.get_controller(ParamId::HeightCtl), gains);
+}
+
+
+pub fn motion<H: Hal>(drone: &mut Drone<H>, setpoint: Setpoint){
 
-    let pwm = height_control(drone, argument);
+    let pwm = height_control(drone, setpoint);
     //Assign motor speed according to the pwm signal
     motor_assign(drone, pwm);
 }
 
-pub fn height_control(drone: &mut Drone, argument: [u16; 4]) -> [f32; 4]{
+pub fn height_control<H: Hal>(drone: &mut Drone<H>, setpoint: Setpoint) -> [f32; 4]{
 
-    let pwm = full_control(drone, argument);
+    // Time since the last update, taken before full control starts the next one
+    let dt = drone.get_dt();
+    let pwm = full_control(drone, setpoint);
 
     let height = (drone.get_height() - drone.get_calibration().height) / 100.0;
 
-    let mut height_controller = drone.get_height_controller();
-
     let current =  height / 2 as f32;
 
-    let height_controlled = height_controller.step(pwm[3], current);
-
-    drone.set_height_controller([height_controlled.1, height_controlled.2], height_controlled.0);
+    let height_controller = drone.get_height_controller();
+    let pwm_change = height_controller.update(pwm[3], current, dt);
+    let telemetry = height_controller.telemetry();
+    drone.set_pid(telemetry);
 
-    let pwm_change = drone.get_height_pwm_change();
-
-    let mut lift = FLOATING_PARAMETER + pwm_change * LIFT_VARIATION_PARAMETER;
-    drone.set_test([lift, current, pwm_change, 0.0]);
+    let motor_max = drone.get_parameters().get_u16(ParamId::MotorMaxControl) as f32;
+    let mut lift = FLOATING_MOTOR / motor_max + pwm_change * LIFT_MOTOR_VARIATION / motor_max;
 
     if pwm[3] == 0.0 {
         lift = 0.0;
     }
+    drone.set_test([lift, current, pwm_change, 0.0]);
     [pwm[0], pwm[1], pwm[2], lift]
 
 }
\ No newline at end of file
@@ -1,6 +1,9 @@
 use crate::drone::{Drone, Getter, Setter};
-use crate::working_mode::panic_mode::panic_mode;
-use protocol::WorkingModes;
+use crate::working_mode::panic_mode::{PanicRamp, panic_check};
+use crate::parameters::ParamId;
+use protocol::{WorkingModes, NackReason, Setpoint};
+use protocol::WorkingModes::{SafeMode, PanicMode, ManualMode, CalibrationMode, YawControlMode, FullControlMode, HeightControlMode, RawSensorMode};
+use crate::hal::Hal;
 
 pub mod manual_mode;
 pub mod panic_mode;
@@ -10,128 +13,376 @@ pub mod full_control_mode;
 pub mod height_control_mode;
 pub mod raw_sensor_mode;
 
-pub fn mode_switch(drone: &mut Drone, new: WorkingModes) {
-    match drone.get_mode() {
-        WorkingModes::SafeMode | WorkingModes::CalibrationMode => {
-            match new {
-                WorkingModes::FullControlMode
-                | WorkingModes::YawControlMode
-                | WorkingModes::HeightControlMode
-                | WorkingModes::RawSensorMode
-                  => { drone.reset_all_controller(); }
-                _ => (),
-            }
-            drone.set_mode(new);
-        },
-        WorkingModes::PanicMode => drone.set_mode(panic_mode()),
-        WorkingModes::ManualMode => {
-            match new {
-                WorkingModes::CalibrationMode
-                | WorkingModes::SafeMode
-                | WorkingModes::PanicMode => {let temp = panic_mode();}
-                WorkingModes::FullControlMode
-                | WorkingModes::YawControlMode
-                | WorkingModes::RawSensorMode => { drone.reset_all_controller();}
-                _ => ()
-            }
-            drone.set_mode(new);
-        },
-        WorkingModes::YawControlMode => {
-            match new {
-                WorkingModes::CalibrationMode
-                | WorkingModes::SafeMode
-                | WorkingModes::PanicMode => {let temp = panic_mode();}
-                WorkingModes::FullControlMode
-                | WorkingModes::RawSensorMode => { drone.reset_all_controller();}
-                _ => ()
-            }
-            drone.set_mode(new);
-        },
-        WorkingModes::FullControlMode => {
-            match new {
-                WorkingModes::CalibrationMode
-                | WorkingModes::SafeMode
-                | WorkingModes::PanicMode => {let temp = panic_mode();}
-                WorkingModes::YawControlMode
-                | WorkingModes::RawSensorMode=> { drone.reset_all_controller();}
-                _ => ()
-            }
-            drone.set_mode(new);
-        },
-        WorkingModes::HeightControlMode => {
-            match new {
-                WorkingModes::CalibrationMode
-                | WorkingModes::SafeMode
-                | WorkingModes::PanicMode => {
-                    drone.reset_height_flag();
-                    drone.set_height_calibration(0.0);
-                    let temp = panic_mode();
-                }
-                WorkingModes::YawControlMode
-                | WorkingModes::FullControlMode
-                | WorkingModes::RawSensorMode=> {
-                    drone.reset_all_controller();
-                    drone.reset_height_flag();
-                    drone.set_height_calibration(0.0);
-                }
-                WorkingModes::HeightControlMode => {
-                    if drone.get_height_flag() != 500{
-                        drone.set_height_flag(1);
-                        drone.set_height_calibration(drone.get_height());
-                    }
-                    else {
-                        drone.set_height_flag(0);
-                        let temp = drone.get_calibration().height;
-                        drone.set_height_calibration(temp);
-                    }
-                }
-                _ => ()
-            }
-            drone.set_mode(new);
+/// Setpoint thrust below which the throttle counts as low
+const THROTTLE_LOW: f32 = 0.02;
+
+/// Time in us HeightControlMode and RawSensorMode spend capturing their reference, 500 ticks at 100 Hz
+const REFERENCE_TIME: u64 = 5_000_000;
+
+/// Sub-state of HeightControlMode and RawSensorMode. They first follow the current height or
+/// attitude as their reference, and then hold on to the captured reference.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Reference {
+    Capturing(u64),     // time in us the capture started
+    Holding,
+}
+
+/// Condition that has to hold before a mode change is carried out
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Guard {
+    Calibrated,     // the DMP offsets were measured in calibration mode
+    ThrottleLow,    // the motors do not jump up when they start
+    BatteryOk,      // the battery is above the panic level
+}
+
+impl Guard {
+    /// Reason reported to the PC when the guard fails
+    fn reason(self) -> NackReason {
+        match self {
+            Guard::Calibrated => NackReason::NotCalibrated,
+            Guard::ThrottleLow => NackReason::ThrottleHigh,
+            Guard::BatteryOk => NackReason::BatteryLow,
         }
-        WorkingModes::RawSensorMode => {
-            match new {
-                WorkingModes::CalibrationMode
-                | WorkingModes::SafeMode
-                | WorkingModes::PanicMode => {
-                    drone.reset_raw_flag();
-                    let temp = panic_mode();
-                }
-                WorkingModes::YawControlMode
-                | WorkingModes::FullControlMode
-                | WorkingModes::HeightControlMode => {
-                    drone.reset_all_controller();
-                    drone.reset_raw_flag();
-                }
-                WorkingModes::RawSensorMode => {
-                    if drone.get_raw_flag() != 501{
-                        drone.set_raw_flag(1);
-                        drone.set_filtered_angles(drone.get_current_attitude());
-                        //drone.set_test([drone.get_calibration().pitch_kal,drone.get_current_attitude().pitch,0.0,0.0]);
+    }
+}
 
-                    }
-                    else {
-                        drone.set_raw_flag(0);
-                        drone.set_kal_calibration(drone.get_filtered_angles());
-                        //drone.set_test([drone.get_calibration().pitch_kal,drone.get_current_attitude().pitch,1.0,1.0]);
-                    }
-                }
-                _ => (),
+/// Work done on a mode change
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    StopMotors,
+    StartPanicRamp,
+    ResetControllers,
+    StartReference,
+    ClearHeightReference,
+}
+
+/// Allowed mode change. A request for `request` in one of the `from` modes enters `enter`, which
+/// is PanicMode when the drone has to land first. The actions run between the exit actions of
+/// the old mode and the entry actions of the new one.
+struct Transition {
+    from: &'static [WorkingModes],
+    request: WorkingModes,
+    enter: WorkingModes,
+    guards: &'static [Guard],
+    actions: &'static [Action],
+}
+
+const FLYING: &[WorkingModes] = &[ManualMode, YawControlMode, FullControlMode, HeightControlMode, RawSensorMode];
+const GROUNDED: &[WorkingModes] = &[SafeMode, CalibrationMode];
+
+/// Guards to start the motors, in manual mode and in the control modes
+const START: &[Guard] = &[Guard::ThrottleLow, Guard::BatteryOk];
+const START_CONTROL: &[Guard] = &[Guard::Calibrated, Guard::ThrottleLow, Guard::BatteryOk];
+
+/// Guards to go from manual mode to a control mode in the air
+const CONTROL: &[Guard] = &[Guard::Calibrated, Guard::BatteryOk];
+
+const RESET: &[Action] = &[Action::ResetControllers];
+
+/// Every mode change the drone accepts, requests that are not in the table are rejected.
+/// A request for the current mode is always accepted and changes nothing.
+const TRANSITIONS: &[Transition] = &[
+    // Landing: safe mode in the air goes through the panic ramp, which ends in safe mode
+    Transition { from: FLYING, request: PanicMode, enter: PanicMode, guards: &[], actions: &[] },
+    Transition { from: FLYING, request: SafeMode, enter: PanicMode, guards: &[], actions: &[] },
+    Transition { from: &[PanicMode], request: SafeMode, enter: PanicMode, guards: &[], actions: &[] },
+    // Panic is always accepted, on the ground the ramp ends in safe mode right away
+    Transition { from: GROUNDED, request: PanicMode, enter: PanicMode, guards: &[], actions: &[] },
+
+    // On the ground
+    Transition { from: &[SafeMode], request: CalibrationMode, enter: CalibrationMode, guards: &[], actions: &[] },
+    Transition { from: &[CalibrationMode], request: SafeMode, enter: SafeMode, guards: &[], actions: &[] },
+
+    // Starting the motors
+    Transition { from: GROUNDED, request: ManualMode, enter: ManualMode, guards: START, actions: &[] },
+    Transition { from: GROUNDED, request: YawControlMode, enter: YawControlMode, guards: START_CONTROL, actions: RESET },
+    Transition { from: GROUNDED, request: FullControlMode, enter: FullControlMode, guards: START_CONTROL, actions: RESET },
+    Transition { from: GROUNDED, request: HeightControlMode, enter: HeightControlMode, guards: START_CONTROL, actions: RESET },
+    Transition { from: GROUNDED, request: RawSensorMode, enter: RawSensorMode, guards: START_CONTROL, actions: RESET },
+
+    // In the air
+    Transition { from: FLYING, request: ManualMode, enter: ManualMode, guards: &[], actions: &[] },
+    Transition { from: &[ManualMode], request: YawControlMode, enter: YawControlMode, guards: CONTROL, actions: RESET },
+    Transition { from: &[ManualMode], request: FullControlMode, enter: FullControlMode, guards: CONTROL, actions: RESET },
+    Transition { from: &[ManualMode], request: HeightControlMode, enter: HeightControlMode, guards: CONTROL, actions: RESET },
+    Transition { from: &[ManualMode], request: RawSensorMode, enter: RawSensorMode, guards: CONTROL, actions: RESET },
+    Transition { from: FLYING, request: YawControlMode, enter: YawControlMode, guards: &[], actions: RESET },
+    Transition { from: FLYING, request: FullControlMode, enter: FullControlMode, guards: &[], actions: RESET },
+    // Height control builds on full control, so its controllers keep running
+    Transition { from: &[FullControlMode], request: HeightControlMode, enter: HeightControlMode, guards: &[], actions: &[] },
+    Transition { from: FLYING, request: HeightControlMode, enter: HeightControlMode, guards: &[], actions: RESET },
+    Transition { from: FLYING, request: RawSensorMode, enter: RawSensorMode, guards: &[], actions: RESET },
+];
+
+/// Actions when a mode is entered
+fn entry_actions(mode: WorkingModes) -> &'static [Action] {
+    match mode {
+        SafeMode => &[Action::StopMotors],
+        PanicMode => &[Action::StartPanicRamp],
+        HeightControlMode | RawSensorMode => &[Action::StartReference],
+        _ => &[],
+    }
+}
+
+/// Actions when a mode is left
+fn exit_actions(mode: WorkingModes) -> &'static [Action] {
+    match mode {
+        HeightControlMode => &[Action::ClearHeightReference],
+        _ => &[],
+    }
+}
+
+fn run_action<H: Hal>(drone: &mut Drone<H>, action: Action) {
+    match action {
+        Action::StopMotors => drone.hal().set_motors([0, 0, 0, 0]),
+        Action::StartPanicRamp => {
+            let motors = drone.hal().get_motors();
+            drone.set_panic_ramp(PanicRamp::new(motors));
+        }
+        Action::ResetControllers => drone.reset_all_controller(),
+        Action::StartReference => {
+            let now = drone.hal().now_us();
+            drone.set_reference(Reference::Capturing(now));
+        }
+        Action::ClearHeightReference => drone.set_height_calibration(0.0),
+    }
+}
+
+fn check_guard<H: Hal>(drone: &mut Drone<H>, guard: Guard, thrust: f32) -> Result<(), NackReason> {
+    let ok = match guard {
+        Guard::Calibrated => drone.get_calibration().is_calibrated(),
+        Guard::ThrottleLow => thrust < THROTTLE_LOW,
+        Guard::BatteryOk => {
+            let battery_min = drone.get_parameters().get_u16(ParamId::BatteryMin);
+            panic_check(drone.hal(), battery_min)
+        }
+    };
+    if ok { Ok(()) } else { Err(guard.reason()) }
+}
+
+/// Leave the current mode and enter a new one, with the exit and entry actions
+fn enter<H: Hal>(drone: &mut Drone<H>, new: WorkingModes, actions: &[Action]) {
+    let actions = exit_actions(drone.get_mode()).iter()
+        .chain(actions.iter())
+        .chain(entry_actions(new).iter());
+    for &action in actions {
+        run_action(drone, action);
+    }
+    drone.set_mode(new);
+}
+
+/// Change the mode without a request, e.g. when the panic ramp is done
+pub fn change_mode<H: Hal>(drone: &mut Drone<H>, new: WorkingModes) {
+    enter(drone, new, &[]);
+}
+
+/// Switch the drone to the requested working mode. The thrust of the setpoint that came with
+/// the request is checked by the throttle guard, requests without a setpoint pass 0.
+/// Returns the reason when the request was rejected, the drone then stays in its mode.
+pub fn mode_switch<H: Hal>(drone: &mut Drone<H>, request: WorkingModes, thrust: f32) -> Result<(), NackReason> {
+    let current = drone.get_mode();
+    if request == current {
+        return Ok(());
+    }
+
+    let transition = TRANSITIONS.iter()
+        .find(|transition| transition.request == request && transition.from.contains(&current))
+        .ok_or(NackReason::ModeRejected(current))?;
+    for &guard in transition.guards {
+        check_guard(drone, guard, thrust)?;
+    }
+
+    if transition.enter != current {
+        enter(drone, transition.enter, transition.actions);
+    }
+    Ok(())
+}
+
+/// Returns true while the reference of the mode is captured, so also when setpoints come in slower
+/// than the control loop runs the capture takes the same time
+fn capture_reference<H: Hal>(drone: &mut Drone<H>) -> bool {
+    match drone.get_reference() {
+        Reference::Capturing(start) => {
+            if drone.hal().now_us().saturating_sub(start) < REFERENCE_TIME {
+                return true;
             }
-            drone.set_mode(new);
+            drone.set_reference(Reference::Holding);
+            false
         }
+        Reference::Holding => false,
     }
 }
 
-//Function used to set the motion of the drone according to the arguments from commands
-pub fn motions(drone: &mut Drone, argument: [u16; 4]) {
+//Function used to set the motion of the drone according to the setpoint from commands
+pub fn motions<H: Hal>(drone: &mut Drone<H>, setpoint: Setpoint) {
     match drone.get_mode() {
-        WorkingModes::ManualMode => manual_mode::motion(drone, argument),
-        WorkingModes::YawControlMode => yaw_control_mode::motion(drone, argument),
-        WorkingModes::FullControlMode | WorkingModes::RawSensorMode => full_control_mode::motion(drone, argument),
+        WorkingModes::ManualMode => manual_mode::motion(drone, setpoint),
+        WorkingModes::YawControlMode => yaw_control_mode::motion(drone, setpoint),
+        WorkingModes::FullControlMode => full_control_mode::motion(drone, setpoint),
+        WorkingModes::RawSensorMode => {
+            // The filtered attitude at the start is the level attitude of the Kalman filters
+            if capture_reference(drone) {
+                let attitude = drone.get_current_attitude();
+                drone.set_filtered_angles(attitude);
+            } else {
+                let angles = drone.get_filtered_angles();
+                drone.set_kal_calibration(angles);
+            }
+            full_control_mode::motion(drone, setpoint)
+        }
         WorkingModes::CalibrationMode => calibration_mode::calibrate(drone),
-        WorkingModes::HeightControlMode => height_control_mode::motion(drone, argument),
+        WorkingModes::HeightControlMode => {
+            // Heights are relative to the height at which the mode started
+            if capture_reference(drone) {
+                let height = drone.get_height();
+                drone.set_height_calibration(height);
+            }
+            height_control_mode::motion(drone, setpoint)
+        }
         _ => (),
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::mock::MockHal;
+    use crate::controllers::MAX_DT;
+
+    const MODES: [WorkingModes; 8] = [SafeMode, PanicMode, ManualMode, CalibrationMode, YawControlMode, FullControlMode, HeightControlMode, RawSensorMode];
+
+    fn letter(mode: WorkingModes) -> char {
+        "SPMCYFHR".chars().nth(MODES.iter().position(|&m| m == mode).unwrap()).unwrap()
+    }
+
+    /// A calibrated drone in the given mode
+    fn drone_in(mode: WorkingModes) -> Drone<MockHal> {
+        let mut drone = Drone::initialize(MockHal::new());
+        for _ in 0..50 {
+            drone.set_calibration([0.0; 2], [0.0; 2], [0.0; 2], 0.0);
+        }
+        drone.set_mode(mode);
+        drone
+    }
+
+    #[test]
+    fn test_transition_table() {
+        // Mode after requesting each mode of MODES from the mode of the row, - when rejected
+        let expected = [
+            (SafeMode, "SPMCYFHR"),
+            (PanicMode, "PP------"),
+            (ManualMode, "PPM-YFHR"),
+            (CalibrationMode, "SPMCYFHR"),
+            (YawControlMode, "PPM-YFHR"),
+            (FullControlMode, "PPM-YFHR"),
+            (HeightControlMode, "PPM-YFHR"),
+            (RawSensorMode, "PPM-YFHR"),
+        ];
+        for (from, row) in expected {
+            for (request, expected) in MODES.iter().zip(row.chars()) {
+                let mut drone = drone_in(from);
+                let result = match mode_switch(&mut drone, *request, 0.0) {
+                    Ok(()) => letter(drone.get_mode()),
+                    Err(reason) => {
+                        assert_eq!(reason, NackReason::ModeRejected(from));
+                        assert_eq!(drone.get_mode(), from);
+                        '-'
+                    }
+                };
+                assert_eq!(result, expected, "{:?} requested in {:?}", request, from);
+            }
+        }
+    }
+
+    #[test]
+    fn test_guards() {
+        let mut drone = Drone::initialize(MockHal::new());
+        assert_eq!(mode_switch(&mut drone, FullControlMode, 0.0), Err(NackReason::NotCalibrated));
+        assert_eq!(mode_switch(&mut drone, ManualMode, 0.0), Ok(()));
+
+        // The throttle only has to be low when the motors start
+        let mut drone = drone_in(SafeMode);
+        assert_eq!(mode_switch(&mut drone, ManualMode, 0.5), Err(NackReason::ThrottleHigh));
+        assert_eq!(mode_switch(&mut drone, FullControlMode, 0.5), Err(NackReason::ThrottleHigh));
+        assert_eq!(drone.get_mode(), SafeMode);
+        let mut drone = drone_in(ManualMode);
+        assert_eq!(mode_switch(&mut drone, FullControlMode, 0.5), Ok(()));
+
+        // A low battery keeps the motors off, on USB power the battery reads close to 0
+        let mut drone = drone_in(SafeMode);
+        drone.hal().battery = 800;
+        assert_eq!(mode_switch(&mut drone, ManualMode, 0.0), Err(NackReason::BatteryLow));
+        drone.hal().battery = 0;
+        assert_eq!(mode_switch(&mut drone, ManualMode, 0.0), Ok(()));
+    }
+
+    #[test]
+    fn test_panic_ramp() {
+        let mut drone = drone_in(ManualMode);
+        drone.hal().motors = [400; 4];
+        assert_eq!(mode_switch(&mut drone, SafeMode, 0.0), Ok(()));
+        assert_eq!(drone.get_mode(), PanicMode);
+
+        for motors in [300, 200, 100] {
+            for _ in 0..100 {
+                panic_mode::panic_mode(&mut drone);
+                assert_eq!(drone.hal().motors, [motors; 4]);
+                assert_eq!(drone.get_mode(), PanicMode);
+            }
+        }
+        panic_mode::panic_mode(&mut drone);
+        assert_eq!(drone.hal().motors, [0; 4]);
+        assert_eq!(drone.get_mode(), SafeMode);
+    }
+
+    #[test]
+    fn test_controller_time_step() {
+        // A long gap counts as a few ticks
+        let mut drone = drone_in(SafeMode);
+        drone.hal().time_us = 5_000_000;
+        drone.set_sample_time(5_000_000);
+        assert_eq!(drone.get_dt(), MAX_DT);
+
+        // Starting the controllers starts the time step over
+        assert_eq!(mode_switch(&mut drone, FullControlMode, 0.0), Ok(()));
+        drone.set_sample_time(5_010_000);
+        assert!((drone.get_dt() - 0.01).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_raw_yaw() {
+        // 164 LSB is 10 deg/s, for a second of samples
+        let mut drone = drone_in(RawSensorMode);
+        drone.hal().gyro.z = 164;
+        let mut imu = raw_sensor_mode::measure_raw(&mut drone, 10_000);
+        for _ in 1..100 {
+            imu = raw_sensor_mode::measure_raw(&mut drone, 10_000);
+        }
+        assert!((imu.rates.yaw_rate - 10.0_f32.to_radians()).abs() < 1e-3, "{}", imu.rates.yaw_rate);
+        assert!((imu.angles.yaw - 10.0_f32.to_radians()).abs() < 1e-3, "{}", imu.angles.yaw);
+    }
+
+    #[test]
+    fn test_reference() {
+        let mut drone = drone_in(FullControlMode);
+        assert_eq!(mode_switch(&mut drone, HeightControlMode, 0.0), Ok(()));
+        assert_eq!(drone.get_reference(), Reference::Capturing(0));
+
+        // The capture takes its time however few setpoints come in
+        drone.set_height(120.0);
+        let setpoint = Setpoint { roll: 0.0, pitch: 0.0, yaw_rate: 0.0, thrust: 0.0 };
+        for _ in 0..2 {
+            motions(&mut drone, setpoint);
+            assert!(matches!(drone.get_reference(), Reference::Capturing(_)));
+            drone.hal().time_us += REFERENCE_TIME / 2;
+        }
+        motions(&mut drone, setpoint);
+        assert_eq!(drone.get_reference(), Reference::Holding);
+        assert_eq!(drone.get_calibration().height, 120.0);
+
+        // Leaving the mode clears the height reference
+        assert_eq!(mode_switch(&mut drone, FullControlMode, 0.0), Ok(()));
+        assert_eq!(drone.get_calibration().height, 0.0);
+    }
+}
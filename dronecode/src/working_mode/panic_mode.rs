@@ -1,35 +1,60 @@
-
-use tudelft_quadrupel::motor::{get_motors, set_motors};
-use tudelft_quadrupel::time::assembly_delay;
-use tudelft_quadrupel::battery::read_battery;
 use protocol::WorkingModes;
+use crate::drone::{Drone, Getter, Setter};
+use crate::working_mode::change_mode;
+use crate::hal::{Battery, Hal};
+
+/// Control loop ticks spent at each step of the panic ramp
+const RAMP_STEP_TICKS: u16 = 100;
+
+/// Steps of the panic ramp, the motors run at 3/4, 1/2 and 1/4 of their speed before they stop
+const RAMP_STEPS: u16 = 3;
+
+/// Ramp that lowers the motors to zero over a few seconds, one step per control loop tick
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PanicRamp {
+    start: [u16; 4],
+    ticks: u16,
+}
+
+impl PanicRamp {
+    /// Start the ramp at the current motor values
+    pub fn new(start: [u16; 4]) -> Self {
+        PanicRamp { start, ticks: 0 }
+    }
+
+    /// Motor values for the next tick, None when the ramp is done and the motors are off
+    pub fn step(&mut self) -> Option<[u16; 4]> {
+        let step = self.ticks / RAMP_STEP_TICKS;
+        if step >= RAMP_STEPS || self.start == [0; 4] {
+            return None;
+        }
+        self.ticks += 1;
+        let fraction = (RAMP_STEPS - step) as u32;
+        Some(self.start.map(|motor| (motor as u32 * fraction / (RAMP_STEPS + 1) as u32) as u16))
+    }
+}
 
-//Mode switch function for panic mode
-pub fn panic_mode() -> WorkingModes{
-    // for i in 10..1 {
-    //     set_motors([i*20,i*20,i*20,i*20]);
-    //     assembly_delay(1_000_000);
-    // }
-    let motors = get_motors();
-    set_motors([motors[0]*3/4,motors[1]*3/4,motors[2]*3/4,motors[3]*3/4]);
-    assembly_delay(5_000_000);  
-    set_motors([motors[0]/2,motors[1]/2,motors[2]/2,motors[3]/2]);
-    assembly_delay(5_000_000);  
-    set_motors([motors[0]/4,motors[1]/4,motors[2]/4,motors[3]/4]);
-    assembly_delay(5_000_000);  
-    set_motors([0,0,0,0]);
-
-    WorkingModes::SafeMode
+/// Run one tick of the panic ramp, the drone goes to safe mode when the motors are off
+pub fn panic_mode<H: Hal>(drone: &mut Drone<H>) {
+    let mut ramp = drone.get_panic_ramp();
+    match ramp.step() {
+        Some(motors) => drone.hal().set_motors(motors),
+        None => {
+            drone.hal().set_motors([0, 0, 0, 0]);
+            change_mode(drone, WorkingModes::SafeMode);
+        }
+    }
+    drone.set_panic_ramp(ramp);
 }
 
 //Different situations that can panic
-pub fn panic_check() -> bool{ 
+pub fn panic_check(battery: &mut impl Battery, battery_min: u16) -> bool{
     // Panic when battery is low
-    let volt = read_battery();
+    let volt = battery.read_battery();
 
-    if volt < 900 && volt > 50 {
+    if volt < battery_min && volt > 50 {
         return false;
     }
 
     true
-}
\ No newline at end of file
+}
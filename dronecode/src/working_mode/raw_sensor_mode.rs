@@ -1,11 +1,30 @@
+use protocol::Estimator;
 use crate::kalman::KalmanFilter;
-use tudelft_quadrupel::mpu::read_raw;
+use crate::complementary::ComplementaryFilter;
+use crate::ahrs::Mahony;
 use crate::drone::{Drone, Getter, Setter};
-use tudelft_quadrupel::time::Instant;
+use crate::yaw_pitch_roll::YawPitchRoll;
+use crate::working_mode::calibration_mode::Calibration;
+use crate::hal::Hal;
 use micromath::F32Ext;
 use core::f32::consts::PI;
 
-static LSB_SENSITIVITY: f32 = 1.0 / 16.4;
+/// Gyro sensitivity in deg/s per LSB
+pub(crate) static LSB_SENSITIVITY: f32 = 1.0 / 16.4;
+
+/// One sample of the raw IMU
+#[derive(Copy, Clone)]
+pub struct RawImu {
+    pub angles: YawPitchRoll,       // pitch and roll from the accelerometer, yaw integrated from the gyro
+    pub rates: YawPitchRollRate,    // gyro rates in rad/s
+    pub accel: [f32; 3],            // accelerometer in g, along the x, y and z axes of the sensor
+}
+
+/// Attitude estimate from the raw IMU. Every estimator of RawSensorMode runs on each tick,
+/// so they can be compared and switched in flight.
+pub trait AttitudeEstimator {
+    fn update(&mut self, imu: &RawImu, dt: f32) -> YawPitchRoll;
+}
 
 #[derive(Copy, Clone)]
 pub struct Kalman {
@@ -14,6 +33,13 @@ pub struct Kalman {
     roll: KalmanFilter,
 }
 
+#[derive(Copy, Clone)]
+pub struct Complementary {
+    yaw: ComplementaryFilter,
+    pitch: ComplementaryFilter,
+    roll: ComplementaryFilter,
+}
+
 #[derive(Copy, Clone)]
 pub struct YawPitchRollRate {
     pub yaw_rate: f32,
@@ -31,46 +57,67 @@ pub fn calculate_altitude(pressure: u32, temperature: i32) -> f32 {
     return h;
 }
 
-pub fn measure_velocity(drone: &mut Drone) -> f32 {
-    let (acc, _) = read_raw().unwrap();
-    let acc_z = acc.z as f32 / 16384.0;
+pub fn measure_velocity<H: Hal>(drone: &mut Drone<H>) -> f32 {
+    let (acc, _) = drone.hal().read_raw();
+    let acc_z = drone.get_imu_calibration().accel(acc)[2];
     drone.set_acceleration_z(acc_z);
     (drone.get_calibration().acceleration_compensation(acc_z) - 1.0) * 9.81 * 100.0
 }
 
 
-pub fn filter(drone: &mut Drone, time: u128) {
-    let dt = (time as f32) / 1_000_000.0;
-    let angles_raw = drone.get_raw_angles();
-    let rates_raw = drone.get_raw_rates();
-    let pitch= drone.get_kalman().pitch.update(angles_raw.pitch, rates_raw.pitch_rate, dt);
-    let roll = drone.get_kalman().roll.update(angles_raw.roll, rates_raw.roll_rate, dt);
-    let yaw = drone.get_kalman().yaw.update(angles_raw.yaw, rates_raw.yaw_rate, dt);
-    let angles_cali = drone.get_calibration().full_compensation_kal([yaw, pitch, roll]);
-    drone.set_current_attitude([angles_cali[0], angles_cali[1], angles_cali[2]]);
-    //drone.set_current_attitude([yaw, pitch, roll]);
+/// Run an estimator and remove the level offset captured at the start of RawSensorMode
+fn estimate(estimator: &mut impl AttitudeEstimator, calibration: &Calibration, imu: &RawImu, dt: f32) -> YawPitchRoll {
+    let angles = estimator.update(imu, dt);
+    let [yaw, pitch, roll] = calibration.full_compensation_kal([angles.yaw, angles.pitch, angles.roll]);
+    YawPitchRoll { yaw, pitch, roll }
+}
 
+/// Run all estimators on a raw IMU sample taken `time` us after the previous one, the selected
+/// one gives the current attitude. Returns the angles of every estimator, in the order of Estimator.
+pub fn filter<H: Hal>(drone: &mut Drone<H>, imu: &RawImu, time: u128) -> [YawPitchRoll; Estimator::ALL.len()] {
+    let dt = (time as f32) / 1_000_000.0;
+    let calibration = drone.get_calibration();
+    let estimates = [
+        estimate(drone.get_kalman(), &calibration, imu, dt),
+        estimate(drone.get_complementary(), &calibration, imu, dt),
+        estimate(drone.get_ahrs(), &calibration, imu, dt),
+    ];
+
+    let attitude = estimates[drone.get_estimator() as usize];
+    drone.set_current_attitude([attitude.yaw, attitude.pitch, attitude.roll]);
+    estimates
 }
 
-pub fn measure_raw(drone: &mut Drone, time: u128) {
+/// Read the raw IMU, `time` us after the previous sample
+pub fn measure_raw<H: Hal>(drone: &mut Drone<H>, time: u128) -> RawImu {
     let dt = (time as f32) / 1_000_000.0;
 
-    let (acc, gyro) = read_raw().unwrap();
+    // The gyro bias and the accelerometer offsets and scales of the IMU calibration are removed
+    let (acc, gyro) = drone.hal().read_raw();
+    let calibration = drone.get_imu_calibration();
+    let accel = calibration.accel(acc);
+    let gyro = calibration.gyro(gyro);
 
-    let pitch_acc = acc.x as f32;
-    let roll_acc = acc.y as f32;
-    let yaw_acc = acc.z as f32;
+    let [pitch_acc, roll_acc, yaw_acc] = accel;
 
-    let raw_to_dps = |raw: i16| -> f32 { raw as f32 * LSB_SENSITIVITY };
+    let raw_to_dps = |raw: f32| -> f32 { raw * LSB_SENSITIVITY };
     let dps_to_rads = |dps: f32| -> f32 { dps * (PI / 180.0) };
 
-    drone.set_raw_rates([dps_to_rads(raw_to_dps(gyro.z)),
-        dps_to_rads(raw_to_dps(gyro.x)),
-        dps_to_rads(raw_to_dps(gyro.y))]);
+    drone.set_raw_rates([dps_to_rads(raw_to_dps(gyro[2])),
+        dps_to_rads(raw_to_dps(gyro[0])),
+        dps_to_rads(raw_to_dps(gyro[1]))]);
 
-    drone.set_raw_angles([dps_to_rads(raw_to_dps(gyro.z)) * dt * 5.0,
+    // The accelerometer does not see the yaw, it is the integral of the yaw rate
+    let yaw = drone.get_raw_angles().yaw + drone.get_raw_rates().yaw_rate * dt;
+    drone.set_raw_angles([yaw,
         micromath::F32Ext::atan2(pitch_acc, micromath::F32Ext::sqrt(roll_acc * roll_acc + yaw_acc * yaw_acc)),
         micromath::F32Ext::atan2(roll_acc, yaw_acc)]);
+
+    RawImu {
+        angles: drone.get_raw_angles(),
+        rates: drone.get_raw_rates(),
+        accel,
+    }
 }
 
 impl Kalman {
@@ -81,4 +128,59 @@ impl Kalman {
             roll: KalmanFilter::default() 
         }
     }
+
+    /// Set the noise variances of the filters of all three axes
+    pub fn set_noise(&mut self, q_angle: f32, q_bias: f32, r_measure: f32) {
+        for filter in [&mut self.yaw, &mut self.pitch, &mut self.roll] {
+            filter.set_covariance_angle(q_angle);
+            filter.set_covariance_bias(q_bias);
+            filter.set_measurement_noise(r_measure);
+        }
+    }
+}
+
+impl AttitudeEstimator for Kalman {
+    fn update(&mut self, imu: &RawImu, dt: f32) -> YawPitchRoll {
+        YawPitchRoll {
+            yaw: self.yaw.update(imu.angles.yaw, imu.rates.yaw_rate, dt),
+            pitch: self.pitch.update(imu.angles.pitch, imu.rates.pitch_rate, dt),
+            roll: self.roll.update(imu.angles.roll, imu.rates.roll_rate, dt),
+        }
+    }
+}
+
+impl Complementary {
+    pub fn new() -> Self {
+        Self {
+            yaw: ComplementaryFilter::default(),
+            pitch: ComplementaryFilter::default(),
+            roll: ComplementaryFilter::default(),
+        }
+    }
+
+    /// Set the time constant of the filters of all three axes
+    pub fn set_time_constant(&mut self, tau: f32) {
+        for filter in [&mut self.yaw, &mut self.pitch, &mut self.roll] {
+            filter.set_time_constant(tau);
+        }
+    }
+}
+
+impl AttitudeEstimator for Complementary {
+    fn update(&mut self, imu: &RawImu, dt: f32) -> YawPitchRoll {
+        YawPitchRoll {
+            yaw: self.yaw.update(imu.angles.yaw, imu.rates.yaw_rate, dt),
+            pitch: self.pitch.update(imu.angles.pitch, imu.rates.pitch_rate, dt),
+            roll: self.roll.update(imu.angles.roll, imu.rates.roll_rate, dt),
+        }
+    }
+}
+
+impl AttitudeEstimator for Mahony {
+    fn update(&mut self, imu: &RawImu, dt: f32) -> YawPitchRoll {
+        // Rates about the x, y and z axes of the accelerometer. The pitch and yaw of the drone
+        // turn the other way than the rotation about y and z.
+        let gyro = [imu.rates.roll_rate, -imu.rates.pitch_rate, -imu.rates.yaw_rate];
+        YawPitchRoll::from(Mahony::update(self, imu.accel, gyro, dt))
+    }
 }
@@ -1,10 +1,30 @@
-use tudelft_quadrupel::mpu::structs::Gyro;
-use tudelft_quadrupel::mpu::{read_raw, read_dmp_bytes};
 use crate::drone::{Drone, Getter, Setter};
-use tudelft_quadrupel::block;
-use crate::drone::motors::{normalize_manual_yaw, motor_assign};
+use crate::controllers::{AxisController, Controller, ControllerKind, Gains, Limits};
+use crate::parameters::{Parameters, ParamId};
+use protocol::Setpoint;
+use crate::hal::Hal;
+use crate::drone::motors::{normalize_manual_yaw, motor_assign, RAD_TO_DEG};
 use crate::yaw_pitch_roll::{yaw_rate, YawPitchRoll};
 
+/// The yaw rate controller gives at most the -1..1 pwm range
+const YAW_LIMITS: Limits = Limits { output: 1.0, integral: 0.3, derivative_filter: 0.5 };
+
+/// Controller of the yaw rate without gains, they are set from the parameters
+pub fn yaw_controller() -> AxisController {
+    AxisController::new(ControllerKind::Positional, Gains::default(), YAW_LIMITS)
+}
+
+/// Take the implementation and the gains from the parameters
+pub fn configure_yaw_controller(controller: &mut AxisController, parameters: &Parameters) {
+    let gains = Gains {
+        kp: parameters.get_f32(ParamId::YawP),
+        ki: parameters.get_f32(ParamId::YawI),
+        kd: parameters.get_f32(ParamId::YawD),
+        kff: parameters.get_f32(ParamId::YawFF),
+    };
+    controller.configure(parameters.get_controller(ParamId::YawCtl), gains);
+}
+
 fn map_velocity_to_f32(data: f32) -> f32 {
     let min_i16 = -560.0;
     let max_i16 = 560.0;
@@ -15,14 +35,13 @@ fn map_velocity_to_f32(data: f32) -> f32 {
 }
 
 ///Do the motion according to the argument from command by changing motor speed
-pub fn motion(drone: &mut Drone, argument: [u16; 4]) {
+pub fn motion<H: Hal>(drone: &mut Drone<H>, setpoint: Setpoint) {
 
-    //Convert from u16 value to required pwm signal for different signal
-    let mut pwm = normalize_manual_yaw(drone, argument);
+    //Convert from the setpoint to required pwm signal for different signal
+    let mut pwm = normalize_manual_yaw(setpoint);
 
-    //PID control
-    yaw_control(drone, pwm[0]);
-    pwm[0] = drone.get_yaw_pwm_change();
+    //PID control, the yaw rate target is scaled like the measured yaw rate
+    pwm[0] = yaw_control(drone, map_velocity_to_f32(-setpoint.yaw_rate * RAD_TO_DEG));
 
     //Assign motor speed according to the pwm signal
     motor_assign(drone, pwm);
@@ -30,12 +49,17 @@ pub fn motion(drone: &mut Drone, argument: [u16; 4]) {
 
 //The input value drone has a parameter called yaw_controller, if you want to change the Kpid value
 //manually, go to drone.rs::initialize()
-pub fn yaw_control(drone: &mut Drone, target_yaw: f32){
+pub fn yaw_control<H: Hal>(drone: &mut Drone<H>, target_yaw: f32) -> f32 {
 
     //let calibrated_yaw = drone.get_calibration().yaw_compensation(angles.yaw);
 
+    // Time since the last update, the rate is taken over the same time
+    let dt = drone.get_dt();
     let velocity = map_velocity_to_f32(-yaw_rate(drone));
     // Calculate PID output
-    let yaw_pwm = drone.get_yaw_controller().step(target_yaw, velocity);
-    drone.set_yaw_controller((yaw_pwm.1, yaw_pwm.2), yaw_pwm.0);
+    let yaw_controller = drone.get_yaw_controller();
+    let yaw_pwm = yaw_controller.update(target_yaw, velocity, dt);
+    let telemetry = yaw_controller.telemetry();
+    drone.set_pid(telemetry);
+    yaw_pwm
 }
@@ -1,8 +1,9 @@
-use tudelft_quadrupel::mpu::structs::Quaternion;
+use crate::hal::{Hal, Quaternion};
 use crate::drone::{Drone, Getter, Setter};
+use crate::real::{Number, Real};
 
 /// This struct holds the yaw, pitch, and roll that the drone things it is in.
-/// The struct is currently implemented using `f32`, you may want to change this to use fixed point arithmetic.
+/// The angles are `f32`, with the `fixed-point` feature they are computed in fixed point.
 #[derive(Debug, Copy, Clone)]
 pub struct YawPitchRoll {
     pub yaw: f32,
@@ -13,52 +14,89 @@ pub struct YawPitchRoll {
 impl From<Quaternion> for YawPitchRoll {
     /// Creates a YawPitchRoll from a Quaternion
     fn from(q: Quaternion) -> Self {
-        let Quaternion { w, x, y, z } = q;
-        let w: f32 = w.to_num();
-        let x: f32 = x.to_num();
-        let y: f32 = y.to_num();
-        let z: f32 = z.to_num();
+        from_quaternion::<Number>(q)
+    }
+}
 
-        let gx = 2.0 * (x * z - w * y);
-        let gy = 2.0 * (w * x + y * z);
-        let gz = w * w - x * x - y * y + z * z;
+/// Convert a Quaternion with the math done in `T`
+pub(crate) fn from_quaternion<T: Real>(q: Quaternion) -> YawPitchRoll {
+    let [w, x, y, z] = [q.w, q.x, q.y, q.z].map(T::from_f32);
+    let one = T::from_f32(1.0);
+    let two = T::from_f32(2.0);
 
-        // yaw: (about Z axis)
-        let yaw =
-            micromath::F32Ext::atan2(2.0 * x * y - 2.0 * w * z, 2.0 * w * w + 2.0 * x * x - 1.0);
+    let gx = two * (x * z - w * y);
+    let gy = two * (w * x + y * z);
+    let gz = w * w - x * x - y * y + z * z;
 
-        // pitch: (nose up/down, about Y axis)
-        let pitch = micromath::F32Ext::atan2(gx, micromath::F32Ext::sqrt(gy * gy + gz * gz));
+    // yaw: (about Z axis)
+    let yaw = (two * x * y - two * w * z).atan2(two * w * w + two * x * x - one);
 
-        // roll: (tilt left/right, about X axis)
-        let roll = micromath::F32Ext::atan2(gy, gz);
+    // pitch: (nose up/down, about Y axis)
+    let pitch = gx.atan2((gy * gy + gz * gz).sqrt());
 
-        Self { yaw, pitch, roll }
-    }
+    // roll: (tilt left/right, about X axis)
+    let roll = gy.atan2(gz);
+
+    YawPitchRoll { yaw: yaw.to_f32(), pitch: pitch.to_f32(), roll: roll.to_f32() }
 }
 
-pub fn yaw_rate(drone: &mut Drone) -> f32{
+pub fn yaw_rate<H: Hal>(drone: &mut Drone<H>) -> f32{
     let time_diff = drone.get_time_diff();
     drone.set_last_time(drone.get_sample_time());
     let current_attitude = drone.get_current_attitude();
-    let rate = ((current_attitude.yaw - drone.get_last_attitude().yaw) * 180 as f32 / 3.1415926) / (time_diff as f32 / 1000 as f32);
+    let rate = ((current_attitude.yaw - drone.get_last_attitude().yaw) * 180 as f32 / 3.1415926) / (time_diff as f32 / 1_000_000 as f32);
 
     drone.set_last_attitude([current_attitude.yaw,current_attitude.pitch, current_attitude.roll]);
     return rate;
 }
 
-pub fn full_rate(drone: &mut Drone, current_attitude: YawPitchRoll) -> [f32; 3] {
+pub fn full_rate<H: Hal>(drone: &mut Drone<H>, current_attitude: YawPitchRoll) -> [f32; 3] {
     let time_diff = drone.get_time_diff();
     drone.set_last_time(drone.get_sample_time());
     let last_attitude = drone.get_last_attitude();
 
-    let yaw_rate = ((current_attitude.yaw - last_attitude.yaw) * 180 as f32 / 3.1415926) / (time_diff as f32 / 1000 as f32);
-    let pitch_rate = ((current_attitude.pitch - last_attitude.pitch) * 180 as f32 / 3.1415926) / (time_diff as f32 / 1000 as f32);
-    let roll_rate = ((current_attitude.roll - last_attitude.roll) * 180 as f32 / 3.1415926) / (time_diff as f32 / 1000 as f32);
+    let yaw_rate = ((current_attitude.yaw - last_attitude.yaw) * 180 as f32 / 3.1415926) / (time_diff as f32 / 1_000_000 as f32);
+    let pitch_rate = ((current_attitude.pitch - last_attitude.pitch) * 180 as f32 / 3.1415926) / (time_diff as f32 / 1_000_000 as f32);
+    let roll_rate = ((current_attitude.roll - last_attitude.roll) * 180 as f32 / 3.1415926) / (time_diff as f32 / 1_000_000 as f32);
 
     drone.set_last_attitude([current_attitude.yaw, current_attitude.pitch, current_attitude.roll]);
 
     return [yaw_rate, pitch_rate, roll_rate];
 }
 
+#[cfg(all(test, feature = "fixed-point"))]
+mod tests {
+    use super::*;
+    use crate::real::Q16;
 
+    /// Quaternion of the given angles, rotated in the order yaw, pitch, roll
+    fn quaternion(yaw: f32, pitch: f32, roll: f32) -> Quaternion {
+        let (sy, cy) = ((yaw / 2.0).sin(), (yaw / 2.0).cos());
+        let (sp, cp) = ((pitch / 2.0).sin(), (pitch / 2.0).cos());
+        let (sr, cr) = ((roll / 2.0).sin(), (roll / 2.0).cos());
+        Quaternion {
+            w: cr * cp * cy + sr * sp * sy,
+            x: sr * cp * cy - cr * sp * sy,
+            y: cr * sp * cy + sr * cp * sy,
+            z: cr * cp * sy - sr * sp * cy,
+        }
+    }
+
+    #[test]
+    fn test_fixed_point_matches_f32() {
+        // The fixed-point square root is exact, the one of micromath is off by up to a few
+        // percent, which only shows in the pitch
+        for yaw in [-3.0, -1.2, 0.0, 0.4, 2.5] {
+            for pitch in [-1.0, -0.3, 0.0, 0.05, 0.6] {
+                for roll in [-1.2, -0.1, 0.0, 0.2, 0.9] {
+                    let q = quaternion(yaw, pitch, roll);
+                    let float = from_quaternion::<f32>(q);
+                    let fixed = from_quaternion::<Q16>(q);
+                    assert!((fixed.yaw - float.yaw).abs() < 2e-3, "yaw {:?} {:?}", fixed, float);
+                    assert!((fixed.pitch - float.pitch).abs() < 3e-2, "pitch {:?} {:?}", fixed, float);
+                    assert!((fixed.roll - float.roll).abs() < 2e-3, "roll {:?} {:?}", fixed, float);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Machine-readable description of the wire protocol, for tools that decode telemetry
+//! without linking to the protocol crate. The types are traced with serde-reflection,
+//! so the description always follows the Serialize implementations.
+
+use protocol::{Packet, Message, WorkingModes, LogLevel, NackReason, ParamValue, Estimator, ImuOrientation, ImuCalibrationStep, ImuCalibrationState, Telemetry, PROTOCOL_VERSION};
+use serde_json::{json, Value};
+use serde_reflection::{Registry, Samples, Tracer, TracerConfig};
+
+/// How postcard puts each kind of format of the type registry on the wire
+fn postcard_layout() -> Value {
+    json!({
+        "UNIT": "no bytes",
+        "BOOL": "1 byte, 0 or 1",
+        "U8": "1 byte",
+        "I8": "1 byte, two's complement",
+        "U16": "varint (LEB128)",
+        "U32": "varint (LEB128)",
+        "U64": "varint (LEB128)",
+        "U128": "varint (LEB128)",
+        "I16": "zigzag encoded, then varint",
+        "I32": "zigzag encoded, then varint",
+        "I64": "zigzag encoded, then varint",
+        "I128": "zigzag encoded, then varint",
+        "F32": "4 bytes, IEEE 754 little endian",
+        "F64": "8 bytes, IEEE 754 little endian",
+        "STR": "varint byte length, then the UTF-8 bytes",
+        "SEQ": "varint element count, then the elements",
+        "TUPLEARRAY": "the elements, no length",
+        "OPTION": "1 byte tag, 0 for None, 1 for Some followed by the value",
+        "STRUCT": "the fields in order, no names",
+        "TUPLE": "the fields in order",
+        "NEWTYPESTRUCT": "the inner value",
+        "TUPLESTRUCT": "the fields in order",
+        "ENUM": "varint variant index, then the variant fields",
+    })
+}
+
+/// Trace all types that are sent over the link
+pub fn registry() -> Registry {
+    let mut tracer = Tracer::new(TracerConfig::default());
+    let samples = Samples::new();
+
+    // Every enum is traced on its own, so all its variants end up in the registry
+    tracer.trace_simple_type::<WorkingModes>().unwrap();
+    tracer.trace_simple_type::<LogLevel>().unwrap();
+    tracer.trace_simple_type::<NackReason>().unwrap();
+    tracer.trace_simple_type::<ParamValue>().unwrap();
+    tracer.trace_simple_type::<Estimator>().unwrap();
+    tracer.trace_simple_type::<ImuOrientation>().unwrap();
+    tracer.trace_simple_type::<ImuCalibrationStep>().unwrap();
+    tracer.trace_simple_type::<ImuCalibrationState>().unwrap();
+    tracer.trace_simple_type::<Telemetry>().unwrap();
+    tracer.trace_simple_type::<Message>().unwrap();
+    tracer.trace_type::<Packet>(&samples).unwrap();
+
+    tracer.registry().unwrap()
+}
+
+/// Protocol description as json: the protocol version, the framing of a packet,
+/// the postcard layout of every kind of format and all types with their fields in wire order
+pub fn schema() -> Value {
+    json!({
+        "protocol_version": PROTOCOL_VERSION,
+        "root": "Packet",
+        "framing": "A Packet is serialized with postcard. Its crc is the CRC-32/CKSUM of seq, source, \
+            destination, message and auth, in that order, serialized with postcard. The serialized packet is COBS encoded and ends with a 0 byte.",
+        "layout": postcard_layout(),
+        "types": registry(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_changes_bump_version() {
+        let snapshot: Value = serde_json::from_str(include_str!("../schema.json")).unwrap();
+        let current = schema();
+
+        if current != snapshot {
+            assert_ne!(current["protocol_version"], snapshot["protocol_version"],
+                "the wire format changed, bump PROTOCOL_VERSION");
+            panic!("the protocol changed, update the snapshot with `cargo run -p protocol-schema > protocol-schema/schema.json`");
+        }
+    }
+}
@@ -0,0 +1,4 @@
+/// Print the protocol schema as json
+fn main() {
+    println!("{}", serde_json::to_string_pretty(&protocol_schema::schema()).unwrap());
+}
@@ -0,0 +1,205 @@
+use core::hash::Hasher;
+use postcard::{serialize_with_flavor, ser_flavors::Flavor};
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher24;
+use crate::{Message, Packet, PacketError};
+
+/// Pre-shared key of the authenticated mode
+pub type AuthKey = [u8; 16];
+
+/// Authentication data of a packet. The counter is increased for every packet that is sent,
+/// so a recorded packet is refused when it is sent again.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Auth {
+    pub counter: u64,
+    pub mac: u32,   // SipHash-2-4 of counter, packet header and message, truncated to 32 bits
+}
+
+/// Parse a key written as 32 hex characters, e.g. from an environment variable
+pub const fn parse_key(hex: &str) -> Option<AuthKey> {
+    let bytes = hex.as_bytes();
+    if bytes.len() != 32 {
+        return None;
+    }
+
+    let mut key = [0u8; 16];
+    let mut i = 0;
+    while i < 32 {
+        let nibble = match bytes[i] {
+            b'0'..=b'9' => bytes[i] - b'0',
+            b'a'..=b'f' => bytes[i] - b'a' + 10,
+            b'A'..=b'F' => bytes[i] - b'A' + 10,
+            _ => return None,
+        };
+        key[i / 2] = (key[i / 2] << 4) | nibble;
+        i += 1;
+    }
+    Some(key)
+}
+
+/// Serializer flavor that feeds the serialized bytes into SipHash, like CrcFlavor does for the checksum
+struct MacFlavor {
+    hasher: SipHasher24,
+}
+
+impl Flavor for MacFlavor {
+    type Output = u32;
+
+    fn try_push(&mut self, data: u8) -> postcard::Result<()> {
+        self.hasher.write(&[data]);
+        Ok(())
+    }
+
+    fn try_extend(&mut self, data: &[u8]) -> postcard::Result<()> {
+        self.hasher.write(data);
+        Ok(())
+    }
+
+    fn finalize(self) -> postcard::Result<u32> {
+        Ok(self.hasher.finish() as u32)
+    }
+}
+
+/// Signs packets on the sending side and checks them on the receiving side.
+/// The counter is the last counter that was sent or accepted. The drone starts counting
+/// from 0 after a restart, so the MAC also covers the session of the drone, which is
+/// increased on every start. Packets recorded in an earlier session are refused.
+#[derive(Debug)]
+pub struct Authenticator {
+    key: AuthKey,
+    session: u32,
+    counter: u64,
+}
+
+impl Authenticator {
+    pub const fn new(key: AuthKey, counter: u64) -> Self {
+        Authenticator { key, session: 0, counter }
+    }
+
+    /// Bind the packets to a session of the drone
+    pub const fn with_session(mut self, session: u32) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Sign the next packets for another session, the drone reports its session in the HelloAck
+    pub fn set_session(&mut self, session: u32) {
+        self.session = session;
+    }
+
+    fn mac(&self, counter: u64, packet: &Packet) -> u32 {
+        let flavor = MacFlavor { hasher: SipHasher24::new_with_key(&self.key) };
+        serialize_with_flavor(&(self.session, counter, packet.seq, packet.source, packet.destination, &packet.message), flavor).unwrap_or(0)
+    }
+
+    /// Add authentication data with the next counter value to the packet
+    pub fn sign(&mut self, packet: &mut Packet) {
+        self.counter += 1;
+        let mac = self.mac(self.counter, packet);
+        packet.auth = Some(Auth { counter: self.counter, mac });
+    }
+
+    /// Check that the packet was signed with the same key and is newer than the packets accepted before
+    pub fn verify(&mut self, packet: &Packet) -> Result<(), PacketError> {
+        let auth = packet.auth.ok_or(PacketError::Unauthenticated)?;
+        if auth.mac != self.mac(auth.counter, packet) {
+            return Err(PacketError::Unauthenticated);
+        }
+        if auth.counter <= self.counter {
+            return Err(PacketError::Replayed);
+        }
+
+        self.counter = auth.counter;
+        Ok(())
+    }
+}
+
+impl Packet {
+    /// Deserialize a cobs encoded packet and check its authentication. Hello is accepted without,
+    /// so the handshake can tell the PC that the drone only takes authenticated commands.
+    pub fn decode_authenticated(buf: &mut [u8], authenticator: &mut Authenticator) -> Result<Packet, PacketError> {
+        let packet = Packet::decode(buf)?;
+        match packet.message {
+            Message::Hello(_) if packet.auth.is_none() => Ok(packet),
+            _ => authenticator.verify(&packet).map(|_| packet),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FrameDecoder, LinkInfo, MAX_PACKET_SIZE};
+
+    const KEY: AuthKey = [7; 16];
+
+    #[test]
+    fn test_parse_key() {
+        let key = parse_key("000102030405060708090a0b0c0d0eFF").unwrap();
+        assert_eq!(key[1], 1);
+        assert_eq!(key[15], 0xff);
+        assert_eq!(parse_key("0001"), None);
+        assert_eq!(parse_key("000102030405060708090a0b0c0d0eXX"), None);
+    }
+
+    #[test]
+    fn test_authenticated_packets() {
+        let mut sender = Authenticator::new(KEY, 100);
+        let mut receiver = Authenticator::new(KEY, 0);
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+
+        let mut packet = Packet::with_seq(Message::ManualMode(1, 2, 3, 4), 5);
+        sender.sign(&mut packet);
+        let bytes = packet.encode_into(&mut buf).unwrap();
+        let mut recorded = [0u8; MAX_PACKET_SIZE];
+        recorded[..bytes.len()].copy_from_slice(bytes);
+        assert_eq!(Packet::decode_authenticated(bytes, &mut receiver).unwrap().message, Message::ManualMode(1, 2, 3, 4));
+
+        // The same frame sent again is a replay
+        assert_eq!(Packet::decode_authenticated(&mut recorded, &mut receiver), Err(PacketError::Replayed));
+
+        // Unsigned packets and packets signed with another key are refused
+        let bytes = Packet::new(Message::PanicMode).encode_into(&mut buf).unwrap();
+        assert_eq!(Packet::decode_authenticated(bytes, &mut receiver), Err(PacketError::Unauthenticated));
+
+        let mut packet = Packet::new(Message::PanicMode);
+        Authenticator::new([8; 16], 1000).sign(&mut packet);
+        let bytes = packet.encode_into(&mut buf).unwrap();
+        assert_eq!(Packet::decode_authenticated(bytes, &mut receiver), Err(PacketError::Unauthenticated));
+
+        // Packets of an earlier session are refused, also with a newer counter
+        let mut restarted = Authenticator::new(KEY, 0).with_session(2);
+        let mut packet = Packet::new(Message::PanicMode);
+        sender.sign(&mut packet);
+        let bytes = packet.encode_into(&mut buf).unwrap();
+        assert_eq!(Packet::decode_authenticated(bytes, &mut restarted), Err(PacketError::Unauthenticated));
+
+        sender.set_session(2);
+        let mut packet = Packet::new(Message::PanicMode);
+        sender.sign(&mut packet);
+        let bytes = packet.encode_into(&mut buf).unwrap();
+        assert!(Packet::decode_authenticated(bytes, &mut restarted).is_ok());
+
+        // The handshake works without key
+        let bytes = Packet::new(Message::Hello(LinkInfo::new(1, 0))).encode_into(&mut buf).unwrap();
+        assert!(Packet::decode_authenticated(bytes, &mut receiver).is_ok());
+    }
+
+    #[test]
+    fn test_decoder_counts_auth_errors() {
+        let mut decoder = FrameDecoder::new();
+        decoder.set_authenticator(Authenticator::new(KEY, 0));
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+
+        decoder.receive(Packet::new(Message::SafeMode).encode_into(&mut buf).unwrap());
+        assert_eq!(decoder.next_packet(), Some(Err(PacketError::Unauthenticated)));
+
+        let mut packet = Packet::new(Message::SafeMode);
+        Authenticator::new(KEY, 0).sign(&mut packet);
+        decoder.receive(packet.encode_into(&mut buf).unwrap());
+        assert_eq!(decoder.next_packet().unwrap().unwrap().message, Message::SafeMode);
+
+        assert_eq!(decoder.stats().auth_errors, 1);
+        assert_eq!(decoder.stats().packets, 1);
+    }
+}
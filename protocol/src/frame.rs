@@ -0,0 +1,202 @@
+use core::fmt;
+use heapless::Deque;
+use serde::{Deserialize, Serialize};
+use crate::{Packet, PacketError, Authenticator, MAX_PACKET_SIZE};
+
+/// Size of the ring buffer that holds received bytes until they are decoded
+pub const RX_BUFFER_SIZE: usize = 256;
+
+/// Link quality counters of one side of the link
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct LinkStats {
+    pub packets: u32,           // correctly received packets
+    pub crc_errors: u32,        // frames with a checksum mismatch
+    pub cobs_errors: u32,       // frames that are not valid cobs
+    pub payload_errors: u32,    // frames that do not contain a valid message
+    pub oversize_frames: u32,   // frames longer than MAX_PACKET_SIZE
+    pub bytes_resynced: u32,    // bytes thrown away to find the start of the next frame
+    pub auth_errors: u32,       // frames refused by the authenticated mode
+}
+
+impl fmt::Display for LinkStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ok, {} crc, {} cobs, {} payload, {} too long, {} auth, {} bytes resynced",
+            self.packets, self.crc_errors, self.cobs_errors, self.payload_errors, self.oversize_frames, self.auth_errors, self.bytes_resynced)
+    }
+}
+
+/// Streaming decoder for cobs framed packets. Received bytes are stored in a bounded ring buffer,
+/// complete frames are taken out one at a time and decoded. Bad frames are counted in the link statistics.
+/// With an authenticator set, only authenticated packets are returned.
+pub struct FrameDecoder {
+    ring: Deque<u8, RX_BUFFER_SIZE>,
+    frame: [u8; MAX_PACKET_SIZE],
+    len: usize,
+    oversize: bool,
+    stats: LinkStats,
+    authenticator: Option<Authenticator>,
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        FrameDecoder {
+            ring: Deque::new(),
+            frame: [0; MAX_PACKET_SIZE],
+            len: 0,
+            oversize: false,
+            stats: LinkStats { packets: 0, crc_errors: 0, cobs_errors: 0, payload_errors: 0, oversize_frames: 0, bytes_resynced: 0, auth_errors: 0 },
+            authenticator: None,
+        }
+    }
+
+    /// Only accept packets that are authenticated with the key of this authenticator
+    pub fn set_authenticator(&mut self, authenticator: Authenticator) {
+        self.authenticator = Some(authenticator);
+    }
+
+    /// Number of bytes that can still be stored in the ring buffer
+    pub fn space(&self) -> usize {
+        self.ring.capacity() - self.ring.len()
+    }
+
+    /// Store received bytes in the ring buffer, returns how many bytes fitted
+    pub fn receive(&mut self, data: &[u8]) -> usize {
+        let mut stored = 0;
+        for &byte in data {
+            if self.ring.push_back(byte).is_err() {
+                break;
+            }
+            stored += 1;
+        }
+        stored
+    }
+
+    /// Decode the next complete frame from the ring buffer.
+    /// Returns None when no complete frame has been received yet.
+    pub fn next_packet(&mut self) -> Option<Result<Packet, PacketError>> {
+        while let Some(byte) = self.ring.pop_front() {
+            if byte != 0 {
+                if self.len < MAX_PACKET_SIZE - 1 {
+                    self.frame[self.len] = byte;
+                    self.len += 1;
+                } else {
+                    // Frame does not fit, drop bytes until the end byte
+                    self.oversize = true;
+                    self.stats.bytes_resynced += 1;
+                }
+                continue;
+            }
+
+            // End byte found, the frame including the end byte is decoded
+            let len = self.len + 1;
+            self.len = 0;
+
+            if self.oversize {
+                self.oversize = false;
+                self.stats.oversize_frames += 1;
+                self.stats.bytes_resynced += len as u32;
+                return Some(Err(PacketError::FrameTooLong));
+            }
+
+            // Empty frames carry no data, e.g. an end byte sent to flush the line
+            if len == 1 {
+                continue;
+            }
+
+            self.frame[len - 1] = 0;
+            let result = match &mut self.authenticator {
+                Some(authenticator) => Packet::decode_authenticated(&mut self.frame[..len], authenticator),
+                None => Packet::decode(&mut self.frame[..len]),
+            };
+            match result {
+                Ok(_) => self.stats.packets += 1,
+                Err(PacketError::ChecksumMismatch) => self.stats.crc_errors += 1,
+                Err(PacketError::InvalidPacket) => self.stats.cobs_errors += 1,
+                Err(PacketError::Unauthenticated | PacketError::Replayed) => self.stats.auth_errors += 1,
+                Err(_) => self.stats.payload_errors += 1,
+            }
+            if result.is_err() {
+                self.stats.bytes_resynced += len as u32;
+            }
+            return Some(result);
+        }
+        None
+    }
+
+    /// Link quality counters since the decoder was created
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    fn encode(message: Message, buf: &mut [u8]) -> &mut [u8] {
+        Packet::new(message).encode_into(buf).unwrap()
+    }
+
+    #[test]
+    fn test_decode_split_frames() {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let bytes = encode(Message::ManualMode(1, 2, 3, 4), &mut buf);
+        let mut decoder = FrameDecoder::new();
+
+        // Bytes arrive in pieces, the packet is only returned once the end byte is received
+        let (first, second) = bytes.split_at(5);
+        assert_eq!(decoder.receive(first), first.len());
+        assert!(decoder.next_packet().is_none());
+        decoder.receive(second);
+        assert_eq!(decoder.next_packet().unwrap().unwrap().message, Message::ManualMode(1, 2, 3, 4));
+        assert!(decoder.next_packet().is_none());
+        assert_eq!(decoder.stats().packets, 1);
+    }
+
+    #[test]
+    fn test_resync_after_errors() {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let mut decoder = FrameDecoder::new();
+
+        // Checksum mismatch
+        let bytes = encode(Message::ParamGet(7), &mut buf);
+        let bad_len = bytes.len();
+        bytes[3] ^= 0x01;
+        decoder.receive(bytes);
+        assert_eq!(decoder.next_packet(), Some(Err(PacketError::ChecksumMismatch)));
+
+        // Frame without end byte that overflows the frame buffer
+        for _ in 0..(MAX_PACKET_SIZE / 2) {
+            decoder.receive(&[0x55, 0x55]);
+            assert!(decoder.next_packet().is_none());
+        }
+        decoder.receive(&[0]);
+        assert_eq!(decoder.next_packet(), Some(Err(PacketError::FrameTooLong)));
+
+        // The next good frame is decoded again
+        let bytes = encode(Message::SafeMode, &mut buf);
+        decoder.receive(bytes);
+        assert_eq!(decoder.next_packet().unwrap().unwrap().message, Message::SafeMode);
+
+        let stats = decoder.stats();
+        assert_eq!(stats.packets, 1);
+        assert_eq!(stats.crc_errors, 1);
+        assert_eq!(stats.oversize_frames, 1);
+        assert_eq!(stats.bytes_resynced as usize, bad_len + MAX_PACKET_SIZE + 1);
+    }
+
+    #[test]
+    fn test_ring_buffer_is_bounded() {
+        let mut decoder = FrameDecoder::new();
+        let data = [0x11u8; RX_BUFFER_SIZE + 10];
+        assert_eq!(decoder.receive(&data), RX_BUFFER_SIZE);
+        assert_eq!(decoder.space(), 0);
+    }
+}
@@ -1,16 +1,85 @@
 #![cfg_attr(not(test), no_std)]
 #[cfg(test)]
 extern crate std;
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
-use core::ops::Deref;
-use alloc::{vec::Vec, fmt};
-use crc;
-use postcard::{to_allocvec, to_allocvec_cobs, take_from_bytes_cobs};
+use core::fmt;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use postcard::{serialize_with_flavor, to_slice_cobs, take_from_bytes_cobs, ser_flavors::Flavor};
 use serde::{Deserialize, Serialize};
+use heapless::String;
+
+pub mod frame;
+pub use frame::{FrameDecoder, LinkStats};
+pub mod telemetry;
+pub use telemetry::{Telemetry, TelemetryConfig, TelemetryGroup, CompactDatalog};
+pub mod auth;
+pub use auth::{Auth, AuthKey, Authenticator};
+pub mod queue;
+pub use queue::{PacketManager, Priority, QueueFull, QueueStats};
+pub mod setpoint;
+pub use setpoint::{Setpoint, MAX_ANGLE, MAX_YAW_RATE};
 
 const CRC_CHECKSUM: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
 
+/// Version of the wire protocol. Bump this whenever `Packet`, `Message` or one of the
+/// payload types changes, so that the link handshake catches runner/firmware mismatches.
+pub const PROTOCOL_VERSION: u16 = 19;
+
+/// Capability bits advertised in the link handshake
+pub mod capabilities {
+    pub const MANUAL_MODE: u32 = 1 << 0;
+    pub const CALIBRATION_MODE: u32 = 1 << 1;
+    pub const YAW_CONTROL_MODE: u32 = 1 << 2;
+    pub const FULL_CONTROL_MODE: u32 = 1 << 3;
+    pub const HEIGHT_CONTROL_MODE: u32 = 1 << 4;
+    pub const RAW_SENSOR_MODE: u32 = 1 << 5;
+    pub const DATALOGGING: u32 = 1 << 6;
+    pub const RELIABLE_COMMANDS: u32 = 1 << 7;
+    pub const PARAMETERS: u32 = 1 << 8;
+    pub const LINK_STATS: u32 = 1 << 9;
+    pub const LOG_MESSAGES: u32 = 1 << 10;
+    pub const CRASH_REPORTS: u32 = 1 << 11;
+    pub const TIME_SYNC: u32 = 1 << 12;
+    pub const TELEMETRY_GROUPS: u32 = 1 << 13;
+    pub const COMPACT_DATALOG: u32 = 1 << 14;
+    pub const LOG_DOWNLOAD: u32 = 1 << 15;
+    pub const AUTHENTICATION: u32 = 1 << 16;   // commands must be authenticated
+    pub const ADDRESSING: u32 = 1 << 17;
+    pub const SETPOINTS: u32 = 1 << 18;
+    pub const MODE_GUARDS: u32 = 1 << 19;     // mode changes are checked against guards
+    pub const ESTIMATORS: u32 = 1 << 20;      // attitude estimator selectable with SetEstimator
+    pub const AHRS: u32 = 1 << 21;            // quaternion AHRS from the raw IMU as an estimator
+    pub const IMU_CALIBRATION: u32 = 1 << 22; // raw IMU calibration with ImuCalibrate, stored in flash
+}
+
+/// Maximum length of a parameter name
+pub const PARAM_NAME_LEN: usize = 16;
+
+/// Maximum length of a log message text, longer texts are truncated
+pub const LOG_TEXT_LEN: usize = 64;
+
+/// Maximum length of the source file in a crash report, longer paths keep their end
+pub const CRASH_FILE_LEN: usize = 32;
+
+/// Size of one record in the drone flash log, each record holds one serialized message.
+/// Datalogs are stored as CompactDatalogging to fit.
+pub const LOG_RECORD_SIZE: usize = 128;
+
+/// Maximum number of flash log bytes in one LogChunk
+pub const LOG_CHUNK_LEN: usize = 128;
+
+/// Commands with a sequence number the PC waits for the reply of at once. The drone remembers
+/// the replies to more commands than this, so it can answer a retransmission without executing it.
+pub const MAX_COMMANDS_IN_FLIGHT: usize = 8;
+
+/// System id of the PC in the packet header
+pub const PC_ID: u8 = 0;
+
+/// Destination id for packets meant for every vehicle, only accepted for Hello and safety commands
+pub const BROADCAST_ID: u8 = 255;
 
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
 pub enum WorkingModes {
@@ -40,11 +109,113 @@ impl fmt::Display for WorkingModes {
     }
 }
 
+/// Attitude estimators of RawSensorMode, both run on every tick and the selected one drives the controllers
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Estimator {
+    Kalman,
+    Complementary,
+    Mahony,         // quaternion AHRS
+}
+
+impl Estimator {
+    pub const ALL: [Estimator; 3] = [Estimator::Kalman, Estimator::Complementary, Estimator::Mahony];
+}
+
+impl fmt::Display for Estimator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Estimator::Kalman => write!(f, "Kalman"),
+            Estimator::Complementary => write!(f, "Complementary"),
+            Estimator::Mahony => write!(f, "Mahony"),
+        }
+    }
+}
+
+/// Orientation of the drone in a step of the accelerometer calibration, named after the sensor
+/// axis that points up
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ImuOrientation {
+    ZUp,    // level
+    ZDown,  // upside down
+    XUp,
+    XDown,
+    YUp,
+    YDown,
+}
+
+impl ImuOrientation {
+    pub const ALL: [ImuOrientation; 6] = [ImuOrientation::ZUp, ImuOrientation::ZDown, ImuOrientation::XUp,
+        ImuOrientation::XDown, ImuOrientation::YUp, ImuOrientation::YDown];
+
+    /// Index of the axis that points up, 0 to 2 for x to z, and the gravity it measures in g
+    pub fn axis(self) -> (usize, f32) {
+        match self {
+            ImuOrientation::XUp => (0, 1.0),
+            ImuOrientation::XDown => (0, -1.0),
+            ImuOrientation::YUp => (1, 1.0),
+            ImuOrientation::YDown => (1, -1.0),
+            ImuOrientation::ZUp => (2, 1.0),
+            ImuOrientation::ZDown => (2, -1.0),
+        }
+    }
+}
+
+impl fmt::Display for ImuOrientation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImuOrientation::ZUp => write!(f, "level"),
+            ImuOrientation::ZDown => write!(f, "upside down"),
+            ImuOrientation::XUp => write!(f, "x axis up"),
+            ImuOrientation::XDown => write!(f, "x axis down"),
+            ImuOrientation::YUp => write!(f, "y axis up"),
+            ImuOrientation::YDown => write!(f, "y axis down"),
+        }
+    }
+}
+
+/// Step of the raw IMU calibration, run in SafeMode while the drone is held still
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ImuCalibrationStep {
+    Gyro,                   // measure the gyro bias
+    Accel(ImuOrientation),  // measure the accelerometer, offsets and scales are solved once all six orientations are measured
+    Reset,                  // forget the stored calibration
+}
+
+impl fmt::Display for ImuCalibrationStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImuCalibrationStep::Gyro => write!(f, "gyro bias"),
+            ImuCalibrationStep::Accel(orientation) => write!(f, "accelerometer {}", orientation),
+            ImuCalibrationStep::Reset => write!(f, "reset"),
+        }
+    }
+}
+
+/// Progress of an IMU calibration step, reported by the drone
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ImuCalibrationState {
+    Sampling(u8),           // percentage of the samples collected
+    Measured,               // orientation measured, the other orientations are still missing
+    Done,                   // new calibration is used and stored in flash
+    Failed(NackReason),     // the samples were rejected, the old calibration is kept
+}
+
+impl fmt::Display for ImuCalibrationState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImuCalibrationState::Sampling(percent) => write!(f, "sampling {}%", percent),
+            ImuCalibrationState::Measured => write!(f, "measured"),
+            ImuCalibrationState::Done => write!(f, "done"),
+            ImuCalibrationState::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
 /// Message enum with all possible messages
 /// Data order: pitch, roll, yaw, lift
 /// Datalogging order: Motor 1, Motor 2, Motor 3, Motor 4, Delay,
 /// ypr.yaw, ypr.pitch, ypr.roll, acc.x, acc.y, acc.z, bat, bar
-#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub enum Message {
     HeartBeat,
     SafeMode,
@@ -56,6 +227,32 @@ pub enum Message {
     HeightControlMode(u16, u16, u16, u16, u16, u16, u16, u16),  // last three values are yaw control P, roll pitch control P1 and P2, height control P
     Datalogging(Datalog),
     RawSensorMode(u16, u16, u16, u16, u16, u16, u16), // test raw mode for full control and save the loggings into the flash
+    Hello(LinkInfo),    // sent by the PC when the link is set up
+    HelloAck(LinkInfo), // reply of the drone to Hello
+    Ack(u16),               // command with this sequence number was accepted
+    Nack(u16, NackReason),  // command with this sequence number was rejected
+    ParamList,                  // ask the drone to send all parameters
+    ParamGet(u8),               // ask the drone to send the parameter with this index
+    ParamSet(u8, ParamValue),   // change the parameter with this index
+    Param(ParamInfo),           // description and current value of a drone parameter
+    LinkStatus(LinkStats),      // link quality as seen by the drone
+    Log { level: LogLevel, text: String<LOG_TEXT_LEN> }, // text message from the drone
+    CrashReport(CrashReport),   // sent by the drone panic handler
+    TimeSyncRequest { host_time: u64 },                 // host time in us since the unix epoch
+    TimeSyncReply { host_time: u64, drone_time: u64 },  // host time of the request, drone time in us since boot
+    TelemetryConfig(TelemetryConfig),   // select the telemetry groups and rate, replaces the full Datalogging
+    Telemetry(u64, Telemetry),          // one telemetry group, first value is the drone time in ms since boot
+    CompactDatalogging(CompactDatalog), // full datalog in fixed point encoding
+    LogInfoRequest,                     // ask the drone for the size of its flash log
+    LogInfo { records: u32, record_size: u16, used_bytes: u32 },    // size of the flash log
+    LogReadChunk(u32, u16),             // ask for flash log bytes, values are offset and length
+    LogChunk { offset: u32, data: heapless::Vec<u8, LOG_CHUNK_LEN>, crc: u32 },  // flash log bytes with their chunk_crc
+    LogErase(u32),                      // erase the flash log, only done when the value equals the number of records
+    SetVehicleId(u8),                   // change the system id of the drone, it is stored in flash
+    Setpoint(WorkingModes, Setpoint),   // setpoint in physical units for a flight mode, replaces the stick values of the mode messages
+    SetEstimator(Estimator),            // select the attitude estimator of RawSensorMode, only in SafeMode
+    ImuCalibrate(ImuCalibrationStep),   // start a step of the raw IMU calibration, only in SafeMode
+    ImuCalibrationStatus(ImuCalibrationStep, ImuCalibrationState), // progress of the running calibration step
 }
 
 // Convert Message enum to string
@@ -72,6 +269,178 @@ impl fmt::Display for Message {
             Message::HeightControlMode(_,_,_,_,_,_,_,_) =>write!(f, "HeightControlMode()"),
             Message::RawSensorMode(_,_,_,_,_,_,_) => write!(f, "RawSensorMode()"),
             Message::Datalogging(_) => write!(f, "Datalogging()"),
+            Message::Hello(info) => write!(f, "Hello({})", info),
+            Message::HelloAck(info) => write!(f, "HelloAck({})", info),
+            Message::Ack(seq) => write!(f, "Ack({})", seq),
+            Message::Nack(seq, reason) => write!(f, "Nack({}, {})", seq, reason),
+            Message::ParamList => write!(f, "ParamList"),
+            Message::ParamGet(index) => write!(f, "ParamGet({})", index),
+            Message::ParamSet(index, value) => write!(f, "ParamSet({}, {})", index, value),
+            Message::Param(info) => write!(f, "Param({} = {})", info.name, info.value),
+            Message::LinkStatus(stats) => write!(f, "LinkStatus({})", stats),
+            Message::Log { level, text } => write!(f, "Log({}: {})", level, text),
+            Message::CrashReport(report) => write!(f, "CrashReport({})", report),
+            Message::TimeSyncRequest { host_time } => write!(f, "TimeSyncRequest({})", host_time),
+            Message::TimeSyncReply { host_time, drone_time } => write!(f, "TimeSyncReply({}, {})", host_time, drone_time),
+            Message::TelemetryConfig(config) => write!(f, "TelemetryConfig({:#07b}, {})", config.groups, config.divider),
+            Message::Telemetry(rtc, _) => write!(f, "Telemetry({})", rtc),
+            Message::CompactDatalogging(_) => write!(f, "CompactDatalogging()"),
+            Message::LogInfoRequest => write!(f, "LogInfoRequest"),
+            Message::LogInfo { records, record_size, used_bytes } => write!(f, "LogInfo({} records of {} bytes, {} bytes used)", records, record_size, used_bytes),
+            Message::LogReadChunk(offset, len) => write!(f, "LogReadChunk({}, {})", offset, len),
+            Message::LogChunk { offset, data, .. } => write!(f, "LogChunk({}, {} bytes)", offset, data.len()),
+            Message::LogErase(records) => write!(f, "LogErase({})", records),
+            Message::SetVehicleId(id) => write!(f, "SetVehicleId({})", id),
+            Message::Setpoint(mode, setpoint) => write!(f, "Setpoint({}, {})", mode, setpoint),
+            Message::SetEstimator(estimator) => write!(f, "SetEstimator({})", estimator),
+            Message::ImuCalibrate(step) => write!(f, "ImuCalibrate({})", step),
+            Message::ImuCalibrationStatus(step, state) => write!(f, "ImuCalibrationStatus({}, {})", step, state),
+        }
+    }
+}
+
+impl Message {
+    /// The working mode a command message asks for, None for messages that are not mode commands
+    pub fn mode(&self) -> Option<WorkingModes> {
+        match self {
+            Message::SafeMode => Some(WorkingModes::SafeMode),
+            Message::PanicMode => Some(WorkingModes::PanicMode),
+            Message::ManualMode(..) => Some(WorkingModes::ManualMode),
+            Message::CalibrationMode => Some(WorkingModes::CalibrationMode),
+            Message::YawControlMode(..) => Some(WorkingModes::YawControlMode),
+            Message::FullControlMode(..) => Some(WorkingModes::FullControlMode),
+            Message::HeightControlMode(..) => Some(WorkingModes::HeightControlMode),
+            Message::RawSensorMode(..) => Some(WorkingModes::RawSensorMode),
+            Message::Setpoint(mode, _) => Some(*mode),
+            _ => None,
+        }
+    }
+
+    /// CRC32 of the serialized message, to recognize a command again without keeping a copy
+    pub fn checksum(&self) -> u32 {
+        let flavor = CrcFlavor { digest: CRC_CHECKSUM.digest() };
+        serialize_with_flavor(self, flavor).unwrap()
+    }
+}
+
+/// Severity of a log message
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+// Convert LogLevel enum to string
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogLevel::Error => write!(f, "ERROR"),
+            LogLevel::Warn => write!(f, "WARN"),
+            LogLevel::Info => write!(f, "INFO"),
+            LogLevel::Debug => write!(f, "DEBUG"),
+        }
+    }
+}
+
+/// Format text into a bounded string, the part that does not fit is dropped
+fn truncated<const N: usize>(args: fmt::Arguments) -> String<N> {
+    struct Truncate<const N: usize>(String<N>);
+
+    impl<const N: usize> fmt::Write for Truncate<N> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for c in s.chars() {
+                // Stop at the first character that does not fit, the rest of the text is dropped
+                if self.0.push(c).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let mut text = Truncate(String::new());
+    let _ = fmt::write(&mut text, args);
+    text.0
+}
+
+/// Text of a log message, cut off at LOG_TEXT_LEN bytes
+pub fn log_text(args: fmt::Arguments) -> String<LOG_TEXT_LEN> {
+    truncated(args)
+}
+
+/// What the drone was doing when it panicked
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct CrashReport {
+    pub file: String<CRASH_FILE_LEN>,
+    pub line: u32,
+    pub message: String<LOG_TEXT_LEN>,
+    pub mode: WorkingModes,
+    pub uptime: u64,        // ms since the drone started
+    pub motors: [u16; 4],   // last motor outputs
+}
+
+impl CrashReport {
+    pub fn new(file: &str, line: u32, message: fmt::Arguments, mode: WorkingModes, uptime: u64, motors: [u16; 4]) -> Self {
+        // The end of the path tells most about where the panic happened
+        let mut start = file.len().saturating_sub(CRASH_FILE_LEN);
+        while !file.is_char_boundary(start) {
+            start += 1;
+        }
+
+        CrashReport {
+            file: String::from(&file[start..]),
+            line,
+            message: truncated(message),
+            mode,
+            uptime,
+            motors,
+        }
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {} (mode {}, uptime {} ms, motors {:?})",
+            self.file, self.line, self.message, self.mode, self.uptime, self.motors)
+    }
+}
+
+/// Reasons for the drone to reject a command
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum NackReason {
+    ModeRejected(WorkingModes), // mode switch refused, the drone stays in the given mode
+    Unsupported,                // message is not a command the drone handles
+    UnknownParameter,           // parameter index does not exist
+    ParameterType,              // value type does not match the parameter type
+    ParameterRange,             // value is outside of the parameter limits
+    EraseNotConfirmed,          // record count in LogErase does not match the flash log
+    FlashError,                 // reading or writing the flash failed
+    ConfigFull,                 // no room left to store the configuration, erase the flash log first
+    NotCalibrated,              // the control modes need a calibration first
+    ThrottleHigh,               // the motors only start with the throttle low
+    BatteryLow,                 // battery voltage is below the panic level
+    Moving,                     // the drone moved while the IMU was calibrated
+    WrongOrientation,           // the drone was not held in the orientation of the calibration step
+}
+
+impl fmt::Display for NackReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NackReason::ModeRejected(mode) => write!(f, "mode switch rejected, drone stays in {}", mode),
+            NackReason::Unsupported => write!(f, "unsupported command"),
+            NackReason::UnknownParameter => write!(f, "unknown parameter"),
+            NackReason::ParameterType => write!(f, "wrong parameter type"),
+            NackReason::ParameterRange => write!(f, "parameter out of range"),
+            NackReason::EraseNotConfirmed => write!(f, "erase not confirmed, log size changed"),
+            NackReason::FlashError => write!(f, "flash error"),
+            NackReason::ConfigFull => write!(f, "configuration flash full, erase the flash log"),
+            NackReason::NotCalibrated => write!(f, "drone not calibrated"),
+            NackReason::ThrottleHigh => write!(f, "throttle not low"),
+            NackReason::BatteryLow => write!(f, "battery low"),
+            NackReason::Moving => write!(f, "drone moved during the calibration"),
+            NackReason::WrongOrientation => write!(f, "drone not in the calibration orientation"),
         }
     }
 }
@@ -87,18 +456,26 @@ pub struct Datalog {
     pub yaw: f32,       //DMP yaw
     pub pitch: f32,     //DMP pitch
     pub roll: f32,      //DMP roll
-    pub yaw_f: f32,     //Filterd yaw
-    pub pitch_f: f32,   //Filterd pitch
-    pub roll_f: f32,    //Filterd roll
+    pub yaw_f: f32,     //Kalman filter yaw
+    pub pitch_f: f32,   //Kalman filter pitch
+    pub roll_f: f32,    //Kalman filter roll
+    pub yaw_c: f32,     //Complementary filter yaw
+    pub pitch_c: f32,   //Complementary filter pitch
+    pub roll_c: f32,    //Complementary filter roll
+    pub yaw_m: f32,     //Mahony AHRS yaw
+    pub pitch_m: f32,   //Mahony AHRS pitch
+    pub roll_m: f32,    //Mahony AHRS roll
     pub yaw_r: f32,     //Raw yaw
     pub pitch_r: f32,   //Raw pitch
     pub roll_r: f32,    //Raw roll
     pub bat: u16,       
     pub bar: f32,       
     pub workingmode: WorkingModes,
+    pub estimator: Estimator,   //estimator that drives the controllers in RawSensorMode
     pub arguments: [u16; 4],
     pub control_loop_time: u128,
-    pub test:[f32; 4]
+    pub pid: [f32; 4],  //P, I and D terms and output of the main controller of the working mode
+    pub test:[f32; 4]   //free for debugging values
 }
 
 impl Datalog {
@@ -115,23 +492,184 @@ impl Datalog {
             yaw_f: 0.0, 
             pitch_f: 0.0, 
             roll_f: 0.0, 
+            yaw_c: 0.0,
+            pitch_c: 0.0,
+            roll_c: 0.0,
+            yaw_m: 0.0,
+            pitch_m: 0.0,
+            roll_m: 0.0,
             yaw_r: 0.0, 
             pitch_r: 0.0, 
             roll_r: 0.0, 
             bat: 0, 
             bar: 0.0, 
             workingmode: WorkingModes::SafeMode, 
+            estimator: Estimator::Kalman,
             arguments: [0, 0, 0, 0], 
             control_loop_time: 0,
+            pid: [0.0, 0.0, 0.0, 0.0],
             test:[0.0, 0.0, 0.0, 0.0]
         }
     }
 }
 
+impl Default for Datalog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Value of a runtime parameter, the variant is the parameter type
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ParamValue {
+    F32(f32),
+    U16(u16),
+}
+
+impl fmt::Display for ParamValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamValue::F32(value) => write!(f, "{}", value),
+            ParamValue::U16(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl ParamValue {
+    /// Check that the value has the same type as `other`
+    pub fn same_type(&self, other: &ParamValue) -> bool {
+        core::mem::discriminant(self) == core::mem::discriminant(other)
+    }
+
+    /// Check that the value lies within [min, max], values of a different type never do
+    pub fn within(&self, min: &ParamValue, max: &ParamValue) -> bool {
+        match (self, min, max) {
+            (ParamValue::F32(v), ParamValue::F32(min), ParamValue::F32(max)) => v >= min && v <= max,
+            (ParamValue::U16(v), ParamValue::U16(min), ParamValue::U16(max)) => v >= min && v <= max,
+            _ => false,
+        }
+    }
+}
+
+/// Description and current value of a drone parameter
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ParamInfo {
+    pub index: u8,
+    pub count: u8,          // total number of parameters on the drone
+    pub name: String<PARAM_NAME_LEN>,
+    pub value: ParamValue,
+    pub min: ParamValue,
+    pub max: ParamValue,
+    pub default: ParamValue,
+}
+
+/// Information exchanged in the Hello/HelloAck handshake at link start
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct LinkInfo {
+    pub protocol_version: u16,
+    pub build_id: u32,
+    pub capabilities: u32,
+    pub session: u32,   // boot count of the drone, authenticated packets are only valid in this session
+}
+
+/// Reasons why two sides of the link can not talk to each other
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkError {
+    VersionMismatch { local: u16, remote: u16 },
+    MissingCapabilities(u32),
+}
+
+impl fmt::Display for LinkInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "protocol v{}, build {:08x}, capabilities {:#x}", self.protocol_version, self.build_id, self.capabilities)
+    }
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::VersionMismatch { local, remote } =>
+                write!(f, "protocol version mismatch: runner speaks v{}, drone speaks v{}", local, remote),
+            LinkError::MissingCapabilities(missing) =>
+                write!(f, "drone firmware lacks required capabilities {:#x}", missing),
+        }
+    }
+}
+
+impl LinkInfo {
+    /// Create the handshake information of this side of the link
+    pub fn new(build_id: u32, capabilities: u32) -> Self {
+        LinkInfo { protocol_version: PROTOCOL_VERSION, build_id, capabilities, session: 0 }
+    }
+
+    /// Set the session the drone is in since it started
+    pub const fn with_session(mut self, session: u32) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Check whether the remote side speaks the same protocol version and offers all required capabilities
+    pub fn check_compatible(&self, remote: &LinkInfo, required: u32) -> Result<(), LinkError> {
+        if remote.protocol_version != self.protocol_version {
+            return Err(LinkError::VersionMismatch { local: self.protocol_version, remote: remote.protocol_version });
+        }
+
+        let missing = required & !remote.capabilities;
+        if missing != 0 {
+            return Err(LinkError::MissingCapabilities(missing));
+        }
+        Ok(())
+    }
+}
+
+/// Derive a build id from a build tag, e.g. a crate version or commit hash
+pub const fn build_id(tag: &str) -> u32 {
+    CRC_CHECKSUM.checksum(tag.as_bytes())
+}
+
+/// Checksum of the data in a LogChunk, checked by the PC before the chunk is stored
+pub fn chunk_crc(data: &[u8]) -> u32 {
+    CRC_CHECKSUM.checksum(data)
+}
+
+/// Size of a buffer that fits any encoded packet, including the cobs overhead and end byte
+pub const MAX_PACKET_SIZE: usize = 256;
+
+/// Serializer flavor that feeds the serialized bytes straight into a CRC digest,
+/// so the checksum can be computed without an intermediate buffer
+struct CrcFlavor<'a> {
+    digest: crc::Digest<'a, u32>,
+}
+
+impl<'a> Flavor for CrcFlavor<'a> {
+    type Output = u32;
+
+    fn try_push(&mut self, data: u8) -> postcard::Result<()> {
+        self.digest.update(&[data]);
+        Ok(())
+    }
+
+    fn try_extend(&mut self, data: &[u8]) -> postcard::Result<()> {
+        self.digest.update(data);
+        Ok(())
+    }
+
+    fn finalize(self) -> postcard::Result<u32> {
+        Ok(self.digest.finalize())
+    }
+}
+
 /// A Packet is the message format that contains a command, an argument and a checksum.
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Copy)]
+/// Packets with a non-zero sequence number must be acknowledged with Ack/Nack by the receiver.
+/// In the authenticated mode packets also carry a MAC and counter, see `Authenticator`.
+/// Source and destination are the system ids of the sender and receiver, see `accepted_by`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Packet {
+    pub seq: u16,
+    pub source: u8,
+    pub destination: u8,
     pub message: Message,
+    pub auth: Option<Auth>,
     pub crc: u32,
 }
 
@@ -143,103 +681,97 @@ pub enum PacketError {
     InvalidPayload,
     ChecksumMismatch,
     NoAvailablePacket,
+    BufferTooSmall,
+    FrameTooLong,
+    Unauthenticated,    // MAC missing or wrong
+    Replayed,           // counter not newer than the last accepted packet
 }
 
-///The PacketManager struct is responsible for managing a collection of packets.
-#[derive(Debug, PartialEq)]
-pub struct PacketManager {
-    pub packets: Vec<Packet>,
-}
-
-impl PacketManager {
-    ///creates a new empty PacketManager with an empty vector of packets.
-    pub fn new() -> Self { 
-        PacketManager { packets: Vec::<Packet>::new() }
+impl Packet {
+    /// Create new Packet instance with the message. CRC is created when packet is serialized.
+    pub fn new(message: Message) -> Self {
+        Packet { seq: 0, source: PC_ID, destination: BROADCAST_ID, message, auth: None, crc: 0 }
     }
-    ///Adds a new packet to the vector managed by the PacketManager. If the vector is already full, it will panic with the error message "Too many packets".
-    pub fn add_packet(&mut self, packet: Packet) {
-        self.packets.push(packet);
+
+    /// Create new Packet instance that has to be acknowledged by the receiver
+    pub fn with_seq(message: Message, seq: u16) -> Self {
+        Packet { seq, source: PC_ID, destination: BROADCAST_ID, message, auth: None, crc: 0 }
     }
-    ///removes the first packet from the PacketManager's packet vector and returns it as an Option<Packet>. If the vector is empty, the function returns None.
-    pub fn read_packet(&mut self) -> Option<Packet> {
-        if self.packets.is_empty() {
-            return None;
-        }
-        Some(self.packets.remove(0))
+
+    /// Set the system ids of the sender and the receiver
+    pub fn addressed(mut self, source: u8, destination: u8) -> Self {
+        self.source = source;
+        self.destination = destination;
+        self
     }
-}
 
-impl Packet {
-    /// Create new Packet instance with the message. CRC is created when packet is serialized.
-    pub fn new(message: Message) -> Self {
-        Packet { message: message, crc: 0 }
+    /// Whether the system with this id should handle the packet. Broadcasts are only
+    /// accepted for Hello and the safety commands, everything else has to be addressed.
+    pub fn accepted_by(&self, id: u8) -> bool {
+        self.destination == id
+            || (self.destination == BROADCAST_ID && matches!(self.message, Message::Hello(_) | Message::SafeMode | Message::PanicMode))
     }
 
-    /// Serialize the packet into a byte vector. The CRC checksum is added and cobs is 
-    /// used to insert an end byte which does not appear in the packet data
-    pub fn to_bytes(&mut self) -> Vec<u8> {
+    /// Serialize the packet into the buffer, without allocating. The CRC checksum is added and cobs is
+    /// used to insert an end byte which does not appear in the packet data.
+    /// Returns the part of the buffer that holds the encoded packet.
+    pub fn encode_into<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], PacketError> {
         // Calculate and add the checksum
         self.crc = self.create_checksum();
 
-        // Convert the packet into a byte vector
         // cobs is used to insert an end byte which does not appear in the packet data
-        let byte_vector = to_allocvec_cobs(self).unwrap();
+        to_slice_cobs(self, buf).map_err(|_| PacketError::BufferTooSmall)
+    }
 
-        byte_vector
+    /// Serialize the packet into a byte vector. The CRC checksum is added and cobs is 
+    /// used to insert an end byte which does not appear in the packet data
+    #[cfg(feature = "alloc")]
+    pub fn to_bytes(&mut self) -> Vec<u8> {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let encoded = self.encode_into(&mut buf).unwrap();
+
+        encoded.to_vec()
     }
 
-    /// Deserialize the byte vector into a Packet instance
-    pub fn from_bytes(buf: &mut [u8]) -> Result<Packet, PacketError> {
+    /// Deserialize a cobs encoded packet from the buffer, without allocating.
+    /// The buffer is used for decoding in place. The authentication is not checked, packets
+    /// are received through the FrameDecoder, which checks it when an authenticator is set.
+    pub(crate) fn decode(buf: &mut [u8]) -> Result<Packet, PacketError> {
         // Deserialize the payload into a Packet instance
-        let cobs_result = take_from_bytes_cobs::<Packet>(buf);
-        match cobs_result {
-            Err(_) => Err(PacketError::InvalidPacket),
-            Ok(_) => {
-                let packet;
-                (packet, _) = cobs_result.unwrap();
-        
-                // Verify the checksum and command of the packet
-                if packet.verify_checksum(&packet) {
-                    Ok(packet)
-                } else {
-                    Err(PacketError::ChecksumMismatch)
-                }
-            }
+        let packet = match take_from_bytes_cobs::<Packet>(buf) {
+            Ok((packet, _)) => packet,
+            Err(postcard::Error::DeserializeBadEncoding) => return Err(PacketError::InvalidPacket),
+            Err(_) => return Err(PacketError::InvalidPayload),
+        };
+
+        // Verify the checksum of the packet
+        if packet.verify_checksum(&packet) {
+            Ok(packet)
+        } else {
+            Err(PacketError::ChecksumMismatch)
         }
     }
 
-    /// This function computes the CRC32 checksum of the packet's command and argument fields.
+    /// This function computes the CRC32 checksum of the packet's header, message and authentication data.
     pub fn create_checksum(&mut self) -> u32 {
-        let mut digest = CRC_CHECKSUM.digest();
-        
-        // Update the digest with the message
-        digest.update(to_allocvec(&self.message).unwrap().deref());
-        
-        // Finalize the digest and return the computed checksum
-        digest.finalize()
+        // The serialized sequence number, source, destination, message and authentication data
+        // are fed into the digest while serializing
+        let flavor = CrcFlavor { digest: CRC_CHECKSUM.digest() };
+        serialize_with_flavor(&(self.seq, self.source, self.destination, &self.message, &self.auth), flavor).unwrap()
     }
     
     /// This function verifies the integrity of the packet's data by computing its CRC32 checksum.
     pub fn verify_checksum(&self, packet: &Packet) -> bool {
-        let mut digest = CRC_CHECKSUM.digest();
-
-        // Update the digest with the bytes of the command and argument fields of the provided packet
-        digest.update(to_allocvec(&packet.message).unwrap().deref());
-        
-        // Finalize the digest and compare it with the provided checksum
-        digest.finalize() == packet.crc
+        let flavor = CrcFlavor { digest: CRC_CHECKSUM.digest() };
+        match serialize_with_flavor(&(packet.seq, packet.source, packet.destination, &packet.message, &packet.auth), flavor) {
+            Ok(crc) => crc == packet.crc,
+            Err(_) => false,
+        }
     }
     
     /// Find end byte (>) position in a data packet
     pub fn find_end_byte(buf: &[u8], num: usize) -> usize {
-        let mut end_byte_pos = 0;
-        for i in 0..num {
-            if buf[i] == 62 {
-                end_byte_pos = i;
-                break;
-            } 
-        }
-        end_byte_pos
+        buf[..num].iter().position(|&byte| byte == 62).unwrap_or(0)
     }
 }
 
@@ -268,8 +800,171 @@ mod tests {
         packet.crc = checksum;
         assert!(packet.verify_checksum(&packet));
         assert!(!packet.verify_checksum(&Packet {
+            seq: 0,
+            source: PC_ID,
+            destination: BROADCAST_ID,
             message: Message::PanicMode,
+            auth: None,
             crc: checksum + 1,
         }));
     }
+
+    #[test]
+    fn test_sequence_number_roundtrip() {
+        let mut packet = Packet::with_seq(Message::CalibrationMode, 42);
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let decoded = Packet::decode(packet.encode_into(&mut buf).unwrap()).unwrap();
+        assert_eq!(decoded.seq, 42);
+        assert_eq!(decoded.message.mode(), Some(WorkingModes::CalibrationMode));
+
+        // The sequence number is covered by the checksum
+        let mut tampered = decoded;
+        tampered.seq = 43;
+        assert!(!tampered.verify_checksum(&tampered));
+    }
+
+    #[test]
+    fn test_param_value_limits() {
+        let min = ParamValue::F32(0.0);
+        let max = ParamValue::F32(10.0);
+        assert!(ParamValue::F32(2.5).within(&min, &max));
+        assert!(!ParamValue::F32(10.5).within(&min, &max));
+        assert!(!ParamValue::U16(2).within(&min, &max));
+        assert!(ParamValue::U16(2).same_type(&ParamValue::U16(7)));
+        assert!(!ParamValue::U16(2).same_type(&min));
+    }
+
+    #[test]
+    fn test_param_info_roundtrip() {
+        let info = ParamInfo {
+            index: 3,
+            count: 18,
+            name: String::from("angle_p"),
+            value: ParamValue::F32(0.84),
+            min: ParamValue::F32(0.0),
+            max: ParamValue::F32(100.0),
+            default: ParamValue::F32(0.0),
+        };
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let bytes = Packet::new(Message::Param(info.clone())).encode_into(&mut buf).unwrap();
+        assert_eq!(Packet::decode(bytes).unwrap().message, Message::Param(info));
+    }
+
+    /// Datalog with every field at the value that takes the most bytes
+    fn largest_datalog() -> Datalog {
+        let mut datalog = Datalog::new();
+        [datalog.motor1, datalog.motor2, datalog.motor3, datalog.motor4] = [u16::MAX; 4];
+        datalog.rtc = u64::MAX;
+        for angle in [&mut datalog.yaw, &mut datalog.pitch, &mut datalog.roll, &mut datalog.yaw_f, &mut datalog.pitch_f,
+                      &mut datalog.roll_f, &mut datalog.yaw_c, &mut datalog.pitch_c, &mut datalog.roll_c, &mut datalog.yaw_m,
+                      &mut datalog.pitch_m, &mut datalog.roll_m, &mut datalog.yaw_r, &mut datalog.pitch_r, &mut datalog.roll_r] {
+            *angle = -3.0;
+        }
+        datalog.bat = u16::MAX;
+        datalog.workingmode = WorkingModes::RawSensorMode;
+        datalog.estimator = Estimator::Mahony;
+        datalog.arguments = [u16::MAX; 4];
+        datalog.control_loop_time = u128::MAX;
+        datalog
+    }
+
+    #[test]
+    fn test_encode_decode_without_alloc() {
+        // The largest message has to fit in MAX_PACKET_SIZE
+        let mut packet = Packet::with_seq(Message::Datalogging(largest_datalog()), u16::MAX);
+
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let bytes = packet.encode_into(&mut buf).unwrap();
+        assert_eq!(bytes.last(), Some(&0));
+        assert_eq!(Packet::decode(bytes).unwrap(), packet);
+
+        // A buffer that is too small is reported instead of truncating the packet
+        let mut small = [0u8; 16];
+        assert_eq!(packet.encode_into(&mut small), Err(PacketError::BufferTooSmall));
+
+        // Corrupted data is rejected
+        let bytes = packet.encode_into(&mut buf).unwrap();
+        bytes[10] ^= 0xff;
+        assert!(Packet::decode(bytes).is_err());
+    }
+
+    #[test]
+    fn test_log_text_is_truncated() {
+        assert_eq!(log_text(format_args!("Erasing flash")).as_str(), "Erasing flash");
+
+        let long = log_text(format_args!("{:0>100}", 7));
+        assert_eq!(long.len(), LOG_TEXT_LEN);
+
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let message = Message::Log { level: LogLevel::Warn, text: long };
+        let bytes = Packet::new(message.clone()).encode_into(&mut buf).unwrap();
+        assert_eq!(Packet::decode(bytes).unwrap().message, message);
+    }
+
+    #[test]
+    fn test_crash_report_roundtrip() {
+        let report = CrashReport::new("dronecode/src/working_mode/height_control_mode.rs", 42,
+            format_args!("attempt to divide by {}", 0), WorkingModes::HeightControlMode, 123_456, [400, 410, 420, 430]);
+        assert_eq!(report.file.as_str(), "king_mode/height_control_mode.rs");
+        assert_eq!(report.message.as_str(), "attempt to divide by 0");
+
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let message = Message::CrashReport(report);
+        let bytes = Packet::new(message.clone()).encode_into(&mut buf).unwrap();
+        assert_eq!(Packet::decode(bytes).unwrap().message, message);
+    }
+
+    #[test]
+    fn test_log_chunk_roundtrip() {
+        // A full chunk has to fit in one packet
+        let data = heapless::Vec::from_slice(&[0xff; LOG_CHUNK_LEN]).unwrap();
+        let crc = chunk_crc(&data);
+        let message = Message::LogChunk { offset: u32::MAX, data, crc };
+
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let bytes = Packet::with_seq(message.clone(), u16::MAX).encode_into(&mut buf).unwrap();
+        let decoded = Packet::decode(bytes).unwrap().message;
+        assert_eq!(decoded, message);
+        if let Message::LogChunk { data, crc, .. } = decoded {
+            assert_eq!(chunk_crc(&data), crc);
+        }
+
+        // The largest datalog fits in one flash log record in its compact form
+        let datalog = largest_datalog();
+        let mut record = [0u8; LOG_RECORD_SIZE];
+        assert!(postcard::to_slice(&Message::CompactDatalogging(CompactDatalog::from(&datalog)), &mut record).is_ok());
+    }
+
+    #[test]
+    fn test_addressing() {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let mut packet = Packet::new(Message::ParamList).addressed(PC_ID, 2);
+        let bytes = packet.encode_into(&mut buf).unwrap();
+        let packet = Packet::decode(bytes).unwrap();
+        assert_eq!((packet.source, packet.destination), (PC_ID, 2));
+        assert!(packet.accepted_by(2));
+        assert!(!packet.accepted_by(3));
+
+        // Broadcasts are only accepted for Hello and safety commands
+        assert!(Packet::new(Message::PanicMode).accepted_by(3));
+        assert!(Packet::new(Message::SafeMode).accepted_by(3));
+        assert!(Packet::new(Message::Hello(LinkInfo::new(1, 0))).accepted_by(3));
+        assert!(!Packet::new(Message::ManualMode(0, 0, 0, 0)).accepted_by(3));
+    }
+
+    #[test]
+    fn test_link_compatibility() {
+        let local = LinkInfo::new(build_id("runner"), capabilities::MANUAL_MODE);
+        let remote = LinkInfo::new(build_id("dronecode"), capabilities::MANUAL_MODE | capabilities::DATALOGGING);
+        assert_eq!(local.check_compatible(&remote, capabilities::MANUAL_MODE), Ok(()));
+
+        // Missing capabilities are reported
+        assert_eq!(local.check_compatible(&remote, capabilities::HEIGHT_CONTROL_MODE),
+            Err(LinkError::MissingCapabilities(capabilities::HEIGHT_CONTROL_MODE)));
+
+        // Different protocol versions never match
+        let old = LinkInfo { protocol_version: PROTOCOL_VERSION + 1, ..remote };
+        assert_eq!(local.check_compatible(&old, 0),
+            Err(LinkError::VersionMismatch { local: PROTOCOL_VERSION, remote: PROTOCOL_VERSION + 1 }));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,155 @@
+use heapless::Vec;
+use crate::{Message, Packet};
+
+/// Priority class of a packet, packets of a higher class are always read first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Telemetry,  // periodic data, a newer packet replaces it
+    Normal,     // everything else
+    Urgent,     // safe and panic commands and command replies
+}
+
+impl Message {
+    /// Priority class of the message in the PacketManager
+    pub fn priority(&self) -> Priority {
+        match self {
+            Message::SafeMode | Message::PanicMode | Message::Ack(_) | Message::Nack(..) => Priority::Urgent,
+            Message::Datalogging(_) | Message::Telemetry(..) | Message::CompactDatalogging(_) | Message::LinkStatus(_) => Priority::Telemetry,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+/// Counters of the PacketManager
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    pub added: u32,     // packets that were queued
+    pub dropped: u32,   // queued packets that were thrown out to make room
+    pub rejected: u32,  // packets that were not queued because the queue was full
+}
+
+/// The packet was not queued, the queue is full of packets of a higher class
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueFull;
+
+/// Bounded queue of at most N packets with priority classes. Packets are read highest class first,
+/// oldest first within a class. When the queue is full the oldest packet of the lowest class is dropped,
+/// as long as that class is not higher than the class of the new packet, so telemetry goes first.
+#[derive(Debug)]
+pub struct PacketManager<const N: usize> {
+    // Sorted on class, the next packet to read is at the end. Within a class newer packets come first.
+    packets: Vec<(Priority, Packet), N>,
+    stats: QueueStats,
+}
+
+impl<const N: usize> PacketManager<N> {
+    /// Create an empty PacketManager
+    pub const fn new() -> Self {
+        PacketManager { packets: Vec::new(), stats: QueueStats { added: 0, dropped: 0, rejected: 0 } }
+    }
+
+    /// Add a packet in the class of its message. When the queue is full and no packet of the same
+    /// or a lower class can be dropped, the packet is not queued.
+    pub fn add_packet(&mut self, packet: Packet) -> Result<(), QueueFull> {
+        let priority = packet.message.priority();
+
+        if self.packets.is_full() {
+            let lowest = match self.packets.first() {
+                Some(&(lowest, _)) if lowest <= priority => lowest,
+                _ => {
+                    self.stats.rejected += 1;
+                    return Err(QueueFull);
+                }
+            };
+
+            // The oldest packet of the lowest class is the last one of that class
+            let oldest = self.packets.partition_point(|(class, _)| *class <= lowest) - 1;
+            self.packets.remove(oldest);
+            self.stats.dropped += 1;
+        }
+
+        let index = self.packets.partition_point(|(class, _)| *class < priority);
+        self.packets.insert(index, (priority, packet)).map_err(|_| QueueFull)?;
+        self.stats.added += 1;
+        Ok(())
+    }
+
+    /// Take the next packet out of the queue: the oldest packet of the highest class
+    pub fn read_packet(&mut self) -> Option<Packet> {
+        self.packets.pop().map(|(_, packet)| packet)
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.stats
+    }
+}
+
+impl<const N: usize> Default for PacketManager<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LinkStats;
+
+    fn telemetry(n: u32) -> Packet {
+        Packet::new(Message::LinkStatus(LinkStats { packets: n, ..Default::default() }))
+    }
+
+    fn read_all<const N: usize>(manager: &mut PacketManager<N>) -> std::vec::Vec<Message> {
+        core::iter::from_fn(|| manager.read_packet()).map(|packet| packet.message).collect()
+    }
+
+    #[test]
+    fn test_priority_order() {
+        let mut manager = PacketManager::<8>::new();
+        manager.add_packet(telemetry(1)).unwrap();
+        manager.add_packet(Packet::new(Message::ParamGet(1))).unwrap();
+        manager.add_packet(Packet::new(Message::Ack(1))).unwrap();
+        manager.add_packet(Packet::new(Message::ParamGet(2))).unwrap();
+        manager.add_packet(Packet::new(Message::PanicMode)).unwrap();
+
+        assert_eq!(read_all(&mut manager), [
+            Message::Ack(1),
+            Message::PanicMode,
+            Message::ParamGet(1),
+            Message::ParamGet(2),
+            telemetry(1).message,
+        ]);
+        assert!(manager.is_empty());
+    }
+
+    #[test]
+    fn test_overflow_drops_oldest_telemetry() {
+        let mut manager = PacketManager::<3>::new();
+        manager.add_packet(telemetry(1)).unwrap();
+        manager.add_packet(telemetry(2)).unwrap();
+        manager.add_packet(Packet::new(Message::ParamGet(1))).unwrap();
+
+        // A newer telemetry packet replaces the oldest one
+        manager.add_packet(telemetry(3)).unwrap();
+
+        // Commands replace the remaining telemetry
+        manager.add_packet(Packet::new(Message::SafeMode)).unwrap();
+        manager.add_packet(Packet::new(Message::ParamGet(2))).unwrap();
+        assert_eq!(manager.len(), 3);
+
+        // Without telemetry left, telemetry is refused and urgent packets replace normal ones
+        assert_eq!(manager.add_packet(telemetry(4)), Err(QueueFull));
+        manager.add_packet(Packet::new(Message::PanicMode)).unwrap();
+
+        assert_eq!(read_all(&mut manager), [Message::SafeMode, Message::PanicMode, Message::ParamGet(2)]);
+        assert_eq!(manager.stats(), QueueStats { added: 7, dropped: 4, rejected: 1 });
+    }
+}
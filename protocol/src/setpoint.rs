@@ -0,0 +1,79 @@
+use core::fmt;
+use core::f32::consts::{FRAC_PI_6, TAU};
+use serde::{Deserialize, Serialize};
+
+/// Largest roll and pitch setpoint, in rad (30 degrees)
+pub const MAX_ANGLE: f32 = FRAC_PI_6;
+
+/// Yaw rate setpoint at full stick deflection, in rad/s (360 degrees/s)
+pub const MAX_YAW_RATE: f32 = TAU;
+
+/// Raw stick value of the centered pitch and roll sticks in the legacy mode messages
+const STICK_ZERO: f32 = 32767.0;
+
+/// Raw stick value of the centered yaw stick, the yaw axis has a smaller range
+const STICK_ZERO_YAW: f32 = 8520.0;
+
+/// Full range of the lift stick
+const STICK_LIFT: f32 = 65535.0;
+
+/// Attitude and thrust setpoint in physical units. Positive angles and rates use the
+/// sign conventions of the drone attitude.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Setpoint {
+    pub roll: f32,      // rad
+    pub pitch: f32,     // rad
+    pub yaw_rate: f32,  // rad/s
+    pub thrust: f32,    // normalized 0-1, in HeightControlMode the height target as part of the 2 m range
+}
+
+impl Setpoint {
+    /// Convert the raw stick values of the legacy mode messages, in the order pitch, roll, yaw, lift
+    pub fn from_sticks(sticks: [u16; 4]) -> Self {
+        let [pitch, roll, yaw, lift] = sticks;
+        Setpoint {
+            roll: (roll as f32 - STICK_ZERO) / STICK_ZERO * MAX_ANGLE,
+            pitch: (pitch as f32 - STICK_ZERO) / STICK_ZERO * MAX_ANGLE,
+            yaw_rate: (yaw as f32 - STICK_ZERO_YAW) * 4.0 / STICK_ZERO * MAX_YAW_RATE,
+            thrust: (lift as f32 / STICK_LIFT).min(1.0),
+        }
+    }
+
+    /// Raw stick values of the setpoint, in the order pitch, roll, yaw, lift. Used in the datalog.
+    pub fn sticks(&self) -> [u16; 4] {
+        // Float to integer casts saturate, so out of range setpoints end up at the stick limits
+        [
+            (STICK_ZERO + self.pitch / MAX_ANGLE * STICK_ZERO + 0.5) as u16,
+            (STICK_ZERO + self.roll / MAX_ANGLE * STICK_ZERO + 0.5) as u16,
+            (STICK_ZERO_YAW + self.yaw_rate / MAX_YAW_RATE * STICK_ZERO / 4.0 + 0.5) as u16,
+            (self.thrust * STICK_LIFT + 0.5) as u16,
+        ]
+    }
+}
+
+impl fmt::Display for Setpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "roll {:.3} rad, pitch {:.3} rad, yaw rate {:.3} rad/s, thrust {:.3}",
+            self.roll, self.pitch, self.yaw_rate, self.thrust)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stick_conversion() {
+        // Centered sticks without lift are a zero setpoint
+        assert_eq!(Setpoint::from_sticks([32767, 32767, 8520, 0]), Setpoint::default());
+
+        let setpoint = Setpoint::from_sticks([65534, 0, 8520 + 32767 / 4, 65535]);
+        assert!((setpoint.pitch - MAX_ANGLE).abs() < 1e-6);
+        assert!((setpoint.roll + MAX_ANGLE).abs() < 1e-4);
+        assert!((setpoint.yaw_rate - MAX_YAW_RATE).abs() < 1e-3);
+        assert_eq!(setpoint.thrust, 1.0);
+
+        let sticks = [40000, 20000, 10000, 30000];
+        assert_eq!(Setpoint::from_sticks(sticks).sticks(), sticks);
+    }
+}
@@ -0,0 +1,350 @@
+use serde::{Deserialize, Serialize};
+use crate::{Datalog, WorkingModes, Estimator};
+
+/// Groups of telemetry fields that can be streamed separately
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum TelemetryGroup {
+    Attitude,       // working mode, DMP angles and the angles of all estimators
+    RawImu,         // angles from the raw sensor data
+    Motors,         // motor outputs and the last joystick arguments
+    BatteryBaro,    // battery voltage and pressure
+    Controller,     // control loop time and controller internals
+}
+
+impl TelemetryGroup {
+    pub const ALL: [TelemetryGroup; 5] = [
+        TelemetryGroup::Attitude,
+        TelemetryGroup::RawImu,
+        TelemetryGroup::Motors,
+        TelemetryGroup::BatteryBaro,
+        TelemetryGroup::Controller,
+    ];
+
+    const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Which telemetry groups the drone streams, and how often.
+/// The groups are sent on every `divider`-th tick of the 100 Hz control loop,
+/// without any group the full datalog is sent as a CompactDatalog.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct TelemetryConfig {
+    pub groups: u8,
+    pub divider: u8,
+}
+
+impl TelemetryConfig {
+    /// Config without any group, which streams the compact full datalog
+    pub const fn none(divider: u8) -> Self {
+        TelemetryConfig { groups: 0, divider }
+    }
+
+    pub const fn with(self, group: TelemetryGroup) -> Self {
+        TelemetryConfig { groups: self.groups | group.bit(), divider: self.divider }
+    }
+
+    pub const fn without(self, group: TelemetryGroup) -> Self {
+        TelemetryConfig { groups: self.groups & !group.bit(), divider: self.divider }
+    }
+
+    pub const fn contains(&self, group: TelemetryGroup) -> bool {
+        self.groups & group.bit() != 0
+    }
+
+    /// Check if the full datalog is streamed instead of separate groups
+    pub const fn is_compact(&self) -> bool {
+        self.groups == 0
+    }
+
+    /// Check if telemetry is due on this tick of the control loop
+    pub fn is_due(&self, tick: u64) -> bool {
+        tick.is_multiple_of(self.divider.max(1) as u64)
+    }
+}
+
+impl Default for TelemetryConfig {
+    /// Everything except the controller internals at 20 Hz, the rate of the full datalog
+    fn default() -> Self {
+        TelemetryConfig::none(5)
+            .with(TelemetryGroup::Attitude)
+            .with(TelemetryGroup::RawImu)
+            .with(TelemetryGroup::Motors)
+            .with(TelemetryGroup::BatteryBaro)
+    }
+}
+
+/// Compact telemetry message with the fields of one group
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Telemetry {
+    Attitude { mode: WorkingModes, estimator: Estimator, dmp: [f32; 3], filtered: [f32; 3], complementary: [f32; 3], ahrs: [f32; 3] },
+    RawImu { raw: [f32; 3] },
+    Motors { motors: [u16; 4], arguments: [u16; 4] },
+    BatteryBaro { bat: u16, bar: f32 },
+    Controller { loop_time: u32, pid: [f32; 4], test: [f32; 4] },
+}
+
+impl Telemetry {
+    /// Take the fields of a group from a full datalog
+    pub fn from_datalog(group: TelemetryGroup, d: &Datalog) -> Self {
+        match group {
+            TelemetryGroup::Attitude => Telemetry::Attitude {
+                mode: d.workingmode,
+                estimator: d.estimator,
+                dmp: [d.yaw, d.pitch, d.roll],
+                filtered: [d.yaw_f, d.pitch_f, d.roll_f],
+                complementary: [d.yaw_c, d.pitch_c, d.roll_c],
+                ahrs: [d.yaw_m, d.pitch_m, d.roll_m],
+            },
+            TelemetryGroup::RawImu => Telemetry::RawImu { raw: [d.yaw_r, d.pitch_r, d.roll_r] },
+            TelemetryGroup::Motors => Telemetry::Motors {
+                motors: [d.motor1, d.motor2, d.motor3, d.motor4],
+                arguments: d.arguments,
+            },
+            TelemetryGroup::BatteryBaro => Telemetry::BatteryBaro { bat: d.bat, bar: d.bar },
+            TelemetryGroup::Controller => Telemetry::Controller {
+                loop_time: d.control_loop_time.min(u32::MAX as u128) as u32,
+                pid: d.pid,
+                test: d.test,
+            },
+        }
+    }
+
+    /// Copy the fields of this group into a datalog, the other fields are kept
+    pub fn apply(&self, d: &mut Datalog) {
+        match *self {
+            Telemetry::Attitude { mode, estimator, dmp, filtered, complementary, ahrs } => {
+                d.workingmode = mode;
+                d.estimator = estimator;
+                [d.yaw, d.pitch, d.roll] = dmp;
+                [d.yaw_f, d.pitch_f, d.roll_f] = filtered;
+                [d.yaw_c, d.pitch_c, d.roll_c] = complementary;
+                [d.yaw_m, d.pitch_m, d.roll_m] = ahrs;
+            }
+            Telemetry::RawImu { raw } => [d.yaw_r, d.pitch_r, d.roll_r] = raw,
+            Telemetry::Motors { motors, arguments } => {
+                [d.motor1, d.motor2, d.motor3, d.motor4] = motors;
+                d.arguments = arguments;
+            }
+            Telemetry::BatteryBaro { bat, bar } => {
+                d.bat = bat;
+                d.bar = bar;
+            }
+            Telemetry::Controller { loop_time, pid, test } => {
+                d.control_loop_time = loop_time as u128;
+                d.pid = pid;
+                d.test = test;
+            }
+        }
+    }
+}
+
+/// Scale of the angles in a CompactDatalog, 1e-4 rad resolution and a range of +-3.27 rad
+pub const ANGLE_SCALE: f32 = 10_000.0;
+
+fn angle_to_i16(angle: f32) -> i16 {
+    // Round to the nearest step, the cast saturates angles outside the range
+    let scaled = angle * ANGLE_SCALE;
+    (if scaled >= 0.0 { scaled + 0.5 } else { scaled - 0.5 }) as i16
+}
+
+fn angle_from_i16(angle: i16) -> f32 {
+    angle as f32 / ANGLE_SCALE
+}
+
+/// Datalog in a compact fixed point encoding: angles as scaled i16, loop time in us as u16
+/// and the drone time as wrapping u32 ms. Converted back to a Datalog by the receiver.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct CompactDatalog {
+    pub rtc: u32,
+    pub motors: [u16; 4],
+    pub dmp: [i16; 3],
+    pub filtered: [i16; 3],
+    pub complementary: [i16; 3],
+    pub ahrs: [i16; 3],
+    pub raw: [i16; 3],
+    pub bat: u16,
+    pub bar: f32,
+    pub workingmode: WorkingModes,
+    pub estimator: Estimator,
+    pub arguments: [u16; 4],
+    pub control_loop_time: u16,
+    pub pid: [f32; 4],
+    pub test: [f32; 4],
+}
+
+impl From<&Datalog> for CompactDatalog {
+    fn from(d: &Datalog) -> Self {
+        CompactDatalog {
+            rtc: d.rtc as u32,
+            motors: [d.motor1, d.motor2, d.motor3, d.motor4],
+            dmp: [angle_to_i16(d.yaw), angle_to_i16(d.pitch), angle_to_i16(d.roll)],
+            filtered: [angle_to_i16(d.yaw_f), angle_to_i16(d.pitch_f), angle_to_i16(d.roll_f)],
+            complementary: [angle_to_i16(d.yaw_c), angle_to_i16(d.pitch_c), angle_to_i16(d.roll_c)],
+            ahrs: [angle_to_i16(d.yaw_m), angle_to_i16(d.pitch_m), angle_to_i16(d.roll_m)],
+            raw: [angle_to_i16(d.yaw_r), angle_to_i16(d.pitch_r), angle_to_i16(d.roll_r)],
+            bat: d.bat,
+            bar: d.bar,
+            workingmode: d.workingmode,
+            estimator: d.estimator,
+            arguments: d.arguments,
+            control_loop_time: d.control_loop_time.min(u16::MAX as u128) as u16,
+            pid: d.pid,
+            test: d.test,
+        }
+    }
+}
+
+impl CompactDatalog {
+    /// Convert back to a Datalog. The rtc is extended to 64 bit with the rtc of the previous datalog,
+    /// so it keeps counting when the u32 wraps around.
+    pub fn to_datalog(&self, last_rtc: u64) -> Datalog {
+        Datalog {
+            motor1: self.motors[0],
+            motor2: self.motors[1],
+            motor3: self.motors[2],
+            motor4: self.motors[3],
+            rtc: unwrap_rtc(last_rtc, self.rtc),
+            yaw: angle_from_i16(self.dmp[0]),
+            pitch: angle_from_i16(self.dmp[1]),
+            roll: angle_from_i16(self.dmp[2]),
+            yaw_f: angle_from_i16(self.filtered[0]),
+            pitch_f: angle_from_i16(self.filtered[1]),
+            roll_f: angle_from_i16(self.filtered[2]),
+            yaw_c: angle_from_i16(self.complementary[0]),
+            pitch_c: angle_from_i16(self.complementary[1]),
+            roll_c: angle_from_i16(self.complementary[2]),
+            yaw_m: angle_from_i16(self.ahrs[0]),
+            pitch_m: angle_from_i16(self.ahrs[1]),
+            roll_m: angle_from_i16(self.ahrs[2]),
+            yaw_r: angle_from_i16(self.raw[0]),
+            pitch_r: angle_from_i16(self.raw[1]),
+            roll_r: angle_from_i16(self.raw[2]),
+            bat: self.bat,
+            bar: self.bar,
+            workingmode: self.workingmode,
+            estimator: self.estimator,
+            arguments: self.arguments,
+            control_loop_time: self.control_loop_time as u128,
+            pid: self.pid,
+            test: self.test,
+        }
+    }
+}
+
+/// Extend a wrapping u32 time to the u64 time closest to the last known time
+fn unwrap_rtc(last: u64, rtc: u32) -> u64 {
+    let candidate = (last & !(u32::MAX as u64)) | rtc as u64;
+    if candidate + (1 << 31) < last {
+        candidate + (1 << 32)
+    } else if candidate > last + (1 << 31) && candidate >= (1 << 32) {
+        candidate - (1 << 32)
+    } else {
+        candidate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_groups_rebuild_datalog() {
+        let mut datalog = Datalog::new();
+        datalog.motor3 = 450;
+        datalog.pitch_f = 0.25;
+        datalog.roll_c = 0.125;
+        datalog.pitch_m = -0.0625;
+        datalog.estimator = Estimator::Mahony;
+        datalog.roll_r = -0.5;
+        datalog.bat = 1100;
+        datalog.control_loop_time = 2300;
+        datalog.pid = [0.5, -0.25, 0.125, 0.375];
+        datalog.workingmode = WorkingModes::FullControlMode;
+
+        // Applying all groups gives back the original datalog
+        let mut assembled = Datalog::new();
+        for group in TelemetryGroup::ALL {
+            Telemetry::from_datalog(group, &datalog).apply(&mut assembled);
+        }
+        assert_eq!(assembled, datalog);
+    }
+
+    #[test]
+    fn test_compact_datalog() {
+        let mut datalog = Datalog::new();
+        datalog.motor1 = 420;
+        datalog.motor4 = 399;
+        datalog.rtc = 3_600_000;
+        datalog.yaw = 3.0;
+        datalog.pitch = -0.123456;
+        datalog.roll_f = 0.5;
+        datalog.yaw_c = -0.75;
+        datalog.roll_m = 1.5;
+        datalog.estimator = Estimator::Complementary;
+        datalog.pitch_r = -1.000049;
+        datalog.bat = 1130;
+        datalog.bar = 0.42;
+        datalog.workingmode = WorkingModes::YawControlMode;
+        datalog.arguments = [32767, 32767, 8520, 12000];
+        datalog.control_loop_time = 2345;
+        datalog.pid = [0.5, -0.25, 0.125, 0.375];
+        datalog.test = [1.0, 2.0, 3.0, 4.0];
+
+        let compact = CompactDatalog::from(&datalog);
+        let decoded = compact.to_datalog(3_599_950);
+
+        // Everything except the angles comes back unchanged
+        assert_eq!(decoded.rtc, datalog.rtc);
+        assert_eq!(decoded.motor1, datalog.motor1);
+        assert_eq!(decoded.control_loop_time, datalog.control_loop_time);
+        assert_eq!(decoded.arguments, datalog.arguments);
+        assert_eq!(decoded.estimator, datalog.estimator);
+        assert_eq!(decoded.pid, datalog.pid);
+
+        // The angles are rounded to the angle resolution
+        let max_error = 0.5 / ANGLE_SCALE + f32::EPSILON * 4.0;
+        let angles = |d: &Datalog| [d.yaw, d.pitch, d.roll, d.yaw_f, d.pitch_f, d.roll_f, d.yaw_c, d.pitch_c, d.roll_c, d.yaw_m, d.pitch_m, d.roll_m, d.yaw_r, d.pitch_r, d.roll_r];
+        for (original, rounded) in angles(&datalog).iter().zip(angles(&decoded).iter()) {
+            assert!((original - rounded).abs() <= max_error, "{} became {}", original, rounded);
+        }
+
+        // Bytes saved per frame, in the worst case and for a typical frame
+        let mut buf = [0u8; 256];
+        let typical_full = postcard::to_slice(&datalog, &mut buf).unwrap().len();
+        let typical_compact = postcard::to_slice(&compact, &mut buf).unwrap().len();
+
+        let mut worst = datalog;
+        worst.rtc = u64::MAX;
+        worst.control_loop_time = u128::MAX;
+        let worst_full = postcard::to_slice(&worst, &mut buf).unwrap().len();
+        let worst_compact = postcard::to_slice(&CompactDatalog::from(&worst), &mut buf).unwrap().len();
+
+        assert!(typical_full - typical_compact >= 20);
+        assert!(worst_full - worst_compact >= 40);
+    }
+
+    #[test]
+    fn test_rtc_wraps_around() {
+        assert_eq!(unwrap_rtc(0, 10), 10);
+        assert_eq!(unwrap_rtc(u32::MAX as u64 - 5, 3), u32::MAX as u64 + 4);
+        assert_eq!(unwrap_rtc(u32::MAX as u64 + 4, u32::MAX), u32::MAX as u64);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = TelemetryConfig::default();
+        assert!(config.contains(TelemetryGroup::Attitude));
+        assert!(!config.contains(TelemetryGroup::Controller));
+        assert!(config.is_due(10));
+        assert!(!config.is_due(11));
+
+        let config = config.without(TelemetryGroup::Attitude).with(TelemetryGroup::Controller);
+        assert!(!config.contains(TelemetryGroup::Attitude));
+        assert!(config.contains(TelemetryGroup::Controller));
+
+        // Without groups the compact datalog is sent, a zero divider is treated as every tick
+        assert!(TelemetryConfig::none(1).is_compact());
+        assert!(TelemetryConfig::none(0).with(TelemetryGroup::Motors).is_due(3));
+    }
+}
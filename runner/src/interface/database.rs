@@ -10,6 +10,24 @@ use std::io::Write;
 use std::path::Path;
 use std::time::SystemTime;
 
+/// Time in ns used to name a json file
+fn file_time(host_time: Option<u64>) -> u128 {
+    match host_time {
+        Some(us) => us as u128 * 1000,
+        None => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos(),
+    }
+}
+
+/// Json of a stored message with the host time in us it was made at, so datalogs, log messages
+/// and crash reports can be put on one time axis
+fn timestamped_json(value: &impl Serialize, host_time: u128) -> serde_json::Result<String> {
+    let mut json = serde_json::to_value(value)?;
+    if let Value::Object(fields) = &mut json {
+        fields.insert("host_time".to_string(), Value::from((host_time / 1000) as u64));
+    }
+    serde_json::to_string(&json)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DatabaseManager {
     motor1: u16,
@@ -29,8 +47,10 @@ pub struct DatabaseManager {
 }
 
 impl DatabaseManager {
-    ///Create the Json for it to be stored in the directory
-    pub fn create_json(packet: &Packet)-> () {
+    ///Create the Json for it to be stored in the directory. The file is named after the host time in us
+    ///at which the message was created, the time it was received is used when that is not known.
+    ///The same time is stored in the host_time field.
+    pub fn create_json(packet: &Packet, host_time: Option<u64>)-> () {
 
         // Create folder to store jsons, if it does not exist yet
         let database_exists = Path::new("database").is_dir();
@@ -40,22 +60,45 @@ impl DatabaseManager {
         }
 
         let test = packet;
-        let msg = test.message;
+        let msg = &test.message;
         
         match msg {
             Message::Datalogging(datalog) => {
-                let json = serde_json::to_string(&datalog).unwrap();
-                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+                let now = file_time(host_time);
+                let json = timestamped_json(datalog, now).unwrap();
 
                 let file_name = now.to_string();
                 let mut file = File::create(format!("database/{}.json", file_name)).unwrap();
                 file.write_all(json.as_bytes()).unwrap();
             }
+            Message::Log { .. } | Message::CrashReport(_) => {
+                // Log messages and crash reports are stored with their message type, so they can be told apart from datalogs
+                let now = file_time(host_time);
+                let json = timestamped_json(msg, now).unwrap();
+
+                let suffix = if let Message::CrashReport(_) = msg { "_crash" } else { "_log" };
+                let file_name = now.to_string() + suffix;
+                let mut file = File::create(format!("database/{}.json", file_name)).unwrap();
+                file.write_all(json.as_bytes()).unwrap();
+            }
             _ => ()
         }
         
     }
     
+    /// Store the records downloaded from the drone flash log in one json file
+    pub fn store_flash_log(messages: &[Message]) -> Result<String, Box<dyn Error>> {
+        if !Path::new("database").is_dir() {
+            fs::create_dir("database")?;
+        }
+
+        let json = serde_json::to_string(messages)?;
+        let file_name = format!("database/{}_flash.json", file_time(None));
+        let mut file = File::create(&file_name)?;
+        file.write_all(json.as_bytes())?;
+        Ok(file_name)
+    }
+
     fn read_json_files() -> Result<Vec<Value>, Box<dyn Error>>{
         let mut json_files: Vec<Value> = Vec::new();
         for entry in fs::read_dir("database/")? {
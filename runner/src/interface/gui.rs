@@ -1,35 +1,91 @@
-use std::{thread::sleep, time::Duration};
-use protocol::Datalog;
+use std::{thread::sleep, time::Duration, sync::mpsc::{Receiver, Sender}, collections::VecDeque};
+use protocol::{Datalog, Message, ParamInfo, LogLevel, CrashReport, TelemetryConfig, TelemetryGroup, Estimator};
 use eframe::egui;
 use egui::plot::{Line, Plot, PlotPoints};
 
 use super::settings_logic::SettingsBundle;
+use super::pc_transmission::{DeliveryStatus, CommandStatus, LinkQuality};
+use super::parameters::{ParameterTable, parse_value};
+
+/// Number of drone log messages shown in the GUI
+const LOG_LINES: usize = 100;
+
+/// Telemetry rates that can be selected, as dividers of the 100 Hz control loop
+const TELEMETRY_DIVIDERS: [u8; 6] = [1, 2, 5, 10, 20, 100];
+
+/// Show a time in us as ms, or - when it is not measured yet
+fn micros_to_ms(time: Option<u64>) -> String {
+    match time {
+        Some(us) => format!("{:.1} ms", us as f64 / 1000.0),
+        None => "-".to_string(),
+    }
+}
 
 pub struct QuadrupelGUI {
     rx_gui_pc_command: single_value_channel::Receiver<Option<SettingsBundle>>,
     rx_gui_datalog: single_value_channel::Receiver<Option<Datalog>>,
+    rx_gui_delivery: single_value_channel::Receiver<Option<DeliveryStatus>>,
+    rx_gui_link: single_value_channel::Receiver<Option<LinkQuality>>,
+    rx_gui_crash: single_value_channel::Receiver<Option<CrashReport>>,
+    rx_gui_param: Receiver<ParamInfo>,
+    rx_gui_log: Receiver<(LogLevel, String)>,
+    rx_gui_status: Receiver<CommandStatus>,
+    tx_gui_command: Sender<Message>,
+    parameters: ParameterTable,
+    parameter_status: String,
+    telemetry: TelemetryConfig,
+    log: VecDeque<(LogLevel, String)>,
     settings: SettingsBundle,
+    delivery: Option<DeliveryStatus>,
+    link: LinkQuality,
+    crash: Option<CrashReport>,
     datalog: Datalog,
     battery_vec: Vec<f64>,
     pressure_vec: Vec<f64>,
     dmp_vec: Vec<f64>,
     raw_vec: Vec<f64>,
     filtered_vec: Vec<f64>,
+    complementary_vec: Vec<f64>,
+    ahrs_vec: Vec<f64>,
 }
 
 impl QuadrupelGUI {
-    pub fn new(cc: &eframe::CreationContext<'_>, rx_gui_pc_command: single_value_channel::Receiver<Option<SettingsBundle>>, rx_gui_datalog: single_value_channel::Receiver<Option<Datalog>>) -> Self {
+    pub fn new(cc: &eframe::CreationContext<'_>, rx_gui_pc_command: single_value_channel::Receiver<Option<SettingsBundle>>, rx_gui_datalog: single_value_channel::Receiver<Option<Datalog>>, rx_gui_delivery: single_value_channel::Receiver<Option<DeliveryStatus>>, rx_gui_link: single_value_channel::Receiver<Option<LinkQuality>>, rx_gui_crash: single_value_channel::Receiver<Option<CrashReport>>, rx_gui_param: Receiver<ParamInfo>, rx_gui_log: Receiver<(LogLevel, String)>, rx_gui_status: Receiver<CommandStatus>, tx_gui_command: Sender<Message>) -> Self {
         Self { 
             rx_gui_pc_command: rx_gui_pc_command,
             rx_gui_datalog: rx_gui_datalog,
+            rx_gui_delivery: rx_gui_delivery,
+            rx_gui_link: rx_gui_link,
+            rx_gui_crash: rx_gui_crash,
+            rx_gui_param: rx_gui_param,
+            rx_gui_log: rx_gui_log,
+            rx_gui_status: rx_gui_status,
+            tx_gui_command: tx_gui_command,
+            parameters: ParameterTable::new(),
+            parameter_status: String::new(),
+            telemetry: TelemetryConfig::default(),
+            log: VecDeque::new(),
             settings: SettingsBundle::default(),
+            delivery: None,
+            link: LinkQuality::default(),
+            crash: None,
             datalog: Datalog::new(),
             battery_vec: vec![0.0; 100],
             pressure_vec: vec![0.0; 100],
             dmp_vec: vec![0.0; 100],
             raw_vec: vec![0.0; 100],
             filtered_vec: vec![0.0; 100],
+            complementary_vec: vec![0.0; 100],
+            ahrs_vec: vec![0.0; 100],
+        }
+    }
+
+    /// Add a line to the log window, the oldest line is dropped when it is full
+    fn push_log(&mut self, entry: (LogLevel, String)) {
+        if self.log.len() == LOG_LINES {
+            self.log.pop_front();
         }
+        self.log.push_back(entry);
     }
 }
 
@@ -55,6 +111,142 @@ impl eframe::App for QuadrupelGUI {
              }
              None => ()
          }
+
+         let delivery = *self.rx_gui_delivery.latest();
+         if delivery.is_some() {
+             self.delivery = delivery;
+         }
+
+         if let Some(link) = *self.rx_gui_link.latest() {
+             self.link = link;
+         }
+
+         while let Ok(info) = self.rx_gui_param.try_recv() {
+             self.parameters.update(info);
+         }
+
+         if let Some(crash) = self.rx_gui_crash.latest() {
+             self.crash = Some(crash.clone());
+         }
+
+         // Crash report of the drone, shown on top until the GUI is closed
+         if let Some(crash) = &self.crash {
+             egui::TopBottomPanel::top("crash_report").show(ctx, |ui| {
+                 ui.heading(egui::RichText::new("Drone crashed").color(egui::Color32::from_rgb(255, 80, 80)));
+                 ui.colored_label(egui::Color32::from_rgb(255, 80, 80), crash.to_string());
+             });
+         }
+
+         // Results of the GUI commands, the ones that did not go through are logged with the reason
+         while let Ok(status) = self.rx_gui_status.try_recv() {
+             if let Message::ParamSet(index, _) = *status.message() {
+                 self.parameter_status = match (self.parameters.name(index), &status) {
+                     (Some(name), CommandStatus::Accepted(_)) => format!("{} set", name),
+                     (Some(name), CommandStatus::Rejected(_, reason)) => format!("{} rejected: {}", name, reason),
+                     (Some(name), CommandStatus::TimedOut(_)) => format!("{} not acknowledged", name),
+                     (None, _) => status.to_string(),
+                 };
+             }
+             if !matches!(status, CommandStatus::Accepted(_)) {
+                 self.push_log((LogLevel::Warn, status.to_string()));
+             }
+         }
+
+         // Keep the last log messages of the drone
+         while let Ok(entry) = self.rx_gui_log.try_recv() {
+             self.push_log(entry);
+         }
+
+         // Log messages of the drone
+         egui::Window::new("Drone log").default_pos(egui::Pos2::new(750.0, 20.0)).show(ctx, |ui| {
+             egui::ScrollArea::vertical().max_height(200.0).stick_to_bottom(true).show(ui, |ui| {
+                 for (level, text) in self.log.iter() {
+                     let color = match level {
+                         LogLevel::Error => egui::Color32::from_rgb(255, 80, 80),
+                         LogLevel::Warn => egui::Color32::from_rgb(255, 200, 0),
+                         _ => egui::Color32::from_rgb(200, 200, 200),
+                     };
+                     ui.colored_label(color, format!("{:<5} {}", level.to_string(), text));
+                 }
+             });
+         });
+
+         // Telemetry groups and rate the drone streams
+         egui::Window::new("Telemetry").default_pos(egui::Pos2::new(750.0, 280.0)).show(ctx, |ui| {
+             // Without any group the drone streams the full datalog in compact form
+             let mut compact = self.telemetry.is_compact();
+             if ui.checkbox(&mut compact, "Compact full datalog").changed() {
+                 self.telemetry = if compact { TelemetryConfig::none(self.telemetry.divider) } else { TelemetryConfig { divider: self.telemetry.divider, ..TelemetryConfig::default() } };
+             }
+             ui.separator();
+
+             for group in TelemetryGroup::ALL {
+                 let mut selected = self.telemetry.contains(group);
+                 if ui.checkbox(&mut selected, format!("{:?}", group)).changed() {
+                     self.telemetry = if selected { self.telemetry.with(group) } else { self.telemetry.without(group) };
+                 }
+             }
+
+             egui::ComboBox::from_label("Rate")
+                 .selected_text(format!("{} Hz", 100 / self.telemetry.divider.max(1) as u32))
+                 .show_ui(ui, |ui| {
+                     for divider in TELEMETRY_DIVIDERS {
+                         ui.selectable_value(&mut self.telemetry.divider, divider, format!("{} Hz", 100 / divider as u32));
+                     }
+                 });
+
+             if ui.button("Apply").clicked() {
+                 let _ = self.tx_gui_command.send(Message::TelemetryConfig(self.telemetry));
+             }
+         });
+
+         // Attitude estimator of RawSensorMode, all are logged and this one drives the controllers. It is only
+         // changed in SafeMode
+         egui::Window::new("Estimator").default_pos(egui::Pos2::new(750.0, 320.0)).show(ctx, |ui| {
+             ui.horizontal(|ui| {
+                 for estimator in Estimator::ALL {
+                     if ui.selectable_label(self.datalog.estimator == estimator, estimator.to_string()).clicked() {
+                         let _ = self.tx_gui_command.send(Message::SetEstimator(estimator));
+                     }
+                 }
+             });
+         });
+
+         // Parameter table of the drone
+         egui::Window::new("Parameters").default_pos(egui::Pos2::new(750.0, 350.0)).show(ctx, |ui| {
+             ui.horizontal(|ui| {
+                 if ui.button("Refresh").clicked() {
+                     let _ = self.tx_gui_command.send(Message::ParamList);
+                 }
+                 if ui.button("Dump").clicked() {
+                     self.parameter_status = match self.parameters.dump() {
+                         Ok(()) => "Stored in database/parameters.json".to_string(),
+                         Err(err) => format!("Dump failed: {}", err),
+                     };
+                 }
+                 ui.label(format!("{}/{}", self.parameters.received(), self.parameters.expected()));
+             });
+
+             egui::Grid::new("parameter_grid").show(ui, |ui| {
+                 for entry in self.parameters.entries_mut() {
+                     ui.label(entry.info.name.as_str());
+                     ui.label(entry.info.value.to_string());
+                     ui.add(egui::TextEdit::singleline(&mut entry.input).desired_width(60.0));
+                     if ui.button("Set").clicked() {
+                         match parse_value(&entry.info, &entry.input) {
+                             Ok(value) => {
+                                 let _ = self.tx_gui_command.send(Message::ParamSet(entry.info.index, value));
+                                 self.parameter_status = format!("Setting {} to {}", entry.info.name, value);
+                             }
+                             Err(err) => self.parameter_status = format!("{}: {}", entry.info.name, err),
+                         }
+                     }
+                     ui.end_row();
+                 }
+             });
+
+             ui.label(self.parameter_status.as_str());
+         });
  
          egui::CentralPanel::default().show(ctx, |ui| {
              ui.visuals_mut().override_text_color = Some(egui::Color32::from_rgb(255, 255, 255));
@@ -78,6 +270,10 @@ impl eframe::App for QuadrupelGUI {
                          ui.label("R/P P1:".to_string() + "     " +  self.settings.roll_pitch_control_p1.to_string().as_str());
                          ui.label("R/P P2:".to_string() + "     " +  self.settings.roll_pitch_control_p2.to_string().as_str()); 
                          ui.label("Height P:".to_string() + " " +  self.settings.height_control_p.to_string().as_str()); 
+                         match self.delivery {
+                             Some(status) => ui.label("Mode change: ".to_string() + status.to_string().as_str()),
+                             None => ui.label("Mode change: -"),
+                         };
                          ui.heading("");
                          });
  
@@ -89,11 +285,17 @@ impl eframe::App for QuadrupelGUI {
                              ui.label("Motors:      ".to_string() + self.datalog.motor1.to_string().as_str() + ", " + self.datalog.motor2.to_string().as_str() + ", " + self.datalog.motor3.to_string().as_str() + ", " + self.datalog.motor4.to_string().as_str());
                              ui.label("YPR:            ".to_string() + self.datalog.yaw.to_string().as_str() + ", " + self.datalog.pitch.to_string().as_str() + ", " + self.datalog.roll.to_string().as_str());
                              ui.label("DMP:          ".to_string() + self.datalog.yaw.to_string().as_str() + ", " + self.datalog.pitch.to_string().as_str() + ", " + self.datalog.roll.to_string().as_str());
-                             ui.label("Filtered:     ".to_string() + self.datalog.yaw_f.to_string().as_str() + ", " + self.datalog.pitch_f.to_string().as_str() + ", " + self.datalog.roll_f.to_string().as_str());
+                             ui.label("Kalman:       ".to_string() + self.datalog.yaw_f.to_string().as_str() + ", " + self.datalog.pitch_f.to_string().as_str() + ", " + self.datalog.roll_f.to_string().as_str());
+                             ui.label("Compl.:        ".to_string() + self.datalog.yaw_c.to_string().as_str() + ", " + self.datalog.pitch_c.to_string().as_str() + ", " + self.datalog.roll_c.to_string().as_str());
+                             ui.label("AHRS:          ".to_string() + self.datalog.yaw_m.to_string().as_str() + ", " + self.datalog.pitch_m.to_string().as_str() + ", " + self.datalog.roll_m.to_string().as_str());
                              ui.label("Raw:           ".to_string() + self.datalog.yaw_r.to_string().as_str() + ", " + self.datalog.pitch_r.to_string().as_str() + ", " + self.datalog.roll_r.to_string().as_str());
                              ui.label("Bat:             ".to_string() + self.datalog.bat.to_string().as_str() + " mV");
                              ui.label("Pressure:   ".to_string() + self.datalog.bar.to_string().as_str() + " 10^-5 bar");        
                              ui.label("Looptime: ".to_string() + self.datalog.control_loop_time.to_string().as_str() + " us");        
+                             ui.label("Link PC:     ".to_string() + self.link.pc.to_string().as_str());
+                             ui.label("Link drone: ".to_string() + self.link.drone.to_string().as_str());
+                             ui.label("RTT:            ".to_string() + micros_to_ms(self.link.rtt).as_str());
+                             ui.label("Latency:      ".to_string() + micros_to_ms(self.link.latency).as_str());
                          });
  
                      });
@@ -139,7 +341,7 @@ impl eframe::App for QuadrupelGUI {
                      // DMP, raw and filtered angles graphs
                      ui.allocate_ui_with_layout(eframe::egui::vec2(8000.0, 8000.0), egui::Layout::left_to_right(egui::Align::LEFT), |ui| {
                          ui.allocate_ui_with_layout(eframe::egui::vec2(8000.0, 8000.0), egui::Layout::top_down(egui::Align::LEFT), |ui| {
-                             ui.heading("DMP and filtered pitch");
+                             ui.heading("DMP and estimated pitch");
                              self.dmp_vec.rotate_left(1);
                              self.dmp_vec[99] = self.datalog.pitch as f64;
                              let points: PlotPoints = self.dmp_vec.iter().enumerate().map(|(i, &y)| [i as f64, y]).collect();
@@ -149,6 +351,16 @@ impl eframe::App for QuadrupelGUI {
                              self.filtered_vec[99] = self.datalog.pitch_f as f64;
                              let points: PlotPoints = self.filtered_vec.iter().enumerate().map(|(i, &y)| [i as f64, y]).collect();
                              let line2 = Line::new(points);
+
+                             self.complementary_vec.rotate_left(1);
+                             self.complementary_vec[99] = self.datalog.pitch_c as f64;
+                             let points: PlotPoints = self.complementary_vec.iter().enumerate().map(|(i, &y)| [i as f64, y]).collect();
+                             let line3 = Line::new(points);
+
+                             self.ahrs_vec.rotate_left(1);
+                             self.ahrs_vec[99] = self.datalog.pitch_m as f64;
+                             let points: PlotPoints = self.ahrs_vec.iter().enumerate().map(|(i, &y)| [i as f64, y]).collect();
+                             let line4 = Line::new(points);
                              Plot::new("filtered")
                                  .allow_drag(false)
                                  .view_aspect(2.0)
@@ -157,7 +369,7 @@ impl eframe::App for QuadrupelGUI {
                                  .show_y(false)
                                  .show_background(false)
                                  .allow_scroll(false)
-                                 .show(ui, |plot_ui| {plot_ui.line(line); plot_ui.line(line2);});
+                                 .show(ui, |plot_ui| {plot_ui.line(line); plot_ui.line(line2); plot_ui.line(line3); plot_ui.line(line4);});
                          });
                          ui.heading("    ");
  
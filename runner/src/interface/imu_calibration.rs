@@ -0,0 +1,129 @@
+use super::link::Link;
+use std::{fmt, error::Error, io::{self, stdin, Write}, time::{Duration, Instant}};
+use protocol::{Message, Packet, NackReason, FrameDecoder, ImuCalibrationStep, ImuCalibrationState, ImuOrientation};
+use super::pc_transmission::{request, read_message};
+
+/// The drone reports the progress of a step several times a second, it is gone when it stays quiet this long
+const STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Errors that stop the IMU calibration
+#[derive(Debug)]
+pub enum CalibrationError {
+    NoResponse,
+    Rejected(NackReason),
+    Io(io::Error),
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CalibrationError::NoResponse => write!(f, "drone stopped answering"),
+            CalibrationError::Rejected(reason) => write!(f, "rejected: {}", reason),
+            CalibrationError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for CalibrationError {}
+
+impl From<io::Error> for CalibrationError {
+    fn from(err: io::Error) -> Self {
+        CalibrationError::Io(err)
+    }
+}
+
+/// How the drone is held in an orientation of the accelerometer calibration
+fn placement(orientation: ImuOrientation) -> &'static str {
+    match orientation {
+        ImuOrientation::ZUp => "level, the way it stands on the ground",
+        ImuOrientation::ZDown => "upside down",
+        ImuOrientation::XUp => "on its side with the x axis of the board pointing up",
+        ImuOrientation::XDown => "on its side with the x axis of the board pointing down",
+        ImuOrientation::YUp => "on its side with the y axis of the board pointing up",
+        ImuOrientation::YDown => "on its side with the y axis of the board pointing down",
+    }
+}
+
+/// Steps through the calibration, every command gets its own sequence number
+struct Calibration<'a> {
+    serial: &'a Link,
+    decoder: FrameDecoder,
+    seq: u16,
+}
+
+impl Calibration<'_> {
+    /// Start a step on the drone and wait until it is over, the progress is shown on one line
+    fn run(&mut self, step: ImuCalibrationStep) -> Result<ImuCalibrationState, CalibrationError> {
+        self.seq = self.seq.wrapping_add(1).max(1);
+        let seq = self.seq;
+        request(self.serial, &mut self.decoder, Packet::with_seq(Message::ImuCalibrate(step), seq), |message| match message {
+            Message::Ack(ack) if ack == seq => Some(Ok(())),
+            Message::Nack(nack, reason) if nack == seq => Some(Err(CalibrationError::Rejected(reason))),
+            _ => None,
+        }).unwrap_or(Err(CalibrationError::NoResponse))?;
+
+        let mut last_status = Instant::now();
+        while last_status.elapsed() < STATUS_TIMEOUT {
+            match read_message(self.serial, &mut self.decoder).map(|packet| packet.message) {
+                Some(Message::ImuCalibrationStatus(reported, state)) if reported == step => match state {
+                    ImuCalibrationState::Sampling(percent) => {
+                        print!("\r{}: {}%", step, percent);
+                        io::stdout().flush()?;
+                        last_status = Instant::now();
+                    }
+                    state => {
+                        println!("\r{}: {}", step, state);
+                        return Ok(state);
+                    }
+                },
+                _ => (),
+            }
+        }
+        Err(CalibrationError::NoResponse)
+    }
+
+    /// Ask the user to hold the drone still and run the step, it is repeated until the drone accepts
+    /// the samples. When they can not be stored, trying again does not help.
+    fn run_guided(&mut self, step: ImuCalibrationStep, placement: &str) -> Result<(), CalibrationError> {
+        loop {
+            println!("\rHold the drone still {} and press enter", placement);
+            stdin().read_line(&mut String::new())?;
+            match self.run(step)? {
+                ImuCalibrationState::Failed(reason @ (NackReason::Moving | NackReason::WrongOrientation)) => {
+                    println!("\r{}, trying again", reason)
+                }
+                ImuCalibrationState::Failed(reason) => return Err(CalibrationError::Rejected(reason)),
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Calibrate the raw IMU of the drone, which has to be in SafeMode. The gyro bias is measured
+/// first, the six orientation calibration of the accelerometer follows when the user asks for it.
+/// The drone stores the results in flash.
+pub fn calibrate_imu(serial: &Link) -> Result<(), CalibrationError> {
+    let mut calibration = Calibration { serial, decoder: FrameDecoder::new(), seq: 0 };
+    calibration.run_guided(ImuCalibrationStep::Gyro, "on the ground")?;
+
+    println!("\rCalibrate the accelerometer in six orientations? [y/N]");
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        return Ok(());
+    }
+
+    for orientation in ImuOrientation::ALL {
+        calibration.run_guided(ImuCalibrationStep::Accel(orientation), placement(orientation))?;
+    }
+    Ok(())
+}
+
+/// Forget the IMU calibration stored on the drone
+pub fn reset_imu_calibration(serial: &Link) -> Result<(), CalibrationError> {
+    let mut calibration = Calibration { serial, decoder: FrameDecoder::new(), seq: 0 };
+    match calibration.run(ImuCalibrationStep::Reset)? {
+        ImuCalibrationState::Failed(reason) => Err(CalibrationError::Rejected(reason)),
+        _ => Ok(()),
+    }
+}
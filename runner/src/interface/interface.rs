@@ -1,21 +1,25 @@
 use crossterm::{terminal::{disable_raw_mode, enable_raw_mode}, execute, cursor::Show};
 use std::{error::Error as OtherError, io::{self, stdout}, sync::mpsc::{self, Sender, Receiver}, time::{Instant, Duration}};
-use serial2::{SerialPort};
-use protocol::{self, Message, WorkingModes, Datalog};
-use crate::interface::{pc_transmission::{write_packet, write_message}, settings_logic::{DeviceListener, SettingsBundle}};
+use super::link::Link;
+use protocol::{self, Message, Packet, WorkingModes, Datalog, ParamInfo, FrameDecoder, LogLevel, CrashReport, TelemetryConfig};
+use crate::interface::{pc_transmission::{write_packet, write_message, handshake, ReliableSender, GainSender, Outbox, DeliveryStatus, CommandStatus, LinkQuality}, settings_logic::{DeviceListener, SettingsBundle}};
 use single_value_channel::{Updater};
-use super::{pc_transmission::read_message, database::DatabaseManager, gui::QuadrupelGUI};
+use super::{pc_transmission::{read_message, set_session}, database::DatabaseManager, gui::QuadrupelGUI, time_sync::{ClockSync, SYNC_INTERVAL, sync_request, host_time_us}};
 use eframe::egui::{self};
 
 /// Setup PC terminal interface for PC-drone communication
-pub fn setup_interface(serial: &SerialPort) -> Result<(), Box<dyn OtherError>> {
-
-    // Setup terminal
-    enable_raw_mode()?;
+pub fn setup_interface(serial: &Link) -> Result<(), Box<dyn OtherError>> {
 
     // Put drone in safemode
     write_packet(&serial, Message::SafeMode);
 
+    // Check that runner and firmware speak the same protocol, the drone stays in safemode otherwise
+    let drone_info = handshake(serial)?;
+    println!("\rConnected to drone: {}", drone_info);
+
+    // Setup terminal
+    enable_raw_mode()?;
+
     // Run interface
     let res = run_interface(serial);
 
@@ -36,13 +40,32 @@ pub fn setup_interface(serial: &SerialPort) -> Result<(), Box<dyn OtherError>> {
 }
 
 /// Run the PC terminal interface
-fn run_interface(serial: &SerialPort) -> io::Result<()> {
+fn run_interface(serial: &Link) -> io::Result<()> {
 
     // Channel to let read_serial thread knwo when to exit
     let (tx_exit, rx_exit) = mpsc::channel();
 
     // Channel to send user input to write_serial thread
     let (mut rx_input, tx_input) = single_value_channel::channel();
+
+    // Channel to forward ACK/NACK replies from the read_serial to the write_serial thread
+    let (tx_reply, rx_reply) = mpsc::channel();
+
+    // Channels for parameter access, commands from the GUI to the write_serial thread and parameters from the drone to the GUI
+    let (tx_command, rx_command) = mpsc::channel();
+    let (tx_param, rx_param) = mpsc::channel();
+
+    // Channel to show the result of the GUI commands, the drone gives a reason when it rejects them
+    let (tx_status, rx_status) = mpsc::channel();
+
+    // Channel to let the write_serial thread know the drone restarted, it forgot the telemetry config
+    let (tx_restart, rx_restart) = mpsc::channel();
+
+    // Channel to let the read_serial thread know when a mode change was sent, to measure the latency
+    let (tx_sent, rx_sent) = mpsc::channel();
+
+    // Channel to show log messages of the drone in the GUI
+    let (tx_log, rx_log) = mpsc::channel();
     
     // GUI initialisation
     println!("\rStarting GUI...");
@@ -58,6 +81,9 @@ fn run_interface(serial: &SerialPort) -> io::Result<()> {
     // Channels to send command to drone information and datalog from drone to terminal interface
     let (rx_gui_pc_command, tx_gui_pc_command) = single_value_channel::channel();
     let (rx_gui_datalog, tx_gui_datalog) = single_value_channel::channel();
+    let (rx_gui_delivery, tx_gui_delivery) = single_value_channel::channel();
+    let (rx_gui_link, tx_gui_link) = single_value_channel::channel();
+    let (rx_gui_crash, tx_gui_crash) = single_value_channel::channel();
  
     // Start a user input, write serial and read serial thread.
     std::thread::scope(|s| {
@@ -69,15 +95,15 @@ fn run_interface(serial: &SerialPort) -> io::Result<()> {
 
         // Write serial thread
         s.spawn(|| {
-            write_serial(serial, tx_exit, tx_gui_pc_command, &mut rx_input);
+            write_serial(serial, tx_exit, tx_gui_pc_command, tx_gui_delivery, &mut rx_input, rx_reply, rx_command, tx_status, rx_restart, tx_sent);
         });
 
         // Read serial thread
         s.spawn(|| {
-            read_serial(serial, rx_exit, tx_gui_datalog);
+            read_serial(serial, rx_exit, tx_gui_datalog, tx_gui_link, tx_gui_crash, tx_reply, tx_param, tx_log, tx_restart, rx_sent);
         });
 
-        eframe::run_native("Quadrupel Interface", native_options, Box::new(|cc| Box::new(QuadrupelGUI::new(cc, rx_gui_pc_command, rx_gui_datalog)))).unwrap();
+        eframe::run_native("Quadrupel Interface", native_options, Box::new(|cc| Box::new(QuadrupelGUI::new(cc, rx_gui_pc_command, rx_gui_datalog, rx_gui_delivery, rx_gui_link, rx_gui_crash, rx_param, rx_log, rx_status, tx_command)))).unwrap();
     });
 
     return Ok(())
@@ -112,14 +138,70 @@ fn get_user_input(tx_input: Updater<Option<SettingsBundle>>) {
 }
 
 /// Write messages over serial to drone
-fn write_serial(serial: &SerialPort, tx_exit: Sender<bool>, tx_tui1: Updater<Option<SettingsBundle>>, rx_input: &mut single_value_channel::Receiver<Option<SettingsBundle>>) {
+fn write_serial(serial: &Link, tx_exit: Sender<bool>, tx_tui1: Updater<Option<SettingsBundle>>, tx_delivery: Updater<Option<DeliveryStatus>>, rx_input: &mut single_value_channel::Receiver<Option<SettingsBundle>>, rx_reply: Receiver<Message>, rx_command: Receiver<Message>, tx_status: Sender<CommandStatus>, rx_restart: Receiver<()>, tx_sent: Sender<(WorkingModes, u64)>) {
 
     let mut time = Instant::now();
     let mut paniced_once = false;
+    let mut sender = ReliableSender::new();
+    let mut gains = GainSender::default();
+    let mut last_sync: Option<Instant> = None;
+
+    // Packets of one iteration, sent highest priority first at the end of it
+    let mut out = Outbox::default();
+
+    // Only stream the telemetry the GUI shows, it can be changed from the GUI
+    let mut telemetry = TelemetryConfig::default();
+    sender.send_command(&mut out, Message::TelemetryConfig(telemetry));
+
+    // The gains are set by name, so the indices are needed from the parameter table
+    if cfg!(not(feature = "legacy-setpoints")) {
+        out.write(Message::ParamList);
+    }
 
     // Write messages to drone until exit command is given
     loop {
 
+        // Check if pending mode changes and commands have been accepted or rejected by the drone,
+        // and learn the parameter indices for the gains
+        while let Ok(reply) = rx_reply.try_recv() {
+            if let Some(status) = sender.handle_reply(&reply) {
+                tx_delivery.update(Some(status)).unwrap();
+            }
+            if let Some(status) = sender.handle_command_reply(&reply) {
+                gains.handle_status(&status);
+                let _ = tx_status.send(status);
+            }
+            if let Message::Param(info) = &reply {
+                gains.handle_param(info);
+            }
+        }
+        if let Some(status) = sender.retransmit(&mut out) {
+            tx_delivery.update(Some(status)).unwrap();
+        }
+        for status in sender.retransmit_commands(&mut out) {
+            gains.handle_status(&status);
+            let _ = tx_status.send(status);
+        }
+
+        // Relate the drone clock to the host clock
+        if last_sync.map_or(true, |sync| sync.elapsed() >= SYNC_INTERVAL) {
+            out.write(sync_request());
+            last_sync = Some(Instant::now());
+        }
+
+        // A restarted drone streams its default telemetry, so it gets the config again
+        while let Ok(()) = rx_restart.try_recv() {
+            sender.send_command(&mut out, Message::TelemetryConfig(telemetry));
+        }
+
+        // Forward parameter commands from the GUI
+        while let Ok(command) = rx_command.try_recv() {
+            if let Message::TelemetryConfig(config) = command {
+                telemetry = config;
+            }
+            sender.send_command(&mut out, command);
+        }
+
         // Receive user input from get_user_input thread
         let bundle = *rx_input.latest();
 
@@ -148,51 +230,166 @@ fn write_serial(serial: &SerialPort, tx_exit: Sender<bool>, tx_tui1: Updater<Opt
                     break;
                 }
 
-                // Send message to drone
-                write_message(serial, bundle);
+                // Send message to drone, the setpoint messages do not carry the gains
+                if cfg!(not(feature = "legacy-setpoints")) {
+                    gains.send(&mut out, &mut sender, &bundle);
+                }
+                if let Some(status) = write_message(&mut out, &mut sender, bundle) {
+                    if let DeliveryStatus::Pending(mode) = status {
+                        let _ = tx_sent.send((mode, host_time_us()));
+                    }
+                    tx_delivery.update(Some(status)).unwrap();
+                }
 
             }
         }
 
+        // Send the packets of this iteration
+        out.flush(serial);
+
         // Make sure loop runs at specified frequency
         while time.elapsed() < Duration::from_millis(10) {}
         time = Instant::now();
     }
 }
 
+/// Latency from sending a mode change until the telemetry shows the new mode, in us
+fn command_latency(sent_mode: &mut Option<(WorkingModes, u64)>, datalog: &Datalog) -> Option<u64> {
+    match *sent_mode {
+        Some((mode, sent_at)) if datalog.workingmode == mode => {
+            *sent_mode = None;
+            Some(host_time_us().saturating_sub(sent_at))
+        }
+        _ => None,
+    }
+}
+
 /// Read messages from drone, sent over serial
-fn read_serial(serial: &SerialPort, rx_exit: Receiver<bool>, tx_tui2: Updater<Option<Datalog>>) {
-    let mut shared_buf = Vec::new();
-    let mut buf = [0u8; 255];
-    let debug = false;
+fn read_serial(serial: &Link, rx_exit: Receiver<bool>, tx_tui2: Updater<Option<Datalog>>, tx_link: Updater<Option<LinkQuality>>, tx_crash: Updater<Option<CrashReport>>, tx_reply: Sender<Message>, tx_param: Sender<ParamInfo>, tx_log: Sender<(LogLevel, String)>, tx_restart: Sender<()>, rx_sent: Receiver<(WorkingModes, u64)>) {
+    let mut decoder = FrameDecoder::new();
+    let mut link = LinkQuality::default();
+    let mut clock = ClockSync::new();
+
+    // Last mode change that has not shown up in the telemetry yet, with the host time it was sent
+    let mut sent_mode: Option<(WorkingModes, u64)> = None;
+
+    // Datalog assembled from the telemetry groups of one tick of the drone
+    let mut assembled: Option<Datalog> = None;
+
+    // Drone time of the last datalog, to extend the wrapping time of compact datalogs
+    let mut last_rtc: u64 = 0;
 
     loop {
+        // Read the packet that is sent by the drone
+        let packet_result = read_message(serial, &mut decoder);
 
-        // Either print panic messages or show TUI
-        if debug == true {
-            if let Ok(num) = serial.read(&mut buf) {
-                println!("{:?}", String::from_utf8_lossy(&buf[0..num]));
-            }
+        // Show link quality of both sides in the GUI
+        if decoder.stats() != link.pc {
+            link.pc = decoder.stats();
+            tx_link.update(Some(link)).unwrap();
+        }
+
+        while let Ok(sent) = rx_sent.try_recv() {
+            sent_mode = Some(sent);
+        }
+
+        // Check if packet is received correctly
+        match packet_result {
+            None => (),
+            Some(mut packet) => {
+
+                // Compact datalogs are converted back, so the rest of the runner only deals with Datalog
+                if let Message::CompactDatalogging(compact) = packet.message {
+                    packet.message = Message::Datalogging(compact.to_datalog(last_rtc));
+                }
+
+                match packet.message {
+                    Message::Datalogging(d) => {
+                        last_rtc = d.rtc;
+
+                        // Store datalog in json format, named after the host time the datalog was made
+                        DatabaseManager::create_json(&packet, clock.drone_to_host(d.rtc * 1000));
 
-        } else {
-            // Read the packet that is sent by the drone
-            let packet_result = read_message(serial, &mut shared_buf);
+                        // Latency from sending a mode change until the telemetry shows it
+                        if let Some(latency) = command_latency(&mut sent_mode, &d) {
+                            link.latency = Some(latency);
+                            tx_link.update(Some(link)).unwrap();
+                        }
+
+                        // Send datalog to terminal interface
+                        tx_tui2.update(Some(d)).unwrap();
+                    }
+                    Message::Telemetry(rtc, telemetry) => {
+                        let mut datalog = assembled.unwrap_or_else(Datalog::new);
+
+                        // Telemetry of a new tick, store the datalog of the previous tick
+                        if assembled.is_some() && datalog.rtc != rtc {
+                            DatabaseManager::create_json(&Packet::new(Message::Datalogging(datalog)), clock.drone_to_host(datalog.rtc * 1000));
+                        }
 
-            // Check if packet is received correctly
-            match packet_result {
-                None => (),
-                Some(packet) => {
-                    match packet.message {
-                        Message::Datalogging(d) => {
+                        // Fields of groups that are not streamed keep their last value
+                        datalog.rtc = rtc;
+                        last_rtc = rtc;
+                        telemetry.apply(&mut datalog);
+                        assembled = Some(datalog);
 
-                            // Store datalog in json format
-                            DatabaseManager::create_json(&packet);
+                        // Send datalog to terminal interface
+                        tx_tui2.update(Some(datalog)).unwrap();
 
-                            // Send datalog to terminal interface
-                            tx_tui2.update(Some(d)).unwrap();
+                        if let Some(latency) = command_latency(&mut sent_mode, &datalog) {
+                            link.latency = Some(latency);
+                            tx_link.update(Some(link)).unwrap();
                         }
-                        _ => ()
                     }
+                    Message::Ack(_) | Message::Nack(_, _) => {
+                        // Let the write_serial thread know the drone answered a command
+                        let _ = tx_reply.send(packet.message);
+                    }
+                    Message::Param(info) => {
+                        // Let the write_serial thread know the parameter indices and show the parameter in the GUI
+                        let _ = tx_reply.send(Message::Param(info.clone()));
+                        let _ = tx_param.send(info);
+                    }
+                    Message::Log { level, ref text } => {
+
+                        // Store log message next to the datalogs
+                        DatabaseManager::create_json(&packet, None);
+
+                        // Show log message in the GUI
+                        let _ = tx_log.send((level, text.to_string()));
+                    }
+                    Message::CrashReport(ref report) => {
+
+                        // Store crash report next to the datalogs
+                        DatabaseManager::create_json(&packet, None);
+
+                        // Log crash and show it in the GUI
+                        let _ = tx_log.send((LogLevel::Error, format!("Drone crashed: {}", report)));
+                        tx_crash.update(Some(report.clone())).unwrap();
+                    }
+                    Message::HelloAck(info) => {
+                        // The drone started again, or stored a new session after the flash log was erased.
+                        // The next packets are signed for its new session
+                        set_session(info.session);
+                    }
+                    Message::TimeSyncReply { host_time, drone_time } => {
+                        // The drone clock went back, so the drone restarted. Its clock is synced again
+                        if clock.restarted(drone_time) {
+                            clock = ClockSync::new();
+                            let _ = tx_restart.send(());
+                            let _ = tx_log.send((LogLevel::Warn, "Drone restarted".to_string()));
+                        }
+                        clock.handle_reply(host_time, drone_time, host_time_us());
+                        let estimate = clock.estimate();
+                        link.rtt = estimate.map(|sample| sample.rtt);
+                        link.offset = estimate.map(|sample| sample.offset);
+                        tx_link.update(Some(link)).unwrap();
+                    }
+                    Message::LinkStatus(stats) => {
+                        link.drone = stats;
+                        tx_link.update(Some(link)).unwrap();
+                    }
+                    _ => ()
                 }
             }
         }
@@ -0,0 +1,42 @@
+use serial2::SerialPort;
+use std::{io::{self, Read, Write}, net::TcpStream, time::Duration};
+
+/// Time a read waits for bytes from the drone
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Connection to the drone, the serial port of the board or the TCP socket of the simulator
+pub enum Link {
+    Serial(SerialPort),
+    Simulator(TcpStream),
+}
+
+impl Link {
+    /// Open the serial port of an uploaded board
+    pub fn open_serial(port: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        let mut serial = SerialPort::open(port, 115200)?;
+        serial.set_read_timeout(READ_TIMEOUT)?;
+        Ok(Link::Serial(serial))
+    }
+
+    /// Connect to a running simulator
+    pub fn connect_simulator(address: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        Ok(Link::Simulator(stream))
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Link::Serial(serial) => serial.read(buf),
+            Link::Simulator(stream) => (&*stream).read(buf),
+        }
+    }
+
+    pub fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Link::Serial(serial) => serial.write_all(buf),
+            Link::Simulator(stream) => (&*stream).write_all(buf),
+        }
+    }
+}
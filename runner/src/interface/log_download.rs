@@ -0,0 +1,157 @@
+use super::link::Link;
+use std::{fmt, error::Error, io::{self, Read, Write}};
+use std::fs::{self, OpenOptions};
+use protocol::{Message, Packet, NackReason, FrameDecoder, LOG_CHUNK_LEN, LOG_RECORD_SIZE, chunk_crc};
+use super::{pc_transmission::request, database::DatabaseManager};
+
+/// Bytes downloaded so far, a next download continues at the end of this file
+const PART_FILE: &str = "database/flash_log.part";
+
+/// Errors that stop a flash log download
+#[derive(Debug)]
+pub enum DownloadError {
+    NoResponse,
+    Io(io::Error),
+    Store(Box<dyn Error>),
+    EraseRejected(NackReason),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DownloadError::NoResponse => write!(f, "drone stopped answering"),
+            DownloadError::Io(err) => write!(f, "{}", err),
+            DownloadError::Store(err) => write!(f, "storing the log failed: {}", err),
+            DownloadError::EraseRejected(reason) => write!(f, "erase rejected: {}", reason),
+        }
+    }
+}
+
+impl Error for DownloadError {}
+
+impl From<io::Error> for DownloadError {
+    fn from(err: io::Error) -> Self {
+        DownloadError::Io(err)
+    }
+}
+
+/// Read a chunk of the flash log, chunks with a wrong checksum are requested again
+fn read_chunk(serial: &Link, decoder: &mut FrameDecoder, offset: u32, len: u16) -> Result<Vec<u8>, DownloadError> {
+    request(serial, decoder, Packet::new(Message::LogReadChunk(offset, len)), |message| match message {
+        Message::LogChunk { offset: chunk_offset, data, crc } if chunk_offset == offset && chunk_crc(&data) == crc => Some(data.to_vec()),
+        _ => None,
+    }).ok_or(DownloadError::NoResponse)
+}
+
+/// Download the drone flash log into the database directory, returns the number of records.
+/// The downloaded bytes are kept in a part file, so a download that stopped because the link
+/// dropped continues where it was when it is started again.
+pub fn download_logs(serial: &Link) -> Result<u32, DownloadError> {
+    let mut decoder = FrameDecoder::new();
+
+    let (records, used_bytes) = request(serial, &mut decoder, Packet::new(Message::LogInfoRequest), |message| match message {
+        Message::LogInfo { records, used_bytes, .. } => Some((records, used_bytes)),
+        _ => None,
+    }).ok_or(DownloadError::NoResponse)?;
+
+    fs::create_dir_all("database")?;
+    let mut part = OpenOptions::new().create(true).read(true).append(true).open(PART_FILE)?;
+    let mut offset = part.metadata()?.len() as u32;
+
+    // Only resume when the drone still has the same log, it might have been erased in between
+    if offset > 0 {
+        let len = offset.min(LOG_CHUNK_LEN as u32) as u16;
+        let mut start = vec![0u8; len as usize];
+        part.read_exact(&mut start)?;
+
+        if offset > used_bytes || read_chunk(serial, &mut decoder, 0, len)? != start {
+            println!("\rDrone log changed since the last download, starting again");
+            part.set_len(0)?;
+            offset = 0;
+        }
+    }
+
+    println!("\rDownloading {} records ({} bytes), starting at byte {}", records, used_bytes, offset);
+
+    while offset < used_bytes {
+        let len = (used_bytes - offset).min(LOG_CHUNK_LEN as u32) as u16;
+        let data = read_chunk(serial, &mut decoder, offset, len)?;
+        if data.is_empty() {
+            break;
+        }
+
+        part.write_all(&data)?;
+        offset += data.len() as u32;
+        print!("\r{} / {} bytes", offset, used_bytes);
+        io::stdout().flush()?;
+    }
+    println!();
+
+    // Decode the records and store them with the other logs
+    let bytes = fs::read(PART_FILE)?;
+    let messages = decode_records(&bytes);
+    let file_name = DatabaseManager::store_flash_log(&messages).map_err(DownloadError::Store)?;
+    println!("\rStored {} of {} records in {}", messages.len(), records, file_name);
+
+    fs::remove_file(PART_FILE)?;
+    Ok(records)
+}
+
+/// Decode the flash log records, the datalogs are stored in compact form and come back as full datalogs
+fn decode_records(bytes: &[u8]) -> Vec<Message> {
+    let mut last_rtc = 0;
+    bytes.chunks(LOG_RECORD_SIZE)
+        .filter_map(|record| postcard::from_bytes(record).ok())
+        .map(|message| match message {
+            Message::CompactDatalogging(compact) => {
+                let datalog = compact.to_datalog(last_rtc);
+                last_rtc = datalog.rtc;
+                Message::Datalogging(datalog)
+            }
+            message => message,
+        })
+        .collect()
+}
+
+/// Erase the drone flash log. The number of downloaded records confirms the erase, the drone
+/// refuses it when more records were stored since the download.
+pub fn erase_logs(serial: &Link, records: u32) -> Result<(), DownloadError> {
+    let mut decoder = FrameDecoder::new();
+    let seq = 1;
+
+    request(serial, &mut decoder, Packet::with_seq(Message::LogErase(records), seq), |message| match message {
+        Message::Ack(ack) if ack == seq => Some(Ok(())),
+        Message::Nack(nack, reason) if nack == seq => Some(Err(DownloadError::EraseRejected(reason))),
+        _ => None,
+    }).unwrap_or(Err(DownloadError::NoResponse))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{CompactDatalog, Datalog};
+
+    #[test]
+    fn test_decode_records() {
+        let mut datalog = Datalog::new();
+        datalog.rtc = 3_600_000;
+        datalog.motor2 = 420;
+
+        // Two datalog records, then erased flash
+        let mut bytes = vec![0xff; 3 * LOG_RECORD_SIZE];
+        for record in bytes.chunks_mut(LOG_RECORD_SIZE).take(2) {
+            postcard::to_slice(&Message::CompactDatalogging(CompactDatalog::from(&datalog)), record).unwrap();
+            datalog.rtc += 100;
+        }
+
+        let messages = decode_records(&bytes);
+        assert_eq!(messages.len(), 2);
+        match messages[1] {
+            Message::Datalogging(decoded) => {
+                assert_eq!(decoded.motor2, 420);
+                assert_eq!(decoded.rtc, 3_600_100);
+            }
+            ref message => panic!("{:?}", message),
+        }
+    }
+}
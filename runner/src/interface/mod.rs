@@ -5,4 +5,8 @@ pub mod joystick_mapper;
 pub mod settings_logic;
 pub mod database;
 pub mod plotters_piston;
-pub mod gui;
\ No newline at end of file
+pub mod gui;
+pub mod parameters;
+pub mod time_sync;
+pub mod log_download;pub mod link;
+pub mod imu_calibration;
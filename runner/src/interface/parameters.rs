@@ -0,0 +1,76 @@
+use protocol::{ParamInfo, ParamValue};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Parameter as reported by the drone, together with the text the user typed for it in the GUI
+#[derive(Debug, Clone)]
+pub struct ParameterEntry {
+    pub info: ParamInfo,
+    pub input: String,
+}
+
+/// Copy of the drone parameter table, filled by the Param messages the drone sends
+#[derive(Debug, Default)]
+pub struct ParameterTable {
+    entries: BTreeMap<u8, ParameterEntry>,
+}
+
+impl ParameterTable {
+    pub fn new() -> Self {
+        ParameterTable { entries: BTreeMap::new() }
+    }
+
+    /// Store a parameter received from the drone, the input field is reset to the new value
+    pub fn update(&mut self, info: ParamInfo) {
+        let input = info.value.to_string();
+        self.entries.insert(info.index, ParameterEntry { info, input });
+    }
+
+    /// Number of parameters the drone reported to have, 0 if nothing is received yet
+    pub fn expected(&self) -> usize {
+        self.entries.values().next().map_or(0, |entry| entry.info.count as usize)
+    }
+
+    pub fn received(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Name of a parameter, if the drone reported it
+    pub fn name(&self, index: u8) -> Option<&str> {
+        self.entries.get(&index).map(|entry| entry.info.name.as_str())
+    }
+
+    pub fn entries_mut(&mut self) -> impl Iterator<Item = &mut ParameterEntry> {
+        self.entries.values_mut()
+    }
+
+    /// Store all parameters in database/parameters.json
+    pub fn dump(&self) -> io::Result<()> {
+        if !Path::new("database").is_dir() {
+            fs::create_dir("database")?;
+        }
+
+        let parameters: Vec<&ParamInfo> = self.entries.values().map(|entry| &entry.info).collect();
+        let json = serde_json::to_string_pretty(&parameters)?;
+
+        let mut file = File::create("database/parameters.json")?;
+        file.write_all(json.as_bytes())
+    }
+}
+
+/// Parse the text typed by the user into a value of the same type as the parameter,
+/// values outside the limits are refused before they are sent to the drone
+pub fn parse_value(info: &ParamInfo, text: &str) -> Result<ParamValue, String> {
+    let value = match info.default {
+        ParamValue::F32(_) => text.trim().parse::<f32>().map(ParamValue::F32).map_err(|err| err.to_string())?,
+        ParamValue::U16(_) => text.trim().parse::<u16>().map(ParamValue::U16).map_err(|err| err.to_string())?,
+    };
+
+    if !value.within(&info.min, &info.max) {
+        return Err(format!("{} is outside {}..{}", value, info.min, info.max));
+    }
+
+    Ok(value)
+}
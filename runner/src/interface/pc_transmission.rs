@@ -1,14 +1,158 @@
-use serial2::SerialPort;
-use protocol::{self, Packet, Message, WorkingModes};
+use super::link::Link;
+use std::{fmt, env, error::Error, collections::{HashMap, VecDeque}, sync::{Mutex, atomic::{AtomicU8, Ordering}}, time::{Duration, Instant}};
+use protocol::{self, Packet, PacketManager, Message, WorkingModes, LinkInfo, LinkError, NackReason, FrameDecoder, LinkStats, ParamInfo, ParamValue, Authenticator, auth::parse_key, capabilities, PC_ID, BROADCAST_ID, MAX_COMMANDS_IN_FLIGHT};
 use super::settings_logic::{SettingsBundle};
+use super::time_sync::host_time_us;
+
+/// Build id of this runner, sent to the drone in the link handshake
+pub const RUNNER_BUILD_ID: u32 = protocol::build_id(concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION")));
+
+/// Features the runner needs from the drone firmware
+pub const REQUIRED_CAPABILITIES: u32 = capabilities::MANUAL_MODE
+    | capabilities::CALIBRATION_MODE
+    | capabilities::YAW_CONTROL_MODE
+    | capabilities::FULL_CONTROL_MODE
+    | capabilities::HEIGHT_CONTROL_MODE
+    | capabilities::RAW_SENSOR_MODE
+    | capabilities::DATALOGGING
+    | capabilities::RELIABLE_COMMANDS
+    | capabilities::PARAMETERS
+    | capabilities::LINK_STATS
+    | capabilities::TIME_SYNC
+    | capabilities::TELEMETRY_GROUPS
+    | capabilities::COMPACT_DATALOG
+    | capabilities::LOG_DOWNLOAD
+    | capabilities::ADDRESSING
+    | capabilities::MODE_GUARDS
+    | capabilities::ESTIMATORS
+    | capabilities::AHRS
+    | capabilities::IMU_CALIBRATION
+    | if cfg!(feature = "legacy-setpoints") { 0 } else { capabilities::SETPOINTS };
+
+const HANDSHAKE_ATTEMPTS: usize = 5;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+const ACK_TIMEOUT: Duration = Duration::from_millis(100);
+const MAX_TRANSMISSIONS: usize = 5;
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Number of packets the send loop can write in one iteration
+const OUTBOX_LEN: usize = 16;
+
+/// Environment variable with the pre-shared key, the firmware reads the same variable when it is built
+pub const AUTH_KEY_VAR: &str = "DRONE_AUTH_KEY";
+
+/// Signs the packets that are sent in the authenticated mode. It is shared by all threads
+/// that write to the drone, so the counter keeps increasing.
+static AUTHENTICATOR: Mutex<Option<Authenticator>> = Mutex::new(None);
+
+/// Enable the authenticated mode when the key environment variable is set, returns whether it is enabled.
+/// The counter starts at the host time in us, so it is newer than the packets sent in a previous run.
+pub fn enable_authentication() -> Result<bool, String> {
+    let hex = match env::var(AUTH_KEY_VAR) {
+        Ok(hex) => hex,
+        Err(_) => return Ok(false),
+    };
+    let key = parse_key(hex.trim()).ok_or(format!("{} must be 32 hex characters", AUTH_KEY_VAR))?;
+
+    *AUTHENTICATOR.lock().unwrap() = Some(Authenticator::new(key, host_time_us()));
+    Ok(true)
+}
+
+fn authentication_enabled() -> bool {
+    AUTHENTICATOR.lock().unwrap().is_some()
+}
+
+/// Sign the next packets for the session the drone reported in a HelloAck. The drone sends one
+/// when it starts, and when it could only store its session after the flash log was erased.
+/// Packets signed for the session before are refused.
+pub fn set_session(session: u32) {
+    if let Some(authenticator) = AUTHENTICATOR.lock().unwrap().as_mut() {
+        authenticator.set_session(session);
+    }
+}
+
+/// System id of the drone this runner talks to, learned from the HelloAck.
+/// Until the handshake is done packets are broadcast.
+static DRONE_ID: AtomicU8 = AtomicU8::new(BROADCAST_ID);
+
+pub fn drone_id() -> u8 {
+    DRONE_ID.load(Ordering::Relaxed)
+}
+
+/// Errors that can occur during the link handshake
+#[derive(Debug)]
+pub enum HandshakeError {
+    NoResponse,
+    Incompatible { drone: LinkInfo, error: LinkError },
+    KeyRequired,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandshakeError::NoResponse => write!(f, "drone did not answer the handshake, \
+                it might be running firmware that speaks a different protocol version than v{}", protocol::PROTOCOL_VERSION),
+            HandshakeError::Incompatible { drone, error } => write!(f, "{} (drone: {}), refusing to leave SafeMode", error, drone),
+            HandshakeError::KeyRequired => write!(f, "drone only accepts authenticated commands, set {}", AUTH_KEY_VAR),
+        }
+    }
+}
+
+impl Error for HandshakeError {}
+
+/// Exchange Hello/HelloAck with the drone and check that runner and firmware are compatible
+pub fn handshake(serial: &Link) -> Result<LinkInfo, HandshakeError> {
+    let local = LinkInfo::new(RUNNER_BUILD_ID, REQUIRED_CAPABILITIES);
+    let mut decoder = FrameDecoder::new();
+
+    for _ in 0..HANDSHAKE_ATTEMPTS {
+        write_packet(serial, Message::Hello(local));
+
+        // Wait for the answer, telemetry that is still coming in is skipped
+        let start = Instant::now();
+        while start.elapsed() < HANDSHAKE_TIMEOUT {
+            if let Some(packet) = read_message(serial, &mut decoder) {
+                if let Message::HelloAck(drone) = packet.message {
+                    DRONE_ID.store(packet.source, Ordering::Relaxed);
+                    set_session(drone.session);
+                    if drone.capabilities & capabilities::AUTHENTICATION != 0 && !authentication_enabled() {
+                        return Err(HandshakeError::KeyRequired);
+                    }
+                    return match local.check_compatible(&drone, REQUIRED_CAPABILITIES) {
+                        Ok(()) => Ok(drone),
+                        Err(error) => Err(HandshakeError::Incompatible { drone, error }),
+                    };
+                }
+            }
+        }
+    }
+
+    Err(HandshakeError::NoResponse)
+}
 
 /// Write packet to the drone. Used by the function 'write_message'
-pub fn write_packet(serial: &SerialPort, message: Message) {
+pub fn write_packet(serial: &Link, message: Message) {
 
     // Create packet
-    let mut packet = Packet::new(message);
+    let packet = Packet::new(message);
     // println!("\rCommand to drone: {:?}", message);
 
+    send_packet(serial, packet);
+}
+
+/// Serialize a packet and write it over the serial port, it is signed in the authenticated mode.
+/// Hello and the safety commands are broadcast, so they reach the drone even when its id is not known.
+pub fn send_packet(serial: &Link, packet: Packet) {
+    let destination = match packet.message {
+        Message::Hello(_) | Message::SafeMode | Message::PanicMode => BROADCAST_ID,
+        _ => drone_id(),
+    };
+    let mut packet = packet.addressed(PC_ID, destination);
+
+    if let Some(authenticator) = AUTHENTICATOR.lock().unwrap().as_mut() {
+        authenticator.sign(&mut packet);
+    }
+
     // Serialize packet
     let serialized_packet = packet.to_bytes();
 
@@ -19,18 +163,278 @@ pub fn write_packet(serial: &SerialPort, message: Message) {
     };
 }
 
-/// Read message from the data
-pub fn read_packet(buf: &mut [u8]) -> Result<Packet, ()> {      
-    if let Ok(packet) = Packet::from_bytes(buf) {    
-        // write_packet(packet.message);                   
-        Ok(packet)
-    } else {
-        Err(())
+/// Packets the send loop wrote in one iteration. They are sent highest priority first when the loop
+/// flushes, so safe and panic commands and retransmissions are not stuck behind setpoints.
+/// Packets are signed when they are sent, so the authentication counter keeps the send order.
+#[derive(Default)]
+pub struct Outbox {
+    queue: PacketManager<OUTBOX_LEN>,
+}
+
+impl Outbox {
+    /// Queue a packet, when the outbox is full of more important packets it is dropped
+    pub fn push(&mut self, packet: Packet) {
+        self.queue.add_packet(packet).ok();
+    }
+
+    /// Queue a message without a sequence number
+    pub fn write(&mut self, message: Message) {
+        self.push(Packet::new(message));
+    }
+
+    /// Send all queued packets over the serial port
+    pub fn flush(&mut self, serial: &Link) {
+        while let Some(packet) = self.queue.read_packet() {
+            send_packet(serial, packet);
+        }
+    }
+}
+
+/// Delivery state of the last mode change command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveryStatus {
+    Pending(WorkingModes),
+    Accepted(WorkingModes),
+    Rejected(WorkingModes, NackReason),
+    TimedOut(WorkingModes),
+}
+
+// Convert DeliveryStatus enum to string
+impl fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeliveryStatus::Pending(mode) => write!(f, "{} pending", mode),
+            DeliveryStatus::Accepted(mode) => write!(f, "{} accepted", mode),
+            DeliveryStatus::Rejected(mode, reason) => write!(f, "{} rejected: {}", mode, reason),
+            DeliveryStatus::TimedOut(mode) => write!(f, "{} not acknowledged", mode),
+        }
+    }
+}
+
+/// Final result of a command that is not a mode change, e.g. a parameter change
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandStatus {
+    Accepted(Message),
+    Rejected(Message, NackReason),
+    TimedOut(Message),
+}
+
+impl CommandStatus {
+    pub fn message(&self) -> &Message {
+        match self {
+            CommandStatus::Accepted(message) | CommandStatus::Rejected(message, _) | CommandStatus::TimedOut(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for CommandStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandStatus::Accepted(message) => write!(f, "{} accepted", message),
+            CommandStatus::Rejected(message, reason) => write!(f, "{} rejected: {}", message, reason),
+            CommandStatus::TimedOut(message) => write!(f, "{} not acknowledged", message),
+        }
+    }
+}
+
+/// Link statistics of both sides of the link, the drone reports its own with LinkStatus messages.
+/// Times are in us and only known after the first time sync reply.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkQuality {
+    pub pc: LinkStats,
+    pub drone: LinkStats,
+    pub rtt: Option<u64>,       // round trip time of the best time sync sample
+    pub offset: Option<i64>,    // host clock minus drone clock
+    pub latency: Option<u64>,   // time from sending a mode change until telemetry shows the new mode
+}
+
+/// Command that is waiting for an ACK/NACK from the drone
+struct PendingCommand {
+    seq: u16,
+    message: Message,
+    sent_at: Instant,
+    transmissions: usize,
+}
+
+impl PendingCommand {
+    fn send(out: &mut Outbox, message: Message, seq: u16) -> Self {
+        out.push(Packet::with_seq(message.clone(), seq));
+        PendingCommand { seq, message, sent_at: Instant::now(), transmissions: 1 }
+    }
+
+    /// Send the command again when it was not answered in time, returns false when it was sent
+    /// the maximum number of times
+    fn retransmit(&mut self, out: &mut Outbox) -> bool {
+        if self.sent_at.elapsed() >= ACK_TIMEOUT {
+            if self.transmissions >= MAX_TRANSMISSIONS {
+                return false;
+            }
+            self.transmissions += 1;
+            self.sent_at = Instant::now();
+            out.push(Packet::with_seq(self.message.clone(), self.seq));
+        }
+        true
+    }
+}
+
+/// Sends mode changes with a sequence number and retransmits them until the drone
+/// accepted or rejected them. Commands that the drone always answers, like parameter
+/// changes, are tracked the same way. All other messages are sent fire-and-forget.
+/// No more commands are in flight than the drone remembers the replies of, one place
+/// is kept for a mode change and the other commands wait until they get a place.
+pub struct ReliableSender {
+    next_seq: u16,
+    last_mode: Option<WorkingModes>,
+    pending: Option<(WorkingModes, PendingCommand)>,
+    commands: Vec<PendingCommand>,
+    waiting: VecDeque<Message>,
+}
+
+impl ReliableSender {
+    pub fn new() -> Self {
+        ReliableSender { next_seq: 1, last_mode: None, pending: None, commands: Vec::new(), waiting: VecDeque::new() }
+    }
+
+    fn take_seq(&mut self) -> u16 {
+        let seq = self.next_seq;
+
+        // Sequence number 0 means no acknowledgement is needed, so skip it
+        self.next_seq = self.next_seq.wrapping_add(1).max(1);
+        seq
+    }
+
+    /// Send a message, returns the new delivery status when it starts a mode change
+    pub fn send(&mut self, out: &mut Outbox, message: Message) -> Option<DeliveryStatus> {
+        let mode = message.mode();
+
+        match mode {
+            Some(mode) if Some(mode) != self.last_mode => {
+                let seq = self.take_seq();
+                self.last_mode = Some(mode);
+                self.pending = Some((mode, PendingCommand::send(out, message, seq)));
+                Some(DeliveryStatus::Pending(mode))
+            }
+            _ => {
+                out.write(message);
+                None
+            }
+        }
+    }
+
+    /// Retransmit the pending command when it was not acknowledged in time.
+    /// Returns TimedOut when the drone did not answer after the maximum number of transmissions.
+    pub fn retransmit(&mut self, out: &mut Outbox) -> Option<DeliveryStatus> {
+        let (mode, pending) = self.pending.as_mut()?;
+        if pending.retransmit(out) {
+            return None;
+        }
+
+        let mode = *mode;
+        self.pending = None;
+
+        // Forget the mode, so a next command for the same mode is tracked again
+        self.last_mode = None;
+        Some(DeliveryStatus::TimedOut(mode))
+    }
+
+    /// Handle an Ack or Nack from the drone, returns the final delivery status of the pending command
+    pub fn handle_reply(&mut self, reply: &Message) -> Option<DeliveryStatus> {
+        let (mode, pending) = self.pending.as_ref()?;
+
+        let status = match *reply {
+            Message::Ack(seq) if seq == pending.seq => DeliveryStatus::Accepted(*mode),
+            Message::Nack(seq, reason) if seq == pending.seq => {
+                let mode = *mode;
+                self.last_mode = None;
+                DeliveryStatus::Rejected(mode, reason)
+            }
+            // Replies to commands that have been superseded are ignored
+            _ => return None,
+        };
+
+        self.pending = None;
+        Some(status)
+    }
+
+    /// Send a command that is not a mode change. Commands the drone always answers get a sequence
+    /// number, their result is reported by handle_command_reply or retransmit_commands.
+    pub fn send_command(&mut self, out: &mut Outbox, message: Message) {
+        match message {
+            Message::ParamSet(..) | Message::SetEstimator(_) | Message::TelemetryConfig(_) => {
+                // A retransmission of an older value must not overwrite the new one on the drone
+                if let Message::ParamSet(index, _) = message {
+                    self.commands.retain(|command| !matches!(command.message, Message::ParamSet(pending, _) if pending == index));
+                    self.waiting.retain(|waiting| !matches!(waiting, Message::ParamSet(pending, _) if *pending == index));
+                }
+                self.waiting.push_back(message);
+                self.send_waiting(out);
+            }
+            _ => out.write(message),
+        }
     }
+
+    /// Send the waiting commands that have a place
+    fn send_waiting(&mut self, out: &mut Outbox) {
+        while self.commands.len() < MAX_COMMANDS_IN_FLIGHT - 1 {
+            let Some(message) = self.waiting.pop_front() else { break };
+            let seq = self.take_seq();
+            self.commands.push(PendingCommand::send(out, message, seq));
+        }
+    }
+
+    /// Retransmit the commands that were not answered in time, returns the ones the drone
+    /// did not answer after the maximum number of transmissions. Waiting commands are sent
+    /// when others were answered.
+    pub fn retransmit_commands(&mut self, out: &mut Outbox) -> Vec<CommandStatus> {
+        let mut timed_out = Vec::new();
+        self.commands.retain_mut(|command| {
+            let waiting = command.retransmit(out);
+            if !waiting {
+                timed_out.push(CommandStatus::TimedOut(command.message.clone()));
+            }
+            waiting
+        });
+        self.send_waiting(out);
+        timed_out
+    }
+
+    /// Handle an Ack or Nack from the drone, returns the result of the command it answers
+    pub fn handle_command_reply(&mut self, reply: &Message) -> Option<CommandStatus> {
+        let (seq, result) = match *reply {
+            Message::Ack(seq) => (seq, Ok(())),
+            Message::Nack(seq, reason) => (seq, Err(reason)),
+            _ => return None,
+        };
+        let index = self.commands.iter().position(|command| command.seq == seq)?;
+        let message = self.commands.remove(index).message;
+        Some(match result {
+            Ok(()) => CommandStatus::Accepted(message),
+            Err(reason) => CommandStatus::Rejected(message, reason),
+        })
+    }
+}
+
+/// Write message to the drone, mode changes are tracked by the ReliableSender.
+/// The stick values are converted to a setpoint in physical units.
+#[cfg(not(feature = "legacy-setpoints"))]
+pub fn write_message(out: &mut Outbox, sender: &mut ReliableSender, bundle: SettingsBundle) -> Option<DeliveryStatus> {
+
+    // Match user input with drone message
+    let message = match bundle.mode {
+        WorkingModes::SafeMode => Message::SafeMode,
+        WorkingModes::PanicMode => Message::PanicMode,
+        WorkingModes::CalibrationMode => Message::CalibrationMode,
+        mode => Message::Setpoint(mode, protocol::Setpoint::from_sticks([bundle.pitch, bundle.roll, bundle.yaw, bundle.lift])),
+    };
+
+    // Queue message for the drone
+    sender.send(out, message)
 }
 
-/// Write message to the drone
-pub fn write_message(serial: &SerialPort, bundle: SettingsBundle) {
+/// Write message to the drone, mode changes are tracked by the ReliableSender.
+/// Sends the raw stick values and gains with the mode messages, for firmware without setpoints.
+#[cfg(feature = "legacy-setpoints")]
+pub fn write_message(out: &mut Outbox, sender: &mut ReliableSender, bundle: SettingsBundle) -> Option<DeliveryStatus> {
 
     // Match user input with drone message
     let message = match bundle.mode {
@@ -44,41 +448,298 @@ pub fn write_message(serial: &SerialPort, bundle: SettingsBundle) {
         WorkingModes::RawSensorMode => Message::RawSensorMode(bundle.pitch, bundle.roll, bundle.yaw, bundle.lift, bundle.yaw_control_p, bundle.roll_pitch_control_p1, bundle.roll_pitch_control_p2),
     };
 
-    // Write message over serial
-    write_packet(serial, message);             
+    // Queue message for the drone
+    sender.send(out, message)
+}
+
+/// Names of the gain parameters in the drone parameter table, their indices are learned from the Param messages
+const YAW_P: &str = "yaw_p";
+const ANGLE_P: &str = "angle_p";
+const RATE_P: &str = "rate_p";
+const YAW_RATE_P: &str = "yaw_rate_p";
+const HEIGHT_P: &str = "height_p";
+
+/// The keyboard gains are in steps of 1/10000
+const GAIN_SCALE: f32 = 10000.0;
+
+/// Value of a keyboard gain as a parameter
+fn gain_value(gain: u16) -> ParamValue {
+    ParamValue::F32(gain as f32 / GAIN_SCALE)
+}
+
+/// Sends the gains that are set with the keyboard as parameters, now that the setpoint messages
+/// no longer carry them. Only the gains of the current mode are sent, and only when they changed.
+/// A gain that did not reach the drone is sent again.
+#[derive(Default)]
+pub struct GainSender {
+    indices: HashMap<String, u8>,
+    sent: HashMap<u8, u16>,
+}
+
+impl GainSender {
+    /// Learn the index of a parameter from a Param message of the drone, gains are only sent once it is known
+    pub fn handle_param(&mut self, info: &ParamInfo) {
+        self.indices.insert(info.name.to_string(), info.index);
+    }
+
+    /// Forget a gain the drone rejected or did not answer, so it is sent again
+    pub fn handle_status(&mut self, status: &CommandStatus) {
+        if let CommandStatus::Rejected(Message::ParamSet(index, value), _) | CommandStatus::TimedOut(Message::ParamSet(index, value)) = status {
+            // A newer value that is still pending is kept
+            if self.sent.get(index).map(|&gain| gain_value(gain)) == Some(*value) {
+                self.sent.remove(index);
+            }
+        }
+    }
+
+    pub fn send(&mut self, out: &mut Outbox, sender: &mut ReliableSender, bundle: &SettingsBundle) {
+        let gains = match bundle.mode {
+            WorkingModes::YawControlMode => vec![(YAW_P, bundle.yaw_control_p)],
+            WorkingModes::FullControlMode | WorkingModes::RawSensorMode => vec![
+                (YAW_RATE_P, bundle.yaw_control_p),
+                (ANGLE_P, bundle.roll_pitch_control_p1),
+                (RATE_P, bundle.roll_pitch_control_p2),
+            ],
+            WorkingModes::HeightControlMode => vec![
+                (YAW_RATE_P, bundle.yaw_control_p),
+                (ANGLE_P, bundle.roll_pitch_control_p1),
+                (RATE_P, bundle.roll_pitch_control_p2),
+                (HEIGHT_P, bundle.height_control_p),
+            ],
+            _ => Vec::new(),
+        };
+
+        // The drone answers with the new value, which shows up in the parameter window
+        for (name, gain) in gains {
+            let Some(&index) = self.indices.get(name) else { continue };
+            if self.sent.insert(index, gain) != Some(gain) {
+                sender.send_command(out, Message::ParamSet(index, gain_value(gain)));
+            }
+        }
+    }
 }
 
 
 
-/// Read message from the drone, if available
-pub fn read_message(serial: &SerialPort, shared_buf: &mut Vec<u8>) -> Option<Packet> {
-    // If packets have been received, deserialize them
-    let mut end_byte_idx = shared_buf.iter().position(|&byte| byte == 0);
-    if end_byte_idx.is_none() {
-        let mut read_buf = [1u8; 255];
-        let num = serial.read(&mut read_buf).unwrap_or(0);
+/// Send a request and wait for the answer, the request is repeated when no answer arrives in time.
+/// Messages for which `answer` returns None, e.g. telemetry, are skipped.
+/// Returns None when the drone did not answer.
+pub fn request<T>(serial: &Link, decoder: &mut FrameDecoder, packet: Packet,
+    mut answer: impl FnMut(Message) -> Option<T>) -> Option<T> {
+    for _ in 0..MAX_TRANSMISSIONS {
+        send_packet(serial, packet.clone());
+
+        let start = Instant::now();
+        while start.elapsed() < REQUEST_TIMEOUT {
+            if let Some(packet) = read_message(serial, decoder) {
+                if let Some(result) = answer(packet.message) {
+                    return Some(result);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Read message from the drone, if available. Received bytes are collected by the frame decoder,
+/// bad frames and packets of other vehicles are skipped.
+pub fn read_message(serial: &Link, decoder: &mut FrameDecoder) -> Option<Packet> {
+    let mut read_buf = [0u8; 255];
+    let space = decoder.space().min(read_buf.len());
+    if space > 0 {
+        let num = serial.read(&mut read_buf[..space]).unwrap_or(0);
+        decoder.receive(&read_buf[..num]);
+    }
+
+    let drone = drone_id();
+    while let Some(result) = decoder.next_packet() {
+        if let Ok(packet) = result {
+            if drone == BROADCAST_ID || packet.source == drone {
+                return Some(packet);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, net::{TcpListener, TcpStream}, thread::sleep};
+
+    /// A link to a fake drone on a local socket, the drone end reads what the runner sent
+    fn link_pair() -> (Link, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let link = Link::connect_simulator(&listener.local_addr().unwrap().to_string()).unwrap();
+        let (drone, _) = listener.accept().unwrap();
+        drone.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        (link, drone)
+    }
+
+    /// Send the outbox and return the packets the runner sent since the last call
+    fn received(out: &mut Outbox, link: &Link, drone: &mut TcpStream) -> Vec<Packet> {
+        out.flush(link);
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0u8; 255];
+        while let Ok(num @ 1..) = drone.read(&mut buf) {
+            decoder.receive(&buf[..num]);
+        }
+        std::iter::from_fn(|| decoder.next_packet()).filter_map(Result::ok).collect()
+    }
+
+    #[test]
+    fn test_mode_change_acknowledged() {
+        let (link, mut drone) = link_pair();
+        let mut sender = ReliableSender::new();
+        let mut out = Outbox::default();
+
+        assert_eq!(sender.send(&mut out, Message::CalibrationMode), Some(DeliveryStatus::Pending(WorkingModes::CalibrationMode)));
+        let packets = received(&mut out, &link, &mut drone);
+        assert_eq!(packets.len(), 1);
+        assert_eq!((packets[0].seq, &packets[0].message), (1, &Message::CalibrationMode));
+
+        // Repeating the mode is not tracked again, replies to other commands are ignored
+        assert_eq!(sender.send(&mut out, Message::CalibrationMode), None);
+        assert_eq!(received(&mut out, &link, &mut drone)[0].seq, 0);
+        assert_eq!(sender.handle_reply(&Message::Ack(2)), None);
+        assert_eq!(sender.handle_reply(&Message::Ack(1)), Some(DeliveryStatus::Accepted(WorkingModes::CalibrationMode)));
+        assert_eq!(sender.handle_reply(&Message::Ack(1)), None);
 
-        if num > 0 {
-            shared_buf.extend_from_slice(&read_buf[0..num]);
-            // match std::str::from_utf8(&shared_buf) {
-            //     Ok(debug) => { 
-            //         println!("\n\r {}", debug.trim());
-            //     }
-    
-            //     Err(_) => (),
-            // }
-            end_byte_idx = shared_buf.iter().position(|&byte| byte == 0);
+        // A rejected mode is tracked again when it is sent next
+        assert_eq!(sender.send(&mut out, Message::PanicMode), Some(DeliveryStatus::Pending(WorkingModes::PanicMode)));
+        let reason = NackReason::ModeRejected(WorkingModes::SafeMode);
+        assert_eq!(sender.handle_reply(&Message::Nack(2, reason)), Some(DeliveryStatus::Rejected(WorkingModes::PanicMode, reason)));
+        assert_eq!(sender.send(&mut out, Message::PanicMode), Some(DeliveryStatus::Pending(WorkingModes::PanicMode)));
+        assert_eq!(received(&mut out, &link, &mut drone).iter().map(|packet| packet.seq).collect::<Vec<_>>(), [2, 3]);
+    }
+
+    #[test]
+    fn test_safe_mode_sent_first() {
+        let (link, mut drone) = link_pair();
+        let mut sender = ReliableSender::new();
+        let mut out = Outbox::default();
+
+        sender.send_command(&mut out, Message::ParamSet(3, ParamValue::F32(0.5)));
+        sender.send(&mut out, Message::ParamList);
+        sender.send(&mut out, Message::SafeMode);
+        let packets = received(&mut out, &link, &mut drone);
+        assert_eq!(packets.iter().map(|packet| packet.message.clone()).collect::<Vec<_>>(),
+                   [Message::SafeMode, Message::ParamSet(3, ParamValue::F32(0.5)), Message::ParamList]);
+    }
+
+    #[test]
+    fn test_retransmission_until_timeout() {
+        let (link, mut drone) = link_pair();
+        let mut sender = ReliableSender::new();
+        let mut out = Outbox::default();
+
+        sender.send(&mut out, Message::SafeMode);
+        assert_eq!(sender.retransmit(&mut out), None);
+        for _ in 1..MAX_TRANSMISSIONS {
+            sleep(ACK_TIMEOUT);
+            assert_eq!(sender.retransmit(&mut out), None);
         }
+        let packets = received(&mut out, &link, &mut drone);
+        assert_eq!(packets.len(), MAX_TRANSMISSIONS);
+        assert!(packets.iter().all(|packet| packet.seq == 1 && packet.message == Message::SafeMode));
+
+        sleep(ACK_TIMEOUT);
+        assert_eq!(sender.retransmit(&mut out), Some(DeliveryStatus::TimedOut(WorkingModes::SafeMode)));
+        assert_eq!(sender.retransmit(&mut out), None);
+
+        // The mode is forgotten, so sending it again starts a new command
+        assert_eq!(sender.send(&mut out, Message::SafeMode), Some(DeliveryStatus::Pending(WorkingModes::SafeMode)));
+        assert_eq!(received(&mut out, &link, &mut drone)[0].seq, 2);
     }
 
-    if let Some(end_byte_idx) = end_byte_idx {
-        let packet_result = read_packet(&mut shared_buf[..=end_byte_idx].to_vec());
-        if let Ok(packet) = packet_result {
-            shared_buf.drain(..=end_byte_idx);
-            return Some(packet);
-        } else {
-            shared_buf.drain(..(end_byte_idx + 1));
+    #[test]
+    fn test_command_results() {
+        let (link, mut drone) = link_pair();
+        let mut sender = ReliableSender::new();
+        let mut out = Outbox::default();
+        let set = Message::ParamSet(3, ParamValue::F32(0.5));
+
+        // Commands without an answer are not tracked
+        sender.send_command(&mut out, Message::ParamList);
+        sender.send_command(&mut out, set.clone());
+        sender.send_command(&mut out, Message::SetEstimator(protocol::Estimator::Mahony));
+        sender.send(&mut out, Message::CalibrationMode);
+        let packets = received(&mut out, &link, &mut drone);
+        assert_eq!(packets.iter().map(|packet| packet.seq).collect::<Vec<_>>(), [0, 1, 2, 3]);
+
+        // The mode change and the commands are answered independently
+        assert_eq!(sender.handle_command_reply(&Message::Ack(3)), None);
+        assert_eq!(sender.handle_command_reply(&Message::Nack(1, NackReason::ParameterRange)),
+                   Some(CommandStatus::Rejected(set.clone(), NackReason::ParameterRange)));
+        assert_eq!(sender.handle_command_reply(&Message::Nack(1, NackReason::ParameterRange)), None);
+
+        // The unanswered command is sent again until it times out
+        for _ in 1..MAX_TRANSMISSIONS {
+            sleep(ACK_TIMEOUT);
+            assert!(sender.retransmit_commands(&mut out).is_empty());
         }
+        sleep(ACK_TIMEOUT);
+        assert_eq!(sender.retransmit_commands(&mut out), [CommandStatus::TimedOut(Message::SetEstimator(protocol::Estimator::Mahony))]);
+        let packets = received(&mut out, &link, &mut drone);
+        assert_eq!(packets.iter().filter(|packet| packet.seq == 2).count(), MAX_TRANSMISSIONS - 1);
+        assert_eq!(CommandStatus::Rejected(set, NackReason::ParameterRange).to_string(), "ParamSet(3, 0.5) rejected: parameter out of range");
+    }
+
+    #[test]
+    fn test_commands_in_flight() {
+        let (link, mut drone) = link_pair();
+        let mut sender = ReliableSender::new();
+        let mut out = Outbox::default();
+
+        // One place is kept for a mode change, the last command waits
+        for index in 0..MAX_COMMANDS_IN_FLIGHT as u8 {
+            sender.send_command(&mut out, Message::ParamSet(index, ParamValue::F32(0.5)));
+        }
+        sender.send(&mut out, Message::SafeMode);
+        let packets = received(&mut out, &link, &mut drone);
+        assert_eq!(packets.len(), MAX_COMMANDS_IN_FLIGHT);
+        assert_eq!(packets[0].message, Message::SafeMode);
+
+        // It is sent once another command was answered
+        let last = Message::ParamSet(MAX_COMMANDS_IN_FLIGHT as u8 - 1, ParamValue::F32(0.5));
+        assert!(sender.retransmit_commands(&mut out).is_empty());
+        assert!(!received(&mut out, &link, &mut drone).iter().any(|packet| packet.message == last));
+        sender.handle_command_reply(&Message::Ack(1));
+        assert!(sender.retransmit_commands(&mut out).is_empty());
+        let packets = received(&mut out, &link, &mut drone);
+        let sent = packets.iter().find(|packet| packet.message == last).unwrap();
+        assert_eq!(sent.seq, MAX_COMMANDS_IN_FLIGHT as u16 + 1);
+    }
+
+    #[test]
+    fn test_gains_by_name() {
+        let (link, mut drone) = link_pair();
+        let mut sender = ReliableSender::new();
+        let mut out = Outbox::default();
+        let mut gains = GainSender::default();
+        let mut bundle = SettingsBundle { mode: WorkingModes::YawControlMode, yaw_control_p: 20000, ..Default::default() };
+
+        // Nothing is sent until the index is known
+        gains.send(&mut out, &mut sender, &bundle);
+        assert!(received(&mut out, &link, &mut drone).is_empty());
+        let value = ParamValue::F32(0.0);
+        gains.handle_param(&ParamInfo { index: 5, count: 9, name: "yaw_p".into(), value, min: value, max: value, default: value });
+
+        gains.send(&mut out, &mut sender, &bundle);
+        gains.send(&mut out, &mut sender, &bundle);
+        let packets = received(&mut out, &link, &mut drone);
+        assert_eq!(packets.len(), 1);
+        assert_eq!((packets[0].seq, &packets[0].message), (1, &Message::ParamSet(5, ParamValue::F32(2.0))));
+
+        // A new value replaces the pending one, and a rejected value is sent again
+        bundle.yaw_control_p = 10000;
+        gains.send(&mut out, &mut sender, &bundle);
+        assert_eq!(sender.handle_command_reply(&Message::Ack(1)), None);
+        let status = sender.handle_command_reply(&Message::Nack(2, NackReason::ParameterRange)).unwrap();
+        gains.handle_status(&status);
+        gains.send(&mut out, &mut sender, &bundle);
+        let packets = received(&mut out, &link, &mut drone);
+        assert_eq!(packets.iter().map(|packet| packet.seq).collect::<Vec<_>>(), [2, 3]);
+        assert!(packets.iter().all(|packet| packet.message == Message::ParamSet(5, ParamValue::F32(1.0))));
     }
-    None    
 }
@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+use protocol::Message;
+
+/// Time between two time sync requests
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of recent replies the clock offset and drift are estimated from, about a minute of replies
+const SAMPLES: usize = 64;
+
+/// Replies are only used for the drift when their round trip is at most this much longer than
+/// the shortest one, in us. Longer ones were delayed on one of the ways.
+const DRIFT_RTT_MARGIN: u64 = 2_000;
+
+/// Drone time in us the drift replies have to span before the drift is estimated
+const DRIFT_SPAN: u64 = 10_000_000;
+
+/// Host time in us since the unix epoch
+pub fn host_time_us() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_micros() as u64
+}
+
+/// Time sync request for the drone, carrying the current host time
+pub fn sync_request() -> Message {
+    Message::TimeSyncRequest { host_time: host_time_us() }
+}
+
+/// Result of one time sync request, in us
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SyncSample {
+    pub rtt: u64,           // round trip time of the request
+    pub offset: i64,        // host time minus drone time
+    pub drone_time: u64,    // drone time of the reply
+}
+
+/// Estimates the offset and drift between the drone clock (us since boot) and the host clock
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    samples: VecDeque<SyncSample>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        ClockSync { samples: VecDeque::with_capacity(SAMPLES) }
+    }
+
+    /// Handle a TimeSyncReply that was received at host time `now`
+    pub fn handle_reply(&mut self, host_time: u64, drone_time: u64, now: u64) -> SyncSample {
+        let rtt = now.saturating_sub(host_time);
+
+        // Assume the drone answered halfway the round trip
+        let offset = (host_time + rtt / 2) as i64 - drone_time as i64;
+
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        let sample = SyncSample { rtt, offset, drone_time };
+        self.samples.push_back(sample);
+        sample
+    }
+
+    /// Whether a reply with this drone time comes from a drone that restarted since the last reply,
+    /// its clock started from 0 again
+    pub fn restarted(&self, drone_time: u64) -> bool {
+        self.samples.back().is_some_and(|last| drone_time < last.drone_time)
+    }
+
+    /// Best estimate of the clock offset. The sample with the shortest round trip is used,
+    /// as it is the least affected by delays in the serial buffers and the drone control loop.
+    pub fn estimate(&self) -> Option<SyncSample> {
+        self.samples.iter().min_by_key(|sample| sample.rtt).copied()
+    }
+
+    /// Rate at which the offset changes, in us per us of drone time. The line through the replies
+    /// with a short round trip is fitted, None until they span long enough.
+    pub fn drift(&self) -> Option<f64> {
+        let best = self.estimate()?;
+        let samples: Vec<&SyncSample> = self.samples.iter().filter(|sample| sample.rtt <= best.rtt + DRIFT_RTT_MARGIN).collect();
+        let first = samples.iter().map(|sample| sample.drone_time).min()?;
+        let last = samples.iter().map(|sample| sample.drone_time).max()?;
+        if last - first < DRIFT_SPAN {
+            return None;
+        }
+
+        // Least squares, relative to the best sample to keep the numbers small
+        let n = samples.len() as f64;
+        let points = samples.iter().map(|sample| ((sample.drone_time as i64 - best.drone_time as i64) as f64, (sample.offset - best.offset) as f64));
+        let (sx, sy, sxx, sxy) = points.fold((0.0, 0.0, 0.0, 0.0), |(sx, sy, sxx, sxy), (x, y)| (sx + x, sy + y, sxx + x * x, sxy + x * y));
+        Some((n * sxy - sx * sy) / (n * sxx - sx * sx))
+    }
+
+    /// Convert a drone time in us since boot to host time in us since the unix epoch. The offset of the
+    /// best reply is corrected for the drift since then.
+    pub fn drone_to_host(&self, drone_time: u64) -> Option<u64> {
+        let best = self.estimate()?;
+        let drift = self.drift().unwrap_or(0.0) * (drone_time as i64 - best.drone_time as i64) as f64;
+        Some((drone_time as i64 + best.offset + drift.round() as i64) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Host time of the epoch of the drone clock in the tests
+    const BOOT: u64 = 1_700_000_000_000_000;
+
+    /// Reply to a request sent at drone time `drone_time`, with the given delay on the way there and back.
+    /// The drone clock runs `ppm` slower than the host clock.
+    fn reply(clock: &mut ClockSync, drone_time: u64, ppm: u64, there: u64, back: u64) -> SyncSample {
+        let host = |drone: u64| BOOT + drone + drone * ppm / 1_000_000;
+        let host_time = host(drone_time) - there;
+        clock.handle_reply(host_time, drone_time, host(drone_time) + back)
+    }
+
+    #[test]
+    fn test_offset() {
+        let mut clock = ClockSync::new();
+        assert_eq!(clock.estimate(), None);
+        assert_eq!(clock.drone_to_host(0), None);
+
+        // The reply that was held up on the way back is not used
+        let sample = reply(&mut clock, 1_000_000, 0, 500, 500);
+        assert_eq!(sample, SyncSample { rtt: 1_000, offset: BOOT as i64, drone_time: 1_000_000 });
+        reply(&mut clock, 2_000_000, 0, 500, 20_000);
+        assert_eq!(clock.estimate(), Some(sample));
+        assert_eq!(clock.drone_to_host(5_000_000), Some(BOOT + 5_000_000));
+
+        // Only the recent replies count
+        for k in 0..SAMPLES as u64 {
+            reply(&mut clock, 3_000_000 + k * 1_000_000, 0, 800, 800);
+        }
+        assert_eq!(clock.estimate().unwrap().rtt, 1_600);
+        assert!(!clock.restarted(3_000_000 + SAMPLES as u64 * 1_000_000));
+        assert!(clock.restarted(500_000));
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut clock = ClockSync::new();
+        for k in 0..20 {
+            // Every third reply is delayed on one of the ways
+            let delay = if k % 3 == 0 { 5_000 } else { 300 };
+            reply(&mut clock, k * 1_000_000, 100, 300, delay);
+            if k < 10 {
+                assert_eq!(clock.drift(), None);
+            }
+        }
+        let drift = clock.drift().unwrap();
+        assert!((drift - 100e-6).abs() < 2e-6, "{}", drift);
+
+        // An hour later the mapped time is still within a ms
+        let hour = 3_600_000_000;
+        let error = clock.drone_to_host(hour).unwrap() as i64 - (BOOT + hour + hour * 100 / 1_000_000) as i64;
+        assert!(error.abs() < 1_000, "{}", error);
+    }
+}
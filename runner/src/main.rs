@@ -1,30 +1,182 @@
-use serial2::SerialPort;
-use std::{time::Duration, env::args};
+use std::{env::args, io::stdin};
 use tudelft_serial_upload::{upload_file_or_stop, PortSelector};
 
 mod interface;
 use crate::interface::interface::setup_interface;
+use crate::interface::pc_transmission::{handshake, enable_authentication, request};
+use crate::interface::log_download::{download_logs, erase_logs};
+use crate::interface::imu_calibration::{calibrate_imu, reset_imu_calibration};
+use crate::interface::link::Link;
+use protocol::{Message, Packet, FrameDecoder};
+
+/// Command line action that downloads the drone flash log instead of starting the interface
+const DOWNLOAD_LOGS: &str = "download-logs";
+
+/// Command line action that changes the vehicle id of the drone, followed by the new id
+const SET_ID: &str = "set-id";
+
+/// Command line action that calibrates the raw IMU of the drone, optionally followed by `reset`
+/// to forget the stored calibration
+const CALIBRATE_IMU: &str = "calibrate-imu";
+const RESET: &str = "reset";
+
+/// Command line option to connect to the simulator instead of uploading to the board,
+/// optionally followed by its address
+const SIMULATOR: &str = "simulator";
+
+/// Address the simulator listens on by default
+const SIMULATOR_ADDRESS: &str = "127.0.0.1:7777";
+
+/// What the runner does after uploading the firmware
+enum Action {
+    Interface,
+    DownloadLogs,
+    SetId(String),
+    CalibrateImu { reset: bool },
+}
+
+/// Where the runner finds the drone
+enum Target {
+    Board(Option<String>),  // file to upload
+    Simulator(String),      // address of the simulator
+}
+
+/// Split the command line into the drone to connect to and the action
+fn parse_args() -> (Target, Action) {
+    let mut target = Target::Board(None);
+    let mut action = Action::Interface;
+
+    let mut args = args().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            DOWNLOAD_LOGS => action = Action::DownloadLogs,
+            SET_ID => action = Action::SetId(args.next().unwrap_or_default()),
+            CALIBRATE_IMU => action = Action::CalibrateImu { reset: args.next_if(|next| next == RESET).is_some() },
+            SIMULATOR => {
+                let address = args.next_if(|next| next.contains(':')).unwrap_or_else(|| SIMULATOR_ADDRESS.to_string());
+                target = Target::Simulator(address);
+            }
+            _ => target = Target::Board(Some(arg)),
+        }
+    }
+    (target, action)
+}
 
 fn main()  {
     // Clear terminal
     print! ("\x1B[2J\x1B[1;1H");
 
-    // Open serial port
-    let serial = open_serial();
+    // Sign all commands when a key is provided
+    match enable_authentication() {
+        Ok(true) => println!("\rAuthenticated commands enabled"),
+        Ok(false) => (),
+        Err(err) => {
+            println!("\r{}", err);
+            return;
+        }
+    }
+
+    // Open serial port, or connect to the simulator
+    let (target, action) = parse_args();
+    let serial = match target {
+        Target::Board(file) => open_serial(file),
+        Target::Simulator(address) => match Link::connect_simulator(&address) {
+            Ok(link) => link,
+            Err(err) => {
+                println!("\rCannot connect to the simulator at {}: {}", address, err);
+                return;
+            }
+        },
+    };
+
+    match action {
+        Action::Interface => (),
+        Action::DownloadLogs => return download(&serial),
+        Action::SetId(id) => return set_id(&serial, &id),
+        Action::CalibrateImu { reset } => return calibrate(&serial, reset),
+    }
 
     let res = setup_interface(&serial);
 
     print!("\x1B[2J\x1B[1;1H");
-    println!("\rInterface stopped: {:?}", res);
+    match res {
+        Ok(()) => println!("\rInterface stopped"),
+        Err(err) => println!("\rInterface stopped: {}", err),
+    }
 }
 
 /// Open serial port
-fn open_serial() -> SerialPort {
-    let file = args().nth(1);
+fn open_serial(file: Option<String>) -> Link {
     let port = upload_file_or_stop(PortSelector::AutoManufacturer, file);
-    let mut serial = SerialPort::open(port, 115200).unwrap();
+    Link::open_serial(port).unwrap()
+}
+
+/// Download the drone flash log and erase it when the user confirms
+fn download(serial: &Link) {
+    if let Err(err) = handshake(serial) {
+        println!("\rLog download stopped: {}", err);
+        return;
+    }
+
+    let records = match download_logs(serial) {
+        Ok(records) => records,
+        Err(err) => {
+            println!("\rLog download stopped: {}, run {} again to resume", err, DOWNLOAD_LOGS);
+            return;
+        }
+    };
+
+    println!("\rErase the flash log of the drone? [y/N]");
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).ok();
+    if answer.trim().eq_ignore_ascii_case("y") {
+        match erase_logs(serial, records) {
+            Ok(()) => println!("\rFlash log erased"),
+            Err(err) => println!("\rFlash log not erased: {}", err),
+        }
+    }
+}
 
-    serial.set_read_timeout(Duration::from_secs(1)).unwrap();
+/// Store a new vehicle id on the drone
+fn set_id(serial: &Link, id: &str) {
+    let id = match id.parse::<u8>() {
+        Ok(id) => id,
+        Err(_) => {
+            println!("\rUsage: {} <id>, with an id from 1 to 254", SET_ID);
+            return;
+        }
+    };
 
-    serial
-}
\ No newline at end of file
+    if let Err(err) = handshake(serial) {
+        println!("\rVehicle id not changed: {}", err);
+        return;
+    }
+
+    let seq = 1;
+    let reply = request(serial, &mut FrameDecoder::new(), Packet::with_seq(Message::SetVehicleId(id), seq), |message| match message {
+        Message::Ack(ack) if ack == seq => Some(Ok(())),
+        Message::Nack(nack, reason) if nack == seq => Some(Err(reason)),
+        _ => None,
+    });
+
+    match reply {
+        Some(Ok(())) => println!("\rVehicle id changed to {}", id),
+        Some(Err(reason)) => println!("\rVehicle id not changed: {}", reason),
+        None => println!("\rVehicle id not changed: drone did not answer"),
+    }
+}
+
+/// Run the IMU calibration of the drone, or forget the stored one
+fn calibrate(serial: &Link, reset: bool) {
+    if let Err(err) = handshake(serial) {
+        println!("\rIMU not calibrated: {}", err);
+        return;
+    }
+
+    let result = if reset { reset_imu_calibration(serial) } else { calibrate_imu(serial) };
+    match (result, reset) {
+        (Ok(()), false) => println!("\rIMU calibration stored on the drone"),
+        (Ok(()), true) => println!("\rIMU calibration reset"),
+        (Err(err), _) => println!("\rIMU calibration stopped: {}", err),
+    }
+}
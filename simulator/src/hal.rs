@@ -0,0 +1,407 @@
+//! Peripherals of the simulated drone. The flight code drives the motors of the model, every tick
+//! of the control loop advances the model, and the UART is a TCP socket the runner connects to.
+
+use std::io::{self, Read, Write, ErrorKind};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use eslgroup14::hal::{Accel, Gyro, Quaternion, FlashError, Led, Imu, Barometer, Battery, Motors, Uart, Clock, Flash, Leds};
+use eslgroup14::hal::mock::MockHal;
+use crate::model::Quadrotor;
+use crate::sensors;
+
+/// Length of a physics step in us, a control loop tick is split into steps of this length
+const STEP_US: u64 = 1_000;
+
+/// Iterations of the delay loop per us on the 16 MHz nRF51
+const DELAY_LOOPS_PER_US: usize = 4;
+
+/// Temperature of the air in centi-degrees Celsius
+const TEMPERATURE: i32 = 2000;
+
+/// Bytes that can wait for a slow runner, like the send buffer of the UART
+const SEND_BUFFER: usize = 4096;
+
+pub struct SimHal {
+    pub quad: Quadrotor,
+    /// Motor limit, clock, flash and LEDs, which need no physics
+    pub peripherals: MockHal,
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+    unsent: Vec<u8>,            // bytes the socket did not take yet, sent on the next tick
+    realtime: Option<Instant>,  // wall clock time of the current simulated time
+}
+
+impl SimHal {
+    /// Simulate the given vehicle without a link, as fast as the flight code runs.
+    /// The UART then is the one of the mock peripherals, so a test can talk to the flight code.
+    pub fn new(quad: Quadrotor) -> Self {
+        SimHal { quad, peripherals: MockHal::new(), listener: None, stream: None, unsent: Vec::new(), realtime: None }
+    }
+
+    /// Accept a runner connection on the given address, one runner at a time
+    pub fn listen(mut self, address: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        self.listener = Some(listener);
+        Ok(self)
+    }
+
+    /// Keep the simulated time in step with the wall clock, so the control loop runs at 100 Hz
+    pub fn realtime(mut self) -> Self {
+        self.realtime = Some(Instant::now());
+        self
+    }
+
+    /// Advance the model and the clock
+    fn advance(&mut self, us: u64) {
+        let mut left = us;
+        while left > 0 {
+            let step = left.min(STEP_US);
+            self.quad.step(self.peripherals.motors, step as f32 / 1_000_000.0);
+            left -= step;
+        }
+        self.peripherals.time_us += us;
+
+        // A host that falls behind slows the simulation down, the lost time is not made up
+        if let Some(last) = self.realtime {
+            let target = last + Duration::from_micros(us);
+            let now = Instant::now();
+            self.realtime = Some(if target > now {
+                sleep(target - now);
+                target
+            } else {
+                now
+            });
+        }
+    }
+
+    /// The connected runner, a waiting runner is accepted first
+    fn connection(&mut self) -> Option<&mut TcpStream> {
+        if self.stream.is_none() {
+            if let Some(Ok((stream, address))) = self.listener.as_ref().map(|listener| listener.accept()) {
+                if stream.set_nonblocking(true).and_then(|_| stream.set_nodelay(true)).is_ok() {
+                    println!("Runner connected from {}", address);
+                    self.stream = Some(stream);
+                    self.unsent.clear();
+                }
+            }
+        }
+        self.stream.as_mut()
+    }
+
+    /// Write as much of the unsent bytes as the socket takes without blocking
+    fn flush(&mut self) {
+        while !self.unsent.is_empty() {
+            let result = match self.stream.as_mut() {
+                Some(stream) => stream.write(&self.unsent),
+                None => return,
+            };
+            match result {
+                Ok(num) if num > 0 => {
+                    self.unsent.drain(..num);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                _ => {
+                    self.disconnect();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.unsent.clear();
+        if self.stream.take().is_some() {
+            println!("Runner disconnected");
+        }
+    }
+}
+
+impl Imu for SimHal {
+    fn read_dmp(&mut self) -> Quaternion {
+        sensors::dmp(&self.quad)
+    }
+
+    fn read_raw(&mut self) -> (Accel, Gyro) {
+        sensors::raw(&self.quad)
+    }
+}
+
+impl Barometer for SimHal {
+    fn read_pressure(&mut self) -> u32 {
+        sensors::pressure(&self.quad, TEMPERATURE)
+    }
+
+    fn read_temperature(&mut self) -> i32 {
+        TEMPERATURE
+    }
+}
+
+impl Battery for SimHal {
+    fn read_battery(&mut self) -> u16 {
+        sensors::battery(&self.quad)
+    }
+}
+
+impl Motors for SimHal {
+    fn set_motors(&mut self, motors: [u16; 4]) {
+        self.peripherals.set_motors(motors);
+    }
+
+    fn get_motors(&self) -> [u16; 4] {
+        self.peripherals.get_motors()
+    }
+
+    fn set_motor_max(&mut self, max: u16) {
+        self.peripherals.set_motor_max(max);
+    }
+}
+
+impl Uart for SimHal {
+    fn send_bytes(&mut self, bytes: &[u8]) -> bool {
+        if self.listener.is_none() {
+            return self.peripherals.send_bytes(bytes);
+        }
+
+        // Without a runner the bytes are lost, like on an unplugged cable
+        if self.connection().is_none() {
+            return true;
+        }
+
+        // Bytes the socket does not take are kept for the next tick, a packet that does not fit is dropped whole
+        if self.unsent.len() + bytes.len() > SEND_BUFFER {
+            return false;
+        }
+        self.unsent.extend_from_slice(bytes);
+        self.flush();
+        true
+    }
+
+    fn receive_bytes(&mut self, buf: &mut [u8]) -> usize {
+        if self.listener.is_none() {
+            return self.peripherals.receive_bytes(buf);
+        }
+
+        let result = match self.connection() {
+            Some(stream) => stream.read(buf),
+            None => return 0,
+        };
+        match result {
+            Ok(0) => {
+                self.disconnect();
+                0
+            }
+            Ok(num) => num,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => 0,
+            Err(_) => {
+                self.disconnect();
+                0
+            }
+        }
+    }
+}
+
+impl Clock for SimHal {
+    fn now_us(&self) -> u64 {
+        self.peripherals.now_us()
+    }
+
+    fn set_tick_frequency(&mut self, hz: u64) {
+        self.peripherals.set_tick_frequency(hz);
+    }
+
+    fn wait_for_next_tick(&mut self) {
+        self.flush();
+        self.advance(self.peripherals.tick_us);
+    }
+
+    fn delay(&mut self, cycles: usize) {
+        self.advance((cycles / DELAY_LOOPS_PER_US) as u64);
+    }
+}
+
+impl Flash for SimHal {
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.peripherals.read(address, buf)
+    }
+
+    fn write(&mut self, address: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        self.peripherals.write(address, bytes)
+    }
+
+    fn chip_erase(&mut self) -> Result<(), FlashError> {
+        self.peripherals.chip_erase()
+    }
+}
+
+impl Leds for SimHal {
+    fn set_led(&mut self, led: Led, on: bool) {
+        self.peripherals.set_led(led, on);
+    }
+
+    fn toggle_led(&mut self, led: Led) {
+        self.peripherals.toggle_led(led);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eslgroup14::control::run_control_loop;
+    use eslgroup14::yaw_pitch_roll::YawPitchRoll;
+    use crate::model::QuadParams;
+    use protocol::{Message, Packet, ParamValue, Setpoint, WorkingModes, Estimator, NackReason, PC_ID, MAX_PACKET_SIZE};
+
+    /// Gains of the angle, rate, yaw rate and height loops, by their index in the parameter table.
+    /// The table has no proportional gains by default, the integral gains are the defaults.
+    const GAINS: [(u8, f32); 4] = [(3, 0.5), (4, 0.5), (7, 0.5), (8, 1.0)];
+
+    /// Index of the rate_i and height_i gains in the parameter table
+    const RATE_I: u8 = 5;
+    const HEIGHT_I: u8 = 9;
+
+    /// Ticks spent in calibration mode, the control modes need 50 DMP samples
+    const CALIBRATION_TICKS: u64 = 60;
+
+    /// Send a message to the flight code like the runner does
+    fn send(hal: &mut SimHal, message: Message) {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let bytes = Packet::new(message).addressed(PC_ID, 1).encode_into(&mut buf).unwrap();
+        assert!(hal.peripherals.receive(bytes));
+    }
+
+    fn attitude(quad: &Quadrotor) -> YawPitchRoll {
+        YawPitchRoll::from(sensors::dmp(quad))
+    }
+
+    /// Fly the real control loop against the model: calibrate on the ground, set GAINS and `gains` and then
+    /// send the message `flight` gives for the time in s since the start of the flight, once per tick
+    fn fly(gains: &[(u8, f32)], seconds: f32, mut flight: impl FnMut(f32, &mut Quadrotor) -> Message) -> Quadrotor {
+        let gains: Vec<_> = GAINS.iter().chain(gains).copied().collect();
+        let flight_start = CALIBRATION_TICKS + 1 + gains.len() as u64;
+        let hal = run_control_loop(SimHal::new(Quadrotor::new(QuadParams::default())), |hal, tick| {
+            hal.peripherals.tx.clear();
+            let message = match tick {
+                0..CALIBRATION_TICKS => Message::CalibrationMode,
+                CALIBRATION_TICKS => Message::SafeMode,
+                _ if tick < flight_start => {
+                    let (index, gain) = gains[(tick - CALIBRATION_TICKS - 1) as usize];
+                    Message::ParamSet(index, ParamValue::F32(gain))
+                }
+                _ => {
+                    let time = (tick - flight_start) as f32 / 100.0;
+                    if time > seconds {
+                        return false;
+                    }
+                    flight(time, &mut hal.quad)
+                }
+            };
+            send(hal, message);
+            true
+        });
+        hal.quad
+    }
+
+    fn setpoint(pitch: f32, roll: f32, thrust: f32) -> Setpoint {
+        Setpoint { roll, pitch, yaw_rate: 0.0, thrust }
+    }
+
+    /// The estimator is chosen on the ground, in the air the attitude of the controllers would jump
+    #[test]
+    fn test_estimator_only_on_ground() {
+        let commands = [
+            Message::SetEstimator(Estimator::Mahony),
+            Message::Setpoint(WorkingModes::ManualMode, setpoint(0.0, 0.0, 0.0)),
+            Message::SetEstimator(Estimator::Kalman),
+        ];
+        let mut replies = Vec::new();
+        run_control_loop(SimHal::new(Quadrotor::new(QuadParams::default())), |hal, tick| {
+            replies.extend(hal.peripherals.take_messages().into_iter().filter(|message| matches!(message, Message::Ack(_) | Message::Nack(..))));
+            hal.peripherals.tx.clear();
+            let Some(command) = commands.get(tick as usize) else { return false };
+            let mut buf = [0u8; MAX_PACKET_SIZE];
+            let bytes = Packet::with_seq(command.clone(), tick as u16 + 1).addressed(PC_ID, 1).encode_into(&mut buf).unwrap();
+            hal.peripherals.receive(bytes);
+            true
+        });
+        assert_eq!(replies, [Message::Ack(1), Message::Ack(2), Message::Nack(3, NackReason::ModeRejected(WorkingModes::ManualMode))]);
+    }
+
+    /// Hover in full control, recover from a kick in roll and follow a step in pitch
+    #[test]
+    fn test_full_control_step() {
+        let (mut roll, mut pitch, mut overshoot) = (0.0f32, 0.0f32, 0.0f32);
+        fly(&[], 4.0, |time, quad| {
+            let angles = attitude(quad);
+            match time {
+                t if (0.5..0.51).contains(&t) => quad.state.angular_velocity[0] = 1.0,
+                t if (1.8..2.0).contains(&t) => roll = roll.max(angles.roll.abs()),
+                t if t >= 2.0 => overshoot = overshoot.max(angles.pitch - 0.1),
+                _ => {}
+            }
+            if time >= 3.0 {
+                pitch = pitch.max((angles.pitch - 0.1).abs());
+            }
+            // The mode is only entered with the thrust down
+            let thrust = if time == 0.0 { 0.0 } else { 0.34 };
+            let step = if time < 2.0 { 0.0 } else { 0.1 };
+            Message::Setpoint(WorkingModes::FullControlMode, setpoint(step, 0.0, thrust))
+        });
+        assert!(roll < 0.01, "roll {roll} left after the kick");
+        assert!(pitch < 0.01, "pitch off the step by {pitch}");
+        assert!(overshoot < 0.05, "pitch overshoot {overshoot}");
+    }
+
+    /// Capture the reference on the ground, take off to 0.5 m and step up to 0.8 m in height control.
+    /// The height loop has no derivative, so it swings around the target and only the average is checked.
+    #[test]
+    fn test_height_control_step() {
+        let (mut highest, mut low, mut high) = (0.0f32, 0.0f32, 0.0f32);
+        fly(&[], 25.0, |time, quad| {
+            let height = quad.state.position[2];
+            highest = highest.max(height);
+            if (10.0..16.0).contains(&time) { low += height / 600.0; }
+            if time >= 19.0 { high += height / 600.0; }
+            let thrust = if time < 6.0 { 0.0 } else if time < 16.0 { 0.25 } else { 0.4 };
+            Message::Setpoint(WorkingModes::HeightControlMode, setpoint(0.0, 0.0, thrust))
+        });
+        assert!(highest < 2.0, "climbed to {highest} m");
+        assert!((low - 0.5).abs() < 0.3, "held {low} m instead of 0.5 m");
+        assert!(high > low + 0.2, "held {high} m after the step up from {low} m");
+    }
+
+    /// Average roll in the last second of a hover in full control, with a constant disturbance in roll
+    fn hover_roll(gains: &[(u8, f32)]) -> f32 {
+        let mut roll = 0.0;
+        fly(gains, 6.0, |time, quad| {
+            quad.state.angular_velocity[0] += 0.01;
+            if time >= 5.0 { roll += attitude(quad).roll / 100.0; }
+            let thrust = if time == 0.0 { 0.0 } else { 0.34 };
+            Message::Setpoint(WorkingModes::FullControlMode, setpoint(0.0, 0.0, thrust))
+        });
+        roll
+    }
+
+    /// Average height while holding 0.5 m in height control, after taking off like test_height_control_step
+    fn hover_height(gains: &[(u8, f32)]) -> f32 {
+        let mut height = 0.0;
+        fly(gains, 16.0, |time, quad| {
+            if time >= 10.0 { height += quad.state.position[2] / 600.0; }
+            let thrust = if time < 6.0 { 0.0 } else { 0.25 };
+            Message::Setpoint(WorkingModes::HeightControlMode, setpoint(0.0, 0.0, thrust))
+        });
+        height
+    }
+
+    /// The default integral gains take out the offset a proportional controller leaves
+    #[test]
+    fn test_integral_gains() {
+        assert!(hover_roll(&[]).abs() < 0.002);
+        assert!(hover_roll(&[(RATE_I, 0.0)]) > 0.01);
+
+        assert!((hover_height(&[]) - 0.5).abs() < 0.1);
+        assert!(hover_height(&[(HEIGHT_I, 0.0)]) < 0.4);
+    }
+}
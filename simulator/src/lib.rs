@@ -0,0 +1,7 @@
+//! Software-in-the-loop simulator of the drone. A rigid-body quadrotor model stands in for the
+//! hardware, its peripherals implement the traits of `eslgroup14::hal` so the real control loop
+//! flies it at 100 Hz. The runner connects over TCP instead of the serial port.
+
+pub mod model;
+pub mod sensors;
+pub mod hal;
@@ -0,0 +1,50 @@
+use std::env::args;
+use eslgroup14::control::control_loop;
+use simulator::hal::SimHal;
+use simulator::model::{QuadParams, Quadrotor};
+
+/// Address the runner connects to, e.g. `cargo run -p runner -- simulator`
+const DEFAULT_ADDRESS: &str = "127.0.0.1:7777";
+
+/// Vehicle and address from the command line, given as `mass=<kg>`, `inertia=<x>,<y>,<z>` in kg m^2
+/// and `address=<host:port>`
+fn parse_args() -> Result<(QuadParams, String), String> {
+    let mut params = QuadParams::default();
+    let mut address = DEFAULT_ADDRESS.to_string();
+
+    for arg in args().skip(1) {
+        let (key, value) = arg.split_once('=').ok_or(format!("expected key=value, got {}", arg))?;
+        match key {
+            "mass" => params.mass = value.parse().map_err(|_| format!("bad mass {}", value))?,
+            "inertia" => {
+                let inertia: Vec<f32> = value.split(',').filter_map(|axis| axis.parse().ok()).collect();
+                params.inertia = inertia.try_into().map_err(|_| format!("bad inertia {}", value))?;
+            }
+            "address" => address = value.to_string(),
+            _ => return Err(format!("unknown option {}", key)),
+        }
+    }
+    Ok((params, address))
+}
+
+fn main() {
+    let (params, address) = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            println!("{}", err);
+            println!("Usage: simulator [mass=<kg>] [inertia=<x>,<y>,<z>] [address=<host:port>]");
+            return;
+        }
+    };
+
+    let hal = match SimHal::new(Quadrotor::new(params)).listen(&address) {
+        Ok(hal) => hal.realtime(),
+        Err(err) => {
+            println!("Cannot listen on {}: {}", address, err);
+            return;
+        }
+    };
+
+    println!("Simulated drone listening on {}, hovering at motor value {:.0}", address, params.hover_motor());
+    control_loop(hal);
+}
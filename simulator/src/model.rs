@@ -0,0 +1,265 @@
+//! Rigid-body model of the quadrotor. The body frame is the frame of the MPU: x points to
+//! motor 1 (front), y to motor 4 (left) and z up. The world frame has z up as well.
+
+/// Gravitational acceleration in m/s^2
+pub const GRAVITY: f32 = 9.81;
+
+/// Motor positions as multiples of the arm length, in the order of `motor_assign`
+///        m1
+///        |
+/// m4 —— o —— m2
+///        |
+///        m3
+const MOTOR_POSITIONS: [[f32; 2]; 4] = [[1.0, 0.0], [0.0, -1.0], [-1.0, 0.0], [0.0, 1.0]];
+
+/// Direction of the yaw reaction torque of each motor, m1 and m3 spin the other way than m2 and m4
+const MOTOR_SPIN: [f32; 4] = [1.0, -1.0, 1.0, -1.0];
+
+pub type Vec3 = [f32; 3];
+
+/// Physical properties of the simulated vehicle
+#[derive(Debug, Copy, Clone)]
+pub struct QuadParams {
+    pub mass: f32,                  // kg
+    pub inertia: Vec3,              // kg m^2, about the body x, y and z axes
+    pub arm_length: f32,            // m, from the center to a motor
+    pub thrust_coefficient: f32,    // N per squared motor value
+    pub torque_coefficient: f32,    // yaw torque in Nm per N of thrust
+    pub motor_time_constant: f32,   // s
+    pub linear_drag: f32,           // N per m/s
+    pub angular_drag: f32,          // Nm per rad/s
+    pub battery_full: f32,          // V
+    pub battery_empty: f32,         // V
+    pub battery_capacity: f32,      // Ah
+    pub battery_resistance: f32,    // Ohm
+    pub current_per_thrust: f32,    // A per N of thrust
+}
+
+impl Default for QuadParams {
+    /// Roughly the quadrupel frame, hovering at a motor value of 350 on a full 3S battery
+    fn default() -> Self {
+        QuadParams {
+            mass: 0.55,
+            inertia: [3.0e-3, 3.0e-3, 5.5e-3],
+            arm_length: 0.17,
+            thrust_coefficient: 1.1e-5,
+            torque_coefficient: 0.016,
+            motor_time_constant: 0.03,
+            linear_drag: 0.25,
+            angular_drag: 2.0e-3,
+            battery_full: 12.4,
+            battery_empty: 10.8,
+            battery_capacity: 1.3,
+            battery_resistance: 0.05,
+            current_per_thrust: 1.5,
+        }
+    }
+}
+
+impl QuadParams {
+    /// Motor value at which the four motors together carry the weight
+    pub fn hover_motor(&self) -> f32 {
+        (self.mass * GRAVITY / (4.0 * self.thrust_coefficient)).sqrt()
+    }
+}
+
+/// Motion of the vehicle, the attitude rotates body vectors into the world frame
+#[derive(Debug, Copy, Clone)]
+pub struct QuadState {
+    pub position: Vec3,         // m
+    pub velocity: Vec3,         // m/s
+    pub acceleration: Vec3,     // m/s^2, of the last step
+    pub attitude: [f32; 4],     // unit quaternion w, x, y, z
+    pub angular_velocity: Vec3, // rad/s in the body frame
+    pub motor_speed: [f32; 4],  // motor values after the motor lag
+    pub current: f32,           // A
+    pub charge_used: f32,       // Ah
+}
+
+impl Default for QuadState {
+    /// Level on the ground with the motors off
+    fn default() -> Self {
+        QuadState {
+            position: [0.0; 3],
+            velocity: [0.0; 3],
+            acceleration: [0.0; 3],
+            attitude: [1.0, 0.0, 0.0, 0.0],
+            angular_velocity: [0.0; 3],
+            motor_speed: [0.0; 4],
+            current: 0.0,
+            charge_used: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Quadrotor {
+    pub params: QuadParams,
+    pub state: QuadState,
+}
+
+impl Quadrotor {
+    pub fn new(params: QuadParams) -> Self {
+        Quadrotor { params, state: QuadState::default() }
+    }
+
+    /// Thrust of each motor in N
+    pub fn thrusts(&self) -> [f32; 4] {
+        self.state.motor_speed.map(|speed| self.params.thrust_coefficient * speed * speed)
+    }
+
+    /// Battery voltage under the current load
+    pub fn battery_voltage(&self) -> f32 {
+        let p = &self.params;
+        let discharged = (self.state.charge_used / p.battery_capacity).min(1.0);
+        p.battery_full - (p.battery_full - p.battery_empty) * discharged - p.battery_resistance * self.state.current
+    }
+
+    /// Advance the model by dt seconds with the given motor values. Steps should be short
+    /// compared to the motor time constant, e.g. 1 ms.
+    pub fn step(&mut self, motors: [u16; 4], dt: f32) {
+        let p = self.params;
+        let s = &mut self.state;
+
+        // First order motor response
+        let lag = (dt / p.motor_time_constant).min(1.0);
+        for (speed, &motor) in s.motor_speed.iter_mut().zip(motors.iter()) {
+            *speed += (motor as f32 - *speed) * lag;
+        }
+        let thrusts = s.motor_speed.map(|speed| p.thrust_coefficient * speed * speed);
+        let total: f32 = thrusts.iter().sum();
+
+        // Thrust along the body z axis, gravity and air drag
+        let thrust = rotate(s.attitude, [0.0, 0.0, total]);
+        let force = [
+            thrust[0] - p.linear_drag * s.velocity[0],
+            thrust[1] - p.linear_drag * s.velocity[1],
+            thrust[2] - p.linear_drag * s.velocity[2] - p.mass * GRAVITY,
+        ];
+        s.acceleration = force.map(|f| f / p.mass);
+
+        // Arm torques of the motor thrusts, yaw reaction torques and rotational drag
+        let mut torque = [0.0; 3];
+        for i in 0..4 {
+            let [x, y] = MOTOR_POSITIONS[i];
+            torque[0] += y * p.arm_length * thrusts[i];
+            torque[1] -= x * p.arm_length * thrusts[i];
+            torque[2] += MOTOR_SPIN[i] * p.torque_coefficient * thrusts[i];
+        }
+        let w = s.angular_velocity;
+        let momentum = [p.inertia[0] * w[0], p.inertia[1] * w[1], p.inertia[2] * w[2]];
+        let gyroscopic = cross(w, momentum);
+        for axis in 0..3 {
+            let angular_acceleration = (torque[axis] - gyroscopic[axis] - p.angular_drag * w[axis]) / p.inertia[axis];
+            s.angular_velocity[axis] += angular_acceleration * dt;
+        }
+
+        for axis in 0..3 {
+            s.velocity[axis] += s.acceleration[axis] * dt;
+            s.position[axis] += s.velocity[axis] * dt;
+        }
+        s.attitude = integrate(s.attitude, s.angular_velocity, dt);
+
+        // The ground holds the vehicle until the thrust lifts it
+        if s.position[2] <= 0.0 && s.velocity[2] <= 0.0 {
+            s.position[2] = 0.0;
+            s.velocity = [0.0; 3];
+            s.acceleration = [0.0; 3];
+            s.angular_velocity = [0.0; 3];
+        }
+
+        s.current = total * p.current_per_thrust;
+        s.charge_used += s.current * dt / 3600.0;
+    }
+}
+
+pub fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn multiply(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    [
+        a[0] * b[0] - a[1] * b[1] - a[2] * b[2] - a[3] * b[3],
+        a[0] * b[1] + a[1] * b[0] + a[2] * b[3] - a[3] * b[2],
+        a[0] * b[2] - a[1] * b[3] + a[2] * b[0] + a[3] * b[1],
+        a[0] * b[3] + a[1] * b[2] - a[2] * b[1] + a[3] * b[0],
+    ]
+}
+
+/// Rotate a body vector into the world frame
+pub fn rotate(q: [f32; 4], v: Vec3) -> Vec3 {
+    let [_, x, y, z] = multiply(multiply(q, [0.0, v[0], v[1], v[2]]), [q[0], -q[1], -q[2], -q[3]]);
+    [x, y, z]
+}
+
+/// Rotate a world vector into the body frame
+pub fn rotate_inverse(q: [f32; 4], v: Vec3) -> Vec3 {
+    rotate([q[0], -q[1], -q[2], -q[3]], v)
+}
+
+/// Integrate the attitude over a body rate, the result is normalized again
+fn integrate(q: [f32; 4], rate: Vec3, dt: f32) -> [f32; 4] {
+    let dq = multiply(q, [0.0, rate[0], rate[1], rate[2]]);
+    let q = [0, 1, 2, 3].map(|i| q[i] + 0.5 * dq[i] * dt);
+    let norm = q.iter().map(|c| c * c).sum::<f32>().sqrt();
+    q.map(|c| c / norm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eslgroup14::hal::Quaternion;
+    use eslgroup14::yaw_pitch_roll::YawPitchRoll;
+
+    /// Run the model for the given time in ms with constant motor values
+    fn fly(motors: [u16; 4], ms: u32) -> Quadrotor {
+        let mut quad = Quadrotor::new(QuadParams::default());
+        quad.state.position[2] = 10.0;
+        quad.state.motor_speed = motors.map(|motor| motor as f32);
+        for _ in 0..ms {
+            quad.step(motors, 0.001);
+        }
+        quad
+    }
+
+    fn angles(quad: &Quadrotor) -> YawPitchRoll {
+        let [w, x, y, z] = quad.state.attitude;
+        YawPitchRoll::from(Quaternion { w, x, y, z })
+    }
+
+    #[test]
+    fn test_hover() {
+        let hover = QuadParams::default().hover_motor().round() as u16;
+        let quad = fly([hover; 4], 1000);
+        assert!((quad.state.position[2] - 10.0).abs() < 0.05);
+
+        // Without thrust it falls and stops on the ground
+        let mut quad = fly([0; 4], 1000);
+        assert!(quad.state.position[2] < 6.0);
+        for _ in 0..3000 {
+            quad.step([0; 4], 0.001);
+        }
+        assert_eq!(quad.state.position[2], 0.0);
+    }
+
+    #[test]
+    fn test_motor_layout() {
+        // The attitude follows the conventions of the flight code: more thrust on m1 pitches the
+        // nose up, more thrust on m4 rolls positive and m1 and m3 together yaw negative
+        let pitch = angles(&fly([360, 350, 340, 350], 200));
+        assert!(pitch.pitch > 0.05 && pitch.roll.abs() < 0.01 && pitch.yaw.abs() < 0.01);
+
+        let roll = angles(&fly([350, 340, 350, 360], 200));
+        assert!(roll.roll > 0.05 && roll.pitch.abs() < 0.01 && roll.yaw.abs() < 0.01);
+
+        let yaw = angles(&fly([360, 340, 360, 340], 500));
+        assert!(yaw.yaw < -0.05 && yaw.pitch.abs() < 0.01 && yaw.roll.abs() < 0.01);
+    }
+
+    #[test]
+    fn test_battery() {
+        let quad = fly([350; 4], 1000);
+        assert!(quad.battery_voltage() < QuadParams::default().battery_full);
+        assert!(quad.battery_voltage() > QuadParams::default().battery_empty);
+    }
+}
@@ -0,0 +1,76 @@
+//! Sensor readings of the simulated vehicle, in the units of the quadrupel drivers
+
+use eslgroup14::hal::{Accel, Gyro, Quaternion};
+use crate::model::{Quadrotor, rotate_inverse, GRAVITY};
+
+/// Accelerometer sensitivity in LSB per g
+const ACCEL_LSB: f32 = 16384.0;
+
+/// Gyroscope sensitivity in LSB per deg/s
+const GYRO_LSB: f32 = 16.4;
+
+/// Pressure at sea level in 10^-5 bar
+const PRESSURE_SEA_LEVEL: f32 = 101325.0;
+
+/// Temperature lapse rate in degrees Celsius per m
+const LAPSE_RATE: f32 = 0.0065;
+
+/// Orientation as the motion processor reports it
+pub fn dmp(quad: &Quadrotor) -> Quaternion {
+    let [w, x, y, z] = quad.state.attitude;
+    Quaternion { w, x, y, z }
+}
+
+/// Raw accelerometer and gyroscope sample. The accelerometer measures the specific force in the
+/// body frame, the gyroscope axes are the ones raw sensor mode reads: x the pitch rate, y the
+/// roll rate and z the yaw rate.
+pub fn raw(quad: &Quadrotor) -> (Accel, Gyro) {
+    let s = &quad.state;
+    let [ax, ay, az] = rotate_inverse(s.attitude, [s.acceleration[0], s.acceleration[1], s.acceleration[2] + GRAVITY]);
+    let to_accel = |a: f32| (a / GRAVITY * ACCEL_LSB) as i16;
+
+    let [wx, wy, wz] = s.angular_velocity;
+    let to_gyro = |rate: f32| (rate.to_degrees() * GYRO_LSB) as i16;
+
+    (
+        Accel { x: to_accel(ax), y: to_accel(ay), z: to_accel(az) },
+        Gyro { x: to_gyro(-wy), y: to_gyro(wx), z: to_gyro(-wz) },
+    )
+}
+
+/// Barometer pressure in 10^-5 bar at the altitude of the vehicle, the inverse of the
+/// barometric formula in raw sensor mode
+pub fn pressure(quad: &Quadrotor, temperature: i32) -> u32 {
+    let kelvin = temperature as f32 / 100.0 + 273.15;
+    (PRESSURE_SEA_LEVEL / (1.0 + LAPSE_RATE * quad.state.position[2] / kelvin).powf(5.257)).round() as u32
+}
+
+/// Battery voltage in 10 mV
+pub fn battery(quad: &Quadrotor) -> u16 {
+    (quad.battery_voltage() * 100.0).round() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::QuadParams;
+
+    #[test]
+    fn test_level_at_rest() {
+        let quad = Quadrotor::new(QuadParams::default());
+        let (accel, gyro) = raw(&quad);
+        assert_eq!(accel, Accel { x: 0, y: 0, z: 16384 });
+        assert_eq!(gyro, Gyro::default());
+        assert_eq!(pressure(&quad, 2000), 101325);
+        assert_eq!(battery(&quad), 1240);
+    }
+
+    #[test]
+    fn test_pressure_altitude() {
+        // About 12 Pa per m close to the ground
+        let mut quad = Quadrotor::new(QuadParams::default());
+        quad.state.position[2] = 10.0;
+        let drop = 101325 - pressure(&quad, 2000);
+        assert!((115..125).contains(&drop));
+    }
+}